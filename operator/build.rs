@@ -0,0 +1,11 @@
+//! Generates the gRPC service of `proto/operator.proto` with the `grpc` feature, with a
+//! vendored `protoc` so it doesn't have to be installed.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::configure().compile_protos(&["proto/operator.proto"], &["proto"])?;
+    }
+    Ok(())
+}
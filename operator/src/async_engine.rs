@@ -0,0 +1,351 @@
+//! Async engine for operator built on tokio.
+//!
+//! It manages the services like [Engine] does, but runs on a tokio runtime instead of
+//! blocking on its own event loop, so it can be embedded in async applications.
+//!
+//! NOTE: orphaned processes are not reaped, so it can't be used as an init.
+
+use std::{
+    os::{
+        fd::OwnedFd,
+        unix::{net::UnixDatagram, process::ExitStatusExt},
+    },
+    time::Instant,
+};
+
+use log::{error, info, warn};
+use nix::{
+    sys::{signal::Signal, wait::WaitStatus},
+    unistd::Pid,
+};
+use tokio::{
+    io::unix::AsyncFd,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+    time::sleep_until,
+};
+
+use crate::{
+    audit::Peer,
+    engine::{Engine, Event, Launcher},
+    executor::{Context, Executor, Job},
+    ipc::{self, AsyncIPCServer, AsyncIPCStream, IPCMessage, READ_TIMEOUT},
+    notify,
+    service::Service,
+    webhook::StateChange,
+    Error, Result,
+};
+
+/// A request from operatorctl, the credentials of its client and the channel to send
+/// its response on.
+type Request = (
+    IPCMessage,
+    Option<Peer>,
+    oneshot::Sender<Option<IPCMessage>>,
+);
+
+/// Service handler for operator running on tokio.
+pub struct AsyncEngine {
+    /// the engine doing the book-keeping of the services.
+    engine: Engine,
+    /// exits of the services, sent by the tasks waiting on them.
+    exits: mpsc::UnboundedReceiver<(i32, Option<WaitStatus>)>,
+    /// events of the sockets of the services, sent by the tasks watching them.
+    events: mpsc::UnboundedReceiver<Event>,
+}
+
+impl AsyncEngine {
+    /// Create a new engine.
+    pub fn new() -> Self {
+        let (tx, exits) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let mut engine = Engine::new();
+        engine.launcher = Launcher::Tokio(tx, events_tx);
+
+        Self {
+            engine,
+            exits,
+            events,
+        }
+    }
+
+    /// Start the engine and manage the services.
+    ///
+    /// SIGTERM and SIGINT are forwarded to the services, and this returns once all of them
+    /// exited.
+    pub async fn run(&mut self) -> Result<()> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+
+        // create an ipc server for comms b/w operator and operatorctl, before loading the
+        // services so they're not started twice when another operator is running.
+        let ipc_server = AsyncIPCServer::new()?;
+
+        self.engine.load();
+        self.engine.notify_changes();
+        let ipc_server = ipc_server.with_limits(self.engine.ipc.limits);
+        let (requests_tx, mut requests) = mpsc::unbounded_channel::<Request>();
+        let (subscriptions_tx, mut subscriptions) =
+            mpsc::unbounded_channel::<mpsc::UnboundedSender<StateChange>>();
+
+        // the clients of the query socket are accepted by a task of their own.
+        if let Some(path) = self.engine.ipc.query_socket.clone() {
+            let server = AsyncIPCServer::bind(&path).and_then(|server| {
+                ipc::open_to_all(&path)?;
+                Ok(server.with_limits(self.engine.ipc.limits))
+            });
+            match server {
+                Ok(server) => {
+                    let requests_tx = requests_tx.clone();
+                    let subscriptions_tx = subscriptions_tx.clone();
+                    tokio::spawn(async move {
+                        loop {
+                            match server.accept().await {
+                                Ok(stream) => {
+                                    _ = tokio::spawn(serve(
+                                        stream,
+                                        true,
+                                        requests_tx.clone(),
+                                        subscriptions_tx.clone(),
+                                    ))
+                                }
+                                Err(Error::Busy) => {}
+                                Err(e) => error!("Failed to accept a connection: {e}"),
+                            }
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to bind the query socket {}: {e}", path.display()),
+            }
+        }
+
+        loop {
+            let deadline = self.engine.reactor.next_deadline();
+
+            tokio::select! {
+                Some((pid, wait_stat)) = self.exits.recv() => match wait_stat {
+                    Some(wait_stat) => self.engine.handle_exit(pid, wait_stat),
+                    None => self.engine.handle_lost(pid),
+                },
+                Some(event) = self.events.recv() => match event {
+                    Event::Connection(name) => self.engine.activate(&name),
+                    Event::Notify(name, message) => self.engine.handle_notify(&name, &message),
+                    Event::Executed(pid) => self.engine.executed(pid),
+                },
+                stream = ipc_server.accept() => match stream {
+                    Ok(stream) => _ = tokio::spawn(serve(
+                        stream,
+                        false,
+                        requests_tx.clone(),
+                        subscriptions_tx.clone(),
+                    )),
+                    Err(Error::Busy) => {}
+                    Err(e) => error!("Failed to accept a connection: {e}"),
+                },
+                Some((msg, peer, tx)) = requests.recv() => match msg {
+                    // answered once the service reached the state.
+                    IPCMessage::WaitFor { name, state, timeout } => {
+                        self.engine.wait_for(&name, &state, timeout, move |info| {
+                            _ = tx.send(Some(IPCMessage::WaitForResponse(info)));
+                        });
+                    }
+                    msg => _ = tx.send(self.engine.handle_message(msg, peer)),
+                },
+                Some(changes) = subscriptions.recv() => {
+                    self.engine.subscribe(move |change| changes.send(change.clone()).is_ok());
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                    for token in self.engine.reactor.expired() {
+                        self.engine.handle_timer(token);
+                    }
+                }
+                _ = sigterm.recv() => self.engine.handle_signal(Signal::SIGTERM as i32),
+                _ = sigint.recv() => self.engine.handle_signal(Signal::SIGINT as i32),
+            }
+
+            self.engine.notify_changes();
+            if self.engine.is_done() {
+                info!("All services exited.");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Read the request of a client without blocking the engine, and send the response
+/// once it's handled, only a query if it's a client of the query socket.
+async fn serve(
+    mut stream: AsyncIPCStream,
+    query: bool,
+    requests_tx: mpsc::UnboundedSender<Request>,
+    subscriptions_tx: mpsc::UnboundedSender<mpsc::UnboundedSender<StateChange>>,
+) -> Result<()> {
+    let Ok(msg) = tokio::time::timeout(READ_TIMEOUT, stream.read()).await else {
+        warn!("Dropping an IPC client that sent no request in time");
+        return Ok(());
+    };
+    let msg = match msg {
+        Ok(msg) => msg,
+        // e.g. a client that only wanted the greeting of operator.
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => {
+            warn!("Dropping an IPC client that sent no valid request: {e}");
+            return Ok(());
+        }
+    };
+    // the clients of the query socket can't act on the services.
+    if query && !msg.is_query() {
+        return stream.write(&IPCMessage::ReadOnly).await;
+    }
+    if let IPCMessage::Subscribe = msg {
+        let (tx, mut changes) = mpsc::unbounded_channel();
+        _ = subscriptions_tx.send(tx);
+        while let Some(change) = changes.recv().await {
+            stream.write(&IPCMessage::Event(change)).await?;
+        }
+        return Ok(());
+    }
+
+    let (tx, rx) = oneshot::channel();
+    _ = requests_tx.send((msg, stream.peer(), tx));
+
+    if let Ok(Some(response)) = rx.await {
+        stream.write(&response).await?;
+    }
+    Ok(())
+}
+
+impl Default for AsyncEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Spawn the executor of a service and send its exit on `exits`, and on `events` when
+/// its error pipe is readable.
+///
+/// Returns its pid and its error pipe.
+pub(crate) fn spawn(
+    service: &Service,
+    exits: mpsc::UnboundedSender<(i32, Option<WaitStatus>)>,
+    events: mpsc::UnboundedSender<Event>,
+) -> std::io::Result<(i32, OwnedFd)> {
+    let executor = Executor::new(&Job::Start(Context::new(service)))?;
+    let (mut command, errors) = executor.command()?;
+    let mut child = command.spawn()?;
+    drop(executor);
+    let pid = child.id().unwrap() as i32;
+
+    let watched = AsyncFd::new(errors.try_clone()?)?;
+    tokio::spawn(async move {
+        if let Err(e) = watched.readable().await {
+            error!("Failed to wait for the start of PID {pid}: {e}");
+        }
+        _ = events.send(Event::Executed(pid));
+    });
+
+    tokio::spawn(async move {
+        let wait_stat = match child.wait().await {
+            Ok(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => WaitStatus::Exited(Pid::from_raw(pid), code),
+                (_, Some(signo)) => WaitStatus::Signaled(
+                    Pid::from_raw(pid),
+                    Signal::try_from(signo).unwrap_or(Signal::SIGKILL),
+                    status.core_dumped(),
+                ),
+                _ => return,
+            },
+            Err(e) => {
+                error!("Failed to wait for PID {pid}: {e}");
+                return;
+            }
+        };
+
+        _ = exits.send((pid, Some(wait_stat)));
+    });
+
+    Ok((pid, errors))
+}
+
+/// Send the exit of an adopted process on `exits` once its pidfd is readable.
+pub(crate) fn watch(
+    pid: i32,
+    pidfd: OwnedFd,
+    exits: mpsc::UnboundedSender<(i32, Option<WaitStatus>)>,
+) {
+    tokio::spawn(async move {
+        let pidfd = match AsyncFd::new(pidfd) {
+            Ok(pidfd) => pidfd,
+            Err(e) => {
+                error!("Failed to watch PID {pid}: {e}");
+                return;
+            }
+        };
+
+        if let Err(e) = pidfd.readable().await {
+            error!("Failed to wait for PID {pid}: {e}");
+            return;
+        }
+        _ = exits.send((pid, None));
+    });
+}
+
+/// Send a connection event on `events` once the socket of a service is readable.
+pub(crate) fn activate(name: String, fd: OwnedFd, events: mpsc::UnboundedSender<Event>) {
+    tokio::spawn(async move {
+        let fd = match AsyncFd::new(fd) {
+            Ok(fd) => fd,
+            Err(e) => {
+                error!("Failed to watch the socket of {name}: {e}");
+                return;
+            }
+        };
+
+        if let Err(e) = fd.readable().await {
+            error!("Failed to wait for a connection to {name}: {e}");
+            return;
+        }
+        _ = events.send(Event::Connection(name));
+    });
+}
+
+/// Send the notifications received on the notification socket of a service on `events`.
+pub(crate) fn notifications(
+    name: String,
+    socket: UnixDatagram,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    tokio::spawn(async move {
+        let socket = match AsyncFd::new(socket) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Failed to watch the notification socket of {name}: {e}");
+                return;
+            }
+        };
+
+        loop {
+            let mut guard = match socket.readable().await {
+                Ok(guard) => guard,
+                Err(e) => {
+                    error!("Failed to wait for a notification from {name}: {e}");
+                    return;
+                }
+            };
+            // the readiness is cleared once the socket is drained.
+            let mut buf = [0; notify::MAX_MESSAGE];
+            while let Ok(received) = guard.try_io(|socket| socket.get_ref().recv(&mut buf)) {
+                let message = match received {
+                    Ok(n) => String::from_utf8_lossy(&buf[..n]).into_owned(),
+                    Err(e) => {
+                        error!("Failed to receive a notification from {name}: {e}");
+                        return;
+                    }
+                };
+                if events.send(Event::Notify(name.clone(), message)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,182 @@
+//! Audit log of the control actions.
+//!
+//! Every request acting on the services, e.g. a start or a stop, is appended as a JSON
+//! line to `audit.jsonl` in the state dir, with the uid and pid of the client that sent
+//! it, read from the IPC socket, and its outcome. It's reviewed with `operatorctl audit`.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helper::{create_state_dir, op_state_dir},
+    ipc::{BulkAction, IPCMessage},
+    power::PowerAction,
+    Result,
+};
+
+/// Credentials of an IPC client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub uid: u32,
+    pub pid: i32,
+}
+
+/// What came of a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// The request was carried out
+    Done,
+    /// The service is not loaded, or no service was matched
+    NotFound,
+    /// The request was refused, for the given reason
+    Rejected(String),
+}
+
+/// An entry of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the request was handled, in seconds since the epoch
+    pub timestamp: u64,
+    /// User of the client, if its credentials could be read
+    pub uid: Option<u32>,
+    /// Process of the client
+    pub pid: Option<i32>,
+    /// The request, e.g. `stop`
+    pub action: String,
+    /// The service acted on, or the pattern of a bulk action
+    pub service: Option<String>,
+    /// Arguments of the request, e.g. the properties set
+    pub detail: Option<String>,
+    pub outcome: Outcome,
+}
+
+impl AuditEntry {
+    /// Entry of a request sent by `peer`, if it acts on the services.
+    ///
+    /// Its outcome is set once it's handled.
+    pub(crate) fn of(msg: &IPCMessage, peer: Option<Peer>) -> Option<Self> {
+        let (action, service, detail) = match msg {
+            IPCMessage::Start { name } => ("start", Some(name), None),
+            IPCMessage::Stop { name } => ("stop", Some(name), None),
+            IPCMessage::Restart { name } => ("restart", Some(name), None),
+            IPCMessage::ReloadService { name } => ("reload", Some(name), None),
+            IPCMessage::Enable { name } => ("enable", Some(name), None),
+            IPCMessage::Disable { name } => ("disable", Some(name), None),
+            IPCMessage::Mask { name } => ("mask", Some(name), None),
+            IPCMessage::Unmask { name } => ("unmask", Some(name), None),
+            IPCMessage::ReloadFile { name } => ("reload-file", Some(name), None),
+            IPCMessage::Scale { name, replicas } => {
+                ("scale", Some(name), Some(replicas.to_string()))
+            }
+            IPCMessage::SetProperty {
+                name,
+                properties,
+                persist,
+            } => {
+                let mut detail = properties
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if *persist {
+                    detail.push_str(" (persisted)");
+                }
+                ("set-property", Some(name), Some(detail))
+            }
+            IPCMessage::Exec { name, command } => ("exec", Some(name), Some(command.join(" "))),
+            // attaching only to read the output doesn't act on the service.
+            IPCMessage::Attach { name, stdin: true } => {
+                ("attach", Some(name), Some("stdin".to_string()))
+            }
+            IPCMessage::Bulk { action, filter } => {
+                let action = match action {
+                    BulkAction::Start => "start",
+                    BulkAction::Stop => "stop",
+                    BulkAction::Restart => "restart",
+                };
+                let tags =
+                    (!filter.tags.is_empty()).then(|| format!("tags {}", filter.tags.join(",")));
+                (action, filter.pattern.as_ref(), tags)
+            }
+            IPCMessage::ReExec => ("re-exec", None, None),
+            IPCMessage::Power(PowerAction::PowerOff) => ("poweroff", None, None),
+            IPCMessage::Power(PowerAction::Reboot) => ("reboot", None, None),
+            _ => return None,
+        };
+
+        Some(Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            uid: peer.map(|peer| peer.uid),
+            pid: peer.map(|peer| peer.pid),
+            action: action.to_string(),
+            service: service.cloned(),
+            detail,
+            outcome: Outcome::Done,
+        })
+    }
+}
+
+/// Outcome of a request from its response, `found` being whether its service is loaded.
+pub(crate) fn outcome(response: Option<&IPCMessage>, found: bool) -> Outcome {
+    match response {
+        Some(IPCMessage::BulkResponse(names)) if names.is_empty() => Outcome::NotFound,
+        Some(IPCMessage::BulkResponse(_)) => Outcome::Done,
+        Some(
+            IPCMessage::SetPropertyResponse(Some(Err(e)))
+            | IPCMessage::ReloadFileResponse(Some(Err(e)))
+            | IPCMessage::ExecResponse(Some(Err(e)))
+            | IPCMessage::AttachResponse(Some(Err(e)))
+            | IPCMessage::PowerResponse(Err(e)),
+        ) => Outcome::Rejected(e.clone()),
+        Some(IPCMessage::Error(e)) if found => Outcome::Rejected(e.clone()),
+        _ if !found => Outcome::NotFound,
+        _ => Outcome::Done,
+    }
+}
+
+/// Path of the audit log.
+fn path() -> PathBuf {
+    PathBuf::from(format!("{}/audit.jsonl", op_state_dir()))
+}
+
+/// Append an entry to the audit log.
+pub fn record(entry: &AuditEntry) -> Result<()> {
+    let path = path();
+    create_state_dir()?;
+
+    let mut line = serde_json::to_string(entry).map_err(io::Error::other)?;
+    line.push('\n');
+    // a single write, so concurrent entries are not interleaved.
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// The last `limit` entries of the audit log, the oldest first.
+pub fn read(limit: usize) -> Result<Vec<AuditEntry>> {
+    let contents = match std::fs::read_to_string(path()) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    // a line cut short by a crash of operator is skipped.
+    let entries = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect::<Vec<_>>();
+    Ok(entries[entries.len().saturating_sub(limit)..].to_vec())
+}
@@ -0,0 +1,197 @@
+//! Backends running the program of the services.
+//!
+//! The `type` of a service selects its backend. The engine only forks, tracks and
+//! signals the process of a service, the backend decides what that process executes.
+
+use std::{ffi::CString, io, path::PathBuf};
+
+use log::warn;
+
+use nix::sys::signal::Signal;
+
+use crate::{
+    container::ContainerBackend,
+    sandbox,
+    service::{Service, ServiceType},
+    state,
+};
+
+/// Shell running the `command` of the services.
+const SHELL: &str = "/bin/sh";
+
+/// Runs the program of a service.
+pub trait ServiceBackend: Sync {
+    /// Check the keys of a loaded service and set the executable it runs.
+    fn load(&self, service: &mut Service) -> Result<(), String>;
+
+    /// Arguments the executable is run with.
+    fn args(&self, service: &Service) -> Option<Vec<CString>> {
+        service.args.clone()
+    }
+
+    /// Prepare the program right before it's executed, once the process is set up.
+    ///
+    /// This should only be run in the context of a forked child process.
+    fn prepare(&self, _service: &Service) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Ask the running service to stop.
+    fn stop(&self, service: &Service) {
+        service.signal_stop(Signal::SIGTERM);
+    }
+
+    /// Properties of the service specific to the backend, shown by `operatorctl show`.
+    fn properties(&self, _service: &Service) -> Vec<(String, String)> {
+        vec![]
+    }
+}
+
+/// Backend of a service type.
+pub fn of(service_type: ServiceType) -> &'static dyn ServiceBackend {
+    match service_type {
+        ServiceType::Exec => &ExecBackend,
+        ServiceType::Shell => &ShellBackend,
+        ServiceType::Container => &ContainerBackend,
+        ServiceType::Forking => &ForkingBackend,
+    }
+}
+
+/// Error for a key set for a backend that doesn't use it.
+pub(crate) fn unused_key(key: &str, service_type: &str) -> String {
+    format!("`{key}` can't be set for a service of type \"{service_type}\"")
+}
+
+/// Forks and executes the executable with its args.
+pub struct ExecBackend;
+
+impl ServiceBackend for ExecBackend {
+    fn load(&self, service: &mut Service) -> Result<(), String> {
+        if service.image.is_some() {
+            return Err(unused_key("image", "exec"));
+        }
+        if service.pid_file.is_some() {
+            return Err(unused_key("pid_file", "exec"));
+        }
+        if service.executable.as_os_str().is_empty() {
+            return Err("missing field `executable` or `command`".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `command` with `/bin/sh -c`.
+///
+/// The shell is the program executed, so it's the process of the service.
+pub struct ShellBackend;
+
+impl ServiceBackend for ShellBackend {
+    fn load(&self, service: &mut Service) -> Result<(), String> {
+        if service.command.is_none() {
+            return Err("missing field `command`".to_string());
+        }
+        if service.image.is_some() {
+            return Err(unused_key("image", "shell"));
+        }
+        if service.pid_file.is_some() {
+            return Err(unused_key("pid_file", "shell"));
+        }
+        shell(service)
+    }
+}
+
+/// Set the shell running the `command` of a service as its executable.
+fn shell(service: &mut Service) -> Result<(), String> {
+    let Some(ref command) = service.command else {
+        return Ok(());
+    };
+    if !service.executable.as_os_str().is_empty() || service.args.is_some() {
+        return Err("`command` can't be set along with `executable` or `args`".to_string());
+    }
+
+    let command =
+        CString::new(command.as_str()).map_err(|_| "`command` contains a nul byte".to_string())?;
+    service.executable = PathBuf::from(SHELL);
+    service.args = Some(vec![CString::new("-c").unwrap(), command]);
+    Ok(())
+}
+
+/// Runs a daemon that forks into the background, with its executable or `command`.
+///
+/// The process started exits once it forked the daemon, which has to write its pid to
+/// the `pid_file` before. The engine tracks that pid from then on, the service is
+/// ready once it's read.
+pub struct ForkingBackend;
+
+impl ForkingBackend {
+    /// Pid of the daemon written to the pidfile of a service.
+    ///
+    /// A pidfile of a process that is not running is stale.
+    pub(crate) fn main_pid(service: &Service) -> Result<i32, String> {
+        let path = Self::pid_file(service).ok_or("no `pid_file`")?;
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let pid = contents
+            .trim()
+            .parse::<i32>()
+            .ok()
+            .filter(|pid| *pid > 0)
+            .ok_or_else(|| format!("{} doesn't contain a pid", path.display()))?;
+
+        // a zombie exited already, its parent just didn't reap it yet.
+        match state::proc_stat(pid) {
+            Some(stat) if stat.state != 'Z' => Ok(pid),
+            _ => Err(format!(
+                "{} is stale, PID {pid} is not running",
+                path.display()
+            )),
+        }
+    }
+
+    /// Remove the pidfile left by a previous run of a service, so it's not mistaken
+    /// for the one of the daemon about to be started.
+    pub(crate) fn remove_pid_file(service: &Service) {
+        let Some(path) = Self::pid_file(service) else {
+            return;
+        };
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove the pidfile {}: {e}", path.display())
+            }
+            _ => {}
+        }
+    }
+
+    /// Path of the pidfile, in the root dir of the service.
+    fn pid_file(service: &Service) -> Option<PathBuf> {
+        let root = service.root_dir.as_deref().unwrap_or("/".as_ref());
+        Some(sandbox::in_root(root, service.pid_file.as_deref()?))
+    }
+}
+
+impl ServiceBackend for ForkingBackend {
+    fn load(&self, service: &mut Service) -> Result<(), String> {
+        if service.pid_file.is_none() {
+            return Err("missing field `pid_file`".to_string());
+        }
+        if service.image.is_some() {
+            return Err(unused_key("image", "forking"));
+        }
+        // the daemon is ready once its pid is read.
+        if service.notify {
+            return Err(unused_key("notify", "forking"));
+        }
+        if service.command.is_none() && service.executable.as_os_str().is_empty() {
+            return Err("missing field `executable` or `command`".to_string());
+        }
+        shell(service)
+    }
+
+    fn properties(&self, service: &Service) -> Vec<(String, String)> {
+        service
+            .pid_file
+            .iter()
+            .map(|path| ("pid_file".to_string(), path.display().to_string()))
+            .collect()
+    }
+}
@@ -1,10 +1,117 @@
-use operator::{engine::Engine, log};
+use std::path::PathBuf;
+
+use ::log::error;
+use clap::{Parser, Subcommand};
+use operator::{daemon, engine::Engine, executor, foreground, helper, log, Error};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Cli {
+    /// Run as an init: reap orphaned processes and forward SIGTERM/SIGINT to the services
+    #[arg(long, conflicts_with_all = ["user", "daemonize"])]
+    init: bool,
+    /// Supervise the services of the current user, with the dirs and the socket in its home
+    #[arg(long)]
+    user: bool,
+    /// Run in the background, detached from the terminal, and write the pid to a pidfile
+    #[arg(long)]
+    daemonize: bool,
+    /// Pidfile of --daemonize, defaults to /run/operator.pid
+    #[arg(long, requires = "daemonize")]
+    pid_file: Option<PathBuf>,
+    /// Resume supervising the services of the operator that re-executed itself
+    #[arg(long, hide = true)]
+    resume: bool,
+    /// Stop the operator already serving the socket and take its place
+    #[arg(long)]
+    takeover: bool,
+    /// Load and check the service files, print the order they are started in and exit
+    #[arg(long, conflicts_with_all = ["init", "daemonize", "resume", "takeover"])]
+    dry_run: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a single service in the foreground, with its output on the terminal, to debug it
+    Run {
+        /// Name of the service, or path of its service file
+        service: String,
+    },
+}
 
 fn main() {
+    // the processes of the services are set up by operator executed again.
+    executor::run_if_executor();
+
+    let cli = Cli::parse();
+    helper::set_user_mode(cli.user);
+
+    if cli.dry_run {
+        std::process::exit(dry_run());
+    }
+
     // setup logging
     log::init().unwrap();
 
+    if let Some(Command::Run { service }) = cli.command {
+        match foreground::run(&service) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                eprintln!("Failed to run {service}: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // a re-executed operator is already a daemon, with the same pid.
+    if cli.daemonize && !cli.resume {
+        let pid_file = cli.pid_file.unwrap_or_else(daemon::default_pid_file);
+        if let Err(e) = daemon::daemonize(&pid_file) {
+            eprintln!("Failed to daemonize: {e}");
+            std::process::exit(1);
+        }
+    }
+
     // create an engine
     let mut engine = Engine::new();
-    engine.run();
+    engine.set_init(cli.init);
+    engine.set_resume(cli.resume);
+    engine.set_take_over(cli.takeover);
+    match engine.run() {
+        Ok(()) => {}
+        Err(e @ Error::AlreadyRunning(_)) => {
+            error!("{e}, run with --takeover to replace it");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print the boot order and the errors of a dry run, returns the exit code.
+fn dry_run() -> i32 {
+    let dry_run = Engine::dry_run();
+    let width = dry_run
+        .order
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or_default();
+    println!("Start order:");
+    for (i, (name, start)) in dry_run.order.iter().enumerate() {
+        println!("{:>4}. {name:<width$}  {start}", i + 1);
+    }
+    if dry_run.errors.is_empty() {
+        return 0;
+    }
+    println!("Errors:");
+    for error in &dry_run.errors {
+        // e.g. a parse error pointing to the line of a file.
+        println!("      {}", error.trim_end().replace('\n', "\n      "));
+    }
+    1
 }
@@ -0,0 +1,167 @@
+//! Linux capabilities of the services.
+//!
+//! A service with `capabilities` keeps only the listed ones, even after switching to
+//! its `user`, and can't gain the others back through setuid or file capabilities.
+
+use std::io;
+
+use nix::libc;
+use serde::{Deserialize, Serialize};
+
+/// Names of the capabilities, indexed by their number.
+const NAMES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// `_LINUX_CAPABILITY_VERSION_3`, with 64 bits capability sets.
+const VERSION: u32 = 0x20080522;
+
+/// A Linux capability, written as its name (`"CAP_NET_BIND_SERVICE"`)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Capability(pub u8);
+
+impl TryFrom<String> for Capability {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        let upper = name.to_uppercase();
+        let full = match upper.starts_with("CAP_") {
+            true => upper,
+            false => format!("CAP_{upper}"),
+        };
+        NAMES
+            .iter()
+            .position(|cap| *cap == full)
+            .map(|cap| Capability(cap as u8))
+            .ok_or_else(|| format!("unknown capability `{name}`"))
+    }
+}
+
+impl From<Capability> for String {
+    fn from(cap: Capability) -> Self {
+        cap.to_string()
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match NAMES.get(self.0 as usize) {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn prctl(option: libc::c_int, arg: libc::c_ulong) -> io::Result<()> {
+    if unsafe { libc::prctl(option, arg, 0, 0, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Drop the capabilities that are not in `caps` from the bounding set, so they can't be
+/// gained back, and keep the others across the switch to the user of the service.
+///
+/// This should be run before dropping privileges, and [restrict] after.
+pub fn bound(caps: &[Capability]) -> io::Result<()> {
+    let last = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last| last.trim().parse::<u8>().ok())
+        .unwrap_or(NAMES.len() as u8 - 1);
+
+    for cap in (0..=last).filter(|cap| !caps.contains(&Capability(*cap))) {
+        prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong)?;
+    }
+    prctl(libc::PR_SET_KEEPCAPS, 1)
+}
+
+/// Set the capabilities of the current process to `caps` and make them ambient so they
+/// are kept across exec, even by a program that is not run as root.
+pub fn restrict(caps: &[Capability]) -> io::Result<()> {
+    let mut data = [CapData::default(); 2];
+    for Capability(cap) in caps {
+        let set = &mut data[*cap as usize / 32];
+        let bit = 1 << (cap % 32);
+        set.effective |= bit;
+        set.permitted |= bit;
+        set.inheritable |= bit;
+    }
+
+    let mut header = CapHeader {
+        version: VERSION,
+        pid: 0,
+    };
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for Capability(cap) in caps {
+        let raise = libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong;
+        if unsafe { libc::prctl(libc::PR_CAP_AMBIENT, raise, *cap as libc::c_ulong, 0, 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Prevent the current process and its children from gaining privileges through
+/// setuid binaries or file capabilities.
+pub fn no_new_privs() -> io::Result<()> {
+    prctl(libc::PR_SET_NO_NEW_PRIVS, 1)
+}
@@ -0,0 +1,97 @@
+//! cgroup v2 support.
+//!
+//! Every service runs in its own cgroup under [op_cgroup_root], which holds its resource
+//! controls and tracks all of its processes, even the ones that left its process group.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::{helper::op_cgroup_root, service::Service};
+
+/// Controllers used by the resource controls of the services.
+const CONTROLLERS: &str = "+cpu +memory +pids";
+
+/// Path of the cgroup of a service.
+pub fn path(name: &str) -> PathBuf {
+    PathBuf::from(op_cgroup_root()).join(name)
+}
+
+/// Create the cgroup of a service and apply its resource controls.
+///
+/// Controls that can't be applied, e.g. when their controller is not available, are
+/// skipped.
+pub fn create(service: &Service) -> io::Result<PathBuf> {
+    let root = PathBuf::from(op_cgroup_root());
+
+    // cgroup.procs is created along with every cgroup, its absence means it's not a
+    // cgroup v2 hierarchy.
+    let is_cgroup = |path: &Path| path.join("cgroup.procs").is_file();
+    if !is_cgroup(&root) && !root.parent().is_some_and(is_cgroup) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is not in a cgroup v2 hierarchy", root.display()),
+        ));
+    }
+    std::fs::create_dir_all(&root)?;
+
+    // controllers have to be enabled in every ancestor of the cgroup. the ones that are
+    // not available are skipped, the controls using them will fail to be written.
+    if let Some(parent) = root.parent() {
+        _ = std::fs::write(parent.join("cgroup.subtree_control"), CONTROLLERS);
+    }
+    _ = std::fs::write(root.join("cgroup.subtree_control"), CONTROLLERS);
+
+    let path = path(&service.name);
+    std::fs::create_dir_all(&path)?;
+
+    let controls = [
+        ("memory.max", service.memory_max.map(|max| max.to_string())),
+        (
+            "cpu.weight",
+            service.cpu_weight.map(|weight| weight.to_string()),
+        ),
+        ("cpu.max", service.cpu_max.clone()),
+        ("pids.max", service.pids_max.map(|max| max.to_string())),
+    ];
+    for (file, value) in controls {
+        // unset controls are reset, in case they were set by a previous start.
+        let value = value.unwrap_or_else(|| default(file).to_string());
+        if let Err(e) = std::fs::write(path.join(file), &value) {
+            if value != default(file) {
+                warn!("Failed to set {file} of service {}: {e}", service.name);
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+/// Default value of a resource control.
+fn default(file: &str) -> &'static str {
+    match file {
+        "cpu.weight" => "100",
+        "cpu.max" => "max 100000",
+        _ => "max",
+    }
+}
+
+/// Move the current process to a cgroup.
+pub fn join(path: &Path) -> io::Result<()> {
+    std::fs::write(path.join("cgroup.procs"), "0")
+}
+
+/// Pids of the processes in a cgroup.
+pub fn procs(path: &Path) -> Vec<i32> {
+    std::fs::read_to_string(path.join("cgroup.procs"))
+        .map(|procs| procs.lines().filter_map(|pid| pid.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Kill all the processes in a cgroup with SIGKILL.
+pub fn kill(path: &Path) -> io::Result<()> {
+    std::fs::write(path.join("cgroup.kill"), "1")
+}
@@ -0,0 +1,132 @@
+//! Failure injection, to check that the restart policies and the alerting of the
+//! services work.
+//!
+//! With the `chaos` feature, operator injects faults in its supervision when `[chaos]`
+//! is set in `operator.toml`, or `OP_CHAOS=1` with the default chances: the restarts
+//! are delayed further, the processes of the services are started late like slow
+//! children, and the responses to the IPC clients are dropped, the clients seeing the
+//! connection closed. Library users script their own faults with [Faults] and
+//! [Engine::set_faults](crate::engine::Engine::set_faults).
+
+use std::{collections::hash_map::RandomState, hash::BuildHasher, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ipc::IPCMessage;
+
+/// Faults injected by the engine, none by default
+pub trait Faults: Send {
+    /// Delay added to the restart delay of a service that exited.
+    fn restart_delay(&mut self, _service: &str) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Delay before the process of a service is started, as if it was slow to start.
+    fn start_delay(&mut self, _service: &str) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Whether the response to a request is dropped. The request is still handled.
+    fn drop_response(&mut self, _request: &IPCMessage) -> bool {
+        false
+    }
+}
+
+/// Config of the chaos mode, `[chaos]` in `operator.toml`
+///
+/// The chances are between 0 and 1, and the injected delays are picked up to their
+/// maximum.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosConfig {
+    /// Chance a restart is delayed further
+    pub restart_delay_chance: f64,
+    /// Maximum delay added to a restart, in milliseconds
+    pub max_restart_delay_ms: u64,
+    /// Chance the process of a service is started late
+    pub slow_start_chance: f64,
+    /// Maximum delay before the process of a service is started, in milliseconds
+    pub max_slow_start_ms: u64,
+    /// Chance the response to a request is dropped
+    pub drop_response_chance: f64,
+    /// Seed of the faults, to inject the same ones again, random if unset
+    pub seed: Option<u64>,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            restart_delay_chance: 0.5,
+            max_restart_delay_ms: 5000,
+            slow_start_chance: 0.2,
+            max_slow_start_ms: 3000,
+            drop_response_chance: 0.1,
+            seed: None,
+        }
+    }
+}
+
+/// Whether the chaos mode is enabled by `OP_CHAOS=1`.
+pub fn enabled_by_env() -> bool {
+    std::env::var("OP_CHAOS").is_ok_and(|chaos| chaos == "1")
+}
+
+/// Random faults of a [ChaosConfig]
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    config: ChaosConfig,
+    /// state of the xorshift generator, never 0.
+    state: u64,
+}
+
+impl Chaos {
+    /// Faults of `config`, picked with its seed.
+    pub fn new(config: ChaosConfig) -> Self {
+        let seed = config
+            .seed
+            .unwrap_or_else(|| RandomState::new().hash_one("chaos"));
+        Self {
+            config,
+            state: seed | 1,
+        }
+    }
+
+    /// Next random number of the generator.
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Whether a fault of the given chance happens.
+    fn happens(&mut self, chance: f64) -> bool {
+        // the 53 bits of precision of a f64.
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < chance
+    }
+
+    /// A delay up to `max_ms` milliseconds, if a fault of the given chance happens.
+    fn delay(&mut self, chance: f64, max_ms: u64) -> Duration {
+        match self.happens(chance) {
+            true => Duration::from_millis(self.next() % max_ms.saturating_add(1)),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+impl Faults for Chaos {
+    fn restart_delay(&mut self, _service: &str) -> Duration {
+        self.delay(
+            self.config.restart_delay_chance,
+            self.config.max_restart_delay_ms,
+        )
+    }
+
+    fn start_delay(&mut self, _service: &str) -> Duration {
+        self.delay(self.config.slow_start_chance, self.config.max_slow_start_ms)
+    }
+
+    fn drop_response(&mut self, _request: &IPCMessage) -> bool {
+        self.happens(self.config.drop_response_chance)
+    }
+}
@@ -0,0 +1,674 @@
+//! Client controlling operator over its IPC socket, through `operatorctl --stdio` run
+//! over SSH, or over TLS with the `remote` feature.
+//!
+//! Each request is sent on its own connection, like `operatorctl` does.
+
+use std::{
+    io,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    time::Duration,
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+use crate::{
+    audit::AuditEntry,
+    helper::{op_socket_path, user_mode},
+    history::HistoryEntry,
+    ipc::{self, BulkAction, Frame, Hello, IPCMessage, IPCStream, LOCAL_ONLY},
+    journal::EventQuery,
+    power::PowerAction,
+    service::{BootTiming, Edge, Filter, LoadError, ServiceInfo},
+    webhook::StateChange,
+    Error, Result,
+};
+
+/// Client of a running operator.
+#[derive(Debug, Clone)]
+pub struct Client {
+    socket: PathBuf,
+    /// SSH destination of the host of the operator, reached instead of the socket.
+    ssh: Option<String>,
+    /// operator of another host, reached instead of the one of the socket.
+    #[cfg(feature = "remote")]
+    remote: Option<crate::remote::Remote>,
+}
+
+/// A connection to operator.
+enum Stream {
+    Local(IPCStream),
+    Ssh(Ssh),
+    #[cfg(feature = "remote")]
+    Remote(Box<crate::remote::TlsStream>),
+}
+
+impl Stream {
+    /// Read the greeting of operator, an error if it refused the client instead.
+    ///
+    /// NOTE: the greeting is read by [IPCStream] itself on the socket.
+    fn read_greeting(&mut self) -> Result<()> {
+        let greeting = match self {
+            Stream::Local(_) => return Ok(()),
+            Stream::Ssh(ssh) => match ipc::read_greeting(&mut ssh.stdout) {
+                Err(Error::Incompatible(None)) => {
+                    Err(ssh.failure().unwrap_or(Error::Incompatible(None)))
+                }
+                greeting => greeting,
+            },
+            #[cfg(feature = "remote")]
+            Stream::Remote(stream) => ipc::read_greeting(stream.as_mut()),
+        };
+        match greeting? {
+            Frame::Hello(hello) => hello.check(),
+            Frame::Message(msg) => Err(unexpected(*msg)),
+        }
+    }
+
+    fn read(&mut self) -> Result<IPCMessage> {
+        match self {
+            Stream::Local(stream) => stream.read(),
+            Stream::Ssh(ssh) => match ipc::read_message(&mut ssh.stdout) {
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    Err(ssh.failure().unwrap_or(Error::Io(e)))
+                }
+                result => result,
+            },
+            #[cfg(feature = "remote")]
+            Stream::Remote(stream) => ipc::read_message(stream.as_mut()),
+        }
+    }
+}
+
+/// A connection through `operatorctl --stdio` run on another host with `ssh`.
+struct Ssh {
+    destination: String,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Ssh {
+    /// Run `operatorctl --stdio` on `destination`, with `--user` in user mode.
+    fn connect(destination: &str) -> Result<Self> {
+        let mut command = Command::new("ssh");
+        // no terminal, the connection carries the messages as they are.
+        command.args(["-T", destination, "operatorctl", "--stdio"]);
+        if user_mode() {
+            command.arg("--user");
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        Ok(Self {
+            destination: destination.to_string(),
+            stdin: child.stdin.take().expect("stdin is piped"),
+            stdout: child.stdout.take().expect("stdout is piped"),
+            child,
+        })
+    }
+
+    /// Error of `ssh` if it exited with a failure, e.g. when the host can't be reached.
+    ///
+    /// NOTE: it waits for `ssh` to exit, it must be done with the connection.
+    fn failure(&mut self) -> Option<Error> {
+        let status = self.child.wait().ok()?;
+        (!status.success()).then(|| {
+            let e = format!("ssh {} exited with {status}", self.destination);
+            io::Error::other(e).into()
+        })
+    }
+}
+
+impl Drop for Ssh {
+    fn drop(&mut self) {
+        // a subscription or an attachment is only ended by the client.
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    /// Client of the operator listening on [op_socket_path].
+    pub fn new() -> Self {
+        Self::with_socket(op_socket_path())
+    }
+
+    /// Client of the operator listening on `socket`.
+    pub fn with_socket(socket: impl AsRef<Path>) -> Self {
+        Self {
+            socket: socket.as_ref().to_path_buf(),
+            ssh: None,
+            #[cfg(feature = "remote")]
+            remote: None,
+        }
+    }
+
+    /// Client of the operator of the host `destination` of `ssh`, e.g. `admin@mybox`,
+    /// through `operatorctl --stdio`.
+    ///
+    /// [Client::exec] and attaching to the stdin of a service are refused by it.
+    pub fn ssh(destination: &str) -> Self {
+        Self {
+            ssh: Some(destination.to_string()),
+            ..Self::new()
+        }
+    }
+
+    /// Client of the operator of another host.
+    ///
+    /// [Client::exec] and attaching to the stdin of a service are refused by it.
+    #[cfg(feature = "remote")]
+    pub fn remote(remote: crate::remote::Remote) -> Self {
+        Self {
+            remote: Some(remote),
+            ..Self::new()
+        }
+    }
+
+    /// Whether operator is reached on its socket.
+    fn is_local(&self) -> bool {
+        #[cfg(feature = "remote")]
+        if self.remote.is_some() {
+            return false;
+        }
+        self.ssh.is_none()
+    }
+
+    /// Connect and send a message, after the greeting of the client.
+    fn send(&self, msg: &IPCMessage) -> Result<Stream> {
+        #[cfg(feature = "remote")]
+        if let Some(ref remote) = self.remote {
+            let mut stream = remote.connect()?;
+            ipc::write_message(&mut stream, msg)?;
+            let mut stream = Stream::Remote(Box::new(stream));
+            stream.read_greeting()?;
+            return Ok(stream);
+        }
+        if let Some(ref destination) = self.ssh {
+            let mut ssh = Ssh::connect(destination)?;
+            let sent =
+                ipc::greet(&mut ssh.stdin).and_then(|()| ipc::write_message(&mut ssh.stdin, msg));
+            if let Err(e) = sent {
+                return Err(ssh.failure().unwrap_or(e));
+            }
+            let mut stream = Stream::Ssh(ssh);
+            stream.read_greeting()?;
+            return Ok(stream);
+        }
+        self.send_local(msg).map(Stream::Local)
+    }
+
+    /// Greeting of operator, with the version of its protocol and its features.
+    pub fn hello(&self) -> Result<Hello> {
+        match self.greet()? {
+            Frame::Hello(hello) => Ok(hello),
+            Frame::Message(msg) => Err(unexpected(*msg)),
+        }
+    }
+
+    /// Connect and greet operator without a request.
+    fn greet(&self) -> Result<Frame> {
+        #[cfg(feature = "remote")]
+        if let Some(ref remote) = self.remote {
+            return ipc::read_greeting(remote.connect()?);
+        }
+        if let Some(ref destination) = self.ssh {
+            let mut ssh = Ssh::connect(destination)?;
+            return match ipc::greet(&mut ssh.stdin)
+                .and_then(|()| ipc::read_greeting(&mut ssh.stdout))
+            {
+                Err(e) => Err(ssh.failure().unwrap_or(e)),
+                frame => frame,
+            };
+        }
+        let stream = UnixStream::connect(&self.socket)?;
+        ipc::greet(&stream)?;
+        ipc::read_greeting(&stream)
+    }
+
+    /// Connect to the socket and send a message.
+    fn send_local(&self, msg: &IPCMessage) -> Result<IPCStream> {
+        let stream = IPCStream::connect(&self.socket.to_string_lossy())?;
+        match stream.write(msg) {
+            Ok(()) => Ok(stream),
+            // operator may have refused the connection before the message was sent.
+            Err(e) => match stream.read() {
+                Ok(IPCMessage::Busy) => Err(Error::Busy),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Send a message and read its response.
+    fn request(&self, msg: &IPCMessage) -> Result<IPCMessage> {
+        self.send(msg)?.read()
+    }
+
+    /// Send a command and read its outcome, the ones without a response were handled
+    /// once operator closes the connection.
+    fn command(&self, msg: &IPCMessage) -> Result<()> {
+        match self.send(msg)?.read() {
+            Ok(IPCMessage::Ok) => Ok(()),
+            Ok(IPCMessage::Error(reason)) => Err(Error::Rejected(reason)),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(e) => Err(e),
+            Ok(msg) => Err(unexpected(msg)),
+        }
+    }
+
+    /// Status of a service.
+    pub fn status(&self, name: &str) -> Result<ServiceInfo> {
+        match self.request(&IPCMessage::Status {
+            name: name.to_string(),
+        })? {
+            IPCMessage::StatusResponse(info) => {
+                info.ok_or_else(|| Error::NotFound(name.to_string()))
+            }
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Configuration and runtime state of a service.
+    pub fn show(&self, name: &str) -> Result<Vec<(String, String)>> {
+        match self.request(&IPCMessage::Show {
+            name: name.to_string(),
+        })? {
+            IPCMessage::ShowResponse(properties) => {
+                properties.ok_or_else(|| Error::NotFound(name.to_string()))
+            }
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// All the loaded services.
+    pub fn list(&self) -> Result<Vec<ServiceInfo>> {
+        self.list_matching(Filter::default())
+    }
+
+    /// The loaded services matched by a filter, matched by operator.
+    pub fn list_matching(&self, filter: Filter) -> Result<Vec<ServiceInfo>> {
+        match self.request(&IPCMessage::List { filter })? {
+            IPCMessage::ListResponse(services) => Ok(services),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// The service files that failed to load.
+    pub fn load_errors(&self) -> Result<Vec<LoadError>> {
+        match self.request(&IPCMessage::LoadErrors)? {
+            IPCMessage::LoadErrorsResponse(errors) => Ok(errors),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// How long the services took to start at boot.
+    pub fn analyze(&self) -> Result<Vec<BootTiming>> {
+        match self.request(&IPCMessage::Analyze)? {
+            IPCMessage::AnalyzeResponse(timings) => Ok(timings),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// The services and the dependencies between them.
+    pub fn graph(&self) -> Result<(Vec<String>, Vec<Edge>)> {
+        match self.request(&IPCMessage::Graph)? {
+            IPCMessage::GraphResponse { services, edges } => Ok((services, edges)),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// The last `limit` starts and exits of a service, the oldest first.
+    pub fn history(&self, name: &str, limit: usize) -> Result<Vec<HistoryEntry>> {
+        match self.request(&IPCMessage::History {
+            name: name.to_string(),
+            limit,
+        })? {
+            IPCMessage::HistoryResponse(entries) => Ok(entries),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// The last `limit` entries of the audit log, the oldest first.
+    pub fn audit(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+        match self.request(&IPCMessage::Audit { limit })? {
+            IPCMessage::AuditResponse(entries) => Ok(entries),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// The recent state changes of the services matched by a query, the oldest first.
+    pub fn events(&self, query: EventQuery) -> Result<Vec<StateChange>> {
+        match self.request(&IPCMessage::Events { query })? {
+            IPCMessage::EventsResponse(changes) => Ok(changes),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// The last `lines` lines of output of a service, even if its log file is gone.
+    pub fn output(&self, name: &str, lines: usize) -> Result<Vec<String>> {
+        match self.request(&IPCMessage::Output {
+            name: name.to_string(),
+            lines,
+        })? {
+            IPCMessage::OutputResponse(output) => {
+                output.ok_or_else(|| Error::NotFound(name.to_string()))
+            }
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Read the service file of a service and its drop-ins again, the changes apply
+    /// from its next start.
+    pub fn reload_file(&self, name: &str) -> Result<()> {
+        match self.request(&IPCMessage::ReloadFile {
+            name: name.to_string(),
+        })? {
+            IPCMessage::ReloadFileResponse(Some(result)) => result.map_err(Error::Rejected),
+            IPCMessage::ReloadFileResponse(None) => Err(Error::NotFound(name.to_string())),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// The service file of a service followed by its drop-ins, in the order they are
+    /// merged.
+    pub fn sources(&self, name: &str) -> Result<Vec<PathBuf>> {
+        match self.request(&IPCMessage::Sources {
+            name: name.to_string(),
+        })? {
+            IPCMessage::SourcesResponse(sources) => {
+                sources.ok_or_else(|| Error::NotFound(name.to_string()))
+            }
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Override properties of a service until operator reads the service files again,
+    /// or for good in a drop-in if `persist`.
+    pub fn set_property(
+        &self,
+        name: &str,
+        properties: Vec<(String, String)>,
+        persist: bool,
+    ) -> Result<()> {
+        match self.request(&IPCMessage::SetProperty {
+            name: name.to_string(),
+            properties,
+            persist,
+        })? {
+            IPCMessage::SetPropertyResponse(Some(result)) => result.map_err(Error::Rejected),
+            IPCMessage::SetPropertyResponse(None) => Err(Error::NotFound(name.to_string())),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Stop a service and start it again once it exited.
+    pub fn restart(&self, name: &str) -> Result<()> {
+        self.command(&IPCMessage::Restart {
+            name: name.to_string(),
+        })
+    }
+
+    /// Start, stop or restart the services matched by a filter.
+    ///
+    /// Returns the names of the services matched.
+    pub fn bulk(&self, action: BulkAction, filter: Filter) -> Result<Vec<String>> {
+        match self.request(&IPCMessage::Bulk { action, filter })? {
+            IPCMessage::BulkResponse(names) => Ok(names),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Wait until a service is in a state, e.g. `running`, has failed, or `timeout`
+    /// elapsed.
+    ///
+    /// Returns the status of the service once the wait ended, in the state or not.
+    pub fn wait_for(&self, name: &str, state: &str, timeout: Duration) -> Result<ServiceInfo> {
+        match self.request(&IPCMessage::WaitFor {
+            name: name.to_string(),
+            state: state.to_string(),
+            timeout,
+        })? {
+            IPCMessage::WaitForResponse(info) => {
+                info.ok_or_else(|| Error::NotFound(name.to_string()))
+            }
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Send several requests on one connection, handled in order.
+    ///
+    /// Returns the response of each request, `None` for the ones that have none like
+    /// [IPCMessage::Start].
+    pub fn batch(&self, requests: Vec<IPCMessage>) -> Result<Vec<Option<IPCMessage>>> {
+        match self.request(&IPCMessage::Batch(requests))? {
+            IPCMessage::BatchResponse(responses) => Ok(responses),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Start a service.
+    pub fn start(&self, name: &str) -> Result<()> {
+        self.command(&IPCMessage::Start {
+            name: name.to_string(),
+        })
+    }
+
+    /// Stop a service.
+    pub fn stop(&self, name: &str) -> Result<()> {
+        self.command(&IPCMessage::Stop {
+            name: name.to_string(),
+        })
+    }
+
+    /// Reload the configuration of a running service.
+    pub fn reload(&self, name: &str) -> Result<()> {
+        self.command(&IPCMessage::ReloadService {
+            name: name.to_string(),
+        })
+    }
+
+    /// Start a service at boot, or not.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        let name = name.to_string();
+        self.command(&match enabled {
+            true => IPCMessage::Enable { name },
+            false => IPCMessage::Disable { name },
+        })
+    }
+
+    /// Prevent a service from being started, or allow it again.
+    pub fn set_masked(&self, name: &str, masked: bool) -> Result<()> {
+        let name = name.to_string();
+        self.command(&match masked {
+            true => IPCMessage::Mask { name },
+            false => IPCMessage::Unmask { name },
+        })
+    }
+
+    /// Set the number of replicas of a replicated service.
+    pub fn scale(&self, name: &str, replicas: u32) -> Result<()> {
+        self.command(&IPCMessage::Scale {
+            name: name.to_string(),
+            replicas,
+        })
+    }
+
+    /// Re-execute operator without restarting the services.
+    pub fn re_exec(&self) -> Result<()> {
+        self.command(&IPCMessage::ReExec)
+    }
+
+    /// Stop the services, then power off or reboot the machine operator is the init of.
+    pub fn power(&self, action: PowerAction) -> Result<()> {
+        match self.request(&IPCMessage::Power(action))? {
+            IPCMessage::PowerResponse(result) => result.map_err(Error::Rejected),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Run a command in the context of a service, with the stdin, stdout and stderr of
+    /// the current process.
+    pub fn exec(&self, name: &str, command: Vec<String>) -> Result<Exec> {
+        // the fds can only be passed on the socket.
+        if !self.is_local() {
+            return Err(Error::Rejected(LOCAL_ONLY.to_string()));
+        }
+
+        let stream = self.send_local(&IPCMessage::Exec {
+            name: name.to_string(),
+            command,
+        })?;
+        stream.send_fds(&[0, 1, 2])?;
+        match stream.read()? {
+            IPCMessage::ExecResponse(Some(Ok(pid))) => Ok(Exec { pid, stream }),
+            IPCMessage::ExecResponse(Some(Err(e))) => Err(Error::Rejected(e)),
+            IPCMessage::ExecResponse(None) => Err(Error::NotFound(name.to_string())),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Receive the output of a service from now on, and write to its stdin if `stdin`.
+    pub fn attach(&self, name: &str, stdin: bool) -> Result<Attachment> {
+        // the input is written on the connection, which only a socket can be cloned of.
+        if stdin && !self.is_local() {
+            return Err(Error::Rejected(LOCAL_ONLY.to_string()));
+        }
+        let mut stream = self.send(&IPCMessage::Attach {
+            name: name.to_string(),
+            stdin,
+        })?;
+        match stream.read()? {
+            IPCMessage::AttachResponse(Some(Ok(tty))) => Ok(Attachment { stream, tty }),
+            IPCMessage::AttachResponse(Some(Err(e))) => Err(Error::Rejected(e)),
+            IPCMessage::AttachResponse(None) => Err(Error::NotFound(name.to_string())),
+            msg => Err(unexpected(msg)),
+        }
+    }
+
+    /// Receive the state changes of the services from now on.
+    pub fn subscribe(&self) -> Result<Subscription> {
+        self.send(&IPCMessage::Subscribe).map(Subscription)
+    }
+}
+
+/// The state changes of the services, as they happen.
+///
+/// The iterator ends when operator closes the connection.
+pub struct Subscription(Stream);
+
+impl Iterator for Subscription {
+    type Item = Result<StateChange>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.read() {
+            Ok(IPCMessage::Event(change)) => Some(Ok(change)),
+            Ok(msg) => Some(Err(unexpected(msg))),
+            // the connection was closed.
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// The output of a service, as it's written.
+///
+/// The iterator ends when operator closes the connection.
+pub struct Attachment {
+    stream: Stream,
+    tty: bool,
+}
+
+impl Attachment {
+    /// Writer to the stdin of the service, if it was attached with `stdin`.
+    pub fn input(&self) -> Result<Input> {
+        match self.stream {
+            Stream::Local(ref stream) => stream.try_clone().map(Input),
+            _ => Err(Error::Rejected(LOCAL_ONLY.to_string())),
+        }
+    }
+
+    /// Whether the service runs in a terminal, the input is then read by its line
+    /// discipline.
+    pub fn tty(&self) -> bool {
+        self.tty
+    }
+}
+
+impl Iterator for Attachment {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.stream.read() {
+            Ok(IPCMessage::OutputData(bytes)) => Some(Ok(bytes)),
+            Ok(msg) => Some(Err(unexpected(msg))),
+            // the connection was closed.
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// The stdin of an attached service.
+pub struct Input(IPCStream);
+
+impl Input {
+    /// Write to the stdin of the service, dropped if it's not running.
+    pub fn write(&self, bytes: &[u8]) -> Result<()> {
+        self.0.write(&IPCMessage::Input(bytes.to_vec()))
+    }
+}
+
+/// A command run by [Client::exec].
+pub struct Exec {
+    pid: i32,
+    stream: IPCStream,
+}
+
+impl Exec {
+    /// The process of the command.
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Send a signal to the command.
+    pub fn kill(&self, signal: i32) -> Result<()> {
+        let signal = Signal::try_from(signal).map_err(io::Error::from)?;
+        kill(Pid::from_raw(self.pid), signal).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    /// Wait for the command to exit.
+    ///
+    /// Returns its exit code, 128 + the signal if it was killed.
+    pub fn wait(&self) -> Result<i32> {
+        match self.stream.read()? {
+            IPCMessage::ExecExited(code) => Ok(code),
+            msg => Err(unexpected(msg)),
+        }
+    }
+}
+
+/// Error of a message that doesn't answer the request.
+fn unexpected(msg: IPCMessage) -> Error {
+    match msg {
+        IPCMessage::Busy => Error::Busy,
+        IPCMessage::Unauthorized => Error::Rejected("the token was refused".to_string()),
+        IPCMessage::ReadOnly => {
+            Error::Rejected("the query socket only answers queries".to_string())
+        }
+        msg => Error::Protocol(format!("unexpected message {msg:?}")),
+    }
+}
@@ -0,0 +1,68 @@
+//! Configuration of operator itself, read from `operator.toml`.
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coredump::CoredumpConfig, helper::op_config_file, ipc::IpcConfig, journal::JournalConfig,
+    webhook::Webhook, Error, Result,
+};
+
+/// Configuration of operator
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Maximum number of services starting at once at boot, unbounded if unset
+    #[serde(default)]
+    pub max_parallel_starts: Option<usize>,
+    /// Seconds the services have to exit when operator shuts down, before the ones left
+    /// are killed with SIGKILL, unbounded if unset
+    #[serde(default)]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// Env vars of operator passed to the services, all of them if unset
+    #[serde(default)]
+    pub pass_environment: Option<Vec<String>>,
+    /// Core dumps of the services, limited by their `limits.core` and left where the
+    /// kernel wrote them if unset
+    pub coredump: Option<CoredumpConfig>,
+    /// Endpoints notified of the state changes of the services
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    /// Journal of the recent state changes of the services
+    #[serde(default)]
+    pub journal: JournalConfig,
+    /// IPC sockets and the limits of their clients
+    #[serde(default)]
+    pub ipc: IpcConfig,
+    /// HTTP control API, not served if unset
+    #[cfg(feature = "http")]
+    pub http: Option<crate::http::HttpApi>,
+    /// gRPC API, not served if unset
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<crate::grpc::GrpcApi>,
+    /// Listener of the remote clients, not served if unset
+    #[cfg(feature = "remote")]
+    pub remote: Option<crate::remote::RemoteConfig>,
+    /// Faults injected in the supervision, none if unset
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<crate::chaos::ChaosConfig>,
+}
+
+impl Config {
+    /// Read the config file, the default config is used if there is none.
+    pub fn read() -> Result<Self> {
+        let path = op_config_file();
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let config =
+                    toml::from_str::<Self>(&contents).map_err(|e| Error::parse(&path, e))?;
+                for webhook in &config.webhooks {
+                    webhook.check().map_err(|e| Error::parse(&path, e))?;
+                }
+                Ok(config)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Services run in a container with podman or docker.
+//!
+//! The container of a service is created right before it's started and removed once it
+//! stops. The process of the service is `<runtime> start --attach`, which forwards the
+//! signals to the container and its output to the log of the service, so it's tracked
+//! and restarted like any other.
+
+use std::{
+    ffi::CString,
+    io,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use log::{error, info};
+use nix::errno::Errno;
+
+use crate::{
+    backend::{unused_key, ServiceBackend},
+    service::{RestartStrategy, Service, Status},
+};
+
+/// Runtimes looked up in `PATH`, in order of preference.
+const RUNTIMES: [&str; 2] = ["podman", "docker"];
+
+/// Runs the image of a service in a container.
+pub struct ContainerBackend;
+
+impl ServiceBackend for ContainerBackend {
+    fn load(&self, service: &mut Service) -> Result<(), String> {
+        if !service.executable.as_os_str().is_empty() {
+            return Err(unused_key("executable", "container"));
+        }
+        if service.command.is_some() {
+            return Err(unused_key("command", "container"));
+        }
+        if service.pid_file.is_some() {
+            return Err(unused_key("pid_file", "container"));
+        }
+        if service.image.is_none() {
+            return Err("missing field `image`".to_string());
+        }
+        // the new container would have the name of the previous one.
+        if service.restart_strategy == RestartStrategy::StartThenStop {
+            return Err(
+                "`restart_strategy = \"start-then-stop\"` can't be set for a container".to_string(),
+            );
+        }
+        service.executable = runtime(&service.path());
+        Ok(())
+    }
+
+    fn args(&self, service: &Service) -> Option<Vec<CString>> {
+        Some(
+            [
+                "start".to_string(),
+                "--attach".to_string(),
+                name(&service.name),
+            ]
+            .into_iter()
+            .map(|arg| CString::new(arg).unwrap())
+            .collect(),
+        )
+    }
+
+    // the container gets the environment set up for the runtime.
+    fn prepare(&self, service: &Service) -> io::Result<()> {
+        create(service)
+    }
+
+    fn properties(&self, service: &Service) -> Vec<(String, String)> {
+        let mut properties = vec![];
+        let mut push = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                properties.push((key.to_string(), value));
+            }
+        };
+
+        push("image", service.image.clone());
+        push(
+            "ports",
+            (!service.ports.is_empty()).then(|| service.ports.join(" ")),
+        );
+        push(
+            "volumes",
+            (!service.volumes.is_empty()).then(|| service.volumes.join(" ")),
+        );
+        push("network", service.network.clone());
+        push("container", Some(name(&service.name)));
+        // the runtime isn't asked: show is answered on the engine thread, which would
+        // also reap an `inspect` run on another one. The container runs as long as
+        // `start --attach` does, and it's removed once it exited.
+        push(
+            "container.state",
+            matches!(service.status, Some(Status::Running)).then(|| "running".to_string()),
+        );
+        properties
+    }
+}
+
+/// Name of the container of a service.
+pub fn name(service: &str) -> String {
+    format!("operator-{service}")
+}
+
+/// The container runtime, podman if it's installed or docker.
+///
+/// The name of the first one is returned if neither is found, so starting the service
+/// fails with the executable not found.
+fn runtime(path: &str) -> PathBuf {
+    RUNTIMES
+        .iter()
+        .find_map(|runtime| {
+            std::env::split_paths(path)
+                .map(|dir| dir.join(runtime))
+                .find(|runtime| runtime.is_file())
+        })
+        .unwrap_or_else(|| PathBuf::from(RUNTIMES[0]))
+}
+
+/// Create the container of a service, replacing the one left by a previous start.
+fn create(service: &Service) -> io::Result<()> {
+    let name = name(&service.name);
+    let runtime = &service.executable;
+
+    // the container is left behind if the runtime was killed.
+    let removed = Command::new(runtime)
+        .args(["rm", "--force", &name])
+        .stdout(Stdio::null())
+        .status()?;
+    if !removed.success() {
+        info!("No container {name} to remove");
+    }
+
+    let mut create = Command::new(runtime);
+    create.args(["create", "--rm", "--name", &name]);
+    for port in &service.ports {
+        create.args(["--publish", port]);
+    }
+    for volume in &service.volumes {
+        create.args(["--volume", volume]);
+    }
+    if let Some(ref network) = service.network {
+        create.args(["--network", network]);
+    }
+    // the values are taken from the environment of the runtime, so the secrets don't
+    // show up in its arguments.
+    let keys = service.env.iter().flat_map(|env| env.keys());
+    for key in keys.chain(service.secrets.keys()) {
+        create.args(["--env", key]);
+    }
+    create.arg(service.image.as_deref().unwrap_or_default());
+    for arg in service.args.iter().flatten() {
+        create.arg(arg.to_str().map_err(|_| Errno::EINVAL)?);
+    }
+
+    // the id of the container would be written to the log.
+    if !create.stdout(Stdio::null()).status()?.success() {
+        error!("Failed to create the container of {}", service.name);
+        return Err(Errno::ECANCELED.into());
+    }
+    Ok(())
+}
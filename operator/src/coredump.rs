@@ -0,0 +1,190 @@
+//! Core dumps of the services that crashed.
+//!
+//! When the process of a service is killed by a signal that dumped its core, e.g.
+//! SIGSEGV, operator looks for the core where `/proc/sys/kernel/core_pattern` says the
+//! kernel wrote it. Where it went is sent with the state change of the crash and shown
+//! by `operatorctl status`. The cores piped by the kernel to a handler, e.g.
+//! systemd-coredump, are left to it.
+//!
+//! With a `[coredump]` section in `operator.toml`, the services get an unlimited
+//! `RLIMIT_CORE` unless their `limits.core` is set, and their cores are moved to a dir
+//! per service or piped to a command.
+
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::SystemTime,
+};
+
+use log::{error, warn};
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+
+use crate::{helper::glob_match, sandbox, service::Service};
+
+/// What is done with the cores of the services
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CoredumpConfig {
+    /// Dir the cores are moved to, in a dir per service, `<dir>/<service>/`
+    pub dir: Option<PathBuf>,
+    /// Command the cores are piped to instead, run with `/bin/sh -c`
+    pub handler: Option<String>,
+}
+
+/// Find the core dumped by `pid`, the process of a service killed by `signal`, and
+/// move it or pipe it following `config`.
+///
+/// Returns where it went: its path, the handler it was piped to, or why it wasn't found.
+pub(crate) fn collect(
+    config: Option<&CoredumpConfig>,
+    service: &Service,
+    pid: i32,
+    signal: Signal,
+) -> String {
+    let core = match find(service, pid, signal) {
+        Ok(core) => core,
+        Err(location) => return location,
+    };
+    let Some(config) = config else {
+        return core.display().to_string();
+    };
+    if let Some(ref handler) = config.handler {
+        return match pipe(handler, &core, service, pid, signal) {
+            Ok(()) => format!("piped to {handler:?} from {}", core.display()),
+            Err(e) => {
+                error!(
+                    "Failed to pipe the core of {} to {handler:?}: {e}",
+                    service.name
+                );
+                core.display().to_string()
+            }
+        };
+    }
+    if let Some(ref dir) = config.dir {
+        let dir = dir.join(&service.name);
+        match move_to(&core, &dir) {
+            Ok(moved) => return moved.display().to_string(),
+            Err(e) => error!(
+                "Failed to move the core of {} to {}: {e}",
+                service.name,
+                dir.display()
+            ),
+        }
+    }
+    core.display().to_string()
+}
+
+/// The core written by the kernel for `pid`, or where it went if it's not a file.
+fn find(service: &Service, pid: i32, signal: Signal) -> Result<PathBuf, String> {
+    let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern")
+        .map_err(|e| format!("unknown, failed to read the core pattern: {e}"))?;
+    let pattern = pattern.trim();
+    if let Some(handler) = pattern.strip_prefix('|') {
+        let handler = handler.split_whitespace().next().unwrap_or_default();
+        return Err(format!("piped to {handler} by the kernel"));
+    }
+
+    let mut glob = expand(pattern, pid, signal);
+    // the kernel appends the pid to a pattern without it.
+    let uses_pid = std::fs::read_to_string("/proc/sys/kernel/core_uses_pid")
+        .is_ok_and(|uses_pid| uses_pid.trim() == "1");
+    if uses_pid && !pattern.contains("%p") {
+        glob.push_str(&format!(".{pid}"));
+    }
+
+    // a relative core is written in the working dir of the process.
+    let glob = match service.working_dir {
+        Some(ref working_dir) => working_dir.join(glob),
+        None => std::env::current_dir().unwrap_or_default().join(glob),
+    };
+    let glob = match service.root_dir {
+        Some(ref root) => sandbox::in_root(root, &glob),
+        None => glob,
+    };
+    let not_found = || format!("not found at {}", glob.display());
+    let (Some(dir), Some(name)) = (glob.parent(), glob.file_name()) else {
+        return Err(not_found());
+    };
+    if dir.to_string_lossy().contains('*') {
+        return Err(not_found());
+    }
+
+    // a core left by a previous process may match a pattern without the pid.
+    let since = service
+        .started_at
+        .and_then(|started_at| SystemTime::now().checked_sub(started_at.elapsed()))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let name = name.to_string_lossy();
+    std::fs::read_dir(dir)
+        .map_err(|_| not_found())?
+        .flatten()
+        .filter(|entry| glob_match(&name, &entry.file_name().to_string_lossy()))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .filter(|(modified, _)| *modified >= since)
+        .max()
+        .map(|(_, core)| core)
+        .ok_or_else(not_found)
+}
+
+/// A core pattern with the specifiers operator knows replaced, and the others by `*`.
+fn expand(pattern: &str, pid: i32, signal: Signal) -> String {
+    let mut expanded = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some('p' | 'P' | 'i' | 'I') => expanded.push_str(&pid.to_string()),
+            Some('s') => expanded.push_str(&(signal as i32).to_string()),
+            Some('h') => match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+                Ok(hostname) => expanded.push_str(hostname.trim()),
+                Err(_) => expanded.push('*'),
+            },
+            // e.g. the time of the dump or the name of the executable.
+            Some(_) => expanded.push('*'),
+            None => {}
+        }
+    }
+    expanded
+}
+
+/// Move a core to `dir`, returns its new path.
+fn move_to(core: &Path, dir: &Path) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let moved = dir.join(core.file_name().unwrap_or_default());
+    // the dir may be on another filesystem.
+    if std::fs::rename(core, &moved).is_err() {
+        std::fs::copy(core, &moved)?;
+        std::fs::remove_file(core)?;
+    }
+    Ok(moved)
+}
+
+/// Pipe a core to a handler from a thread, so a slow handler doesn't block the engine.
+///
+/// The handler gets the name of the service, the pid, the signal and the path of the
+/// core in `OP_CORE_SERVICE`, `OP_CORE_PID`, `OP_CORE_SIGNAL` and `OP_CORE_FILE`, and
+/// may remove it.
+fn pipe(handler: &str, core: &Path, service: &Service, pid: i32, signal: Signal) -> io::Result<()> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(handler)
+        .stdin(Stdio::from(File::open(core)?))
+        .env("OP_CORE_SERVICE", &service.name)
+        .env("OP_CORE_PID", pid.to_string())
+        .env("OP_CORE_SIGNAL", signal.as_str())
+        .env("OP_CORE_FILE", core)
+        .spawn()?;
+    let handler = handler.to_string();
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) if !status.success() => warn!("Core handler {handler:?} failed: {status}"),
+        // the engine reaped it.
+        Ok(_) | Err(_) => {}
+    });
+    Ok(())
+}
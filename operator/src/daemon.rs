@@ -0,0 +1,136 @@
+//! Daemonization of operator.
+//!
+//! Lets operator be started from rc scripts on systems without another supervisor:
+//! the daemon is detached from the terminal and the session of the script, and its pid
+//! is written to a pidfile.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use nix::{
+    fcntl::OFlag,
+    unistd::{chdir, dup2, fork, getpid, getuid, pipe2, setsid, ForkResult},
+};
+
+use crate::helper::{op_service_log_dir, user_mode};
+
+/// Default path of the pidfile, in `$XDG_RUNTIME_DIR` in user mode.
+pub fn default_pid_file() -> PathBuf {
+    match user_mode() {
+        true => match std::env::var("XDG_RUNTIME_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("operator.pid"),
+            _ => PathBuf::from(format!("/tmp/operator-{}.pid", getuid())),
+        },
+        false => PathBuf::from("/run/operator.pid"),
+    }
+}
+
+/// Detach operator from its terminal and session, and write its pid to `pid_file`.
+///
+/// The process calling it exits once the daemon wrote the pidfile, with a status of 1
+/// if it failed to, so it only returns in the daemon. stdin is redirected to
+/// `/dev/null`, stdout and stderr are appended to `operator.log` in the log dir.
+///
+/// This must be called before any thread is spawned.
+pub fn daemonize(pid_file: &Path) -> io::Result<()> {
+    if let Some(pid) = running(pid_file)? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("operator is already running with pid {pid}"),
+        ));
+    }
+
+    std::fs::create_dir_all(op_service_log_dir())?;
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}/operator.log", op_service_log_dir()))?;
+    let null = File::open("/dev/null")?;
+
+    // the daemon writes why it failed to the pipe, and closes it once it wrote the
+    // pidfile.
+    let (r_fd, w_fd) = pipe2(OFlag::O_CLOEXEC)?;
+    let (r_fd, w_fd) = unsafe { (OwnedFd::from_raw_fd(r_fd), OwnedFd::from_raw_fd(w_fd)) };
+
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        drop(w_fd);
+        let mut error = String::new();
+        _ = File::from(r_fd).read_to_string(&mut error);
+        if error.is_empty() {
+            std::process::exit(0);
+        }
+        eprintln!("Failed to daemonize: {error}");
+        std::process::exit(1);
+    }
+    drop(r_fd);
+    let mut status = File::from(w_fd);
+
+    // a new session has no controlling terminal, and the second fork makes sure the
+    // daemon, which is not its leader, never acquires one again.
+    let detached = setsid().and_then(|_| unsafe { fork() });
+    match detached {
+        Ok(ForkResult::Parent { .. }) => unsafe { nix::libc::_exit(0) },
+        Ok(ForkResult::Child) => {}
+        Err(e) => {
+            _ = write!(status, "{e}");
+            unsafe { nix::libc::_exit(1) };
+        }
+    }
+
+    let setup = || -> io::Result<()> {
+        chdir("/")?;
+        dup2(null.as_raw_fd(), nix::libc::STDIN_FILENO)?;
+        dup2(log.as_raw_fd(), nix::libc::STDOUT_FILENO)?;
+        dup2(log.as_raw_fd(), nix::libc::STDERR_FILENO)?;
+        std::fs::write(pid_file, format!("{}\n", getpid()))
+    };
+    if let Err(e) = setup() {
+        _ = write!(status, "{}: {e}", pid_file.display());
+        unsafe { nix::libc::_exit(1) };
+    }
+
+    info!("Daemonized, pid written to {}", pid_file.display());
+    Ok(())
+}
+
+/// Pid of the operator that wrote the pidfile, if it's still running.
+///
+/// A pidfile left by an operator that is gone is stale, including when its pid was
+/// reused by another program.
+fn running(pid_file: &Path) -> io::Result<Option<i32>> {
+    let contents = match std::fs::read_to_string(pid_file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let Ok(pid) = contents.trim().parse::<i32>() else {
+        info!("Ignoring the invalid pidfile {}", pid_file.display());
+        return Ok(None);
+    };
+    // /proc/<pid>/stat starts with `<pid> (<comm>) <state>`.
+    let stat = |pid: &str| -> Option<(String, char)> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        let (comm, rest) = stat.split_once(" (")?.1.rsplit_once(") ")?;
+        Some((comm.to_string(), rest.chars().next()?))
+    };
+    let operator = match (stat(&pid.to_string()), stat("self")) {
+        // a zombie exited already, its parent just didn't reap it yet.
+        (Some((comm, state)), Some((own, _))) => state != 'Z' && comm == own,
+        _ => false,
+    };
+    if operator && pid != getpid().as_raw() {
+        return Ok(Some(pid));
+    }
+
+    info!(
+        "Ignoring the stale pidfile {} of pid {pid}",
+        pid_file.display()
+    );
+    Ok(None)
+}
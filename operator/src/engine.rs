@@ -1,31 +1,308 @@
 use nix::{
     errno::Errno,
-    libc::siginfo_t,
-    poll::{poll, PollFd, PollFlags},
     sys::{
-        signal::{kill, sigaction, SaFlags, SigAction, SigSet, Signal},
-        wait::{waitpid, WaitStatus},
+        prctl::set_child_subreaper,
+        resource::RLIM_INFINITY,
+        signal::{kill, SigSet, Signal},
+        signalfd::{SfdFlags, SignalFd},
+        wait::{waitid, waitpid, Id, WaitPidFlag, WaitStatus},
     },
-    unistd::{fork, ForkResult, Pid},
+    unistd::{execv, getpid, Pid},
 };
 
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, Chaos, ChaosConfig, Faults};
 use crate::{
-    ipc::{self, IPCMessage},
-    service::Service,
+    audit::{self, AuditEntry, Peer},
+    backend::ForkingBackend,
+    cgroup,
+    config::Config,
+    coredump::{self, CoredumpConfig},
+    executor::{self, Context, Executor, Job},
+    helper::{in_foreground, op_service_log_dir, op_socket_path, user_mode},
+    history::{self, HistoryEntry, RunEvent},
+    ipc::{self, BulkAction, IPCMessage, IPCStream, IpcConfig},
+    journal::Journal,
+    notify,
+    output::Output,
+    power::{self, PowerAction},
+    property,
+    reactor::{Reactor, TimerId},
+    secrets::Secrets,
+    service::{
+        BootTiming, Crash, ExecutableId, FailReason, KillMode, Limit, LoadError, Reload, Restart,
+        RestartStrategy, Service, ServiceInfo, ServiceType, Status, Stdin,
+    },
+    socket,
+    state::{self, ServiceState, State},
+    usage::{self, SAMPLE_INTERVAL},
+    webhook::{self, Notifier, StateChange},
+    Error,
 };
 use log::{error, info, warn};
 use std::{
-    collections::HashMap,
-    os::fd::{AsFd, AsRawFd},
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    ffi::CString,
+    hash::BuildHasher,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, net::UnixDatagram},
+    },
+    path::PathBuf,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// Sources and timers of the engine's event loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Token {
+    /// The signalfd is readable.
+    Signal,
+    /// A client connected to the ipc server.
+    Ipc,
+    /// A client connected to the query socket.
+    Query,
+    /// The request of a client was read.
+    Request,
+    /// The restart delay of a service elapsed.
+    Restart(String),
+    /// The pidfd of a process adopted from a previous operator is readable.
+    Adopted(i32),
+    /// The error pipe of a process being started is readable, it executed its program
+    /// or failed to.
+    Executed(i32),
+    /// The usage of the services has to be sampled.
+    Sample,
+    /// A connection is pending on the socket of a service.
+    Socket(String),
+    /// The notification socket of a service is readable.
+    Notify(String),
+    /// A service didn't send `WATCHDOG=1` in time.
+    Watchdog(String),
+    /// A service didn't become ready in time.
+    StartTimeout(String),
+    /// A service asked to stop didn't exit in time.
+    StopTimeout(String),
+    /// The services didn't exit in time when operator shut down.
+    ShutdownTimeout,
+    /// The start delay of a service booted elapsed.
+    StartDelay(String),
+    /// A service has to be restarted on its `restart_every` schedule.
+    ScheduledRestart(String),
+    /// A client waited for a service to reach a state for too long, by the id of its wait.
+    WaitTimeout(u64),
+    /// The process of a service started late by the chaos mode has to be started.
+    #[cfg(feature = "chaos")]
+    SlowStart(String),
+}
+
+/// An event of a source of a service, sent by the task of the async engine watching it.
+#[cfg(feature = "tokio")]
+pub(crate) enum Event {
+    /// A connection is pending on the socket of a service.
+    Connection(String),
+    /// A service sent a notification.
+    Notify(String, String),
+    /// The error pipe of a process being started is readable, by its pid.
+    Executed(i32),
+}
+
+/// How the engine starts the processes of the services.
+#[derive(Default)]
+pub(crate) enum Launcher {
+    /// fork() and exec() the service, its exit is reaped on SIGCHLD.
+    #[default]
+    Fork,
+    /// Spawn the service with tokio, its exit is sent by the task waiting on it.
+    /// `None` is sent for adopted processes whose exit status is unknown, and the events
+    /// of the sockets of the services are sent on the second channel.
+    #[cfg(feature = "tokio")]
+    Tokio(
+        tokio::sync::mpsc::UnboundedSender<(i32, Option<WaitStatus>)>,
+        tokio::sync::mpsc::UnboundedSender<Event>,
+    ),
+    /// Fake processes of the services of a test harness, their exits are scripted.
+    #[cfg(feature = "testing")]
+    Fake(crate::testing::FakeProcesses),
+}
+
+impl Launcher {
+    /// Whether the services run real processes, that get a cgroup and are adopted.
+    fn is_real(&self) -> bool {
+        match self {
+            #[cfg(feature = "testing")]
+            Launcher::Fake(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Kill all the processes of a service with SIGKILL.
+    fn kill(&mut self, service: &Service) {
+        match self {
+            #[cfg(feature = "testing")]
+            Launcher::Fake(ref mut processes) => processes.kill(service.pid),
+            _ => service.signal(Signal::SIGKILL),
+        }
+    }
+}
+
+/// How a service waiting for the services it's ordered after is started.
+#[derive(Debug, Clone, Copy)]
+enum Launch {
+    /// At boot, unless it's disabled or manual and not pulled in by an enabled service.
+    Boot { pulled_in: bool },
+    /// By operatorctl.
+    Start,
+}
+
+/// Delay before the first restart of a crashed service, doubled on each consecutive restart.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+/// Maximum delay before restarting a service.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+/// A service running for longer than this is considered healthy and its restart delay is reset.
+const RESTART_RESET: Duration = Duration::from_secs(10);
+/// Maximum jitter added to the scheduled restarts, at most a tenth of their period.
+const MAX_RESTART_JITTER: u64 = 300;
+/// Lines of output kept with the diagnostics of a crash.
+const CRASH_LOG_LINES: usize = 50;
+
+/// Sends a state change to an IPC client, false once it's gone.
+type Subscriber = Box<dyn FnMut(&StateChange) -> bool + Send>;
+
+/// A client waiting for a service to reach a state.
+struct Waiter {
+    name: String,
+    state: String,
+    timeout: TimerId,
+    /// sends the status of the service once it's reached, failed or timed out.
+    respond: Box<dyn FnOnce(Option<ServiceInfo>) + Send>,
+}
+
+/// The previous process of a service restarted with `start-then-stop`.
+struct Previous {
+    /// name of the service.
+    name: String,
+    started_at: Option<Instant>,
+    active_since: Option<u64>,
+    executable_id: Option<ExecutableId>,
+    /// whether the new process is ready and the previous one was asked to stop.
+    stopping: bool,
+}
+
+/// Current time in seconds since the epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// What a dry run of the boot found
+#[derive(Debug, Default)]
+pub struct DryRun {
+    /// Services in the order they are started at boot, with how each one is started
+    pub order: Vec<(String, String)>,
+    /// Files that failed to load and problems of the services
+    pub errors: Vec<String>,
+}
+
 /// Service handler for operator.
 ///
 /// It Handles creation, termination, book-keeping  of the services.
 #[derive(Default)]
 pub struct Engine {
-    /// list of all services loaded by operator.
-    services: HashMap<i32, Service>,
+    /// all services loaded by operator, keyed by name.
+    services: HashMap<String, Service>,
+    /// names of the services that were started, keyed by pid.
+    pids: HashMap<i32, String>,
+    /// template services, keyed by name (`worker@`).
+    templates: HashMap<String, Service>,
+    /// definitions of replicated services, keyed by name.
+    replica_sets: HashMap<String, Service>,
+    /// whether operator runs as an init (PID 1 of a container).
+    init: bool,
+    /// whether operator runs in the foreground of a terminal, Ctrl-C stops the services.
+    foreground: bool,
+    /// whether operator was re-executed and the services keep the status they had.
+    resume: bool,
+    /// whether the operator already serving the socket is stopped instead of exiting.
+    take_over: bool,
+    /// whether operator is waiting for the services to exit before exiting itself.
+    shutting_down: bool,
+    /// signal stopping the services when operator shuts down.
+    shutdown_signal: Option<Signal>,
+    /// time the services have to exit when operator shuts down, before they are killed.
+    shutdown_timeout: Option<Duration>,
+    /// what operator does once the services exited, when it shuts down as an init.
+    power: Option<PowerAction>,
+    /// event loop of the engine.
+    pub(crate) reactor: Reactor<Token>,
+    /// how the processes of the services are started.
+    pub(crate) launcher: Launcher,
+    /// service files that failed to load.
+    load_errors: Vec<LoadError>,
+    /// when operator started loading the services.
+    booted_at: Option<Instant>,
+    /// timestamps of the start of the services at boot, keyed by name.
+    timings: HashMap<String, BootTiming>,
+    /// runtime state of the services, persisted in the runtime dir.
+    state: State,
+    /// state left by the previous operator, of the services that weren't adopted yet.
+    adoptable: HashMap<String, ServiceState>,
+    /// pidfds of the adopted processes that are not our children, keyed by pid.
+    pidfds: HashMap<i32, OwnedFd>,
+    /// error pipes of the processes that didn't execute the program of their service
+    /// yet, keyed by pid.
+    executing: HashMap<i32, OwnedFd>,
+    /// listening sockets of the services, keyed by name.
+    sockets: HashMap<String, OwnedFd>,
+    /// last output of the services, keyed by name, kept across their restarts.
+    outputs: HashMap<String, Output>,
+    /// notification sockets of the services, keyed by name.
+    notify_sockets: HashMap<String, UnixDatagram>,
+    /// services stopped by operatorctl to be started again once they exited.
+    restarting: HashSet<String>,
+    /// previous processes of the services restarted with `start-then-stop`, keyed by pid.
+    previous: HashMap<i32, Previous>,
+    /// services waiting for the services they are ordered after to be ready.
+    queue: Vec<(String, Launch)>,
+    /// maximum number of services starting at once at boot, unbounded if unset.
+    max_parallel_starts: Option<usize>,
+    /// `pass_environment` of the config.
+    pass_environment: Option<Vec<String>>,
+    /// `[coredump]` of the config.
+    coredump: Option<CoredumpConfig>,
+    /// sends the state changes of the services to the webhooks.
+    notifier: Notifier,
+    /// recent state changes of the services.
+    journal: Journal,
+    /// IPC sockets and the limits of their clients.
+    pub(crate) ipc: IpcConfig,
+    /// last status of the services sent to the webhooks, keyed by name.
+    notified: HashMap<String, Status>,
+    /// how operator lost track of the processes of services, keyed by name, sent with
+    /// their next state change.
+    lost: HashMap<String, String>,
+    /// where the cores dumped by services went, keyed by name, sent with their next
+    /// state change.
+    cores: HashMap<String, String>,
+    /// diagnostics of the services that crashed, keyed by name, sent with their next
+    /// state change.
+    crashes: HashMap<String, Crash>,
+    /// pid and `/proc/<pid>/status` of the child being reaped, read before it was.
+    reaping: Option<(i32, String)>,
+    /// IPC clients receiving the state changes.
+    subscribers: Vec<Subscriber>,
+    /// connections of `operatorctl exec` waiting for the exit of their command, keyed
+    /// by its pid.
+    execs: HashMap<i32, IPCStream>,
+    /// clients waiting for services to reach a state, keyed by the id of their wait.
+    waiters: HashMap<u64, Waiter>,
+    /// id of the next wait.
+    next_wait: u64,
+    /// faults injected in the supervision, to test it.
+    #[cfg(feature = "chaos")]
+    faults: Option<Box<dyn Faults>>,
 }
 
 impl Engine {
@@ -35,209 +312,3131 @@ impl Engine {
         Self::default()
     }
 
-    /// handler for SIGCHILD.
-    extern "C" fn signal_handler(
-        _: std::ffi::c_int,
-        s_info: *mut siginfo_t,
-        _: *mut std::ffi::c_void,
-    ) {
-        // since signals are not reentrant safe, we just pipe the pid to engine.
-        if let Err(e) = comms::write_to_pipe(unsafe { s_info.as_ref().unwrap().si_pid() }) {
-            error!("Failed to write to pipe: {e}");
+    /// Run operator as an init.
+    ///
+    /// Orphaned processes are adopted and reaped, and SIGTERM/SIGINT are forwarded
+    /// to the services before operator exits.
+    pub fn set_init(&mut self, init: bool) {
+        self.init = init;
+    }
+
+    /// Resume supervising the services of the operator that re-executed itself.
+    ///
+    /// Running services are adopted like after a restart, and the ones that are not
+    /// keep their status instead of being started at boot.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// Stop the operator already serving the IPC socket, and take its place once it
+    /// exited, instead of refusing to run.
+    pub fn set_take_over(&mut self, take_over: bool) {
+        self.take_over = take_over;
+    }
+
+    /// Bound the number of services starting at once at boot, instead of the
+    /// `max_parallel_starts` of the config.
+    pub fn set_max_parallel_starts(&mut self, max: usize) {
+        self.max_parallel_starts = Some(max);
+    }
+
+    /// Kill the services left once operator has been shutting down for `timeout`,
+    /// instead of after the `shutdown_timeout_secs` of the config.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = Some(timeout);
+    }
+
+    /// Inject faults in the supervision of the services, instead of the ones of the
+    /// `[chaos]` config.
+    #[cfg(feature = "chaos")]
+    pub fn set_faults(&mut self, faults: impl Faults + 'static) {
+        self.faults = Some(Box::new(faults));
+    }
+
+    /// Start a service at boot unless it's disabled or masked.
+    ///
+    /// Disabled and manual services are started if they are `pulled_in` by an enabled
+    /// one.
+    fn boot(&mut self, mut service: Service, pulled_in: bool) {
+        if (!service.starts_at_boot() && !pulled_in) || service.masked {
+            info!(
+                "Service {} is disabled, manual or masked, not starting it",
+                service.name
+            );
+            service.status = Some(Status::Stopped);
+            self.services.insert(service.name.clone(), service);
+            return;
+        }
+
+        let booted = self.booted_at.map(|booted_at| booted_at.elapsed());
+        if let Some(delay) = service
+            .start_delay()
+            .zip(booted)
+            .map(|(delay, booted)| delay.saturating_sub(booted))
+            .filter(|delay| !delay.is_zero())
+        {
+            info!("Starting service {} in {delay:?}", service.name);
+            self.reactor
+                .add_timer(delay, Token::StartDelay(service.name.clone()));
+            service.status = Some(Status::Stopped);
+            self.services.insert(service.name.clone(), service);
+            return;
+        }
+
+        if service.socket.as_ref().is_some_and(|socket| !socket.eager) {
+            self.listen(service);
+            return;
         }
+
+        self.spawn(service);
     }
 
-    /// Start the engine and manage the services.
-    pub fn run(&mut self) {
-        // setup a signal handler for SIGCHILD
-        let sa = SigAction::new(
-            nix::sys::signal::SigHandler::SigAction(Self::signal_handler),
-            SaFlags::SA_RESTART | SaFlags::SA_SIGINFO,
-            SigSet::empty(),
+    /// Bind the socket of a service unless it's already bound.
+    ///
+    /// Returns the listening fd, `None` if the service has no socket.
+    fn bind(&mut self, service: &Service) -> std::io::Result<Option<RawFd>> {
+        let Some(ref socket) = service.socket else {
+            return Ok(None);
+        };
+        if let Some(fd) = self.sockets.get(&service.name) {
+            return Ok(Some(fd.as_raw_fd()));
+        }
+
+        info!(
+            "Binding the socket of {} on {}",
+            service.name, socket.listen
         );
+        let fd = socket::bind(&socket.listen)?;
+        let raw_fd = fd.as_raw_fd();
+        self.sockets.insert(service.name.clone(), fd);
+        Ok(Some(raw_fd))
+    }
 
-        match unsafe { sigaction(Signal::SIGCHLD, &sa) } {
-            Ok(sigac) => {
-                info!("Signal handler registered: {sigac:?}");
+    /// Wait for the first connection to the socket of a service to start it.
+    fn listen(&mut self, mut service: Service) {
+        service.status = Some(match self.bind(&service) {
+            Ok(_) => {
+                info!("Service {} is waiting for a connection", service.name);
+                self.watch_socket(&service.name);
+                Status::Listening
             }
             Err(e) => {
-                error!("Failed to register signal handler: {e}");
-                return;
+                error!("Failed to bind the socket of {}: {e}", service.name);
+                Status::Failed {
+                    reason: FailReason::ListenError(e.raw_os_error().unwrap_or(0)),
+                }
             }
+        });
+        self.services.insert(service.name.clone(), service);
+    }
+
+    /// Watch the socket of a service for a connection.
+    fn watch_socket(&mut self, name: &str) {
+        let Some(fd) = self.sockets.get(name) else {
+            return;
+        };
+        match self.launcher {
+            Launcher::Fork => {
+                if let Err(e) = self.reactor.register(fd, Token::Socket(name.to_string())) {
+                    error!("Failed to watch the socket of {name}: {e}");
+                }
+            }
+            #[cfg(feature = "tokio")]
+            Launcher::Tokio(_, ref events) => match fd.try_clone() {
+                Ok(fd) => crate::async_engine::activate(name.to_string(), fd, events.clone()),
+                Err(e) => error!("Failed to watch the socket of {name}: {e}"),
+            },
+            // the connections are made by the test.
+            #[cfg(feature = "testing")]
+            Launcher::Fake(_) => {}
+        }
+    }
+
+    /// Stop watching the socket of a service, its process accepts the connections.
+    fn unwatch_socket(&mut self, name: &str) {
+        if let (Launcher::Fork, Some(fd)) = (&self.launcher, self.sockets.get(name)) {
+            _ = self
+                .reactor
+                .deregister(fd, &Token::Socket(name.to_string()));
+        }
+    }
+
+    /// Bind the notification socket of a service that notifies or has a watchdog, unless
+    /// it's already bound.
+    ///
+    /// Returns the path of the socket, a service without one is considered ready at exec.
+    fn bind_notify(&mut self, service: &Service) -> Option<PathBuf> {
+        if !service.notify && service.watchdog_sec.is_none() {
+            return None;
+        }
+        let path = notify::path(&service.name);
+        if self.notify_sockets.contains_key(&service.name) {
+            return Some(path);
         }
 
-        let service_files = Service::read_service_files().unwrap();
-        for mut service in service_files.into_iter() {
-            info!("Handing service creation for {service:?}");
+        let socket = match notify::bind(&service.name, service.user.as_deref()) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!(
+                    "Failed to bind the notification socket of {}: {e}",
+                    service.name
+                );
+                return None;
+            }
+        };
+        match self.launcher {
+            Launcher::Fork => {
+                let token = Token::Notify(service.name.clone());
+                if let Err(e) = self.reactor.register(&socket, token) {
+                    error!(
+                        "Failed to watch the notification socket of {}: {e}",
+                        service.name
+                    );
+                }
+            }
+            #[cfg(feature = "tokio")]
+            Launcher::Tokio(_, ref events) => match socket.try_clone() {
+                Ok(socket) => {
+                    crate::async_engine::notifications(service.name.clone(), socket, events.clone())
+                }
+                Err(e) => error!(
+                    "Failed to watch the notification socket of {}: {e}",
+                    service.name
+                ),
+            },
+            // the notifications are sent by the test.
+            #[cfg(feature = "testing")]
+            Launcher::Fake(_) => {}
+        }
+        self.notify_sockets.insert(service.name.clone(), socket);
+        Some(path)
+    }
 
-            match unsafe { fork() }.unwrap() {
-                ForkResult::Parent { child } => {
-                    service.status = Some(crate::service::Status::Running);
-                    service.pid = Some(child.as_raw());
+    /// Handle a notification sent by a service.
+    pub(crate) fn handle_notify(&mut self, name: &str, message: &str) {
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        // a process left by a service that exited may still send some.
+        if !matches!(service.status, Some(Status::Running)) {
+            return;
+        }
 
-                    self.services.insert(child.as_raw(), service);
+        let mut ready = false;
+        for line in message.lines() {
+            match line.split_once('=') {
+                Some(("READY", "1")) if !service.ready => {
+                    info!("Service {name} is ready");
+                    service.ready = true;
+                    ready = true;
+                    if let Some(timer) = service.start_timeout.take() {
+                        self.reactor.cancel_timer(timer);
+                    }
                 }
-                ForkResult::Child => {
-                    service.start();
+                Some(("STATUS", text)) => service.status_text = Some(text.to_string()),
+                Some(("WATCHDOG", "1")) => {
+                    if let (Some(timer), Some(interval)) =
+                        (service.watchdog, service.watchdog_interval())
+                    {
+                        self.reactor.cancel_timer(timer);
+                        service.watchdog = Some(
+                            self.reactor
+                                .add_timer(interval, Token::Watchdog(name.to_string())),
+                        );
+                    }
                 }
+                _ => {}
             }
         }
 
-        // create an ipc server for comms b/w operator and operatorctl.
-        let ipc_server = ipc::IPCServer::new().unwrap();
+        if ready {
+            if let Some(timing) = self
+                .timings
+                .get_mut(name)
+                .filter(|timing| timing.ready.is_none())
+            {
+                timing.ready = self.booted_at.map(|booted_at| booted_at.elapsed());
+            }
+            self.retire(name);
+            self.boot_queued();
+        }
+    }
 
-        // we are polling on the read-end of the pipe in the signal handler and the ipc server.
-        let r_fd = comms::read_fd();
-        let ipc_fd = ipc_server.as_fd();
-        loop {
-            let mut fds = vec![
-                PollFd::new(&r_fd, PollFlags::POLLIN),
-                PollFd::new(&ipc_fd, PollFlags::POLLIN),
-            ];
+    /// Kill a service that didn't send `WATCHDOG=1` in time, it's restarted according
+    /// to its restart policy.
+    fn watchdog_expired(&mut self, name: &str) {
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        service.watchdog = None;
+        if matches!(service.status, Some(Status::Running)) {
+            error!("Watchdog of service {name} expired, killing it");
+            service.signal(Signal::SIGABRT);
+        }
+    }
 
-            while let Err(e) = poll(&mut fds, -1) {
-                match e {
-                    Errno::EINTR => continue,
-                    e => {
-                        panic!("select() failed with {e}");
-                    }
-                }
+    /// Kill a service that didn't become ready in time, it's failed and restarted
+    /// according to its restart policy.
+    fn start_timed_out(&mut self, name: &str) {
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        service.start_timeout = None;
+        if service.is_starting() {
+            error!("Service {name} didn't become ready in time, killing it");
+            service.timed_out = true;
+            let handing_over = self.previous.values().any(|previous| previous.name == name);
+            match service.pid {
+                // the previous process in the same cgroup is kept.
+                Some(pid) if handing_over => _ = kill(Pid::from_raw(-pid), Signal::SIGKILL),
+                _ => service.signal(Signal::SIGKILL),
             }
+        }
+    }
 
-            for fd in fds {
-                // fds that ready to be processed have revents value that is non zero.
-                if fd.revents().unwrap().bits() < 1 {
-                    continue;
+    /// Boot a service once its start delay elapsed, unless it was started or stopped
+    /// by operatorctl since.
+    fn delayed_start(&mut self, name: &str) {
+        let Some(service) = self.services.get(name) else {
+            return;
+        };
+        if !matches!(service.status, Some(Status::Stopped)) || service.stopping {
+            return;
+        }
+        let service = self.services.remove(name).unwrap();
+        self.boot(service, true);
+    }
+
+    /// Start a service on a connection to its socket.
+    pub(crate) fn activate(&mut self, name: &str) {
+        self.unwatch_socket(name);
+
+        // a service started by operatorctl in the meantime accepts it.
+        if self
+            .services
+            .get(name)
+            .is_some_and(|service| matches!(service.status, Some(Status::Listening)))
+        {
+            info!("Starting service {name} on a connection to its socket");
+            self.start(name);
+        }
+    }
+
+    /// Start a service, and its failure handlers if it fails to start.
+    fn spawn(&mut self, mut service: Service) {
+        if self.shutting_down {
+            info!("Shutting down, not starting service {}", service.name);
+            service.status = Some(Status::Stopped);
+            service.failure_env.clear();
+            self.services.insert(service.name.clone(), service);
+            return;
+        }
+
+        #[cfg(feature = "chaos")]
+        if let Some(delay) = self
+            .faults
+            .as_mut()
+            .map(|faults| faults.start_delay(&service.name))
+            .filter(|delay| !delay.is_zero())
+        {
+            warn!("Chaos: starting service {} in {delay:?}", service.name);
+            self.reactor
+                .add_timer(delay, Token::SlowStart(service.name.clone()));
+            service.status = Some(Status::Stopped);
+            self.services.insert(service.name.clone(), service);
+            return;
+        }
+        self.spawn_now(service);
+    }
+
+    /// Start the process of a service started late by the chaos mode, unless it was
+    /// started or stopped by operatorctl since.
+    #[cfg(feature = "chaos")]
+    fn slow_start(&mut self, name: &str) {
+        let Some(service) = self.services.get(name) else {
+            return;
+        };
+        if !matches!(service.status, Some(Status::Stopped)) || service.stopping {
+            return;
+        }
+        let service = self.services.remove(name).unwrap();
+        self.spawn_now(service);
+    }
+
+    /// Start a service without delay, and its failure handlers if it fails to start.
+    ///
+    /// Every start goes through here, a masked service isn't started whatever asked for
+    /// it: operatorctl, a restart or a changed executable.
+    fn spawn_now(&mut self, mut service: Service) {
+        if service.masked {
+            warn!("Service {} is masked, not starting it", service.name);
+            service.status = Some(Status::Stopped);
+            service.failure_env.clear();
+            self.services.insert(service.name.clone(), service);
+            return;
+        }
+
+        let name = service.name.clone();
+        if let Err(reason) = self.launch(service) {
+            if let Some(service) = self.services.get_mut(&name) {
+                service.failure_env.clear();
+            }
+            self.start_failed(&name, reason);
+        }
+    }
+
+    /// Record the failed start of a service and start its failure handlers.
+    fn start_failed(&mut self, name: &str, reason: FailReason) {
+        Self::record(
+            name,
+            HistoryEntry {
+                reason: Some(reason.to_string()),
+                ..HistoryEntry::new(RunEvent::Crash)
+            },
+        );
+        self.failed(name, None, &reason.to_string());
+    }
+
+    /// Append an entry to the history of a service.
+    fn record(name: &str, entry: HistoryEntry) {
+        if let Err(e) = history::record(name, &entry) {
+            warn!("Failed to record the history of {name}: {e}");
+        }
+    }
+
+    /// Start a service, indexing it by its pid.
+    ///
+    /// Returns why it failed if its process couldn't be started. Whether it executed its
+    /// program is known once the process does, see [Engine::executed].
+    fn launch(&mut self, mut service: Service) -> Result<(), FailReason> {
+        info!("Handing service creation for {service:?}");
+
+        // the executable is looked up in the `PATH` the program gets.
+        service.pass_environment(self.pass_environment.as_deref());
+        // the services can dump their core, unless their limit says otherwise.
+        if self.coredump.is_some() && service.limits.core.is_none() {
+            service.limits.core = Some(Limit(RLIM_INFINITY));
+        }
+        if let Err(reason) = service.validate() {
+            error!("Service {} can't be started: {reason}", service.name);
+            service.status = Some(Status::Failed { reason });
+            self.services.insert(service.name.clone(), service);
+            return Err(reason);
+        }
+
+        let failed = service.requires.iter().find(|dep| {
+            self.services
+                .get(*dep)
+                .is_some_and(|dep| dep.masked || matches!(dep.status, Some(Status::Failed { .. })))
+        });
+        if let Some(dep) = failed {
+            error!(
+                "Service {} can't be started: required service {dep} failed",
+                service.name
+            );
+            service.status = Some(Status::Failed {
+                reason: FailReason::DependencyFailed,
+            });
+            self.services.insert(service.name.clone(), service);
+            return Err(FailReason::DependencyFailed);
+        }
+
+        self.unwatch_socket(&service.name);
+        service.listen_fd = match self.bind(&service) {
+            Ok(fd) => fd,
+            Err(e) => {
+                error!("Failed to bind the socket of {}: {e}", service.name);
+                let reason = FailReason::ListenError(e.raw_os_error().unwrap_or(0));
+                service.status = Some(Status::Failed { reason });
+                self.services.insert(service.name.clone(), service);
+                return Err(reason);
+            }
+        };
+
+        service.notify_socket = self.bind_notify(&service);
+        service.ready = false;
+        service.status_text = None;
+        service.executable_id = service.executable_id();
+        service.executable_change = None;
+        service.replaced_by = None;
+
+        service.cgroup = match self.launcher.is_real().then(|| cgroup::create(&service)) {
+            Some(Ok(cgroup)) => Some(cgroup),
+            Some(Err(e)) => {
+                warn!("Failed to create the cgroup of {}: {e}", service.name);
+                None
+            }
+            None => None,
+        };
+
+        service.secret_env = match Secrets::read(&service.secrets) {
+            Ok(secrets) => secrets,
+            Err((file, e)) => {
+                error!(
+                    "Failed to read the secret {} of {}: {e}",
+                    file.display(),
+                    service.name
+                );
+                let reason = FailReason::SecretError(e.raw_os_error().unwrap_or(0));
+                service.status = Some(Status::Failed { reason });
+                self.services.insert(service.name.clone(), service);
+                return Err(reason);
+            }
+        };
+
+        if service.service_type == ServiceType::Forking {
+            ForkingBackend::remove_pid_file(&service);
+        }
+
+        let output = self.outputs.entry(service.name.clone()).or_default();
+        let captured = match service.tty {
+            true => output.capture_tty(&service.name),
+            false => output.capture(&service.name),
+        };
+        let output_fd = match captured {
+            Ok(fd) => Some(fd),
+            Err(e) => {
+                warn!("Failed to capture the output of {}: {e}", service.name);
+                None
+            }
+        };
+        service.output_fd = output_fd.as_ref().map(AsRawFd::as_raw_fd);
+        let input_fd = match service.stdin {
+            // the terminal is the stdin.
+            _ if service.tty => None,
+            Stdin::Pipe => match output.capture_input() {
+                Ok(fd) => Some(fd),
+                Err(e) => {
+                    warn!("Failed to create the stdin of {}: {e}", service.name);
+                    None
                 }
+            },
+            Stdin::Inherit => {
+                output.close_input();
+                None
+            }
+        };
+        service.input_fd = match service.tty {
+            true => service.output_fd,
+            false => input_fd.as_ref().map(AsRawFd::as_raw_fd),
+        };
 
-                if fd.as_fd().as_raw_fd() == r_fd.as_raw_fd() {
-                    // read from the pipe for childs that have exited
-                    if let Ok(pid) = comms::read_from_pipe() {
-                        let wait_stat = match waitpid(Pid::from_raw(pid), None) {
-                            Ok(ws) => ws,
-                            Err(e) => {
-                                error!("waitpid() for PID {} failed : {e}.", pid);
-                                continue;
-                            }
-                        };
+        let forked = self.booted_at.map(|booted_at| booted_at.elapsed());
+        let spawned = match self.launcher {
+            Launcher::Fork => Self::fork(&service),
+            #[cfg(feature = "tokio")]
+            Launcher::Tokio(ref exits, ref events) => {
+                crate::async_engine::spawn(&service, exits.clone(), events.clone())
+            }
+            #[cfg(feature = "testing")]
+            Launcher::Fake(ref mut processes) => processes.spawn(&service.name),
+        };
+        // e.g. operator is out of fds or processes.
+        let (pid, errors) = match spawned {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                error!("Failed to start service {}: {e}", service.name);
+                service.secret_env = Secrets::default();
+                let reason = FailReason::ExecError(e.raw_os_error().unwrap_or(0));
+                service.status = Some(Status::Failed { reason });
+                self.services.insert(service.name.clone(), service);
+                return Err(reason);
+            }
+        };
 
-                        if let Some(service) = self.services.get_mut(&pid) {
-                            match wait_stat {
-                                WaitStatus::Exited(_, _) => {
-                                    service.status = Some(crate::service::Status::Stopped);
-                                }
-                                WaitStatus::Signaled(_, _, _) => {
-                                    service.status = Some(crate::service::Status::Stopped);
-                                }
-                                e => {
-                                    info!("waitpid() returned {e:?}")
-                                }
-                            }
-                        }
-                    } else {
-                        continue;
-                    }
-                } else {
-                    let stream = ipc_server.accept().unwrap();
-                    let msg = stream.read().unwrap();
-
-                    match msg {
-                        IPCMessage::Start { .. } => {}
-                        IPCMessage::Stop { name } => {
-                            if let Some((pid, _)) = self
-                                .services
-                                .iter()
-                                .find(|(_, service)| service.name == name)
-                            {
-                                info!("Asking service {name} to terminate.");
-                                if let Err(e) = kill(Pid::from_raw(*pid), Signal::SIGTERM) {
-                                    error!("kill() failed with {e}");
-                                }
-                            } else {
-                                warn!("No service found to kill")
-                            }
-                        }
-                        IPCMessage::Status { name } => {
-                            if let Some((pid, service)) =
-                                self.services.iter().find(|(_, v)| v.name == name)
-                            {
-                                stream
-                                    .write(&IPCMessage::StatusResponse(Some((
-                                        *pid,
-                                        service.status.unwrap(),
-                                    ))))
-                                    .unwrap();
-                            } else {
-                                stream.write(&IPCMessage::StatusResponse(None)).unwrap();
-                            }
-                        }
-                        _ => {}
+        service.status = Some(Status::Running);
+        // the pipe is closed once the process and its children are gone.
+        drop(output_fd);
+        service.output_fd = None;
+        drop(input_fd);
+        service.input_fd = None;
+        service.forking = service.service_type == ServiceType::Forking;
+        // only the first start of the services booted is timed.
+        if let Some(timing) = self
+            .timings
+            .get_mut(&service.name)
+            .filter(|timing| timing.forked.is_none())
+        {
+            timing.forked = forked;
+        }
+        service.timed_out = false;
+
+        // the secrets are only kept by the process.
+        service.secret_env = Secrets::default();
+        service.failure_env.clear();
+        service.stopping = false;
+        service.started_at = Some(Instant::now());
+        service.active_since = Some(unix_time());
+        service.pid = Some(pid);
+
+        self.pids.insert(pid, service.name.clone());
+        self.state.services.insert(
+            service.name.clone(),
+            ServiceState::new(pid, Status::Running),
+        );
+        self.services.insert(service.name.clone(), service);
+        self.save_state();
+
+        if matches!(self.launcher, Launcher::Fork) {
+            if let Err(e) = self.reactor.register(&errors, Token::Executed(pid)) {
+                error!("Failed to watch the start of PID {pid}: {e}");
+            }
+        }
+        self.executing.insert(pid, errors);
+        // the outcome is already known for fake processes.
+        self.executed(pid);
+        Ok(())
+    }
+
+    /// Handle the outcome of the start of a process once its error pipe is readable:
+    /// it executed the program of its service, or it failed to and it's failed.
+    pub(crate) fn executed(&mut self, pid: i32) {
+        let Some(errors) = self.executing.remove(&pid) else {
+            return;
+        };
+        let Some(exec_error) = executor::outcome(&errors) else {
+            self.executing.insert(pid, errors);
+            return;
+        };
+        if matches!(self.launcher, Launcher::Fork) {
+            _ = self.reactor.deregister(&errors, &Token::Executed(pid));
+        }
+
+        let Some(service) = self
+            .pids
+            .get(&pid)
+            .and_then(|name| self.services.get_mut(name))
+            .filter(|service| service.pid == Some(pid))
+        else {
+            return;
+        };
+        let name = service.name.clone();
+
+        if let Some(errno) = exec_error {
+            error!("Service {name} failed to start: {}", Errno::from_i32(errno));
+            let reason = FailReason::ExecError(errno);
+            service.status = Some(Status::Failed { reason });
+            service.forking = false;
+            if let Some(state) = self.state.services.get_mut(&name) {
+                state.status = Status::Failed { reason };
+            }
+            self.save_state();
+            self.start_failed(&name, reason);
+            // a service restarted with `start-then-stop` keeps its previous process.
+            self.keep_previous(&name);
+            self.boot_queued();
+            return;
+        }
+
+        if service.notify_socket.is_none() && !service.forking {
+            service.ready = true;
+        }
+        if let Some(interval) = service.watchdog_interval() {
+            service.watchdog = Some(
+                self.reactor
+                    .add_timer(interval, Token::Watchdog(name.clone())),
+            );
+        }
+        if let Some(timeout) = service.start_timeout().filter(|_| !service.ready) {
+            service.start_timeout = Some(
+                self.reactor
+                    .add_timer(timeout, Token::StartTimeout(name.clone())),
+            );
+        }
+        Self::schedule_restart(&mut self.reactor, service);
+        let ready = service.ready;
+        // a service that notifies is ready once it says so, a forking one once its
+        // daemon is.
+        let timed_ready = (service.notify_socket.is_none() || !service.notify) && !service.forking;
+
+        // only the first start of the services booted is timed.
+        if let Some(timing) = self
+            .timings
+            .get_mut(&name)
+            .filter(|timing| timing.exec.is_none())
+        {
+            let exec = self.booted_at.map(|booted_at| booted_at.elapsed());
+            timing.exec = exec;
+            if timed_ready {
+                timing.ready = exec;
+            }
+        }
+        Self::record(
+            &name,
+            HistoryEntry {
+                pid: Some(pid),
+                ..HistoryEntry::new(RunEvent::Start)
+            },
+        );
+
+        if ready {
+            self.retire(&name);
+            self.boot_queued();
+        }
+    }
+
+    /// Adopt the process of a service left running by a previous operator.
+    ///
+    /// Returns whether it was adopted.
+    fn adopt(&mut self, service: &mut Service) -> bool {
+        let Some(state) = self.adoptable.remove(&service.name) else {
+            return false;
+        };
+        // the fd is only ours if we were re-executed, a new operator didn't inherit it.
+        // The output left in the pipe by a process that exited meanwhile is read too.
+        if let Some(fd) = state.output_fd.filter(|_| self.resume) {
+            let output = self.outputs.entry(service.name.clone()).or_default();
+            if let Err(e) = output.resume(&service.name, fd) {
+                warn!(
+                    "Failed to resume capturing the output of {}: {e}",
+                    service.name
+                );
+            }
+        }
+        if !state.is_alive() {
+            // a service waiting for a connection waits again on a socket bound by us.
+            if !self.resume
+                || state.restart
+                || matches!(state.status, Status::Running | Status::Listening)
+            {
+                return false;
+            }
+
+            service.status = Some(state.status);
+            service.pid = Some(state.pid);
+            self.state.services.insert(service.name.clone(), state);
+            self.services.insert(service.name.clone(), service.clone());
+            return true;
+        }
+
+        info!(
+            "Adopting service {} running as PID {}",
+            service.name, state.pid
+        );
+
+        // the children of an operator that exited were reparented.
+        self.watch(state.pid);
+
+        let now = unix_time();
+        // it was ready for the previous operator, and keeps notifying on the same path.
+        service.status = Some(Status::Running);
+        service.pid = Some(state.pid);
+        service.ready = true;
+        service.notify_socket = self.bind_notify(service);
+        service.cgroup = Some(cgroup::path(&service.name)).filter(|cgroup| cgroup.is_dir());
+        service.started_at =
+            Instant::now().checked_sub(Duration::from_secs(now.saturating_sub(state.started_at)));
+        service.active_since = Some(state.started_at);
+        // the executable may have been replaced before, it's not known.
+        service.executable_id = service.executable_id();
+        Self::schedule_restart(&mut self.reactor, service);
+
+        self.pids.insert(state.pid, service.name.clone());
+        self.state.services.insert(service.name.clone(), state);
+        self.services.insert(service.name.clone(), service.clone());
+        true
+    }
+
+    /// Watch the exit of the process of a service that may not be our child.
+    ///
+    /// We can't wait for a process that is not our child, so we watch a pidfd instead.
+    fn watch(&mut self, pid: i32) {
+        if state::proc_stat(pid).is_some_and(|stat| stat.ppid == getpid().as_raw()) {
+            return;
+        }
+
+        match state::pidfd_open(pid) {
+            Ok(pidfd) => match self.launcher {
+                Launcher::Fork => {
+                    if let Err(e) = self.reactor.register(&pidfd, Token::Adopted(pid)) {
+                        error!("Failed to watch PID {pid}: {e}");
                     }
+                    self.pidfds.insert(pid, pidfd);
                 }
+                #[cfg(feature = "tokio")]
+                Launcher::Tokio(ref exits, _) => {
+                    crate::async_engine::watch(pid, pidfd, exits.clone())
+                }
+                #[cfg(feature = "testing")]
+                Launcher::Fake(_) => {}
+            },
+            Err(e) => error!("Failed to watch PID {pid}: {e}"),
+        }
+    }
+
+    /// Track the daemon of a forking service once the process that forked it exited.
+    fn forked(&mut self, name: &str, pid: i32) {
+        info!("Service {name} forked its daemon as PID {pid}");
+        self.watch(pid);
+
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        service.pid = Some(pid);
+        service.ready = true;
+        if let Some(timer) = service.start_timeout.take() {
+            self.reactor.cancel_timer(timer);
+        }
+        self.pids.insert(pid, name.to_string());
+        let started_at = self.state.services.get(name).map(|state| state.started_at);
+        let mut state = ServiceState::new(pid, Status::Running);
+        state.started_at = started_at.unwrap_or(state.started_at);
+        self.state.services.insert(name.to_string(), state);
+        self.save_state();
+
+        if let Some(timing) = self
+            .timings
+            .get_mut(name)
+            .filter(|timing| timing.ready.is_none())
+        {
+            timing.ready = self.booted_at.map(|booted_at| booted_at.elapsed());
+        }
+        self.retire(name);
+        self.boot_queued();
+    }
+
+    /// Replace operator with a new instance of its binary, which adopts the services.
+    ///
+    /// The binary is executed from its path, so an upgraded operator is picked up.
+    fn reexec(&mut self) {
+        if !matches!(self.launcher, Launcher::Fork) {
+            warn!("Only the operator daemon can be re-executed");
+            return;
+        }
+
+        let exe = match std::env::current_exe() {
+            // the path of a binary that was replaced.
+            Ok(exe) => match exe.to_string_lossy().strip_suffix(" (deleted)") {
+                Some(path) => path.into(),
+                None => exe,
+            },
+            Err(e) => {
+                error!("Failed to find the operator binary: {e}");
+                return;
+            }
+        };
+
+        let mut args = std::env::args_os()
+            .map(|arg| CString::new(arg.as_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        if !args.iter().any(|arg| arg.as_bytes() == b"--resume") {
+            args.push(CString::new("--resume").unwrap());
+        }
+
+        // the new operator adopts the services from the runtime state, and keeps reading
+        // their output from the pipes it inherits.
+        for (name, output) in &self.outputs {
+            if let Some(state) = self.state.services.get_mut(name) {
+                state.output_fd = output.inherit();
             }
         }
+        self.save_state();
+        info!("Re-executing operator from {exe:?}");
+        let exe = CString::new(exe.as_os_str().as_bytes()).unwrap();
+        let Err(e) = execv(&exe, &args);
+        error!("Failed to re-execute operator: {e}");
+    }
+
+    /// Persist the runtime state of the services.
+    fn save_state(&self) {
+        // fake processes can't be adopted.
+        if !self.launcher.is_real() {
+            return;
+        }
+        if let Err(e) = self.state.write() {
+            error!("Failed to persist the runtime state: {e}");
+        }
     }
-}
 
-/// Helper functions for communicating b/w single handler and engine using pipes.
-mod comms {
-    use std::os::fd::BorrowedFd;
+    /// Fork the executor of a service, which executes its program.
+    ///
+    /// Returns the pid of the child, and the pipe the errno of a failed start is
+    /// written to.
+    fn fork(service: &Service) -> std::io::Result<(i32, OwnedFd)> {
+        Executor::new(&Job::Start(Context::new(service)))?.spawn()
+    }
+
+    /// Start a loaded service that is not running.
+    fn start(&mut self, name: &str) {
+        self.instantiate(name);
+
+        let Some(service) = self.services.get_mut(name) else {
+            warn!("No service found to start");
+            return;
+        };
 
-    use lazy_static::lazy_static;
-    use nix::unistd::{pipe, read, write};
+        if service.masked || matches!(service.status, Some(Status::Running)) {
+            match service.masked {
+                true => warn!("Service {name} is masked, not starting it"),
+                false => warn!("Service {name} is already running"),
+            }
+            // the env of a failure is only for a process started for it.
+            service.failure_env.clear();
+            return;
+        }
 
-    use serde::{Deserialize, Serialize};
+        // the services it requires or wants are started first.
+        let mut pulled_in = Self::pulled_in(vec![name.to_string()], |name| {
+            self.services
+                .get(name)
+                .map(|service| [&service.requires[..], &service.wants[..]].concat())
+                .unwrap_or_default()
+        });
+        pulled_in.pop();
+
+        for dep in pulled_in {
+            self.instantiate(&dep);
+            match self.services.get(&dep) {
+                Some(service)
+                    if service.masked || matches!(service.status, Some(Status::Running)) => {}
+                Some(_) => self.enqueue(dep, Launch::Start),
+                None => warn!("Service {name} depends on {dep} which is not loaded"),
+            }
+        }
 
-    /// All the signal data provided by signal handler
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct SignalData {
-        /// process id of the child
-        pub pid: i32,
-        /// user id of the child
-        pub uid: u32,
-        /// status of the child
-        pub status: i32,
-        /// errno of the child
-        pub errno: i32,
-        /// im not sure actually what code is
-        pub code: i32,
+        self.enqueue(name.to_string(), Launch::Start);
+        self.boot_queued();
     }
 
-    lazy_static! {
-        /// This pipe is used to send data b/w signal handler and engine.
-        ///
-        /// PIPES.0 - read fd
-        /// PIPES.1 - write fd
-        static ref PIPES: (i32, i32) = pipe().unwrap();
+    /// Queue a service to be started once the services it's ordered after are ready.
+    fn enqueue(&mut self, name: String, launch: Launch) {
+        if !self.queue.iter().any(|(queued, _)| *queued == name) {
+            self.queue.push((name, launch));
+        }
     }
 
-    /// Read signal data from the pipe if any
+    /// Start the queued services whose `after` services are ready.
     ///
-    /// NOTE: Does not block
-    pub fn read_from_pipe() -> anyhow::Result<i32> {
-        let mut buf = [0; 4];
-        let n_bytes = read(PIPES.0, &mut buf)?;
+    /// A service waits for the ones queued before it, the ones queued after it are part
+    /// of an ordering cycle. At boot, a service also waits until fewer than
+    /// `max_parallel_starts` services are starting.
+    fn boot_queued(&mut self) {
+        let mut i = 0;
+        while i < self.queue.len() {
+            let (ref name, launch) = self.queue[i];
+            let blocked = self.services.get(name).is_some_and(|service| {
+                service.after.iter().any(|dep| {
+                    self.queue[..i].iter().any(|(queued, _)| queued == dep)
+                        || self.services.get(dep).is_some_and(Service::is_starting)
+                })
+            });
+            // the services started at boot wait for a slot, not the ones started by
+            // operatorctl.
+            let boots = match launch {
+                Launch::Boot { pulled_in } => self.services.get(name).is_some_and(|service| {
+                    (service.starts_at_boot() || pulled_in) && !service.masked
+                }),
+                Launch::Start => false,
+            };
+            let throttled = boots
+                && self
+                    .max_parallel_starts
+                    .is_some_and(|max| self.starting() >= max);
+            if blocked || throttled {
+                i += 1;
+                continue;
+            }
 
-        if n_bytes == 0 {
-            anyhow::bail!("Faild to read, probably invalid")
-        } else {
-            debug_assert!(n_bytes == buf.len());
-            Ok(i32::from_le_bytes(buf))
+            let (name, _) = self.queue.remove(i);
+            let Some(service) = self.services.remove(&name) else {
+                continue;
+            };
+            match launch {
+                Launch::Boot { pulled_in } => self.boot(service, pulled_in),
+                Launch::Start => self.spawn(service),
+            }
+        }
+    }
+
+    /// Number of services started but not ready yet.
+    fn starting(&self) -> usize {
+        self.services
+            .values()
+            .filter(|service| service.is_starting())
+            .count()
+    }
+
+    /// Create the instance of a template service on its first start.
+    fn instantiate(&mut self, name: &str) {
+        if self.services.contains_key(name) {
+            return;
+        }
+
+        if let Some((template, instance)) = name.split_once('@') {
+            if let Some(template) = self.templates.get(&format!("{template}@")) {
+                let service = template.instantiate(instance);
+                self.services.insert(service.name.clone(), service);
+            }
         }
     }
 
-    /// Write signal data to a pipe
+    /// Services pulled in by `roots` through `requires` and `wants`, dependencies first.
     ///
-    /// NOTE: Does not block
-    #[inline]
-    pub fn write_to_pipe(val: i32) -> anyhow::Result<()> {
-        let n_bytes = write(PIPES.1, &val.to_le_bytes())?;
-        debug_assert!(n_bytes == std::mem::size_of::<i32>());
-        Ok(())
+    /// The roots are included after their dependencies.
+    fn pulled_in(roots: Vec<String>, deps: impl Fn(&str) -> Vec<String>) -> Vec<String> {
+        let mut pulled_in = vec![];
+        let mut visited = HashSet::new();
+        // a service is pushed back once its dependencies were visited.
+        let mut stack = roots
+            .into_iter()
+            .rev()
+            .map(|root| (root, false))
+            .collect::<Vec<_>>();
+
+        while let Some((name, visited_deps)) = stack.pop() {
+            if visited_deps {
+                pulled_in.push(name);
+                continue;
+            }
+            if !visited.insert(name.clone()) {
+                continue;
+            }
+
+            let deps = deps(&name);
+            stack.push((name, true));
+            stack.extend(deps.into_iter().rev().map(|dep| (dep, false)));
+        }
+
+        pulled_in
     }
 
-    /// Returns a BorrowedFd
-    pub fn read_fd<'a>() -> BorrowedFd<'a> {
-        unsafe { BorrowedFd::borrow_raw(PIPES.0) }
+    /// Order services so each one comes after the ones in its `after`.
+    ///
+    /// Services in an ordering cycle are left in their order.
+    fn boot_order(mut services: Vec<Service>) -> Vec<Service> {
+        let names = services
+            .iter()
+            .map(|service| service.name.clone())
+            .collect::<HashSet<_>>();
+        let mut ordered = Vec::<Service>::with_capacity(services.len());
+        let mut booted = HashSet::new();
+
+        while !services.is_empty() {
+            let (ready, blocked): (Vec<_>, Vec<_>) = services.into_iter().partition(|service| {
+                service
+                    .after
+                    .iter()
+                    .all(|dep| booted.contains(dep) || !names.contains(dep))
+            });
+
+            if ready.is_empty() {
+                let cycle = blocked
+                    .iter()
+                    .map(|service| &service.name)
+                    .collect::<Vec<_>>();
+                warn!("Ordering cycle between services {cycle:?}, ignoring it");
+                ordered.extend(blocked);
+                break;
+            }
+
+            booted.extend(ready.iter().map(|service| service.name.clone()));
+            ordered.extend(ready);
+            services = blocked;
+        }
+
+        ordered
     }
+
+    /// Grow or shrink the number of replicas of a replicated service.
+    fn scale(&mut self, name: &str, replicas: u32) -> Result<(), String> {
+        let Some(set) = self.replica_sets.get_mut(name) else {
+            return Err(format!("no {name} replicated service found"));
+        };
+
+        let current = set.replicas.unwrap_or(0);
+        set.replicas = Some(replicas);
+        info!("Scaling service {name} from {current} to {replicas} replicas");
+
+        if replicas > current {
+            let new = (current + 1..=replicas)
+                .map(|index| set.replica(index))
+                .collect::<Vec<_>>();
+            for service in new {
+                self.boot(service, false);
+            }
+            return Ok(());
+        }
+
+        let is_removed = |service: &Service| matches!(service.replica, Some((ref set, index)) if set == name && index > replicas);
+
+        self.services.retain(|_, service| {
+            if !is_removed(service) {
+                return true;
+            }
+
+            // running replicas are forgotten once they exit.
+            if matches!(service.status, Some(Status::Running)) {
+                service.stopping = true;
+                Self::terminate(service);
+                return true;
+            }
+            false
+        });
+        self.state
+            .services
+            .retain(|name, _| self.services.contains_key(name));
+        self.save_state();
+        Ok(())
+    }
+
+    /// Enable or disable a service at boot.
+    fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let Some(service) = self.services.get_mut(name) else {
+            return Err(format!("no {name} service found"));
+        };
+        service.set_enabled(enabled).map_err(|e| {
+            error!("Failed to persist enabled state of {name}: {e}");
+            format!("failed to persist the enabled state of {name}: {e}")
+        })
+    }
+
+    /// Read the service file of a loaded service again, it applies from its next start.
+    ///
+    /// Only this service is changed, not the other instances or replicas of its file.
+    fn reload_file(&mut self, name: &str) -> Result<(), String> {
+        let loaded = &self.services[name];
+        let Some(path) = loaded.sources.first().cloned() else {
+            return Err(format!("{name} has no service file"));
+        };
+        let service = Service::from_file(&path).map_err(|e| e.to_string())?;
+
+        let mut reloaded = if let Some((set, index)) = loaded.replica.clone() {
+            if service.name != set || service.replicas.is_none() {
+                return Err(format!(
+                    "{} doesn't define the replicas of {set}",
+                    path.display()
+                ));
+            }
+            let replica = service.replica(index);
+            self.replica_sets.insert(set, service);
+            replica
+        } else if service.is_template() {
+            let Some(instance) = name.strip_prefix(&service.name) else {
+                return Err(format!("{} doesn't define {name}", path.display()));
+            };
+            let instance = service.instantiate(instance);
+            self.templates.insert(service.name.clone(), service);
+            instance
+        } else if service.name == name && service.replicas.is_none() {
+            service
+        } else {
+            return Err(format!("{} doesn't define {name}", path.display()));
+        };
+
+        reloaded.keep_runtime(loaded);
+        info!("Service file of {name} reloaded from {}", path.display());
+        self.services.insert(name.to_string(), reloaded);
+        Ok(())
+    }
+
+    /// Override properties of a service, they apply from its next start.
+    fn set_property(
+        service: &mut Service,
+        properties: &[(String, String)],
+        persist: bool,
+    ) -> Result<(), String> {
+        let overrides = property::apply(service, properties)?;
+        info!("Properties of {} set: {properties:?}", service.name);
+        if persist {
+            let path = property::persist(service, overrides).map_err(|e| e.to_string())?;
+            info!(
+                "Properties of {} persisted to {}",
+                service.name,
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Mask or unmask a service, a masked one isn't restarted anymore.
+    fn set_masked(&mut self, name: &str, masked: bool) -> Result<(), String> {
+        let Some(service) = self.services.get_mut(name) else {
+            return Err(format!("no {name} service found"));
+        };
+        let persisted = service.set_masked(masked).map_err(|e| {
+            error!("Failed to persist masked state of {name}: {e}");
+            format!("failed to persist the masked state of {name}: {e}")
+        });
+        if masked {
+            for timer in [
+                service.restart_timer.take(),
+                service.scheduled_restart.take(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                self.reactor.cancel_timer(timer);
+            }
+            self.restarting.remove(name);
+        }
+        persisted
+    }
+
+    /// Ask a service to stop, its backend sends SIGTERM to the processes of its
+    /// `kill_mode` by default.
+    fn terminate(service: &Service) {
+        service.backend().stop(service);
+    }
+
+    /// Ask a running service to reload its configuration.
+    fn reload(&self, service: &Service) -> Result<(), String> {
+        let (Some(pid), Some(Status::Running)) = (service.pid, service.status) else {
+            return Err(format!("{} is not running", service.name));
+        };
+
+        match service.reload_action() {
+            Some(Reload::Signal(signal)) => {
+                info!("Sending {signal} to service {} to reload.", service.name);
+                kill(Pid::from_raw(pid), signal).map_err(|e| {
+                    error!("kill() failed with {e}");
+                    format!("failed to send {signal} to {}: {e}", service.name)
+                })
+            }
+            Some(Reload::Command(cmd)) => {
+                info!("Running `{cmd}` to reload service {}.", service.name);
+                let res = match self.launcher {
+                    // the child is reaped by the SIGCHLD handler like any other.
+                    Launcher::Fork => std::process::Command::new("/bin/sh")
+                        .arg("-c")
+                        .arg(&cmd)
+                        .env("MAINPID", pid.to_string())
+                        .spawn()
+                        .map(drop),
+                    // tokio reaps the child once it's dropped.
+                    #[cfg(feature = "tokio")]
+                    Launcher::Tokio(..) => tokio::process::Command::new("/bin/sh")
+                        .arg("-c")
+                        .arg(&cmd)
+                        .env("MAINPID", pid.to_string())
+                        .spawn()
+                        .map(drop),
+                    #[cfg(feature = "testing")]
+                    Launcher::Fake(_) => Ok(()),
+                };
+
+                res.map_err(|e| {
+                    error!("Failed to run reload command for {}: {e}", service.name);
+                    format!("failed to run the reload command of {}: {e}", service.name)
+                })
+            }
+            None => Err(format!("{} has no exec_reload set", service.name)),
+        }
+    }
+
+    /// Load the service files and start the services.
+    pub(crate) fn load(&mut self) {
+        self.booted_at = Some(Instant::now());
+        // the log dir of a per-user operator is in the home of the user, it may not
+        // exist yet.
+        if user_mode() {
+            if let Err(e) = std::fs::create_dir_all(op_service_log_dir()) {
+                error!("Failed to create the log dir: {e}");
+            }
+        }
+        match Config::read() {
+            Ok(config) => {
+                #[cfg(feature = "http")]
+                if let Some(api) = config.http {
+                    if let Err(e) = crate::http::serve(api) {
+                        error!("Failed to serve the HTTP API: {e}");
+                    }
+                }
+                #[cfg(feature = "grpc")]
+                if let Some(api) = config.grpc {
+                    if let Err(e) = crate::grpc::serve(api) {
+                        error!("Failed to serve the gRPC API: {e}");
+                    }
+                }
+                #[cfg(feature = "remote")]
+                if let Some(remote) = config.remote {
+                    if let Err(e) = crate::remote::serve(remote) {
+                        error!("Failed to serve the remote clients: {e}");
+                    }
+                }
+                #[cfg(feature = "chaos")]
+                if self.faults.is_none() {
+                    let chaos = match config.chaos {
+                        Some(chaos) => Some(chaos),
+                        None => chaos::enabled_by_env().then(ChaosConfig::default),
+                    };
+                    if let Some(chaos) = chaos {
+                        warn!("Chaos mode enabled, injecting faults: {chaos:?}");
+                        self.faults = Some(Box::new(Chaos::new(chaos)));
+                    }
+                }
+                if self.max_parallel_starts.is_none() {
+                    self.max_parallel_starts = config.max_parallel_starts;
+                }
+                if self.shutdown_timeout.is_none() {
+                    self.shutdown_timeout = config.shutdown_timeout_secs.map(Duration::from_secs);
+                }
+                self.pass_environment = config.pass_environment;
+                self.coredump = config.coredump;
+                self.journal = Journal::new(config.journal);
+                self.ipc = config.ipc;
+                match Notifier::new(config.webhooks) {
+                    Ok(notifier) => self.notifier = notifier,
+                    Err(e) => error!("Failed to start the webhooks thread: {e}"),
+                }
+            }
+            Err(e) => error!("Failed to read the config file: {e}"),
+        }
+        self.adoptable = match State::read() {
+            Ok(state) => state.services,
+            Err(e) => {
+                error!("Failed to read the runtime state: {e}");
+                HashMap::new()
+            }
+        };
+
+        let service_files = match Service::read_service_files() {
+            Ok((services, errors)) => {
+                self.load_errors = errors;
+                services
+            }
+            Err(e) => {
+                error!("Failed to read the service files: {e}");
+                return;
+            }
+        };
+
+        self.boot_services(service_files);
+        self.adoptable.clear();
+        self.save_state();
+        self.reactor.add_timer(SAMPLE_INTERVAL, Token::Sample);
+    }
+
+    /// Start the services loaded at boot, in the order of their dependencies.
+    ///
+    /// The templates and replicated services are started as their instances.
+    pub(crate) fn boot_services(&mut self, service_files: Vec<Service>) {
+        let (boot, definitions) = Self::expand(service_files);
+        for service in definitions {
+            match service.is_template() {
+                true => self.templates.insert(service.name.clone(), service),
+                false => self.replica_sets.insert(service.name.clone(), service),
+            };
+        }
+        let pulled_in = Self::pulled_in_at_boot(&boot);
+
+        let loaded = self.booted_at.get_or_insert_with(Instant::now).elapsed();
+        for mut service in Self::boot_order(boot) {
+            let pulled_in = pulled_in.contains(&service.name);
+            self.timings.insert(
+                service.name.clone(),
+                BootTiming {
+                    name: service.name.clone(),
+                    loaded,
+                    forked: None,
+                    exec: None,
+                    ready: None,
+                    after: service.after.clone(),
+                },
+            );
+
+            // services left running are adopted right away, the others are started
+            // once the services they are ordered after are ready.
+            if !self.adopt(&mut service) {
+                self.queue
+                    .push((service.name.clone(), Launch::Boot { pulled_in }));
+                self.services.insert(service.name.clone(), service);
+            }
+        }
+
+        self.boot_queued();
+    }
+
+    /// Replace the templates and the replicated services by their instances and replicas.
+    ///
+    /// Returns the services to boot, and the definitions of the templates and replicated
+    /// services.
+    fn expand(service_files: Vec<Service>) -> (Vec<Service>, Vec<Service>) {
+        let mut boot = vec![];
+        let mut definitions = vec![];
+        for service in service_files.into_iter() {
+            if service.is_template() {
+                for instance in 1..=service.instances.unwrap_or(0) {
+                    boot.push(service.instantiate(&instance.to_string()));
+                }
+                definitions.push(service);
+                continue;
+            }
+
+            if let Some(replicas) = service.replicas {
+                for index in 1..=replicas {
+                    boot.push(service.replica(index));
+                }
+                definitions.push(service);
+                continue;
+            }
+
+            boot.push(service);
+        }
+        (boot, definitions)
+    }
+
+    /// Services required or wanted by the enabled ones, they are started too at boot,
+    /// even manual ones.
+    fn pulled_in_at_boot(boot: &[Service]) -> HashSet<String> {
+        let enabled = boot
+            .iter()
+            .filter(|service| service.starts_at_boot())
+            .map(|service| service.name.clone())
+            .collect();
+        Self::pulled_in(enabled, |name| {
+            boot.iter()
+                .find(|service| service.name == name)
+                .map(|service| [&service.requires[..], &service.wants[..]].concat())
+                .unwrap_or_default()
+        })
+        .into_iter()
+        .collect()
+    }
+
+    /// Load the config and the service files like at boot, and return the order the
+    /// services would be started in and the errors found, without starting any.
+    pub fn dry_run() -> DryRun {
+        let mut dry_run = DryRun::default();
+        if let Err(e) = Config::read() {
+            dry_run.errors.push(e.to_string());
+        }
+        let service_files = match Service::read_service_files() {
+            Ok((services, errors)) => {
+                dry_run.errors.extend(
+                    errors
+                        .iter()
+                        .map(|error| format!("{}: {}", error.path.display(), error.error)),
+                );
+                services
+            }
+            Err(e) => {
+                dry_run
+                    .errors
+                    .push(format!("Failed to read the service files: {e}"));
+                return dry_run;
+            }
+        };
+
+        let (boot, definitions) = Self::expand(service_files);
+        let pulled_in = Self::pulled_in_at_boot(&boot);
+        // the instances of templates are created on their first start.
+        let known = |name: &str| {
+            boot.iter().any(|service| service.name == name)
+                || name.split_once('@').is_some_and(|(template, _)| {
+                    let template = format!("{template}@");
+                    definitions.iter().any(|service| service.name == template)
+                })
+        };
+
+        let order = Self::boot_order(boot.clone());
+        for (i, service) in order.iter().enumerate() {
+            for edge in service.edges() {
+                if !known(&edge.to) {
+                    dry_run.errors.push(format!(
+                        "{}: unknown service `{}` in `{}`",
+                        service.name, edge.to, edge.dependency
+                    ));
+                }
+            }
+            for handler in service.on_failure.iter().filter(|handler| !known(handler)) {
+                dry_run.errors.push(format!(
+                    "{}: unknown service `{handler}` in `on_failure`",
+                    service.name
+                ));
+            }
+            // a service ordered before one it's after is in an ordering cycle.
+            let cycle = service
+                .after
+                .iter()
+                .filter(|dep| order[i..].iter().any(|later| later.name == **dep))
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            if !cycle.is_empty() {
+                dry_run.errors.push(format!(
+                    "{}: ordering cycle through {}, ignored at boot",
+                    service.name,
+                    cycle.join(", ")
+                ));
+            }
+
+            let started =
+                (service.starts_at_boot() || pulled_in.contains(&service.name)) && !service.masked;
+            let mut start = match service.validate() {
+                _ if service.masked => "masked".to_string(),
+                _ if !started => "not started, disabled or manual".to_string(),
+                // the log dir belongs to the environment of operator, not to the service.
+                Err(reason) if !matches!(reason, FailReason::LogDirNotWritable) => {
+                    dry_run.errors.push(format!("{}: {reason}", service.name));
+                    format!("fails to start: {reason}")
+                }
+                _ => match service.start_delay() {
+                    Some(delay) => format!("started after {delay:?}"),
+                    None if service.socket.as_ref().is_some_and(|socket| !socket.eager) => {
+                        "started on the first connection to its socket".to_string()
+                    }
+                    None if !service.starts_at_boot() => "started, pulled in".to_string(),
+                    None => "started".to_string(),
+                },
+            };
+            if !service.after.is_empty() {
+                start.push_str(&format!(" (after {})", service.after.join(", ")));
+            }
+            dry_run.order.push((service.name.clone(), start));
+        }
+        dry_run
+    }
+
+    /// Start the engine and manage the services.
+    ///
+    /// It only returns if it couldn't be set up, operator exits once the services it
+    /// was asked to stop are gone.
+    pub fn run(&mut self) -> crate::Result<()> {
+        // signals are blocked and read from a signalfd instead of running a handler,
+        // the kernel keeps them pending until they are read so none are lost.
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGCHLD);
+
+        if self.init {
+            // adopt orphaned processes even if we are not PID 1.
+            if let Err(e) = set_child_subreaper(true) {
+                error!("Failed to become a child subreaper: {e}");
+            }
+
+            mask.add(Signal::SIGTERM);
+            mask.add(Signal::SIGINT);
+            mask.add(Signal::SIGUSR1);
+            mask.add(Signal::SIGUSR2);
+        } else if in_foreground() {
+            // the services are in sessions of their own, a Ctrl-C would kill operator
+            // alone and leave them running.
+            self.foreground = true;
+            mask.add(Signal::SIGINT);
+        }
+
+        mask.thread_block()
+            .map_err(|e| setup_error("block signals", e))?;
+        let mut signal_fd =
+            SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+                .map_err(|e| setup_error("create signalfd", e))?;
+
+        // create an ipc server for comms b/w operator and operatorctl, before loading the
+        // services so they're not started twice when another operator is running.
+        let socket_path = op_socket_path();
+        let ipc_server = match self.take_over {
+            true => ipc::IPCServer::take_over(&socket_path),
+            false => ipc::IPCServer::bind(&socket_path),
+        };
+        let ipc_server = match ipc_server {
+            Ok(ipc_server) => ipc_server,
+            Err(e @ Error::AlreadyRunning(_)) => return Err(e),
+            Err(e) => {
+                return Err(setup_error(
+                    &format!("create the IPC socket {socket_path}"),
+                    e,
+                ))
+            }
+        };
+
+        self.load();
+        self.notify_changes();
+        let ipc_server = ipc_server.with_limits(self.ipc.limits);
+        let query_server = self.ipc.query_socket.clone().and_then(|path| {
+            let server = ipc::IPCServer::bind(&path).and_then(|server| {
+                ipc::open_to_all(&path)?;
+                Ok(server.with_limits(self.ipc.limits))
+            });
+            server
+                .inspect_err(|e| error!("Failed to bind the query socket {}: {e}", path.display()))
+                .ok()
+        });
+
+        let requests = ipc::Requests::new().map_err(|e| setup_error("read the IPC requests", e))?;
+
+        // we are polling on the signalfd and the ipc server.
+        let register = |e| setup_error("register with epoll", e);
+        self.reactor
+            .register(&signal_fd, Token::Signal)
+            .map_err(register)?;
+        self.reactor
+            .register(&ipc_server, Token::Ipc)
+            .map_err(register)?;
+        self.reactor
+            .register(&requests, Token::Request)
+            .map_err(register)?;
+        if let Some(ref query_server) = query_server {
+            self.reactor
+                .register(query_server, Token::Query)
+                .map_err(register)?;
+        }
+        loop {
+            // an EINTR is retried by the reactor.
+            let tokens = self
+                .reactor
+                .poll()
+                .map_err(|e| setup_error("wait with epoll_wait()", e))?;
+
+            for token in tokens {
+                match token {
+                    Token::Signal => loop {
+                        match signal_fd.read_signal() {
+                            Ok(Some(info)) if info.ssi_signo == Signal::SIGCHLD as u32 => {
+                                self.reap()
+                            }
+                            Ok(Some(info)) => self.handle_signal(info.ssi_signo as i32),
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Failed to read from signalfd: {e}");
+                                break;
+                            }
+                        }
+                    },
+                    Token::Ipc | Token::Query => {
+                        let (server, query) = match query_server {
+                            Some(ref server) if token == Token::Query => (server, true),
+                            _ => (&ipc_server, false),
+                        };
+                        match server.accept() {
+                            Ok(stream) => requests.read(stream, query),
+                            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                            // the client was told, and a flood isn't logged line by line.
+                            Err(Error::Busy) => {}
+                            Err(e) => warn!("Failed to accept an IPC connection: {e}"),
+                        }
+                    }
+                    Token::Request => {
+                        for request in requests.received() {
+                            self.handle_request(request);
+                        }
+                    }
+                    Token::Adopted(pid) => {
+                        if let Some(pidfd) = self.pidfds.remove(&pid) {
+                            _ = self.reactor.deregister(&pidfd, &token);
+                        }
+                        self.handle_lost(pid);
+                        self.exit_if_done();
+                    }
+                    Token::Executed(pid) => self.executed(pid),
+                    Token::Socket(name) => self.activate(&name),
+                    Token::Notify(ref name) => {
+                        let messages = self
+                            .notify_sockets
+                            .get(name)
+                            .map(notify::recv)
+                            .unwrap_or_default();
+                        for message in messages {
+                            self.handle_notify(name, &message);
+                        }
+                    }
+                    token => self.handle_timer(token),
+                }
+            }
+
+            self.notify_changes();
+        }
+    }
+
+    /// Send the status changes of the services since the last call to the webhooks and
+    /// the subscribers, and answer the clients waiting for the states reached.
+    pub(crate) fn notify_changes(&mut self) {
+        self.notified
+            .retain(|name, _| self.services.contains_key(name));
+
+        let mut changes = vec![];
+        for service in self.services.values() {
+            let status = service.current_status();
+            let old = self
+                .notified
+                .insert(service.name.clone(), status)
+                .unwrap_or(Status::Stopped);
+            if webhook::state(old) != webhook::state(status) {
+                let mut change = StateChange::new(&service.name, old, status, service.exit_code);
+                if let Some(lost) = self.lost.remove(&service.name) {
+                    change.reason = Some(lost);
+                }
+                change.core = self.cores.remove(&service.name);
+                change.crash = self.crashes.remove(&service.name);
+                changes.push(change);
+            }
+        }
+
+        // the services whose process was lost or crashed but that didn't change state.
+        self.lost.clear();
+        self.cores.clear();
+        self.crashes.clear();
+        for change in changes {
+            self.broadcast(change);
+        }
+
+        let done = self
+            .waiters
+            .iter()
+            .filter(|(_, waiter)| match self.services.get(&waiter.name) {
+                Some(service) => {
+                    let status = service.current_status();
+                    // a failed service won't reach the state without being started again.
+                    status.name() == waiter.state || matches!(status, Status::Failed { .. })
+                }
+                None => true,
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in done {
+            self.end_wait(id);
+        }
+    }
+
+    /// Send the status of a service to `respond` once it's in `state`, e.g. `running`,
+    /// once it failed, or once `timeout` elapsed.
+    ///
+    /// `None` is sent if the service is not found.
+    pub(crate) fn wait_for(
+        &mut self,
+        name: &str,
+        state: &str,
+        timeout: Duration,
+        respond: impl FnOnce(Option<ServiceInfo>) + Send + 'static,
+    ) {
+        let id = self.next_wait;
+        self.next_wait += 1;
+        let timeout = self.reactor.add_timer(timeout, Token::WaitTimeout(id));
+        self.waiters.insert(
+            id,
+            Waiter {
+                name: name.to_string(),
+                state: state.to_string(),
+                timeout,
+                respond: Box::new(respond),
+            },
+        );
+        // answered right away if it's already in the state.
+        self.notify_changes();
+    }
+
+    /// Send the current status of the service of a wait to its client.
+    fn end_wait(&mut self, id: u64) {
+        let Some(waiter) = self.waiters.remove(&id) else {
+            return;
+        };
+        self.reactor.cancel_timer(waiter.timeout);
+        (waiter.respond)(self.services.get(&waiter.name).map(Service::info));
+    }
+
+    /// Send a change to the subscribers and the webhooks, and record it in the journal.
+    fn broadcast(&mut self, change: StateChange) {
+        self.journal.record(&change);
+        self.subscribers
+            .retain_mut(|subscriber| subscriber(&change));
+        self.notifier.send(change);
+    }
+
+    /// Send the state changes of the services to `subscriber` until it returns false.
+    pub(crate) fn subscribe(
+        &mut self,
+        subscriber: impl FnMut(&StateChange) -> bool + Send + 'static,
+    ) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    /// Handle a timer that expired.
+    pub(crate) fn handle_timer(&mut self, token: Token) {
+        match token {
+            Token::Restart(name) => self.restart(&name),
+            Token::Watchdog(name) => self.watchdog_expired(&name),
+            Token::StartTimeout(name) => self.start_timed_out(&name),
+            Token::StopTimeout(name) => self.stop_timed_out(&name),
+            Token::ShutdownTimeout => {
+                error!("The services didn't stop in time, killing them");
+                self.kill_services();
+            }
+            Token::StartDelay(name) => self.delayed_start(&name),
+            #[cfg(feature = "chaos")]
+            Token::SlowStart(name) => self.slow_start(&name),
+            Token::ScheduledRestart(name) => self.restart_on_schedule(&name),
+            Token::WaitTimeout(id) => self.end_wait(id),
+            Token::Sample => {
+                self.sample();
+                self.sweep();
+                self.check_executables();
+                self.reactor.add_timer(SAMPLE_INTERVAL, Token::Sample);
+            }
+            _ => {}
+        }
+    }
+
+    /// Sample the memory and CPU usage of the running services.
+    fn sample(&mut self) {
+        for service in self.services.values_mut() {
+            service.usage = match service.status {
+                Some(Status::Running) => {
+                    usage::sample(service, service.usage.map(|(_, sample)| sample))
+                }
+                _ => None,
+            };
+        }
+    }
+
+    /// Reconcile the running services with their processes, whose exit operator may
+    /// have missed, e.g. if its SIGCHLD was lost or another wait() reaped it.
+    ///
+    /// A service whose process is gone is handled like after its exit, and the state
+    /// change says how operator lost track of it.
+    fn sweep(&mut self) {
+        // the fake pids are no process.
+        if !self.launcher.is_real() {
+            return;
+        }
+
+        let pids = self
+            .services
+            .values()
+            .filter(|service| matches!(service.status, Some(Status::Running)))
+            .filter_map(|service| Some((service.pid?, service.name.clone())))
+            .collect::<Vec<_>>();
+        for (pid, name) in pids {
+            // the children of the async engine are waited for by tokio.
+            let status = if matches!(self.launcher, Launcher::Fork) {
+                waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG))
+            } else {
+                Err(Errno::ECHILD)
+            };
+            let status = match status {
+                // an adopted process, or a child reaped by another wait().
+                Err(Errno::ECHILD) => {
+                    kill(Pid::from_raw(pid), None).map(|()| WaitStatus::StillAlive)
+                }
+                status => status,
+            };
+            let lost = match status {
+                Ok(WaitStatus::StillAlive) => continue,
+                // the SIGCHLD of the exit was read without reaping it, unless it's
+                // still pending.
+                Ok(status) => {
+                    if !sigchld_pending() {
+                        error!("Service {name} exited without operator noticing, PID {pid}");
+                        self.lost
+                            .insert(name, format!("the exit of PID {pid} was missed"));
+                    }
+                    self.handle_exit(pid, status);
+                    continue;
+                }
+                Err(Errno::ESRCH) => format!("PID {pid} disappeared"),
+                Err(e) => {
+                    warn!("Failed to check whether PID {pid} of {name} is alive: {e}");
+                    continue;
+                }
+            };
+
+            error!("The process of service {name} is gone, PID {pid}");
+            if let Some(pidfd) = self.pidfds.remove(&pid) {
+                _ = self.reactor.deregister(&pidfd, &Token::Adopted(pid));
+            }
+            self.lost.insert(name, lost.clone());
+            self.exited(pid, None, None, Some(lost));
+            self.stop_in_order();
+        }
+        self.exit_if_done();
+    }
+
+    /// Check whether the executables of the running services were replaced.
+    ///
+    /// A change is acted on once the executable is unchanged for a sample, so a binary
+    /// being copied in place is not run half written.
+    fn check_executables(&mut self) {
+        let mut restarts = vec![];
+        let mut changes = vec![];
+        for service in self.services.values_mut() {
+            if !matches!(service.status, Some(Status::Running))
+                || service.stopping
+                || service.executable_id.is_none()
+            {
+                continue;
+            }
+            // missing while it's being replaced.
+            let Some(current) = service.executable_id() else {
+                continue;
+            };
+            if Some(current) == service.executable_id {
+                service.executable_change = None;
+                service.replaced_by = None;
+                continue;
+            }
+            if service.replaced_by == Some(current) {
+                continue;
+            }
+            if service.executable_change != Some(current) {
+                service.executable_change = Some(current);
+                continue;
+            }
+
+            warn!(
+                "The executable of {} changed since it was started",
+                service.name
+            );
+            service.executable_change = None;
+            service.replaced_by = Some(current);
+            let mut change =
+                StateChange::new(&service.name, Status::Running, Status::Running, None);
+            change.reason = Some("executable changed".to_string());
+            changes.push(change);
+            if service.restart_on_binary_change {
+                restarts.push(service.name.clone());
+            }
+        }
+
+        // the services whose process was lost or crashed but that didn't change state.
+        self.lost.clear();
+        self.cores.clear();
+        self.crashes.clear();
+        for change in changes {
+            self.broadcast(change);
+        }
+        for name in restarts {
+            info!("Restarting {name} on the change of its executable");
+            self.restart_service(&name);
+        }
+    }
+
+    /// Stop a service, or stop waiting for a connection to its socket.
+    fn stop(&mut self, name: &str) {
+        self.restarting.remove(name);
+        self.retire(name);
+        // a service waiting for a connection stops waiting.
+        if let Some(service) = self
+            .services
+            .get_mut(name)
+            .filter(|service| matches!(service.status, Some(Status::Listening)))
+        {
+            service.status = Some(Status::Stopped);
+            self.unwatch_socket(name);
+        } else if let Some(service) = self.services.get_mut(name) {
+            // also cancels a pending restart.
+            service.stopping = true;
+            if let Some(state) = self.state.services.get_mut(name) {
+                state.restart = false;
+            }
+            if matches!(service.status, Some(Status::Running)) {
+                info!("Asking service {name} to terminate.");
+                Self::terminate(service);
+                self.arm_stop_timeout(name);
+            }
+        } else {
+            warn!("No service found to kill")
+        }
+    }
+
+    /// Kill a service asked to stop if it doesn't exit within its stop timeout.
+    fn arm_stop_timeout(&mut self, name: &str) {
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        if let (None, Some(timeout)) = (service.stop_timeout, service.stop_timeout()) {
+            service.stop_timeout = Some(
+                self.reactor
+                    .add_timer(timeout, Token::StopTimeout(name.to_string())),
+            );
+        }
+    }
+
+    /// Kill a service that didn't exit in time once asked to stop.
+    fn stop_timed_out(&mut self, name: &str) {
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        service.stop_timeout = None;
+        if service.stopping && matches!(service.status, Some(Status::Running)) {
+            warn!("Service {name} didn't stop in time, killing it");
+            self.launcher.kill(service);
+        }
+    }
+
+    /// Stop a service and start it again once it exited, or start it if it's not running.
+    fn restart_service(&mut self, name: &str) {
+        let handing_over = self.previous.values().any(|previous| previous.name == name);
+        match self.services.get(name).map(|service| service.status) {
+            Some(Some(Status::Running))
+                if self.services[name].restart_strategy == RestartStrategy::StartThenStop
+                    && !self.services[name].is_starting()
+                    && !self.services[name].stopping
+                    && !handing_over =>
+            {
+                self.start_then_stop(name);
+            }
+            Some(Some(Status::Running)) => {
+                self.stop(name);
+                self.restarting.insert(name.to_string());
+            }
+            Some(_) => self.start(name),
+            None => warn!("No service found to restart"),
+        }
+    }
+
+    /// Restart a running service by starting a new process, the previous one is stopped
+    /// once the new one is ready, or kept if it fails to start.
+    fn start_then_stop(&mut self, name: &str) {
+        let Some(mut service) = self.services.remove(name) else {
+            return;
+        };
+        let Some(pid) = service.pid else {
+            self.services.insert(name.to_string(), service);
+            return;
+        };
+
+        info!("Starting a new process of {name} before stopping PID {pid}");
+        self.pids.remove(&pid);
+        for timer in [service.watchdog.take(), service.scheduled_restart.take()]
+            .into_iter()
+            .flatten()
+        {
+            self.reactor.cancel_timer(timer);
+        }
+        self.previous.insert(
+            pid,
+            Previous {
+                name: name.to_string(),
+                started_at: service.started_at,
+                active_since: service.active_since,
+                executable_id: service.executable_id,
+                stopping: false,
+            },
+        );
+        self.spawn(service);
+
+        match self.services.get(name) {
+            Some(service) if matches!(service.status, Some(Status::Running)) => {
+                if service.ready {
+                    self.retire(name);
+                }
+            }
+            _ => self.keep_previous(name),
+        }
+    }
+
+    /// Stop the previous process of a service restarted with `start-then-stop`.
+    fn retire(&mut self, name: &str) {
+        let kill_mode = self.services.get(name).map(|service| service.kill_mode);
+        for (pid, previous) in self
+            .previous
+            .iter_mut()
+            .filter(|(_, previous)| previous.name == name && !previous.stopping)
+        {
+            info!("Stopping the previous process {pid} of {name}");
+            previous.stopping = true;
+            // the previous process shares the cgroup of the new one, only its process
+            // group is signaled.
+            let res = match kill_mode {
+                Some(KillMode::Group) => match kill(Pid::from_raw(-pid), Signal::SIGTERM) {
+                    Err(Errno::ESRCH) => kill(Pid::from_raw(*pid), Signal::SIGTERM),
+                    res => res,
+                },
+                _ => kill(Pid::from_raw(*pid), Signal::SIGTERM),
+            };
+            if let Err(e) = res {
+                error!("kill() failed with {e}");
+            }
+        }
+    }
+
+    /// Keep the previous process of a service whose new process failed to start.
+    fn keep_previous(&mut self, name: &str) {
+        let Some(pid) = self
+            .previous
+            .iter()
+            .find(|(_, previous)| previous.name == name && !previous.stopping)
+            .map(|(pid, _)| *pid)
+        else {
+            return;
+        };
+        let previous = self.previous.remove(&pid).unwrap();
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+
+        warn!("The new process of {name} failed to start, keeping PID {pid}");
+        service.status = Some(Status::Running);
+        service.pid = Some(pid);
+        service.ready = true;
+        service.started_at = previous.started_at;
+        service.active_since = previous.active_since;
+        // the executable of the failed process is not acted on again.
+        service.replaced_by = service
+            .executable_id
+            .filter(|id| previous.executable_id != Some(*id));
+        service.executable_id = previous.executable_id;
+        service.executable_change = None;
+        if let Some(interval) = service.watchdog_interval() {
+            service.watchdog = Some(
+                self.reactor
+                    .add_timer(interval, Token::Watchdog(name.to_string())),
+            );
+        }
+        Self::schedule_restart(&mut self.reactor, service);
+
+        self.pids.insert(pid, name.to_string());
+        let mut state = ServiceState::new(pid, Status::Running);
+        state.started_at = previous.active_since.unwrap_or(state.started_at);
+        self.state.services.insert(name.to_string(), state);
+        self.save_state();
+    }
+
+    /// Set the timer restarting a running service on its `restart_every` schedule.
+    ///
+    /// A random jitter is added so the services on the same schedule don't restart at once.
+    fn schedule_restart(reactor: &mut Reactor<Token>, service: &mut Service) {
+        let Some(ref schedule) = service.restart_every else {
+            return;
+        };
+        let now = unix_time();
+        let Some(next) = schedule.next(service.active_since.unwrap_or(now), now) else {
+            warn!("The restart schedule of {} never matches", service.name);
+            return;
+        };
+        let jitter = (schedule.period(next) / 10).min(MAX_RESTART_JITTER);
+        let jitter = match jitter {
+            0 => 0,
+            jitter => RandomState::new().hash_one(&service.name) % jitter,
+        };
+
+        let delay = Duration::from_secs(next.saturating_sub(now) + jitter);
+        info!(
+            "Service {} is restarted on schedule in {delay:?}",
+            service.name
+        );
+        service.scheduled_restart =
+            Some(reactor.add_timer(delay, Token::ScheduledRestart(service.name.clone())));
+    }
+
+    /// Restart a running service on its `restart_every` schedule.
+    fn restart_on_schedule(&mut self, name: &str) {
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        service.scheduled_restart = None;
+        if !matches!(service.status, Some(Status::Running)) || service.stopping {
+            return;
+        }
+        info!("Restarting service {name} on schedule");
+        service.restarting_on_schedule = true;
+        self.restart_service(name);
+    }
+
+    /// Restart a crashed service once its restart delay elapsed.
+    fn restart(&mut self, name: &str) {
+        let Some(service) = self.services.get_mut(name) else {
+            return;
+        };
+        service.restart_timer = None;
+
+        // it was started or stopped by operatorctl in the meantime.
+        if service.stopping || matches!(service.status, Some(Status::Running)) {
+            return;
+        }
+
+        info!("Restarting service {name}");
+        let service = self.services.remove(name).unwrap();
+        self.spawn(service);
+    }
+
+    /// Stop the services on a signal and exit once they are gone.
+    pub(crate) fn handle_signal(&mut self, signo: i32) {
+        let Ok(signal) = Signal::try_from(signo) else {
+            return;
+        };
+
+        // Ctrl-C in the terminal, the services didn't get it.
+        if signal == Signal::SIGINT && self.foreground {
+            match self.shutting_down {
+                true => {
+                    warn!("Received {signal} again, killing the services.");
+                    self.kill_services();
+                }
+                false => {
+                    info!("Received {signal}, stopping the services, again to kill them.");
+                    self.shutdown(Signal::SIGTERM);
+                }
+            }
+            return;
+        }
+
+        info!("Received {signal}, stopping the services.");
+        let action = match signal {
+            Signal::SIGUSR1 => PowerAction::Reboot,
+            Signal::SIGUSR2 => PowerAction::PowerOff,
+            signal => return self.shutdown(signal),
+        };
+        if let Err(e) = self.power(action) {
+            error!("{e}");
+        }
+    }
+
+    /// Stop the services, then power off or reboot once they exited.
+    ///
+    /// Returns why it was refused, operator has to be an init.
+    fn power(&mut self, action: PowerAction) -> Result<(), String> {
+        if !self.init {
+            return Err(format!(
+                "operator can only {action} as an init, run with --init"
+            ));
+        }
+        info!("Stopping the services to {action}");
+        self.power = Some(action);
+        self.shutdown(Signal::SIGTERM);
+        Ok(())
+    }
+
+    /// Stop the services with `signal`, the ones depending on others first, and exit
+    /// once they are gone.
+    ///
+    /// The services left are killed once the shutdown timeout elapsed.
+    fn shutdown(&mut self, signal: Signal) {
+        if !self.shutting_down {
+            if let Some(timeout) = self.shutdown_timeout {
+                self.reactor.add_timer(timeout, Token::ShutdownTimeout);
+            }
+        }
+        self.shutting_down = true;
+        self.shutdown_signal = Some(signal);
+        for pid in self.previous.keys() {
+            _ = kill(Pid::from_raw(*pid), signal);
+        }
+        // a second signal is forwarded to the services already stopping.
+        for service in self.services.values() {
+            if service.stopping && matches!(service.status, Some(Status::Running)) {
+                service.signal_stop(signal);
+            }
+        }
+        self.stop_in_order();
+
+        self.exit_if_done();
+    }
+
+    /// Stop the running services no other running service is ordered after or
+    /// requires when operator is shutting down, the ones they depend on are stopped once
+    /// they exited.
+    fn stop_in_order(&mut self) {
+        let Some(signal) = self.shutdown_signal else {
+            return;
+        };
+        let running = |service: &&Service| matches!(service.status, Some(Status::Running));
+        let needed = self
+            .services
+            .values()
+            .filter(running)
+            .flat_map(|service| {
+                service
+                    .after
+                    .iter()
+                    .chain(&service.requires)
+                    .filter(|dep| **dep != service.name)
+            })
+            .collect::<HashSet<_>>();
+        let mut stop = self
+            .services
+            .values()
+            .filter(running)
+            .filter(|service| !service.stopping && !needed.contains(&service.name))
+            .map(|service| service.name.clone())
+            .collect::<Vec<_>>();
+        // the services of a dependency cycle are stopped together.
+        if stop.is_empty() && !self.services.values().filter(running).any(|s| s.stopping) {
+            stop = self
+                .services
+                .values()
+                .filter(running)
+                .map(|service| service.name.clone())
+                .collect();
+        }
+
+        for name in stop {
+            let Some(service) = self.services.get_mut(&name) else {
+                continue;
+            };
+            info!("Asking service {name} to terminate.");
+            service.stopping = true;
+            service.signal_stop(signal);
+            self.arm_stop_timeout(&name);
+        }
+    }
+
+    /// Kill the services left when operator shuts down, e.g. once the shutdown timeout
+    /// elapsed.
+    fn kill_services(&mut self) {
+        for pid in self.previous.keys() {
+            _ = kill(Pid::from_raw(*pid), Signal::SIGKILL);
+        }
+        for service in self.services.values_mut() {
+            if matches!(service.status, Some(Status::Running)) {
+                service.stopping = true;
+                self.launcher.kill(service);
+            }
+        }
+    }
+
+    /// Pids of the processes asked to stop that didn't exit yet, with the name of their
+    /// service.
+    #[cfg(feature = "testing")]
+    pub(crate) fn stopping(&self) -> Vec<(i32, String)> {
+        let services = self
+            .services
+            .values()
+            .filter(|service| service.stopping && matches!(service.status, Some(Status::Running)))
+            .filter_map(|service| Some((service.pid?, service.name.clone())));
+        let previous = self
+            .previous
+            .iter()
+            .filter(|(_, previous)| previous.stopping)
+            .map(|(pid, previous)| (*pid, previous.name.clone()));
+        services.chain(previous).collect()
+    }
+
+    /// Whether operator is shutting down and all the services exited.
+    pub(crate) fn is_done(&self) -> bool {
+        self.shutting_down
+            && !self
+                .services
+                .values()
+                .any(|service| matches!(service.status, Some(Status::Running)))
+    }
+
+    /// Exit operator if it's shutting down and all the services exited.
+    fn exit_if_done(&self) {
+        if matches!(self.launcher, Launcher::Fork) && self.is_done() {
+            match self.power {
+                // the kernel panics if the PID 1 exits, and another init would be the
+                // one to power off.
+                Some(action) if getpid().as_raw() == 1 => {
+                    info!("All services exited, syncing the filesystems to {action}.");
+                    let e = power::power(action);
+                    error!("Failed to {action}: {e}");
+                }
+                Some(action) => warn!("operator is not PID 1, leaving the {action} to the init."),
+                None => info!("All services exited, exiting."),
+            }
+            std::process::exit(0);
+        }
+    }
+
+    /// Reap all the children that exited.
+    ///
+    /// Children that are not services (orphans adopted in init mode, reload commands) are
+    /// reaped too.
+    fn reap(&mut self) {
+        loop {
+            // the child that exited is left unreaped until its status in /proc is read.
+            let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+            let pid = match waitid(Id::All, flags) {
+                Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+                Ok(wait_stat) => wait_stat.pid(),
+                Err(e) => {
+                    error!("waitid() failed : {e}.");
+                    break;
+                }
+            };
+            let Some(pid) = pid else {
+                break;
+            };
+            self.reaping = std::fs::read_to_string(format!("/proc/{pid}/status"))
+                .ok()
+                .map(|status| (pid.as_raw(), status));
+
+            match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => break,
+                Ok(wait_stat) => self.handle_exit(pid.as_raw(), wait_stat),
+                Err(e) => {
+                    error!("waitpid() failed : {e}.");
+                    break;
+                }
+            }
+            self.reaping = None;
+        }
+
+        self.exit_if_done();
+    }
+
+    /// Update a service that exited and restart it if its restart policy asks for it.
+    ///
+    /// When operator is shutting down, the services it depended on are stopped next.
+    pub(crate) fn handle_exit(&mut self, pid: i32, wait_stat: WaitStatus) {
+        if let Some(stream) = self.execs.remove(&pid) {
+            let code = match wait_stat {
+                WaitStatus::Exited(_, code) => code,
+                WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+                _ => {
+                    self.execs.insert(pid, stream);
+                    return;
+                }
+            };
+            info!("Command {pid} of operatorctl exec exited with {code}");
+            if let Err(e) = stream.write(&IPCMessage::ExecExited(code)) {
+                warn!("Failed to send the exit code of command {pid}: {e}");
+            }
+            return;
+        }
+
+        let (exit_code, signal, failure) = match wait_stat {
+            WaitStatus::Exited(_, code) => (
+                Some(code as u8),
+                None,
+                (code != 0).then(|| format!("exited with code {code}")),
+            ),
+            WaitStatus::Signaled(_, signal, _) => {
+                (None, Some(signal), Some(format!("killed by {signal}")))
+            }
+            e => {
+                info!("waitpid() returned {e:?}");
+                return;
+            }
+        };
+        if let WaitStatus::Signaled(_, signal, true) = wait_stat {
+            self.dumped(pid, signal);
+        }
+
+        self.exited(pid, exit_code, signal, failure);
+        self.stop_in_order();
+    }
+
+    /// Collect the core dumped by the process of a service.
+    fn dumped(&mut self, pid: i32, signal: Signal) {
+        let Some(service) = self
+            .pids
+            .get(&pid)
+            .and_then(|name| self.services.get_mut(name))
+            .filter(|service| service.pid == Some(pid))
+        else {
+            return;
+        };
+        let core = coredump::collect(self.coredump.as_ref(), service, pid, signal);
+        error!("Service {} dumped its core: {core}", service.name);
+        service.core = Some(core.clone());
+        self.cores.insert(service.name.clone(), core);
+    }
+
+    /// Handle the exit of an adopted process that is not our child.
+    ///
+    /// Its exit status is unknown, so it's considered failed.
+    pub(crate) fn handle_lost(&mut self, pid: i32) {
+        self.exited(pid, None, None, Some("exit status unknown".to_string()));
+        self.stop_in_order();
+    }
+
+    /// Handle the exit of a service, `failure` describes how it failed.
+    fn exited(
+        &mut self,
+        pid: i32,
+        exit_code: Option<u8>,
+        signal: Option<Signal>,
+        failure: Option<String>,
+    ) {
+        // the errno of a process that failed to start is written before it exits.
+        self.executed(pid);
+
+        if let Some(previous) = self.previous.remove(&pid) {
+            info!("The previous process {pid} of {} exited", previous.name);
+            Self::record(
+                &previous.name,
+                HistoryEntry {
+                    pid: Some(pid),
+                    exit_code,
+                    duration: previous.started_at.map(|started_at| started_at.elapsed()),
+                    ..HistoryEntry::new(RunEvent::Stop)
+                },
+            );
+            return;
+        }
+
+        // the service may have been started again since, under another pid.
+        let service = self
+            .pids
+            .remove(&pid)
+            .and_then(|name| self.services.get_mut(&name))
+            .filter(|service| service.pid == Some(pid));
+        let Some(service) = service else {
+            info!("PID {pid} that exited is not a service");
+            return;
+        };
+
+        // the new process of a service restarted with `start-then-stop` exited before it
+        // was ready, the previous one keeps running.
+        let name = service.name.clone();
+        if self
+            .previous
+            .values()
+            .any(|previous| previous.name == name && !previous.stopping)
+        {
+            for timer in [
+                service.start_timeout.take(),
+                service.scheduled_restart.take(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                self.reactor.cancel_timer(timer);
+            }
+            service.forking = false;
+            let reason = match std::mem::take(&mut service.timed_out) {
+                true => FailReason::StartTimeout.to_string(),
+                false => failure.unwrap_or_else(|| "exited before it was ready".to_string()),
+            };
+            Self::record(
+                &name,
+                HistoryEntry {
+                    pid: Some(pid),
+                    exit_code,
+                    reason: Some(reason),
+                    duration: service.started_at.map(|started_at| started_at.elapsed()),
+                    ..HistoryEntry::new(RunEvent::Crash)
+                },
+            );
+            self.keep_previous(&name);
+            return;
+        }
+
+        // the exit codes of `success_exit_codes` are clean exits.
+        let mut failure = failure
+            .filter(|_| !exit_code.is_some_and(|code| service.success_exit_codes.contains(&code)));
+        // the process of a forking service exits once it forked the daemon.
+        if std::mem::take(&mut service.forking)
+            && failure.is_none()
+            && !service.stopping
+            && !self.shutting_down
+        {
+            match ForkingBackend::main_pid(service) {
+                Ok(pid) => {
+                    let name = service.name.clone();
+                    self.forked(&name, pid);
+                    return;
+                }
+                Err(e) => {
+                    error!("Service {} failed to fork its daemon: {e}", service.name);
+                    failure = Some(e);
+                }
+            }
+        }
+
+        if let Some(timer) = service.watchdog.take() {
+            self.reactor.cancel_timer(timer);
+        }
+        if let Some(timer) = service.start_timeout.take() {
+            self.reactor.cancel_timer(timer);
+        }
+        if let Some(timer) = service.stop_timeout.take() {
+            self.reactor.cancel_timer(timer);
+        }
+        if let Some(timer) = service.scheduled_restart.take() {
+            self.reactor.cancel_timer(timer);
+        }
+        let on_schedule = std::mem::take(&mut service.restarting_on_schedule);
+        let timed_out = std::mem::take(&mut service.timed_out);
+        if timed_out {
+            failure = Some(FailReason::StartTimeout.to_string());
+        }
+
+        // the runs of services that failed to start were recorded then.
+        if matches!(service.status, Some(Status::Running)) {
+            let event = match failure {
+                Some(_) if !service.stopping && !self.shutting_down => RunEvent::Crash,
+                _ if on_schedule => RunEvent::Restart,
+                _ => RunEvent::Stop,
+            };
+            Self::record(
+                &service.name,
+                HistoryEntry {
+                    pid: Some(pid),
+                    exit_code,
+                    reason: failure.clone().filter(|_| event == RunEvent::Crash),
+                    duration: service.started_at.map(|started_at| started_at.elapsed()),
+                    ..HistoryEntry::new(event)
+                },
+            );
+
+            if let (RunEvent::Crash, Some(ref reason)) = (event, &failure) {
+                let crash = Crash {
+                    reason: reason.clone(),
+                    signal: signal.map(|signal| signal.to_string()),
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|now| now.as_secs())
+                        .unwrap_or_default(),
+                    log: self
+                        .outputs
+                        .get(&service.name)
+                        .map(|output| output.lines(CRASH_LOG_LINES))
+                        .unwrap_or_default(),
+                    proc_status: self
+                        .reaping
+                        .take()
+                        .filter(|(reaped, _)| *reaped == pid)
+                        .map(|(_, status)| status),
+                };
+                service.last_crash = Some(crash.clone());
+                self.crashes.insert(service.name.clone(), crash);
+            }
+        }
+
+        // services that failed to start keep their failure reason, and were handled then.
+        let failure = failure.filter(|_| {
+            matches!(service.status, Some(Status::Running))
+                && !service.stopping
+                && !self.shutting_down
+        });
+        if matches!(service.status, Some(Status::Running)) {
+            service.status = Some(match timed_out {
+                true => Status::Failed {
+                    reason: FailReason::StartTimeout,
+                },
+                false => Status::Stopped,
+            });
+        }
+        if exit_code.is_some() {
+            service.exit_code = exit_code;
+        }
+
+        // the processes left by a service that was stopped die with it.
+        if service.stopping {
+            service.kill_remaining();
+        }
+
+        // replicas removed by scaling down are forgotten once they exit.
+        if let Some((ref set, index)) = service.replica {
+            if self
+                .replica_sets
+                .get(set)
+                .is_none_or(|set| index > set.replicas.unwrap_or(0))
+            {
+                info!("Replica {} was scaled down", service.name);
+                let name = service.name.clone();
+                self.services.remove(&name);
+                self.state.services.remove(&name);
+                self.save_state();
+                return;
+            }
+        }
+
+        let restart = !service.stopping
+            && !self.shutting_down
+            && (match service.restart {
+                Restart::Never => false,
+                Restart::OnFailure => failure.is_some(),
+                Restart::Always => true,
+            } || exit_code.is_some_and(|code| service.restart_on_exit_codes.contains(&code)));
+
+        // a service with a socket is started again on the next connection.
+        let listen =
+            !restart && !service.stopping && !self.shutting_down && service.socket.is_some();
+        if listen {
+            service.status = Some(Status::Listening);
+        }
+
+        if restart {
+            if service
+                .started_at
+                .is_some_and(|started_at| started_at.elapsed() >= RESTART_RESET)
+            {
+                service.restarts = 0;
+            }
+
+            let delay = RESTART_DELAY
+                .saturating_mul(2u32.saturating_pow(service.restarts))
+                .min(MAX_RESTART_DELAY);
+            service.restarts += 1;
+            #[cfg(feature = "chaos")]
+            let delay = match self
+                .faults
+                .as_mut()
+                .map(|faults| faults.restart_delay(&service.name))
+            {
+                Some(injected) if !injected.is_zero() => {
+                    warn!(
+                        "Chaos: delaying the restart of service {} by {injected:?}",
+                        service.name
+                    );
+                    delay + injected
+                }
+                _ => delay,
+            };
+
+            info!("Restarting service {} in {delay:?}", service.name);
+            service.restart_timer = Some(
+                self.reactor
+                    .add_timer(delay, Token::Restart(service.name.clone())),
+            );
+        }
+
+        if let Some(state) = self.state.services.get_mut(&service.name) {
+            state.status = service.status.unwrap_or(Status::Stopped);
+            state.restart = restart;
+        }
+        let name = service.name.clone();
+        if listen {
+            self.watch_socket(&name);
+        }
+        self.save_state();
+
+        if let Some(failure) = failure {
+            self.failed(&name, exit_code, &failure);
+        }
+        if self.restarting.remove(&name) && !self.shutting_down {
+            self.start(&name);
+        }
+
+        // the services ordered after it don't wait for it anymore.
+        self.boot_queued();
+    }
+
+    /// Start the failure handlers of a service, passing them how it failed.
+    fn failed(&mut self, name: &str, exit_code: Option<u8>, reason: &str) {
+        let Some(service) = self.services.get(name) else {
+            return;
+        };
+
+        for handler in service.on_failure.clone() {
+            self.instantiate(&handler);
+            let Some(service) = self.services.get_mut(&handler) else {
+                warn!("Service {name} has failure handler {handler} which is not loaded");
+                continue;
+            };
+
+            // only the process started for this failure gets them.
+            let env = &mut service.failure_env;
+            env.clear();
+            env.insert("OP_FAILED_SERVICE".to_string(), name.to_string());
+            env.insert("OP_FAILED_REASON".to_string(), reason.to_string());
+            if let Some(code) = exit_code {
+                env.insert("OP_FAILED_EXIT_CODE".to_string(), code.to_string());
+            }
+
+            info!("Starting {handler} as service {name} failed: {reason}");
+            self.start(&handler);
+        }
+    }
+
+    /// Handle a request from operatorctl, only a query if it's a client of the query
+    /// socket.
+    fn handle_request(&mut self, request: ipc::Request) {
+        let ipc::Request {
+            stream,
+            msg,
+            query,
+            stdio,
+        } = request;
+        // the clients of the query socket can't act on the services.
+        if query && !msg.is_query() {
+            if let Err(e) = stream.write(&IPCMessage::ReadOnly) {
+                warn!("Failed to respond to an IPC client: {e}");
+            }
+            return;
+        }
+        let peer = stream.peer();
+
+        // the changes are written by a thread, so a slow client doesn't block the engine.
+        if let IPCMessage::Subscribe = msg {
+            let (changes, receiver) = std::sync::mpsc::channel::<StateChange>();
+            let spawned = std::thread::Builder::new()
+                .name("subscriber".to_string())
+                .spawn(move || {
+                    for change in receiver {
+                        if stream.write(&IPCMessage::Event(change)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            match spawned {
+                Ok(_) => self.subscribe(move |change| changes.send(change.clone()).is_ok()),
+                Err(e) => error!("Failed to start the thread of a subscriber: {e}"),
+            }
+            return;
+        }
+
+        if let IPCMessage::Attach { ref name, stdin } = msg {
+            let entry = AuditEntry::of(&msg, peer);
+            let response = self.attach(name, stdin, stream);
+            self.audit(entry, Some(&IPCMessage::AttachResponse(response)));
+            return;
+        }
+
+        if let IPCMessage::Exec {
+            ref name,
+            ref command,
+        } = msg
+        {
+            let entry = AuditEntry::of(&msg, peer);
+            let response = self.exec(name, command, stdio.unwrap_or(Ok(vec![])));
+            let pid = response.as_ref().and_then(|response| response.clone().ok());
+            let response = IPCMessage::ExecResponse(response);
+            self.audit(entry, Some(&response));
+            if let Err(e) = stream.write(&response) {
+                warn!("Failed to respond to an IPC client: {e}");
+            }
+            if let Some(pid) = pid {
+                self.execs.insert(pid, stream);
+            }
+            return;
+        }
+
+        // the response is written once the service reached the state.
+        if let IPCMessage::WaitFor {
+            name,
+            state,
+            timeout,
+        } = msg
+        {
+            self.wait_for(&name, &state, timeout, move |info| {
+                if let Err(e) = stream.write(&IPCMessage::WaitForResponse(info)) {
+                    warn!("Failed to respond to an IPC client: {e}");
+                }
+            });
+            return;
+        }
+
+        if let Some(response) = self.handle_message(msg, peer) {
+            if let Err(e) = stream.write(&response) {
+                warn!("Failed to respond to an IPC client: {e}");
+            }
+        }
+    }
+
+    /// Send the output of a service to a client as it's written, from threads, and write
+    /// what it sends to the stdin of the service if `stdin`.
+    ///
+    /// Returns the response sent.
+    fn attach(
+        &mut self,
+        name: &str,
+        stdin: bool,
+        stream: IPCStream,
+    ) -> Option<Result<bool, String>> {
+        let response = match self.services.get(name) {
+            None => None,
+            Some(service) if stdin && service.stdin != Stdin::Pipe && !service.tty => {
+                Some(Err(format!(
+                    "{name} doesn't read its stdin from operator, set `stdin = \"pipe\"` or `tty = true`"
+                )))
+            }
+            Some(service) => Some(Ok(service.tty)),
+        };
+        if let Err(e) = stream.write(&IPCMessage::AttachResponse(response.clone())) {
+            warn!("Failed to respond to an IPC client: {e}");
+            return response;
+        }
+        if !matches!(response, Some(Ok(_))) {
+            return response;
+        }
+
+        let output = self.outputs.entry(name.to_string()).or_default().clone();
+        if stdin {
+            if let Err(e) = Self::read_input(name, &stream, output.clone()) {
+                error!("Failed to read the input of a client attached to {name}: {e}");
+            }
+        }
+
+        let follower = output.follow();
+        let spawned = std::thread::Builder::new()
+            .name(format!("attach-{name}"))
+            .spawn(move || loop {
+                match follower.recv_timeout(Duration::from_secs(1)) {
+                    Ok(bytes) => {
+                        if stream.write(&IPCMessage::OutputData(bytes)).is_err() {
+                            break;
+                        }
+                    }
+                    // the client is gone, even if the service writes nothing.
+                    Err(RecvTimeoutError::Timeout) if !stream.is_closed() => {}
+                    Err(_) => break,
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Failed to start the thread of a client attached to {name}: {e}");
+        }
+        response
+    }
+
+    /// Write the input sent by an attached client to the stdin of a service, from a thread.
+    fn read_input(name: &str, stream: &IPCStream, output: Output) -> Result<(), Error> {
+        let reader = stream.try_clone()?;
+        // the client may not type anything for a while.
+        reader.set_read_timeout(None)?;
+        std::thread::Builder::new()
+            .name(format!("input-{name}"))
+            .spawn(move || {
+                while let Ok(IPCMessage::Input(bytes)) = reader.read() {
+                    // the service may not be running, the input is dropped.
+                    _ = output.write_input(&bytes);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Run a command in the context of a service, with the stdio sent by the client.
+    ///
+    /// Returns its pid, `None` if the service is not found.
+    fn exec(
+        &mut self,
+        name: &str,
+        command: &[String],
+        stdio: Result<Vec<OwnedFd>, Error>,
+    ) -> Option<Result<i32, String>> {
+        let stdio = match stdio {
+            Ok(stdio) if stdio.len() == 3 => stdio,
+            Ok(_) => return Some(Err("the stdin, stdout and stderr were not sent".to_string())),
+            Err(e) => return Some(Err(format!("failed to receive the stdio: {e}"))),
+        };
+        let mut service = self.services.get(name)?.clone();
+        if service.service_type == ServiceType::Container {
+            return Some(Err(format!(
+                "{name} is a container, use the exec of its runtime"
+            )));
+        }
+
+        // the namespaces and cgroup of the running process are joined, otherwise it's
+        // set up like a new process of the service.
+        let running = service
+            .pid
+            .filter(|_| matches!(service.status, Some(Status::Running)));
+        if running.is_none() {
+            service.cgroup = None;
+        }
+        service.pass_environment(self.pass_environment.as_deref());
+        service.secret_env = match Secrets::read(&service.secrets) {
+            Ok(secrets) => secrets,
+            Err((file, e)) => {
+                return Some(Err(format!(
+                    "failed to read the secret {}: {e}",
+                    file.display()
+                )))
+            }
+        };
+
+        info!("Running {command:?} in the context of {name}");
+        Some(
+            crate::exec::spawn(&service, running, command, &stdio)
+                .map_err(|e| format!("failed to run {command:?}: {e}")),
+        )
+    }
+
+    /// Handle a message from operatorctl sent by `peer` and return the response to send
+    /// back, if any.
+    ///
+    /// The requests acting on the services are recorded in the audit log.
+    pub(crate) fn handle_message(
+        &mut self,
+        msg: IPCMessage,
+        peer: Option<Peer>,
+    ) -> Option<IPCMessage> {
+        let entry = AuditEntry::of(&msg, peer);
+        #[cfg(feature = "chaos")]
+        let dropped = self
+            .faults
+            .as_mut()
+            .is_some_and(|faults| faults.drop_response(&msg));
+        let response = self.respond(msg, peer);
+        self.audit(entry, response.as_ref());
+        #[cfg(feature = "chaos")]
+        if dropped && response.is_some() {
+            warn!("Chaos: dropping the response to an IPC client");
+            return None;
+        }
+        response
+    }
+
+    /// Record a request in the audit log, with the outcome of its response.
+    fn audit(&self, entry: Option<AuditEntry>, response: Option<&IPCMessage>) {
+        let Some(mut entry) = entry else {
+            return;
+        };
+        let found = entry
+            .service
+            .as_ref()
+            .is_none_or(|name| self.services.contains_key(name));
+        entry.outcome = audit::outcome(response, found);
+        if let Err(e) = audit::record(&entry) {
+            error!("Failed to write to the audit log: {e}");
+        }
+    }
+
+    /// Handle a message from operatorctl and return the response to send back, if any.
+    fn respond(&mut self, msg: IPCMessage, peer: Option<Peer>) -> Option<IPCMessage> {
+        match msg {
+            IPCMessage::Start { name } => self.start(&name),
+            IPCMessage::Stop { name } => self.stop(&name),
+            IPCMessage::Restart { name } => self.restart_service(&name),
+            IPCMessage::Bulk { action, filter } => {
+                let mut names = self
+                    .services
+                    .values()
+                    .filter(|service| filter.matches(&service.info()))
+                    .map(|service| service.name.clone())
+                    .collect::<Vec<_>>();
+                names.sort();
+                info!("{action:?} of the services {names:?}");
+                for name in &names {
+                    match action {
+                        BulkAction::Start => self.start(name),
+                        BulkAction::Stop => self.stop(name),
+                        BulkAction::Restart => self.restart_service(name),
+                    }
+                }
+                return Some(IPCMessage::BulkResponse(names));
+            }
+            IPCMessage::Batch(requests) => {
+                let responses = requests
+                    .into_iter()
+                    .map(|msg| match msg {
+                        IPCMessage::Exec { .. } => Some(IPCMessage::ExecResponse(Some(Err(
+                            "exec can't be batched".to_string(),
+                        )))),
+                        IPCMessage::Attach { .. } => Some(IPCMessage::AttachResponse(Some(Err(
+                            "attach can't be batched".to_string(),
+                        )))),
+                        IPCMessage::Subscribe
+                        | IPCMessage::Input(_)
+                        | IPCMessage::Batch(_)
+                        | IPCMessage::WaitFor { .. } => {
+                            warn!("Ignoring a {msg:?} request in a batch");
+                            None
+                        }
+                        msg => self.handle_message(msg, peer),
+                    })
+                    .collect();
+                return Some(IPCMessage::BatchResponse(responses));
+            }
+            IPCMessage::Status { name } => {
+                return Some(IPCMessage::StatusResponse(
+                    self.services.get(&name).map(Service::info),
+                ));
+            }
+            IPCMessage::List { filter } => {
+                let mut services = self
+                    .services
+                    .values()
+                    .map(Service::info)
+                    .filter(|info| filter.matches(info))
+                    .collect::<Vec<_>>();
+                services.sort_by(|a, b| a.name.cmp(&b.name));
+
+                return Some(IPCMessage::ListResponse(services));
+            }
+            IPCMessage::Events { query } => {
+                return Some(IPCMessage::EventsResponse(self.journal.query(&query)));
+            }
+            IPCMessage::LoadErrors => {
+                return Some(IPCMessage::LoadErrorsResponse(self.load_errors.clone()));
+            }
+            IPCMessage::Show { name } => {
+                return Some(IPCMessage::ShowResponse(
+                    self.services.get(&name).map(Service::properties),
+                ));
+            }
+            IPCMessage::Graph => {
+                let mut services = self.services.keys().cloned().collect::<Vec<_>>();
+                services.sort();
+                let edges = services
+                    .iter()
+                    .flat_map(|name| self.services[name].edges())
+                    .collect();
+
+                return Some(IPCMessage::GraphResponse { services, edges });
+            }
+            IPCMessage::Analyze => {
+                return Some(IPCMessage::AnalyzeResponse(
+                    self.timings.values().cloned().collect(),
+                ));
+            }
+            IPCMessage::Audit { limit } => {
+                let entries = audit::read(limit).unwrap_or_else(|e| {
+                    error!("Failed to read the audit log: {e}");
+                    vec![]
+                });
+                return Some(IPCMessage::AuditResponse(entries));
+            }
+            IPCMessage::History { name, limit } => {
+                let entries = history::read(&name, limit).unwrap_or_else(|e| {
+                    error!("Failed to read the history of {name}: {e}");
+                    vec![]
+                });
+                return Some(IPCMessage::HistoryResponse(entries));
+            }
+            IPCMessage::Output { name, lines } => {
+                // a service that never ran has no output yet.
+                return Some(IPCMessage::OutputResponse(self.services.get(&name).map(
+                    |_| {
+                        self.outputs
+                            .get(&name)
+                            .map(|output| output.lines(lines))
+                            .unwrap_or_default()
+                    },
+                )));
+            }
+            IPCMessage::SetProperty {
+                name,
+                properties,
+                persist,
+            } => {
+                return Some(IPCMessage::SetPropertyResponse(
+                    self.services
+                        .get_mut(&name)
+                        .map(|service| Self::set_property(service, &properties, persist)),
+                ));
+            }
+            IPCMessage::ReloadFile { name } => {
+                return Some(IPCMessage::ReloadFileResponse(
+                    self.services
+                        .contains_key(&name)
+                        .then(|| self.reload_file(&name)),
+                ));
+            }
+            IPCMessage::Sources { name } => {
+                return Some(IPCMessage::SourcesResponse(
+                    self.services
+                        .get(&name)
+                        .map(|service| service.sources.clone()),
+                ));
+            }
+            IPCMessage::Enable { name } => return Some(self.set_enabled(&name, true).into()),
+            IPCMessage::Disable { name } => return Some(self.set_enabled(&name, false).into()),
+            IPCMessage::Mask { name } => return Some(self.set_masked(&name, true).into()),
+            IPCMessage::Unmask { name } => return Some(self.set_masked(&name, false).into()),
+            IPCMessage::ReloadService { name } => {
+                let reloaded = match self.services.get(&name) {
+                    Some(service) => self.reload(service),
+                    None => Err(format!("no {name} service found")),
+                };
+                return Some(reloaded.into());
+            }
+            IPCMessage::Scale { name, replicas } => {
+                return Some(self.scale(&name, replicas).into());
+            }
+            IPCMessage::ReExec => self.reexec(),
+            IPCMessage::Power(action) => {
+                return Some(IPCMessage::PowerResponse(self.power(action)));
+            }
+            // the stdio of the command is only received by the connections of the daemon.
+            IPCMessage::Exec { .. } => {
+                return Some(IPCMessage::ExecResponse(Some(Err(
+                    "exec is not supported by this operator".to_string(),
+                ))))
+            }
+            // so is the stream of output.
+            IPCMessage::Attach { .. } => {
+                return Some(IPCMessage::AttachResponse(Some(Err(
+                    "attach is not supported by this operator".to_string(),
+                ))))
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+/// Whether a SIGCHLD is pending, the exits it signals not reaped yet.
+fn sigchld_pending() -> bool {
+    let mut pending = std::mem::MaybeUninit::<nix::libc::sigset_t>::uninit();
+    unsafe {
+        nix::libc::sigpending(pending.as_mut_ptr()) == 0
+            && nix::libc::sigismember(pending.as_ptr(), nix::libc::SIGCHLD) == 1
+    }
+}
+
+/// Error of the engine failing to `what` while it's set up.
+fn setup_error(what: &str, e: impl std::fmt::Display) -> Error {
+    std::io::Error::other(format!("Failed to {what}: {e}")).into()
 }
@@ -0,0 +1,111 @@
+//! Errors of the operator library.
+
+use std::{fmt, io, path::PathBuf};
+
+use crate::ipc::PROTOCOL_VERSION;
+
+/// Result of the operator library
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Error of the operator library
+#[derive(Debug)]
+pub enum Error {
+    /// An IO operation failed
+    Io(io::Error),
+    /// An IPC message couldn't be encoded or decoded, or wasn't the expected one
+    Protocol(String),
+    /// The service is not loaded
+    NotFound(String),
+    /// operator refused the request, for the given reason
+    Rejected(String),
+    /// operator is over its limits of connections or requests, the request wasn't handled
+    Busy,
+    /// Another operator is serving the socket, with this pid if it's known
+    AlreadyRunning(Option<i32>),
+    /// operator speaks another version of the protocol, or is older than the greetings if
+    /// `None`
+    Incompatible(Option<u32>),
+    /// A file couldn't be read or parsed
+    ParseError {
+        file: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+impl Error {
+    /// Error of a file that couldn't be read or parsed.
+    pub(crate) fn parse(
+        file: impl Into<PathBuf>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::ParseError {
+            file: file.into(),
+            source: source.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Protocol(e) => write!(f, "protocol error: {e}"),
+            Error::NotFound(name) => write!(f, "no {name} service found"),
+            Error::Rejected(reason) => write!(f, "{reason}"),
+            Error::Busy => write!(f, "operator is busy, try again later"),
+            Error::AlreadyRunning(Some(pid)) => {
+                write!(f, "operator is already running with pid {pid}")
+            }
+            Error::AlreadyRunning(None) => write!(f, "operator is already running"),
+            Error::Incompatible(Some(version)) if *version < PROTOCOL_VERSION => write!(
+                f,
+                "operator speaks version {version} of the protocol and this client version \
+                 {PROTOCOL_VERSION}, please upgrade operator"
+            ),
+            Error::Incompatible(Some(version)) => write!(
+                f,
+                "operator speaks version {version} of the protocol and this client version \
+                 {PROTOCOL_VERSION}, please upgrade the client"
+            ),
+            Error::Incompatible(None) => write!(
+                f,
+                "operator is older than this client and dropped it, please upgrade operator"
+            ),
+            Error::ParseError { file, source } => write!(f, "{}: {source}", file.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::ParseError { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => Error::Io(e),
+            e => Error::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        match e.is_io() || e.is_eof() {
+            true => Error::Io(e.into()),
+            false => Error::Protocol(e.to_string()),
+        }
+    }
+}
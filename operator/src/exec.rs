@@ -0,0 +1,124 @@
+//! Commands run in the context of a service, by `operatorctl exec`.
+//!
+//! The command is forked by operator with the stdio of operatorctl, passed over the IPC
+//! socket, and set up like the process of the service: in its cgroup and namespaces, as
+//! its user, with its limits and env. It's not a process of the service, it's not
+//! restarted and its exit code is sent back to operatorctl.
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Write},
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    process::exit,
+    time::Duration,
+};
+
+use log::error;
+use nix::{
+    errno::errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::signal::{self, SigHandler, SigSet, Signal},
+    unistd::{dup2, setsid},
+};
+
+use crate::{
+    executor::{self, Context, Executor, Job},
+    helper::close_on_exec,
+    service::Service,
+};
+
+/// How long the setup of a command is waited for.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fork a command in the context of a service, with `stdio` as its stdin, stdout and
+/// stderr.
+///
+/// The namespaces of `running`, the process of the running service, are joined.
+/// Returns the pid of the command.
+pub(crate) fn spawn(
+    service: &Service,
+    running: Option<i32>,
+    command: &[String],
+    stdio: &[OwnedFd],
+) -> io::Result<i32> {
+    if command.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no command"));
+    }
+    if command.iter().any(|arg| arg.contains('\0')) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "nul byte in the command",
+        ));
+    }
+
+    let (pid, errors) = Executor::new(&Job::Exec {
+        context: Context::new(service),
+        running,
+        command: command.to_vec(),
+        stdio: stdio.iter().map(AsRawFd::as_raw_fd).collect(),
+    })?
+    .spawn()?;
+
+    // joining the namespaces of the service is quick, operatorctl is answered once
+    // the command was executed, or it failed.
+    let fd = PollFd::new(&errors, PollFlags::POLLIN);
+    poll(&mut [fd], SETUP_TIMEOUT.as_millis() as i32)?;
+    match executor::outcome(&errors) {
+        Some(Some(errno)) => Err(io::Error::from_raw_os_error(errno)),
+        _ => Ok(pid),
+    }
+}
+
+/// Set up and execute a command in the executor.
+///
+/// The errno of a failed setup or exec() is written to `errors`.
+pub(crate) fn run(
+    service: &Service,
+    running: Option<i32>,
+    command: &[String],
+    stdio: &[RawFd],
+    errors: OwnedFd,
+) -> ! {
+    let command = command
+        .iter()
+        .map(|arg| CString::new(arg.as_str()).unwrap())
+        .collect::<Vec<_>>();
+    let errno = match setup(service, running, stdio) {
+        Ok(()) => {
+            let mut args = command.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+            args.push(core::ptr::null());
+            // a command without a `/` is looked up in the `PATH` of the service.
+            unsafe { nix::libc::execvp(command[0].as_ptr(), args.as_ptr()) };
+            errno()
+        }
+        Err(e) => {
+            error!("Failed to setup a command in {}: {e}", service.name);
+            e.raw_os_error().unwrap_or(0)
+        }
+    };
+    _ = File::from(errors).write_all(&errno.to_le_bytes());
+    exit(-1)
+}
+
+/// Setup the process of the command before exec.
+fn setup(service: &Service, running: Option<i32>, stdio: &[RawFd]) -> io::Result<()> {
+    SigSet::empty().thread_set_mask()?;
+    // the signals forwarded by operatorctl may be ignored by operator, e.g. SIGINT when
+    // it was started in the background.
+    for signal in
+        Signal::iterator().filter(|signal| !matches!(signal, Signal::SIGKILL | Signal::SIGSTOP))
+    {
+        unsafe { signal::signal(signal, SigHandler::SigDfl) }?;
+    }
+    // the signals of the terminal are for operatorctl, which forwards them.
+    setsid()?;
+
+    for (target, fd) in stdio.iter().enumerate() {
+        dup2(*fd, target as RawFd)?;
+    }
+    close_on_exec(3)?;
+
+    service.pass_env()?;
+    service.enter_context(running)
+}
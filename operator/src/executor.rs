@@ -0,0 +1,287 @@
+//! The executor, setting up the process of a service before it executes its program.
+//!
+//! operator is multithreaded, e.g. its IPC clients and the output of the services are
+//! handled by their own threads, so its forked children may only make
+//! async-signal-safe calls: a lock held by another thread when operator forked, e.g. of
+//! the allocator or of the logger, is never released in the child. The child only
+//! executes the operator binary again, as the executor, a single threaded process that
+//! moves into the context of the service, creates its container or network and
+//! executes its program, or the command of `operatorctl exec`.
+//!
+//! The job of the executor is passed as JSON in a memfd. The executor writes the errno
+//! of a failed setup or exec() to a close-on-exec pipe, which the engine reads once
+//! it's readable instead of waiting for the program to be executed.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::File,
+    io::{self, Read, Seek, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        raw::c_char,
+    },
+    path::PathBuf,
+};
+
+use log::error;
+use nix::{
+    errno::{errno, Errno},
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    libc,
+    sys::memfd::{memfd_create, MemFdCreateFlag},
+    unistd::{fork, pipe2, ForkResult},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{exec, secrets::Secrets, service::Service};
+
+/// Argument running the operator binary as the executor.
+const ARG: &str = "--executor";
+/// The binary of operator, still the one running if it was replaced since.
+const EXE: &str = "/proc/self/exe";
+
+/// What the executor is asked to do
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Job {
+    /// Start the process of a service.
+    Start(Context),
+    /// Run a command of `operatorctl exec` with the stdio of operatorctl, joining the
+    /// namespaces of the running process of the service.
+    Exec {
+        context: Context,
+        running: Option<i32>,
+        command: Vec<String>,
+        stdio: Vec<RawFd>,
+    },
+}
+
+/// A service with the runtime state its process is set up with, which isn't
+/// serialized with it
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Context {
+    service: Service,
+    cgroup: Option<PathBuf>,
+    listen_fd: Option<RawFd>,
+    output_fd: Option<RawFd>,
+    input_fd: Option<RawFd>,
+    notify_socket: Option<PathBuf>,
+    passed_env: Option<Vec<String>>,
+    failure_env: HashMap<String, String>,
+    secret_env: Secrets,
+}
+
+impl Context {
+    pub fn new(service: &Service) -> Self {
+        Self {
+            service: service.clone(),
+            cgroup: service.cgroup.clone(),
+            listen_fd: service.listen_fd,
+            output_fd: service.output_fd,
+            input_fd: service.input_fd,
+            notify_socket: service.notify_socket.clone(),
+            passed_env: service.passed_env.clone(),
+            failure_env: service.failure_env.clone(),
+            secret_env: service.secret_env.clone(),
+        }
+    }
+
+    /// The fds of the service its process inherits.
+    fn fds(&self) -> impl Iterator<Item = RawFd> {
+        [self.listen_fd, self.output_fd, self.input_fd]
+            .into_iter()
+            .flatten()
+    }
+
+    fn into_service(self) -> Service {
+        let mut service = self.service;
+        service.cgroup = self.cgroup;
+        service.listen_fd = self.listen_fd;
+        service.output_fd = self.output_fd;
+        service.input_fd = self.input_fd;
+        service.notify_socket = self.notify_socket;
+        service.passed_env = self.passed_env;
+        service.failure_env = self.failure_env;
+        service.secret_env = self.secret_env;
+        service
+    }
+}
+
+/// An executor ready to be forked, everything the child needs is allocated beforehand
+pub(crate) struct Executor {
+    /// the memfd of the job, kept open until the executor is spawned.
+    _job: OwnedFd,
+    /// read and write ends of the pipe the errno is written to.
+    errors: (OwnedFd, OwnedFd),
+    exe: CString,
+    /// the args of the executor, starting with its name.
+    args: Vec<CString>,
+    /// the fds kept open across the exec() of the executor.
+    inherited: Vec<RawFd>,
+}
+
+impl Executor {
+    pub fn new(job: &Job) -> io::Result<Self> {
+        let mut file = File::from(memfd_create(
+            c"operator-executor",
+            MemFdCreateFlag::MFD_CLOEXEC,
+        )?);
+        serde_json::to_writer(&mut file, job)?;
+        // the executor reads it from the offset it shares with us.
+        file.rewind()?;
+        let job_fd = OwnedFd::from(file);
+
+        // the pipe is non-blocking so the engine never waits on it, the child writes
+        // at most an errno to it.
+        let (r_fd, w_fd) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+        let errors = unsafe { (OwnedFd::from_raw_fd(r_fd), OwnedFd::from_raw_fd(w_fd)) };
+
+        let mut inherited = vec![job_fd.as_raw_fd(), errors.1.as_raw_fd()];
+        match job {
+            Job::Start(context) => inherited.extend(context.fds()),
+            Job::Exec { stdio, .. } => inherited.extend(stdio),
+        }
+        let args = [
+            "operator".to_string(),
+            ARG.to_string(),
+            inherited[0].to_string(),
+            inherited[1].to_string(),
+        ]
+        .into_iter()
+        .map(|arg| CString::new(arg).unwrap())
+        .collect();
+
+        Ok(Self {
+            _job: job_fd,
+            errors,
+            exe: CString::new(EXE).unwrap(),
+            args,
+            inherited,
+        })
+    }
+
+    /// Fork and execute the executor.
+    ///
+    /// Returns the pid of the child, and the read end of the pipe the errno of a
+    /// failed start is written to. It's closed once the program was executed.
+    pub fn spawn(self) -> io::Result<(i32, OwnedFd)> {
+        let mut argv = self.args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+        argv.push(core::ptr::null());
+
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => Ok((child.as_raw(), self.errors.0)),
+            ForkResult::Child => unsafe { self.exec_child(&argv) },
+        }
+    }
+
+    /// Command executing the executor, for a launcher forking it by itself.
+    ///
+    /// Returns the read end of the error pipe along with it, like [Executor::spawn].
+    /// The command must be spawned before the executor is dropped, which closes the
+    /// fds the command inherits.
+    #[cfg(feature = "tokio")]
+    pub fn command(&self) -> io::Result<(tokio::process::Command, OwnedFd)> {
+        use std::os::unix::process::CommandExt;
+
+        let mut command = std::process::Command::new(EXE);
+        let mut args = self
+            .args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned());
+        command.arg0(args.next().unwrap_or_default());
+        command.args(args);
+        let inherited = self.inherited.clone();
+        unsafe {
+            command.pre_exec(move || {
+                for fd in &inherited {
+                    fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+                }
+                Ok(())
+            });
+        }
+        Ok((command.into(), self.errors.0.try_clone()?))
+    }
+
+    /// Execute the executor in the forked child, with async-signal-safe calls only.
+    unsafe fn exec_child(&self, argv: &[*const c_char]) -> ! {
+        for fd in &self.inherited {
+            libc::fcntl(*fd, libc::F_SETFD, 0);
+        }
+        libc::execv(self.exe.as_ptr(), argv.as_ptr());
+
+        let errno = errno().to_le_bytes();
+        libc::write(
+            self.errors.1.as_raw_fd(),
+            errno.as_ptr().cast(),
+            errno.len(),
+        );
+        libc::_exit(255)
+    }
+}
+
+/// Run as the executor if operator executed the current binary as one, it doesn't
+/// return then.
+///
+/// It must be called first in `main` by the binaries running the engine, before any
+/// thread is started.
+pub fn run_if_executor() {
+    let args = std::env::args().skip(1).take(3).collect::<Vec<_>>();
+    let [arg, job, errors] = &args[..] else {
+        return;
+    };
+    if arg != ARG {
+        return;
+    }
+    let (Ok(job), Ok(errors)) = (job.parse::<RawFd>(), errors.parse::<RawFd>()) else {
+        std::process::exit(255);
+    };
+
+    _ = crate::log::init();
+    let errors = unsafe { OwnedFd::from_raw_fd(errors) };
+    // the program gets EOF once it's executed, and the commands run to set it up don't
+    // hold the pipe.
+    if let Err(e) = fcntl(errors.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        fail(errors, e.into());
+    }
+
+    let mut contents = vec![];
+    let read = unsafe { File::from_raw_fd(job) }.read_to_end(&mut contents);
+    let job = match read.and_then(|_| serde_json::from_slice(&contents).map_err(io::Error::from)) {
+        Ok(job) => job,
+        Err(e) => {
+            error!("Failed to read the job of the executor: {e}");
+            fail(errors, Errno::EINVAL.into());
+        }
+    };
+
+    match job {
+        Job::Start(context) => context.into_service().start(errors),
+        Job::Exec {
+            context,
+            running,
+            command,
+            stdio,
+        } => exec::run(&context.into_service(), running, &command, &stdio, errors),
+    }
+}
+
+/// Report the errno of a failed setup to the engine and exit.
+fn fail(errors: OwnedFd, e: io::Error) -> ! {
+    let errno = e.raw_os_error().unwrap_or(0);
+    _ = File::from(errors).write_all(&errno.to_le_bytes());
+    std::process::exit(255)
+}
+
+/// Read the outcome of the start of a process from its error pipe.
+///
+/// Returns `None` if the program wasn't executed yet, `Some(None)` if it was and the
+/// errno if the start failed.
+pub(crate) fn outcome(errors: &OwnedFd) -> Option<Option<i32>> {
+    let mut errno = [0; 4];
+    match nix::unistd::read(errors.as_raw_fd(), &mut errno) {
+        Ok(4) => Some(Some(i32::from_le_bytes(errno))),
+        Ok(_) => Some(None),
+        Err(Errno::EAGAIN) => None,
+        Err(e) => Some(Some(e as i32)),
+    }
+}
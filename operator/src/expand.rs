@@ -0,0 +1,104 @@
+//! Expansion of the variables in the service files.
+//!
+//! `${VAR}` is replaced by the env var `VAR` of operator, `%n` by the name of the
+//! service and `%i` by its instance or replica index. `$${` and `%%` are written as
+//! `${` and `%`, a `$` or `%` followed by anything else is kept as it is.
+
+/// What the specifiers of a service expand to.
+pub struct Specifiers<'a> {
+    /// Name of the service, `%n`
+    pub name: &'a str,
+    /// Instance of a template service or index of a replica, `%i`
+    pub instance: Option<&'a str>,
+}
+
+/// Expand the variables and specifiers of `s`.
+///
+/// An undefined env var is an error, `%i` is kept if the service has no instance.
+pub fn expand(s: &str, specifiers: &Specifiers) -> Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(i) = rest.find(['$', '%']) {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable in `{s}`"))?;
+            let var = &after[..end];
+            let value = std::env::var(var).map_err(|_| format!("undefined variable `{var}`"))?;
+            expanded.push_str(&value);
+            rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix("%%") {
+            expanded.push('%');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("%n") {
+            expanded.push_str(specifiers.name);
+            rest = after;
+        } else if let (Some(after), Some(instance)) = (rest.strip_prefix("%i"), specifiers.instance)
+        {
+            expanded.push_str(instance);
+            rest = after;
+        } else {
+            // `$` and `%` are ASCII, the next char starts right after.
+            expanded.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WEB: Specifiers = Specifiers {
+        name: "web@2",
+        instance: Some("2"),
+    };
+
+    #[test]
+    fn variables_and_specifiers() {
+        std::env::set_var("OP_EXPAND_TEST_PORT", "8080");
+        assert_eq!(
+            expand("--port ${OP_EXPAND_TEST_PORT} --name %n-%i", &WEB).unwrap(),
+            "--port 8080 --name web@2-2"
+        );
+        assert_eq!(expand("no variables", &WEB).unwrap(), "no variables");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(expand("$${HOME} 100%%", &WEB).unwrap(), "${HOME} 100%");
+        assert_eq!(expand("%%n %%i", &WEB).unwrap(), "%n %i");
+    }
+
+    #[test]
+    fn unknown_specifiers() {
+        // kept as they are, like `$` not followed by `{`.
+        assert_eq!(expand("%x 50% $HOME $", &WEB).unwrap(), "%x 50% $HOME $");
+        let service = Specifiers {
+            name: "web",
+            instance: None,
+        };
+        assert_eq!(expand("%n.%i", &service).unwrap(), "web.%i");
+    }
+
+    #[test]
+    fn errors() {
+        let e = expand("--port ${OP_EXPAND_TEST_PORT", &WEB).unwrap_err();
+        assert!(e.contains("unterminated variable"), "{e}");
+        let e = expand("${OP_EXPAND_TEST_UNDEFINED}", &WEB).unwrap_err();
+        assert!(
+            e.contains("undefined variable `OP_EXPAND_TEST_UNDEFINED`"),
+            "{e}"
+        );
+    }
+}
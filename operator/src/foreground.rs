@@ -0,0 +1,158 @@
+//! Running a single service in the foreground, to debug it.
+//!
+//! `operator run <name|file>` starts the service with the setup of the daemon: its
+//! cgroup, sandbox, limits, user, env, secrets and socket, but its output goes to the
+//! terminal instead of its log. The signals of the terminal, e.g. Ctrl-C, are forwarded
+//! to its processes, and operator exits with the exit code of the service.
+//!
+//! The notification socket isn't bound, it's the one of the daemon.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use log::{info, warn};
+use nix::{
+    fcntl::OFlag,
+    libc::STDOUT_FILENO,
+    sys::{
+        signal::{kill, SigSet, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{fork, pipe2, ForkResult, Pid},
+};
+
+use crate::{
+    cgroup,
+    config::Config,
+    secrets::Secrets,
+    service::{FailReason, Service},
+    socket, Error, Result,
+};
+
+/// Signals of the terminal forwarded to the service.
+const FORWARDED: [Signal; 4] = [
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+];
+
+/// Run the service of a service file, or of the service dirs by name, until it exits.
+///
+/// Returns its exit code, `128 + <signal>` if it was killed by a signal.
+pub fn run(target: &str) -> Result<i32> {
+    let mut service = find(target)?;
+    service.pass_environment(Config::read()?.pass_environment.as_deref());
+    // the log dir isn't written to.
+    match service.validate() {
+        Ok(()) | Err(FailReason::LogDirNotWritable) => {}
+        Err(reason) => return Err(Error::Rejected(format!("{}: {reason}", service.name))),
+    }
+
+    // the output goes to the terminal, which isn't the one of a `tty` service.
+    service.output_fd = Some(STDOUT_FILENO);
+    service.tty = false;
+    service.secret_env =
+        Secrets::read(&service.secrets).map_err(|(file, e)| Error::parse(file, e))?;
+    let listen = match service.socket {
+        Some(ref socket) => Some(socket::bind(&socket.listen)?),
+        None => None,
+    };
+    service.listen_fd = listen.as_ref().map(AsRawFd::as_raw_fd);
+    service.cgroup = match cgroup::create(&service) {
+        Ok(cgroup) => Some(cgroup),
+        Err(e) => {
+            warn!("Failed to create the cgroup of {}: {e}", service.name);
+            None
+        }
+    };
+
+    let code = supervise(&service)?;
+    info!("Service {} exited with {code}", service.name);
+    Ok(code)
+}
+
+/// Fork a service and forward it the signals until it exits, returns its exit code.
+fn supervise(service: &Service) -> io::Result<i32> {
+    // the signals are read once the service is started, the child unblocks them.
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGCHLD);
+    for signal in FORWARDED {
+        mask.add(signal);
+    }
+    mask.thread_block()?;
+
+    // the child writes the errno of a failed setup or exec() to the pipe.
+    let (r_fd, w_fd) = pipe2(OFlag::O_CLOEXEC)?;
+    let (r_fd, w_fd) = unsafe { (OwnedFd::from_raw_fd(r_fd), OwnedFd::from_raw_fd(w_fd)) };
+    let child = match unsafe { fork() }? {
+        ForkResult::Parent { child } => child,
+        ForkResult::Child => {
+            drop(r_fd);
+            service.start(w_fd);
+        }
+    };
+    drop(w_fd);
+    let mut errno = [0; 4];
+    if File::from(r_fd).read_exact(&mut errno).is_ok() {
+        _ = waitpid(child, None);
+        return Err(io::Error::from_raw_os_error(i32::from_le_bytes(errno)));
+    }
+    info!("Service {} is running as PID {child}", service.name);
+
+    loop {
+        match mask.wait()? {
+            Signal::SIGCHLD => match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+                WaitStatus::Exited(_, code) => return Ok(code),
+                WaitStatus::Signaled(_, signal, _) => return Ok(128 + signal as i32),
+                _ => {}
+            },
+            // the service is its own process group, which gets the signal like it would
+            // from its terminal.
+            signal => {
+                info!("Forwarding {signal} to service {}", service.name);
+                _ = kill(Pid::from_raw(-child.as_raw()), signal);
+            }
+        }
+    }
+}
+
+/// The service of a service file, or the one named `target` in the service dirs, an
+/// instance of a template or a replica included.
+fn find(target: &str) -> Result<Service> {
+    let path = Path::new(target);
+    if path.is_file() {
+        return Service::from_file(path);
+    }
+
+    let (services, _) = Service::read_service_files()?;
+    for service in services {
+        if service.is_template() {
+            match target.split_once('@') {
+                Some((template, instance))
+                    if !instance.is_empty() && service.name == format!("{template}@") =>
+                {
+                    return Ok(service.instantiate(instance))
+                }
+                _ => continue,
+            }
+        }
+        if service.replicas.is_some() {
+            match target.rsplit_once('-') {
+                Some((set, index)) if set == service.name => match index.parse::<u32>() {
+                    Ok(index) => return Ok(service.replica(index)),
+                    Err(_) => continue,
+                },
+                _ => continue,
+            }
+        }
+        if service.name == target {
+            return Ok(service);
+        }
+    }
+    Err(Error::NotFound(target.to_string()))
+}
@@ -0,0 +1,761 @@
+//! Formats of the service files, picked by their extension.
+//!
+//! YAML files are parsed by a small parser of the subset of YAML that service files
+//! need: block mappings and sequences, flow `[...]` and `{...}` collections on a line,
+//! plain and quoted scalars, `|` and `>` block scalars and comments. Anchors, tags and
+//! multiple documents are not supported.
+//!
+//! A plain scalar is typed by the field it's read into: `umask: 0027` or `user: 1000`
+//! are strings for string fields, and numbers for the numeric ones.
+
+use std::path::Path;
+
+use serde::de::{
+    self,
+    value::{Error, MapAccessDeserializer, MapDeserializer, SeqDeserializer},
+    DeserializeOwned, IntoDeserializer, Visitor,
+};
+use serde_json::{Number, Value};
+
+/// Format of a service file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// Format of a file, `None` if its extension is unknown.
+    pub fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Parse the contents of a file.
+    pub fn parse<T: DeserializeOwned>(self, contents: &str) -> Result<T, String> {
+        match self {
+            Format::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            Format::Yaml => T::deserialize(yaml(contents)?).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Parse the contents of a file as a document, to be merged with others.
+    pub fn value(self, contents: &str) -> Result<Value, String> {
+        match self {
+            Format::Toml => toml::from_str::<toml::Value>(contents)
+                .map_err(|e| e.to_string())
+                .and_then(|value| serde_json::to_value(value).map_err(|e| e.to_string())),
+            Format::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            Format::Yaml => yaml(contents).map(Node::into_value),
+        }
+    }
+}
+
+/// Deserialize a document merged from service files, with its scalars typed by the
+/// fields they're read into like the ones of a YAML file.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, String> {
+    T::deserialize(Node::from(value)).map_err(|e| e.to_string())
+}
+
+/// Merge `overrides` over `base`.
+///
+/// The tables of both are merged key by key, any other value of `overrides` replaces
+/// the one of `base`, lists included.
+pub fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => _ = base.insert(key, value),
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// A line of a YAML document, without its indentation and comment.
+struct Line<'a> {
+    /// line number (1-based).
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+/// A node of a YAML document.
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Null,
+    /// a plain scalar, e.g. `true` or `42`, typed when it's deserialized.
+    Plain(String),
+    /// a quoted or block scalar.
+    String(String),
+    Seq(Vec<Node>),
+    Map(Vec<(String, Node)>),
+}
+
+impl Node {
+    /// The value of a node, with its plain scalars typed like YAML 1.2 does.
+    ///
+    /// The numbers that wouldn't be written back the same, e.g. `0027`, are kept as
+    /// strings.
+    fn into_value(self) -> Value {
+        match self {
+            Node::Null => Value::Null,
+            Node::Plain(text) => match typed(&text) {
+                Value::Number(n) if n.to_string() != text => Value::String(text),
+                value => value,
+            },
+            Node::String(text) => Value::String(text),
+            Node::Seq(items) => Value::Array(items.into_iter().map(Node::into_value).collect()),
+            Node::Map(entries) => Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into_value()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The key of a mapping written as this node.
+    fn into_key(self) -> String {
+        match self {
+            Node::Plain(key) | Node::String(key) => key,
+            node => node.into_value().to_string(),
+        }
+    }
+}
+
+impl From<Value> for Node {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Node::Null,
+            Value::Bool(b) => Node::Plain(b.to_string()),
+            Value::Number(n) => Node::Plain(n.to_string()),
+            Value::String(s) => Node::String(s),
+            Value::Array(items) => Node::Seq(items.into_iter().map(Node::from).collect()),
+            Value::Object(map) => Node::Map(
+                map.into_iter()
+                    .map(|(key, value)| (key, Node::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Type a plain scalar like YAML 1.2 does (`true`, `42`, `null`...).
+fn typed(text: &str) -> Value {
+    match text {
+        "" | "null" | "Null" | "NULL" | "~" => Value::Null,
+        "true" | "True" | "TRUE" => Value::Bool(true),
+        "false" | "False" | "FALSE" => Value::Bool(false),
+        _ => {
+            if let Ok(n) = text.parse::<i64>() {
+                Value::Number(n.into())
+            } else if let Ok(n) = text.parse::<u64>() {
+                Value::Number(n.into())
+            } else if let Some(n) = text.parse::<f64>().ok().and_then(Number::from_f64) {
+                Value::Number(n)
+            } else {
+                Value::String(text.to_string())
+            }
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Node {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Node {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Node::Null => visitor.visit_unit(),
+            Node::Plain(text) => match typed(&text) {
+                Value::Null => visitor.visit_unit(),
+                Value::Bool(b) => visitor.visit_bool(b),
+                Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+                    (Some(n), _, _) => visitor.visit_u64(n),
+                    (_, Some(n), _) => visitor.visit_i64(n),
+                    (_, _, n) => visitor.visit_f64(n.unwrap_or_default()),
+                },
+                _ => visitor.visit_string(text),
+            },
+            Node::String(text) => visitor.visit_string(text),
+            Node::Seq(items) => {
+                let mut seq = SeqDeserializer::new(items.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Node::Map(entries) => {
+                let mut map = MapDeserializer::new(entries.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    // a plain scalar read as a string is kept as written.
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Node::Plain(text) => visitor.visit_string(text),
+            node => node.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Node::Null => visitor.visit_none(),
+            Node::Plain(ref text) if typed(text).is_null() => visitor.visit_none(),
+            node => visitor.visit_some(node),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            Node::Plain(variant) | Node::String(variant) => {
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            // a variant with data is a mapping of its name to it.
+            Node::Map(entries) if entries.len() == 1 => visitor.visit_enum(
+                MapAccessDeserializer::new(MapDeserializer::new(entries.into_iter())),
+            ),
+            node => node.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Parse a YAML document.
+fn yaml(contents: &str) -> Result<Node, String> {
+    let mut lines = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let text = strip_comment(line).trim_end();
+        let trimmed = text.trim_start();
+        if trimmed.is_empty() || (text == "---" && lines.is_empty()) {
+            continue;
+        }
+        if line.starts_with('\t') {
+            return Err(format!(
+                "line {}: tabs can't be used for indentation",
+                i + 1
+            ));
+        }
+        lines.push(Line {
+            number: i + 1,
+            indent: text.len() - trimmed.len(),
+            text: trimmed,
+        });
+    }
+
+    if lines.is_empty() {
+        return Ok(Node::Map(vec![]));
+    }
+    let mut parser = Parser { lines, i: 0 };
+    let indent = parser.lines[0].indent;
+    let value = parser.node(indent)?;
+    match parser.lines.get(parser.i) {
+        Some(line) => Err(format!("line {}: unexpected indentation", line.number)),
+        None => Ok(value),
+    }
+}
+
+/// Remove the comment of a line, a `#` at its start or after a space, outside quoted
+/// scalars.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None, '#') if prev.is_whitespace() => return &line[..i],
+            (None, '"' | '\'') if prev.is_whitespace() || matches!(prev, '[' | '{' | ',') => {
+                quote = Some(c)
+            }
+            // escaped quotes don't end the scalar.
+            (Some('"'), '\\') => _ = chars.next(),
+            (Some('\''), '\'') if chars.peek().is_some_and(|&(_, next)| next == '\'') => {
+                _ = chars.next()
+            }
+            (Some(q), c) if c == q => quote = None,
+            _ => {}
+        }
+        prev = c;
+    }
+    line
+}
+
+struct Parser<'a> {
+    lines: Vec<Line<'a>>,
+    /// next line to parse.
+    i: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parse the block node whose lines are indented by `indent`.
+    fn node(&mut self, indent: usize) -> Result<Node, String> {
+        let line = &self.lines[self.i];
+        if line.text == "-" || line.text.starts_with("- ") {
+            self.sequence(indent)
+        } else {
+            self.mapping(indent)
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Node, String> {
+        let mut items = vec![];
+        while let Some(line) = self.lines.get(self.i) {
+            if line.indent != indent || !(line.text == "-" || line.text.starts_with("- ")) {
+                break;
+            }
+
+            let (number, text) = (line.number, line.text);
+            let item = text[1..].trim_start();
+            if item.is_empty() {
+                self.i += 1;
+                items.push(self.nested(indent)?);
+            } else if split_key(item).is_some() {
+                // `- key: value` starts a mapping indented like its first key.
+                let indent = indent + text.len() - item.len();
+                self.lines[self.i] = Line {
+                    number,
+                    indent,
+                    text: item,
+                };
+                items.push(self.mapping(indent)?);
+            } else {
+                self.i += 1;
+                items.push(self.inline(item, number, indent)?);
+            }
+        }
+        Ok(Node::Seq(items))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Node, String> {
+        let mut map = vec![];
+        while let Some(line) = self.lines.get(self.i) {
+            if line.indent != indent {
+                break;
+            }
+            let number = line.number;
+            let (key, value) = split_key(line.text)
+                .ok_or_else(|| format!("line {number}: expected `key: value`"))?;
+            let key = scalar(key, number)?.into_key();
+            if map.iter().any(|(other, _)| *other == key) {
+                return Err(format!("line {number}: duplicate key `{key}`"));
+            }
+            self.i += 1;
+
+            let value = if !value.is_empty() {
+                self.inline(value, number, indent)?
+            } else if self.lines.get(self.i).is_some_and(|next| {
+                next.indent == indent && (next.text == "-" || next.text.starts_with("- "))
+            }) {
+                // the items of a sequence can be indented like its key.
+                self.sequence(indent)?
+            } else {
+                self.nested(indent)?
+            };
+            map.push((key, value));
+        }
+        Ok(Node::Map(map))
+    }
+
+    /// Parse the node indented under a line, null if there is none.
+    fn nested(&mut self, indent: usize) -> Result<Node, String> {
+        match self.lines.get(self.i) {
+            Some(next) if next.indent > indent => self.node(next.indent),
+            _ => Ok(Node::Null),
+        }
+    }
+
+    /// Parse a value written after a key or a `-`.
+    fn inline(&mut self, text: &str, number: usize, indent: usize) -> Result<Node, String> {
+        match text {
+            "|" | "|-" | ">" | ">-" => Ok(Node::String(self.block_scalar(text, indent))),
+            _ if text.starts_with('[') || text.starts_with('{') => {
+                let mut flow = Flow {
+                    text,
+                    pos: 0,
+                    number,
+                };
+                let value = flow.value()?;
+                flow.skip_spaces();
+                match flow.pos == text.len() {
+                    true => Ok(value),
+                    false => Err(format!("line {number}: unexpected `{}`", &text[flow.pos..])),
+                }
+            }
+            _ => scalar(text, number),
+        }
+    }
+
+    /// Read the lines of a `|` (literal) or `>` (folded) block scalar.
+    fn block_scalar(&mut self, style: &str, indent: usize) -> String {
+        let mut lines = vec![];
+        let mut block_indent = None;
+        while let Some(line) = self.lines.get(self.i) {
+            if line.indent <= indent {
+                break;
+            }
+            let block_indent = *block_indent.get_or_insert(line.indent);
+            let padding = " ".repeat(line.indent.saturating_sub(block_indent));
+            lines.push(format!("{padding}{}", line.text));
+            self.i += 1;
+        }
+
+        let separator = if style.starts_with('|') { "\n" } else { " " };
+        let mut block = lines.join(separator);
+        if !style.ends_with('-') && !block.is_empty() {
+            block.push('\n');
+        }
+        block
+    }
+}
+
+/// Split `key: value`, the value is empty if it's on the next lines.
+fn split_key(text: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') if i == 0 => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, ':') if text[i + 1..].is_empty() || text[i + 1..].starts_with(' ') => {
+                return Some((text[..i].trim_end(), text[i + 1..].trim_start()));
+            }
+            (None, '[' | '{') if i == 0 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse a scalar, a plain one is typed when it's deserialized.
+fn scalar(text: &str, number: usize) -> Result<Node, String> {
+    if text.starts_with('"') || text.starts_with('\'') {
+        let mut flow = Flow {
+            text,
+            pos: 0,
+            number,
+        };
+        let value = flow.quoted()?;
+        return match flow.pos == text.len() {
+            true => Ok(Node::String(value)),
+            false => Err(format!("line {number}: unexpected `{}`", &text[flow.pos..])),
+        };
+    }
+    Ok(Node::Plain(text.to_string()))
+}
+
+/// Parser of a flow collection or a quoted scalar.
+struct Flow<'a> {
+    text: &'a str,
+    pos: usize,
+    number: usize,
+}
+
+impl Flow<'_> {
+    fn error(&self, msg: &str) -> String {
+        format!("line {}: {msg}", self.number)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn value(&mut self) -> Result<Node, String> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                while !self.end(']')? {
+                    items.push(self.value()?);
+                }
+                Ok(Node::Seq(items))
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut map = vec![];
+                while !self.end('}')? {
+                    let key = self.value()?.into_key();
+                    self.skip_spaces();
+                    if self.peek() != Some(':') {
+                        return Err(self.error("expected `:` in a flow mapping"));
+                    }
+                    self.pos += 1;
+                    let value = self.value()?;
+                    map.push((key, value));
+                }
+                Ok(Node::Map(map))
+            }
+            Some('"' | '\'') => self.quoted().map(Node::String),
+            Some(_) => {
+                // a plain scalar ends at the next indicator of the collection.
+                let rest = &self.text[self.pos..];
+                let end = rest
+                    .char_indices()
+                    .find(|&(i, c)| {
+                        matches!(c, ',' | ']' | '}') || (c == ':' && rest[i + 1..].starts_with(' '))
+                    })
+                    .map_or(rest.len(), |(i, _)| i);
+                self.pos += end;
+                scalar(rest[..end].trim(), self.number)
+            }
+            None => Err(self.error("unterminated flow collection")),
+        }
+    }
+
+    /// Whether the collection ends with `close`, consuming the `,` between its items.
+    fn end(&mut self, close: char) -> Result<bool, String> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(c) if c == close => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(',') => {
+                self.pos += 1;
+                self.skip_spaces();
+                // a trailing comma is allowed.
+                if self.peek() == Some(close) {
+                    self.pos += 1;
+                    return Ok(true);
+                }
+                Ok(false)
+            }
+            Some(_) if self.text[..self.pos].trim_end().ends_with(['[', '{']) => Ok(false),
+            Some(_) => Err(self.error(&format!("expected `,` or `{close}`"))),
+            None => Err(self.error("unterminated flow collection")),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String, String> {
+        let quote = self.peek().unwrap();
+        self.pos += 1;
+        let mut value = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                // a quote is escaped by doubling it in single quoted scalars.
+                '\'' if quote == '\'' && self.text[self.pos + i + 1..].starts_with('\'') => {
+                    value.push('\'');
+                    chars.next();
+                }
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(value);
+                }
+                '\\' if quote == '"' => {
+                    let escaped = match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        Some((_, '0')) => '\0',
+                        Some((_, c @ ('"' | '\\' | '/'))) => c,
+                        Some((_, 'u')) => {
+                            let hex = chars.by_ref().take(4).map(|(_, c)| c).collect::<String>();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error(&format!("invalid escape `\\u{hex}`")))?
+                        }
+                        _ => return Err(self.error("invalid escape in a quoted scalar")),
+                    };
+                    value.push(escaped);
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error("unterminated quoted scalar"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::service::Service;
+
+    fn parse(contents: &str) -> Value {
+        Format::Yaml.value(contents).unwrap()
+    }
+
+    #[test]
+    fn block_mappings() {
+        let value = parse(
+            "name: web # a comment\n\
+             limits:\n  nofile: 1024\n  core: infinity\n\
+             enabled: true\n\
+             empty:\n",
+        );
+        assert_eq!(
+            value,
+            json!({
+                "name": "web",
+                "limits": {"nofile": 1024, "core": "infinity"},
+                "enabled": true,
+                "empty": null,
+            })
+        );
+        assert!(Format::Yaml
+            .value("a: 1\na: 2\n")
+            .unwrap_err()
+            .contains("duplicate key `a`"));
+        assert!(Format::Yaml.value("\ta: 1\n").is_err());
+    }
+
+    #[test]
+    fn sequences() {
+        let value = parse(
+            "args:\n- -c\n- echo hi\n\
+             env:\n  - key: A\n    value: 1\n  -\n    key: B\n",
+        );
+        assert_eq!(
+            value,
+            json!({
+                "args": ["-c", "echo hi"],
+                "env": [{"key": "A", "value": 1}, {"key": "B"}],
+            })
+        );
+    }
+
+    #[test]
+    fn flow_collections() {
+        let value = parse("args: [a, 'b, c', [1, 2],]\nmap: {x: 1, \"y\": [true], z: }\n");
+        assert_eq!(
+            value,
+            json!({
+                "args": ["a", "b, c", [1, 2]],
+                "map": {"x": 1, "y": [true], "z": null},
+            })
+        );
+        assert!(Format::Yaml.value("args: [a, b\n").is_err());
+        assert!(Format::Yaml.value("args: [a] b\n").is_err());
+    }
+
+    #[test]
+    fn quoting() {
+        let value = parse(
+            "a: \"tab\\there \\\"q\\\" \\u00e9 # not a comment\"\n\
+             b: 'it''s'\n\
+             c: \"42\"\n\
+             d: |\n  line 1\n    line 2\n\
+             e: >-\n  folded\n  text\n",
+        );
+        assert_eq!(
+            value,
+            json!({
+                "a": "tab\there \"q\" é # not a comment",
+                "b": "it's",
+                "c": "42",
+                "d": "line 1\n  line 2\n",
+                "e": "folded text",
+            })
+        );
+        assert!(Format::Yaml.value("a: \"open\n").is_err());
+        assert!(Format::Yaml.value("a: \"\\x\"\n").is_err());
+    }
+
+    #[test]
+    fn non_ascii_whitespace() {
+        // U+00A0 is whitespace of 2 bytes.
+        let value = parse("args: [\u{a0}a,\u{a0}b\u{a0}]\n");
+        assert_eq!(value, json!({"args": ["a", "b"]}));
+    }
+
+    #[test]
+    fn plain_scalars_typed_by_field() {
+        #[derive(Deserialize)]
+        struct Fields {
+            umask: String,
+            user: String,
+            flag: String,
+            uid: u32,
+            ratio: f64,
+            maybe: Option<String>,
+            nothing: Option<u32>,
+        }
+
+        let contents = "umask: 0027\nuser: 1000\nflag: true\nuid: 1000\nratio: 0.5\n\
+                        maybe: 007\nnothing: ~\n";
+        let fields = Format::Yaml.parse::<Fields>(contents).unwrap();
+        assert_eq!(fields.umask, "0027");
+        assert_eq!(fields.user, "1000");
+        assert_eq!(fields.flag, "true");
+        assert_eq!(fields.uid, 1000);
+        assert_eq!(fields.ratio, 0.5);
+        assert_eq!(fields.maybe.as_deref(), Some("007"));
+        assert_eq!(fields.nothing, None);
+        assert!(Format::Yaml.parse::<Fields>("uid: abc\n").is_err());
+
+        // the same for a document merged from drop-ins.
+        let mut document = Format::Yaml.value(contents).unwrap();
+        merge(&mut document, Format::Toml.value("uid = 7").unwrap());
+        let fields = from_value::<Fields>(document).unwrap();
+        assert_eq!(
+            (fields.umask.as_str(), fields.user.as_str()),
+            ("0027", "1000")
+        );
+        assert_eq!(fields.uid, 7);
+    }
+
+    #[test]
+    fn service() {
+        let service = Service::parse(
+            "name: web\nexecutable: /bin/sleep\nargs: [10]\numask: 0027\nuser: 1000\n\
+             limits:\n  nofile: 1024\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        assert_eq!(service.args, Some(vec![CString::new("10").unwrap()]));
+        assert_eq!(service.user.as_deref(), Some("1000"));
+        assert!(service.umask.is_some());
+    }
+}
@@ -0,0 +1,423 @@
+//! gRPC API of operator.
+//!
+//! Served by tonic from a thread of its own when `[grpc]` is set in `operator.toml`, on
+//! a TCP address with TLS, or without TLS on a loopback address. The service
+//! `operator.v1.Operator` of `proto/operator.proto` mirrors the IPC commands, and
+//! `WatchEvents` streams the state changes of the services. The calls are forwarded to
+//! the engine with a [Client], like the ones of `operatorctl`, and must carry the token
+//! of the config in the `authorization: Bearer <token>` metadata. `exec`, attaching and
+//! the power actions are only served on the IPC socket.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{
+    service::Interceptor,
+    transport::{Identity, Server, ServerTlsConfig},
+    Code, Request, Response, Status,
+};
+
+use crate::{
+    client::Client,
+    helper::constant_time_eq,
+    history::{HistoryEntry, RunEvent},
+    service::{Filter, ServiceInfo, Status as ServiceStatus},
+    webhook::StateChange,
+    Error,
+};
+
+use self::proto::{
+    operator_server::{Operator, OperatorServer},
+    Empty, Event, HistoryRequest, HistoryResponse, ListServicesRequest, ListServicesResponse,
+    OutputRequest, OutputResponse, ScaleRequest, ServiceRequest, SetEnabledRequest,
+    SetMaskedRequest, WatchEventsRequest,
+};
+
+/// The messages, client and server of `proto/operator.proto`, generated by tonic.
+pub mod proto {
+    #![allow(clippy::all)]
+    tonic::include_proto!("operator.v1");
+}
+
+/// Default number of lines of `GetOutput`.
+const OUTPUT_LINES: u32 = 100;
+/// Default number of entries of `GetHistory`.
+const HISTORY_LIMIT: u32 = 20;
+/// State changes of a `WatchEvents` call not sent to its client yet.
+const WATCH_BACKLOG: usize = 64;
+
+/// Config of the gRPC API, `[grpc]` in `operator.toml`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GrpcApi {
+    /// Address the API listens on, `127.0.0.1:50051` by default
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// PEM certificate chain of the API, required unless it listens on a loopback address
+    pub cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub key: Option<PathBuf>,
+    /// File containing the token the calls must carry
+    pub token_file: PathBuf,
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 50051)
+}
+
+/// Start serving the API from a thread.
+///
+/// Returns the address it listens on, the port is picked if the one of `listen` is 0.
+pub fn serve(api: GrpcApi) -> io::Result<SocketAddr> {
+    let token = std::fs::read_to_string(&api.token_file)?.trim().to_string();
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the token file is empty",
+        ));
+    }
+    let mut server = Server::builder();
+    match (api.cert, api.key) {
+        (Some(cert), Some(key)) => {
+            let identity = Identity::from_pem(std::fs::read(&cert)?, std::fs::read(key)?);
+            server = server
+                .tls_config(ServerTlsConfig::new().identity(identity))
+                .map_err(|e| invalid(format!("{}: {}", cert.display(), source(&e))))?;
+        }
+        // the token would be sent in clear to a remote client.
+        (None, None) if !api.listen.ip().is_loopback() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not a loopback address, a cert and key are required",
+                    api.listen
+                ),
+            ))
+        }
+        (None, None) => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the cert and key must be set together",
+            ))
+        }
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let listener = std::net::TcpListener::bind(api.listen)?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let listener = {
+        let _runtime = runtime.enter();
+        tokio::net::TcpListener::from_std(listener)?
+    };
+
+    let router = server.add_service(OperatorServer::with_interceptor(Api, Authorize { token }));
+    std::thread::Builder::new()
+        .name("grpc".to_string())
+        .spawn(move || {
+            let served =
+                runtime.block_on(router.serve_with_incoming(TcpListenerStream::new(listener)));
+            if let Err(e) = served {
+                error!("The gRPC API stopped: {}", source(&e));
+            }
+        })?;
+
+    info!("Serving the gRPC API on {addr}");
+    Ok(addr)
+}
+
+/// Refuses the calls without the token of the config.
+#[derive(Clone)]
+struct Authorize {
+    token: String,
+}
+
+impl Interceptor for Authorize {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let authorized = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), self.token.as_bytes()));
+        match authorized {
+            true => Ok(request),
+            false => Err(Status::unauthenticated("invalid token")),
+        }
+    }
+}
+
+/// The service `operator.v1.Operator`, forwarding the calls to the engine.
+struct Api;
+
+#[tonic::async_trait]
+impl Operator for Api {
+    async fn list_services(
+        &self,
+        request: Request<ListServicesRequest>,
+    ) -> Result<Response<ListServicesResponse>, Status> {
+        let request = request.into_inner();
+        let filter = Filter {
+            pattern: request.pattern,
+            states: request.states,
+            tags: request.tags,
+        };
+        let services = blocking(move |client| client.list_matching(filter)).await?;
+        Ok(Response::new(ListServicesResponse {
+            services: services.iter().map(service).collect(),
+        }))
+    }
+
+    async fn get_service(
+        &self,
+        request: Request<ServiceRequest>,
+    ) -> Result<Response<proto::Service>, Status> {
+        let name = named(request.into_inner().name)?;
+        let info = blocking(move |client| client.status(&name)).await?;
+        Ok(Response::new(service(&info)))
+    }
+
+    async fn start_service(
+        &self,
+        request: Request<ServiceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        command(request.into_inner().name, |client, name| client.start(name)).await
+    }
+
+    async fn stop_service(
+        &self,
+        request: Request<ServiceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        command(request.into_inner().name, |client, name| client.stop(name)).await
+    }
+
+    async fn restart_service(
+        &self,
+        request: Request<ServiceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        command(request.into_inner().name, |client, name| {
+            client.restart(name)
+        })
+        .await
+    }
+
+    async fn reload_service(
+        &self,
+        request: Request<ServiceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        command(request.into_inner().name, |client, name| {
+            client.reload(name)
+        })
+        .await
+    }
+
+    async fn set_enabled(
+        &self,
+        request: Request<SetEnabledRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let SetEnabledRequest { name, enabled } = request.into_inner();
+        command(name, move |client, name| client.set_enabled(name, enabled)).await
+    }
+
+    async fn set_masked(
+        &self,
+        request: Request<SetMaskedRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let SetMaskedRequest { name, masked } = request.into_inner();
+        command(name, move |client, name| client.set_masked(name, masked)).await
+    }
+
+    async fn scale_service(
+        &self,
+        request: Request<ScaleRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let ScaleRequest { name, replicas } = request.into_inner();
+        command(name, move |client, name| client.scale(name, replicas)).await
+    }
+
+    async fn get_output(
+        &self,
+        request: Request<OutputRequest>,
+    ) -> Result<Response<OutputResponse>, Status> {
+        let OutputRequest { name, lines } = request.into_inner();
+        let name = named(name)?;
+        let lines = if lines == 0 { OUTPUT_LINES } else { lines };
+        let lines = blocking(move |client| client.output(&name, lines as usize)).await?;
+        Ok(Response::new(OutputResponse { lines }))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<HistoryRequest>,
+    ) -> Result<Response<HistoryResponse>, Status> {
+        let HistoryRequest { name, limit } = request.into_inner();
+        let name = named(name)?;
+        let limit = if limit == 0 { HISTORY_LIMIT } else { limit };
+        let entries = blocking(move |client| {
+            client.status(&name)?;
+            client.history(&name, limit as usize)
+        })
+        .await?;
+        Ok(Response::new(HistoryResponse {
+            entries: entries.iter().map(history_entry).collect(),
+        }))
+    }
+
+    type WatchEventsStream = ReceiverStream<Result<Event, Status>>;
+
+    async fn watch_events(
+        &self,
+        _: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let subscription = blocking(|client| client.subscribe()).await?;
+        let (events, receiver) = mpsc::channel(WATCH_BACKLOG);
+        // the thread ends with the next state change once the call is cancelled.
+        std::thread::Builder::new()
+            .name("grpc-watch".to_string())
+            .spawn(move || {
+                for change in subscription {
+                    let event = change
+                        .map(|change| event(&change))
+                        .map_err(|e| Status::unavailable(e.to_string()));
+                    let failed = event.is_err();
+                    if events.blocking_send(event).is_err() || failed {
+                        return;
+                    }
+                }
+                let ended = Status::unavailable("operator ended the stream");
+                _ = events.blocking_send(Err(ended));
+            })
+            .map_err(|e| status(e.into()))?;
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Send a request with a [Client], off the runtime as it blocks.
+async fn blocking<T: Send + 'static>(
+    request: impl FnOnce(Client) -> crate::Result<T> + Send + 'static,
+) -> Result<T, Status> {
+    tokio::task::spawn_blocking(move || request(Client::new()))
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(status)
+}
+
+/// Send a command about a service, it has to be loaded as the engine ignores the
+/// unknown ones.
+async fn command(
+    name: String,
+    command: impl FnOnce(&Client, &str) -> crate::Result<()> + Send + 'static,
+) -> Result<Response<Empty>, Status> {
+    let name = named(name)?;
+    blocking(move |client| {
+        client.status(&name)?;
+        command(&client, &name)
+    })
+    .await?;
+    Ok(Response::new(Empty {}))
+}
+
+/// The name of the service of a request, which is required.
+// the calls fail with a `Status` of tonic, however large it is.
+#[allow(clippy::result_large_err)]
+fn named(name: String) -> Result<String, Status> {
+    match name.is_empty() {
+        true => Err(Status::invalid_argument(
+            "the name of the service is required",
+        )),
+        false => Ok(name),
+    }
+}
+
+/// The status a call failed with.
+fn status(e: Error) -> Status {
+    let code = match e {
+        Error::NotFound(_) => Code::NotFound,
+        Error::Busy => Code::ResourceExhausted,
+        Error::Rejected(_) => Code::FailedPrecondition,
+        // operator isn't running, or is restarting.
+        Error::Io(_) => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    Status::new(code, e.to_string())
+}
+
+/// A `Service` message.
+fn service(info: &ServiceInfo) -> proto::Service {
+    let reason = match info.status {
+        ServiceStatus::Failed { reason } => Some(reason.to_string()),
+        _ => None,
+    };
+    proto::Service {
+        name: info.name.clone(),
+        description: info.description.clone(),
+        tags: info.tags.clone(),
+        pid: info.pid,
+        state: info.status.name().to_string(),
+        reason,
+        enabled: info.enabled,
+        status_text: info.status_text.clone(),
+        memory_bytes: info.usage.map(|usage| usage.memory),
+        cpu_percent: info.usage.map(|usage| usage.cpu.into()),
+        active_since: info.active_since,
+        uptime_secs: info.uptime.map(|uptime| uptime.as_secs()),
+        stale: info.stale,
+        core: info.core.clone(),
+    }
+}
+
+/// An `Event` message.
+fn event(change: &StateChange) -> Event {
+    Event {
+        service: change.service.clone(),
+        old_state: change.old_state.clone(),
+        new_state: change.new_state.clone(),
+        reason: change.reason.clone(),
+        exit_code: change.exit_code.map(u32::from),
+        core: change.core.clone(),
+        timestamp: change.timestamp,
+    }
+}
+
+/// A `HistoryEntry` message.
+fn history_entry(entry: &HistoryEntry) -> proto::HistoryEntry {
+    let event = match entry.event {
+        RunEvent::Start => "start",
+        RunEvent::Stop => "stop",
+        RunEvent::Crash => "crash",
+        RunEvent::Restart => "restart",
+    };
+    proto::HistoryEntry {
+        event: event.to_string(),
+        timestamp: entry.timestamp,
+        pid: entry.pid,
+        exit_code: entry.exit_code.map(u32::from),
+        reason: entry.reason.clone(),
+        duration_secs: entry.duration.map(|duration| duration.as_secs_f64()),
+    }
+}
+
+/// The message of an error of tonic, with the ones it's caused by.
+fn source(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message = format!("{message}: {e}");
+        source = e.source();
+    }
+    message
+}
+
+/// Error of an invalid config.
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
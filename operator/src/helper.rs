@@ -1,15 +1,323 @@
 //! This module contains helpers used by operator and operatorctl.
 
-/// Directory where the service files are located.
+use std::{
+    fs::DirBuilder,
+    os::{
+        fd::RawFd,
+        unix::{ffi::OsStringExt, fs::DirBuilderExt},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    libc,
+    unistd::{getpgrp, tcgetpgrp},
+};
+
+/// Whether operator runs as a supervisor of the services of the current user.
+static USER_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Use the directories and the socket of a per-user operator instead of the system
+/// ones, which are only writable by root.
+pub fn set_user_mode(user: bool) {
+    USER_MODE.store(user, Ordering::Relaxed);
+}
+
+/// Whether the directories and the socket of a per-user operator are used.
+pub fn user_mode() -> bool {
+    USER_MODE.load(Ordering::Relaxed)
+}
+
+/// Directory of the user set by an XDG env var, `~/<default>` if it's not set.
+fn xdg_dir(var: &str, default: &str) -> String {
+    match std::env::var(var) {
+        Ok(dir) if !dir.is_empty() => dir,
+        _ => format!("{}/{default}", std::env::var("HOME").unwrap_or_default()),
+    }
+}
+
+/// Directories where the service files are located, a service in a directory shadows
+/// the ones of the same name in the directories before it.
 ///
-/// This can be set by the `OP_SERVICE_DIR` env var.
-pub fn op_service_dir() -> String {
-    std::env::var("OP_SERVICE_DIR").unwrap_or_else(|_| "/tmp/op".to_string())
+/// This can be set by the `OP_SERVICE_DIR` env var, as a `:` separated list. In user
+/// mode it defaults to `~/.config/operator/services`.
+pub fn op_service_dirs() -> Vec<PathBuf> {
+    let dirs = std::env::var("OP_SERVICE_DIR").unwrap_or_else(|_| match user_mode() {
+        true => format!(
+            "{}/operator/services",
+            xdg_dir("XDG_CONFIG_HOME", ".config")
+        ),
+        false => "/tmp/op".to_string(),
+    });
+    std::env::split_paths(&dirs)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect()
 }
 
 /// Directory where the log files are located.
 ///
-/// This can be set by the `OP_SERVICE_LOG_DIR` env var.
+/// This can be set by the `OP_SERVICE_LOG_DIR` env var. In user mode it defaults to
+/// `~/.local/state/operator/logs`.
 pub fn op_service_log_dir() -> String {
-    std::env::var("OP_SERVICE_LOG_DIR").unwrap_or_else(|_| "/tmp/oplogs".to_string())
+    std::env::var("OP_SERVICE_LOG_DIR").unwrap_or_else(|_| match user_mode() {
+        true => format!(
+            "{}/operator/logs",
+            xdg_dir("XDG_STATE_HOME", ".local/state")
+        ),
+        false => "/tmp/oplogs".to_string(),
+    })
+}
+
+/// Directory where operator keeps its persistent state, e.g. the enabled and masked
+/// markers and the history of the services.
+///
+/// This can be set by the `OP_STATE_DIR` env var. It defaults to `/var/lib/operator`, and
+/// in user mode to `~/.local/state/operator/state`.
+pub fn op_state_dir() -> String {
+    std::env::var("OP_STATE_DIR").unwrap_or_else(|_| match user_mode() {
+        true => format!(
+            "{}/operator/state",
+            xdg_dir("XDG_STATE_HOME", ".local/state")
+        ),
+        false => "/var/lib/operator".to_string(),
+    })
+}
+
+/// Directory where operator keeps the state that doesn't outlive a reboot, e.g. the
+/// processes of the services in `runtime.toml`.
+///
+/// This can be set by the `OP_RUNTIME_DIR` env var. It defaults to `/run/operator`, and in
+/// user mode to `$XDG_RUNTIME_DIR/operator`, or the state dir if it's not set.
+pub fn op_runtime_dir() -> String {
+    std::env::var("OP_RUNTIME_DIR").unwrap_or_else(|_| match user_mode() {
+        true => match std::env::var("XDG_RUNTIME_DIR") {
+            Ok(dir) if !dir.is_empty() => format!("{dir}/operator"),
+            _ => op_state_dir(),
+        },
+        false => "/run/operator".to_string(),
+    })
+}
+
+/// Create the state dir if it doesn't exist, only accessible by the user of operator.
+pub fn create_state_dir() -> std::io::Result<()> {
+    create_private_dir(op_state_dir())
+}
+
+/// Create the runtime dir if it doesn't exist, only accessible by the user of operator.
+pub fn create_runtime_dir() -> std::io::Result<()> {
+    create_private_dir(op_runtime_dir())
+}
+
+/// Create a dir and its parents if they don't exist, only accessible by the user of
+/// operator.
+pub(crate) fn create_private_dir(path: impl AsRef<Path>) -> std::io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(path)
+}
+
+/// Directory of the notification sockets of the services, next to the runtime dir as
+/// the services running as another user must reach them, `<runtime dir>-notify`.
+pub fn op_notify_dir() -> String {
+    format!("{}-notify", op_runtime_dir())
+}
+
+/// cgroup v2 directory under which operator creates a cgroup per service.
+///
+/// This can be set by the `OP_CGROUP_ROOT` env var. In user mode it defaults to the
+/// cgroup systemd delegates to the user manager.
+pub fn op_cgroup_root() -> String {
+    std::env::var("OP_CGROUP_ROOT").unwrap_or_else(|_| match user_mode() {
+        true => {
+            let uid = nix::unistd::getuid();
+            format!("/sys/fs/cgroup/user.slice/user-{uid}.slice/user@{uid}.service/operator")
+        }
+        false => "/sys/fs/cgroup/operator".to_string(),
+    })
+}
+
+/// Config file of operator.
+///
+/// This can be set by the `OP_CONFIG` env var. In user mode it defaults to
+/// `~/.config/operator/operator.toml`.
+pub fn op_config_file() -> String {
+    std::env::var("OP_CONFIG").unwrap_or_else(|_| match user_mode() {
+        true => format!(
+            "{}/operator/operator.toml",
+            xdg_dir("XDG_CONFIG_HOME", ".config")
+        ),
+        false => "/etc/operator/operator.toml".to_string(),
+    })
+}
+
+/// Path of the unix socket operator listens on.
+///
+/// This can be set by the `OP_SOCKET` env var. In user mode it defaults to
+/// `$XDG_RUNTIME_DIR/operator.sock`, or to `operator.sock` in the runtime dir.
+pub fn op_socket_path() -> String {
+    std::env::var("OP_SOCKET").unwrap_or_else(|_| match user_mode() {
+        true => match std::env::var("XDG_RUNTIME_DIR") {
+            Ok(dir) if !dir.is_empty() => format!("{dir}/operator.sock"),
+            _ => format!("{}/operator.sock", op_runtime_dir()),
+        },
+        false => crate::ipc::SOCKET_PATH.to_string(),
+    })
+}
+
+/// Set an env var of the current process, e.g. of a forked child before exec.
+///
+/// Unlike `std::env::set_var` it doesn't take the lock of the env, which `Command::spawn`
+/// holds while forking so it's never released in the child.
+pub fn setenv(key: &str, value: &str) -> std::io::Result<()> {
+    let key = std::ffi::CString::new(key)?;
+    let value = std::ffi::CString::new(value)?;
+    if unsafe { nix::libc::setenv(key.as_ptr(), value.as_ptr(), 1) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove the env vars of the current process but the ones of `keep`.
+///
+/// This should only be run in the context of a forked child process.
+pub fn retain_env(keep: &[String]) -> std::io::Result<()> {
+    for (key, _) in std::env::vars_os() {
+        if keep.iter().any(|kept| key == kept.as_str()) {
+            continue;
+        }
+        let key = std::ffi::CString::new(key.into_vec())?;
+        if unsafe { nix::libc::unsetenv(key.as_ptr()) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Close the fds from `first` on when the current process executes a program, so it
+/// doesn't inherit the ones of operator.
+///
+/// This should only be run in the context of a forked child process.
+pub fn close_on_exec(first: RawFd) -> std::io::Result<()> {
+    let closed = unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            first as libc::c_uint,
+            libc::c_uint::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        )
+    };
+    if closed == 0 {
+        return Ok(());
+    }
+
+    // close_range() is only available since Linux 5.11.
+    for entry in std::fs::read_dir("/proc/self/fd")? {
+        let name = entry?.file_name();
+        let Some(fd) = name.to_str().and_then(|fd| fd.parse::<RawFd>().ok()) else {
+            continue;
+        };
+        if fd < first {
+            continue;
+        }
+        // the fd of the dir being read is gone once it's read.
+        match fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            Ok(_) | Err(Errno::EBADF) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the current process runs in the foreground of the terminal of its stdin,
+/// where Ctrl-C sends it SIGINT.
+pub fn in_foreground() -> bool {
+    tcgetpgrp(libc::STDIN_FILENO).is_ok_and(|pgrp| pgrp == getpgrp())
+}
+
+/// Whether `name` matches a glob `pattern`, where `*` matches any characters and `?`
+/// a single one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    // position after the last `*` in the pattern, and the one it was matched up to.
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // the `*` matches one more character.
+                Some((after, matched)) => {
+                    star = Some((after, matched + 1));
+                    p = after;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Compare the tokens without leaking how much of them matched through timing.
+#[cfg(any(feature = "http", feature = "remote", feature = "grpc"))]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert!(glob_match("web", "web"));
+        assert!(!glob_match("web", "webs"));
+        assert!(!glob_match("webs", "web"));
+        assert!(!glob_match("web", "Web"));
+    }
+
+    #[test]
+    fn stars() {
+        assert!(glob_match("web-*", "web-1"));
+        assert!(glob_match("web-*", "web-"));
+        assert!(glob_match("*-worker", "mail-worker"));
+        assert!(glob_match("w*b*1", "web-db-1"));
+        // the `*` matches more once the rest of the pattern failed to match.
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("*-db", "web-db-db"));
+        assert!(!glob_match("web-*-1", "web-2"));
+        assert!(glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn question_marks() {
+        assert!(glob_match("web-?", "web-1"));
+        assert!(!glob_match("web-?", "web-"));
+        assert!(!glob_match("web-?", "web-12"));
+        assert!(glob_match("?*", "a"));
+    }
+
+    #[test]
+    fn empty_patterns() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "web"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn trailing_stars() {
+        assert!(glob_match("web**", "web"));
+        assert!(glob_match("web*", "web"));
+        assert!(glob_match("web*", "web-1-2"));
+        assert!(!glob_match("web*?", "web"));
+    }
 }
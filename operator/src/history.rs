@@ -0,0 +1,110 @@
+//! Run history of the services.
+//!
+//! Every start and exit of a service is appended as a JSON line to its file in the
+//! `history` dir of the state dir, so the previous runs of a flaky service can be
+//! reviewed, even across restarts of operator.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helper::{create_state_dir, op_state_dir},
+    Result,
+};
+
+/// What happened to a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunEvent {
+    /// The service was started
+    Start,
+    /// The service exited successfully or was stopped
+    Stop,
+    /// The service failed to start or exited with a failure
+    Crash,
+    /// The service was stopped to be restarted on its `restart_every` schedule
+    Restart,
+}
+
+/// An entry of the history of a service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub event: RunEvent,
+    /// When it happened, in seconds since the epoch
+    pub timestamp: u64,
+    /// The process of the service
+    pub pid: Option<i32>,
+    /// Exit code of the process, if it exited
+    pub exit_code: Option<u8>,
+    /// Why the service crashed
+    pub reason: Option<String>,
+    /// How long the service ran, for an exit
+    pub duration: Option<Duration>,
+}
+
+impl HistoryEntry {
+    /// Entry of an event happening now.
+    pub fn new(event: RunEvent) -> Self {
+        Self {
+            event,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            pid: None,
+            exit_code: None,
+            reason: None,
+            duration: None,
+        }
+    }
+}
+
+/// Path of the history of a service.
+fn path(name: &str) -> PathBuf {
+    PathBuf::from(format!("{}/history/{name}.jsonl", op_state_dir()))
+}
+
+/// Append an entry to the history of a service.
+pub fn record(name: &str, entry: &HistoryEntry) -> Result<()> {
+    let path = path(name);
+    create_state_dir()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut line = serde_json::to_string(entry).map_err(io::Error::other)?;
+    line.push('\n');
+    // a single write, so concurrent entries are not interleaved.
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// The last `limit` entries of the history of a service, the oldest first.
+pub fn read(name: &str, limit: usize) -> Result<Vec<HistoryEntry>> {
+    // the name comes from a client, it must not lead out of the history dir.
+    if name.contains('/') {
+        return Ok(vec![]);
+    }
+    let contents = match std::fs::read_to_string(path(name)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    // a line cut short by a crash of operator is skipped.
+    let entries = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect::<Vec<_>>();
+    Ok(entries[entries.len().saturating_sub(limit)..].to_vec())
+}
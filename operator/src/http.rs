@@ -0,0 +1,308 @@
+//! HTTP control API of operator.
+//!
+//! Served from threads of their own when `[http]` is set in `operator.toml`, on a
+//! loopback address or a unix socket. The requests are forwarded to the engine with a
+//! [Client], like the ones of `operatorctl`, and must carry the token of the config in
+//! `Authorization: Bearer <token>`.
+//!
+//! - `GET /services`: the loaded services
+//! - `GET /services/{name}`: status of a service
+//! - `POST /services/{name}/start|stop|restart`: `restart` is the one of the engine, like
+//!   `operatorctl restart`, following the `restart_strategy` of the service
+//! - `GET /services/{name}/logs?lines=N`: the last lines of the log of a service
+//! - `GET /events`: the state changes of the services as server-sent events
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    os::unix::net::UnixListener,
+    path::PathBuf,
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::Client,
+    helper::{constant_time_eq, op_service_log_dir},
+    socket::Listen,
+    Error,
+};
+
+/// Maximum size of the head of a request.
+const MAX_HEAD: usize = 8192;
+/// Default number of lines of `/services/{name}/logs`.
+const LOG_LINES: usize = 100;
+
+/// Config of the HTTP API
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpApi {
+    /// Address the API listens on, `"tcp:127.0.0.1:8081"` or `"unix:/run/operator-http.sock"`
+    pub listen: Listen,
+    /// File containing the token the requests must carry
+    pub token_file: PathBuf,
+}
+
+/// A request to the API.
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    token: Option<String>,
+}
+
+/// A response of the API.
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: &'static str, body: impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string(&body).unwrap_or_default(),
+        }
+    }
+
+    fn error(status: &'static str, error: impl std::fmt::Display) -> Self {
+        Self::json(status, serde_json::json!({ "error": error.to_string() }))
+    }
+}
+
+/// Start serving the API from a thread.
+pub fn serve(api: HttpApi) -> io::Result<()> {
+    let token = std::fs::read_to_string(&api.token_file)?.trim().to_string();
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the token file is empty",
+        ));
+    }
+
+    match api.listen {
+        Listen::Tcp(addr) => {
+            // the API is only served to the local host, a remote one would need TLS.
+            if !addr.ip().is_loopback() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{addr} is not a loopback address"),
+                ));
+            }
+            let listener = TcpListener::bind(addr)?;
+            spawn("http", move || accept(listener.incoming(), token))?;
+        }
+        Listen::Unix(ref path) => {
+            // the socket file left by a previous operator would make bind() fail.
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            let listener = UnixListener::bind(path)?;
+            spawn("http", move || accept(listener.incoming(), token))?;
+        }
+    }
+
+    info!("Serving the HTTP API on {}", api.listen);
+    Ok(())
+}
+
+/// Answer the connections from a thread each.
+fn accept<S>(incoming: impl Iterator<Item = io::Result<S>>, token: String)
+where
+    S: Read + Write + Send + 'static,
+{
+    for stream in incoming.flatten() {
+        let token = token.clone();
+        if let Err(e) = spawn("http-client", move || handle(stream, &token)) {
+            warn!("Failed to start the thread of an HTTP client: {e}");
+        }
+    }
+}
+
+/// Spawn a named thread, the threads of the clients are detached.
+fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .map(drop)
+}
+
+/// Answer the request of a connection.
+fn handle(mut stream: impl Read + Write, token: &str) {
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Invalid HTTP request: {e}");
+            _ = write_response(&mut stream, Response::error("400 Bad Request", e));
+            return;
+        }
+    };
+
+    let authorized = request
+        .token
+        .as_deref()
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+    if !authorized {
+        _ = write_response(
+            &mut stream,
+            Response::error("401 Unauthorized", "invalid token"),
+        );
+        return;
+    }
+
+    if request.method == "GET" && request.path == "/events" {
+        if let Err(e) = events(&mut stream) {
+            info!("HTTP event stream closed: {e}");
+        }
+        return;
+    }
+
+    let response = route(&request);
+    if let Err(e) = write_response(&mut stream, response) {
+        warn!("Failed to write an HTTP response: {e}");
+    }
+}
+
+/// Handle a request other than `/events`.
+fn route(request: &Request) -> Response {
+    let client = Client::new();
+    let segments = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+
+    let result = match (request.method.as_str(), &segments[..]) {
+        ("GET", ["services"]) => client
+            .list()
+            .map(|services| Response::json("200 OK", services)),
+        ("GET", ["services", name]) => client
+            .status(name)
+            .map(|info| Response::json("200 OK", info)),
+        ("POST", ["services", name, action @ ("start" | "stop" | "restart")]) => {
+            // the service has to be loaded, the engine ignores the unknown ones.
+            client.status(name).and_then(|_| {
+                match *action {
+                    "start" => client.start(name)?,
+                    "stop" => client.stop(name)?,
+                    _ => client.restart(name)?,
+                }
+                Ok(Response::json("202 Accepted", serde_json::json!({})))
+            })
+        }
+        ("GET", ["services", name, "logs"]) => client.status(name).and_then(|_| {
+            let lines = query(&request.query, "lines")
+                .and_then(|lines| lines.parse().ok())
+                .unwrap_or(LOG_LINES);
+            logs(name, lines).map(|body| Response {
+                status: "200 OK",
+                content_type: "text/plain; charset=utf-8",
+                body,
+            })
+        }),
+        (_, ["services", ..]) | (_, ["events"]) => {
+            return Response::error("405 Method Not Allowed", "method not allowed")
+        }
+        _ => return Response::error("404 Not Found", "not found"),
+    };
+
+    result.unwrap_or_else(|e| match e {
+        Error::NotFound(_) => Response::error("404 Not Found", e),
+        e => Response::error("500 Internal Server Error", e),
+    })
+}
+
+/// The last `lines` lines of the log of a service.
+fn logs(name: &str, lines: usize) -> crate::Result<String> {
+    let log = PathBuf::from(format!("{}/{name}.log", op_service_log_dir()));
+    let contents = match std::fs::read(log) {
+        Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+        // the service never ran.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let all = contents.lines().collect::<Vec<_>>();
+    let mut tail = all[all.len().saturating_sub(lines)..].join("\n");
+    if !tail.is_empty() {
+        tail.push('\n');
+    }
+    Ok(tail)
+}
+
+/// Stream the state changes of the services until the client disconnects.
+fn events(stream: &mut impl Write) -> crate::Result<()> {
+    let subscription = Client::new().subscribe()?;
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+    )?;
+    stream.flush()?;
+
+    for change in subscription {
+        let change = change?;
+        let data = serde_json::to_string(&change).unwrap_or_default();
+        stream.write_all(format!("event: state\ndata: {data}\n\n").as_bytes())?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+/// Read the request line and the headers of a request.
+fn read_request(stream: &mut impl Read) -> io::Result<Request> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut reader = BufReader::new(stream.take(MAX_HEAD as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(invalid("invalid request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        token: None,
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("the headers are incomplete or too large"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(request);
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                request.token = value.trim().strip_prefix("Bearer ").map(str::to_string);
+            }
+        }
+    }
+}
+
+/// Write a response and close the connection.
+fn write_response(stream: &mut impl Write, response: Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/// Value of a parameter of a query string.
+fn query<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find_map(|(k, value)| (k == key).then_some(value))
+}
@@ -3,16 +3,306 @@
 //! It contains helpers for creating a IPC server and clients.
 
 use std::{
+    collections::HashMap,
+    fs::{File, Metadata, OpenOptions, Permissions},
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    net::Shutdown,
     os::{
-        fd::{AsFd, BorrowedFd},
-        unix::net::{SocketAddr, UnixListener, UnixStream},
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+        unix::net::{UnixListener, UnixStream},
     },
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
+use bincode::Options;
+use log::{error, info, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    libc,
+    sys::{
+        signal::{kill, Signal},
+        socket::{
+            getsockopt, recv, recvmsg, sendmsg, sockopt, ControlMessage, ControlMessageOwned,
+            MsgFlags,
+        },
+    },
+    unistd::{geteuid, Pid},
+};
 use serde::{Deserialize, Serialize};
 
-use crate::service;
+use crate::{
+    audit::{self, Peer},
+    helper::{create_private_dir, op_socket_path},
+    history, journal,
+    power::PowerAction,
+    service, webhook, Error, Result,
+};
+
+/// Path of the unix socket the system operator listens on by default, in its runtime dir
+/// only root can write to.
+pub const SOCKET_PATH: &str = "/run/operator/operator.sock";
+
+/// Maximum size of an encoded message, larger ones are rejected before being read.
+pub const MAX_MESSAGE_SIZE: u64 = 4 << 20;
+
+/// How long operator waits for the request of a client that connected.
+pub(crate) const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a request is refused to a client of another host, e.g. passing fds.
+pub(crate) const LOCAL_ONLY: &str = "only the clients of the IPC socket can do this";
+
+/// Encoding of the messages, the one of `bincode::serialize` with a size limit.
+fn encoding() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_SIZE)
+}
+
+/// Read a message from a stream, e.g. a TLS connection.
+pub(crate) fn read_message(reader: impl io::Read) -> Result<IPCMessage> {
+    Wire::Bincode.read(reader)
+}
+
+/// Write a message to a stream at once, so it's not split in many small writes.
+pub(crate) fn write_message(mut writer: impl io::Write, msg: &IPCMessage) -> Result<()> {
+    Wire::Bincode.write(&mut writer, msg)
+}
+
+/// Encoding of the messages of a connection to the IPC socket.
+///
+/// A client is answered in the encoding of its requests: a first byte of `{` can't start
+/// a bincode message, so it's one of JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wire {
+    /// bincode, the one of operatorctl
+    Bincode,
+    /// a JSON object per line, for the clients that are not written in Rust
+    Json,
+}
+
+impl Wire {
+    /// Encoding of a connection whose client first sent `byte`.
+    fn of(byte: u8) -> Self {
+        match byte {
+            b'{' => Wire::Json,
+            _ => Wire::Bincode,
+        }
+    }
+
+    /// Read a message, the whitespace before a JSON one is skipped.
+    fn read(self, reader: impl io::Read) -> Result<IPCMessage> {
+        match self {
+            Wire::Bincode => Ok(encoding().deserialize_from(reader)?),
+            Wire::Json => {
+                match serde_json::Deserializer::from_reader(reader.take(MAX_MESSAGE_SIZE))
+                    .into_iter()
+                    .next()
+                {
+                    Some(msg) => Ok(msg?),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                }
+            }
+        }
+    }
+
+    /// Decode the message at the start of `buf`, `None` if it's not whole yet, with the
+    /// number of bytes it took.
+    #[cfg(feature = "tokio")]
+    fn decode(self, buf: &[u8]) -> Result<Option<(IPCMessage, usize)>> {
+        match self {
+            Wire::Bincode => {
+                let mut rest = buf;
+                match encoding().deserialize_from(&mut rest).map_err(Error::from) {
+                    Ok(msg) => Ok(Some((msg, buf.len() - rest.len()))),
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            Wire::Json => {
+                let mut messages = serde_json::Deserializer::from_slice(buf).into_iter();
+                match messages.next() {
+                    Some(Ok(msg)) => Ok(Some((msg, messages.byte_offset()))),
+                    Some(Err(e)) if !e.is_eof() => Err(e.into()),
+                    _ if buf.len() as u64 > MAX_MESSAGE_SIZE => {
+                        Err(Error::Protocol("the message is too large".to_string()))
+                    }
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Encode a message.
+    fn encode(self, msg: &IPCMessage) -> Result<Vec<u8>> {
+        match self {
+            Wire::Bincode => Ok(encoding().serialize(msg)?),
+            Wire::Json => {
+                let mut buf = serde_json::to_vec(msg)?;
+                buf.push(b'\n');
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Write a message at once, so it's not split in many small writes.
+    fn write(self, mut writer: impl io::Write, msg: &IPCMessage) -> Result<()> {
+        writer.write_all(&self.encode(msg)?)?;
+        Ok(writer.flush()?)
+    }
+}
+
+/// Version of the protocol, bumped when the messages change in a way the peers of the
+/// previous version can't decode, e.g. a variant of [IPCMessage] inserted before others.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Bytes an encoded [Hello] starts with, a message never does.
+const HELLO_MAGIC: [u8; 4] = *b"OPHI";
+
+/// Maximum size of the encoded fields of a [Hello].
+const MAX_HELLO_SIZE: u32 = 64 << 10;
+
+/// Greeting a bincode client sends before its first message, and operator answers with
+/// its own before the response, so peers speaking different versions of the protocol
+/// tell it instead of failing to decode the messages.
+///
+/// Its fields are preceded by [HELLO_MAGIC] and their size, so the fields added later
+/// are skipped by the older peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    /// [PROTOCOL_VERSION] of the peer
+    pub version: u32,
+    /// Optional capabilities of the peer, e.g. `remote` when it's built with it
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    /// Greeting of this build.
+    pub fn current() -> Self {
+        let mut features = vec!["json", "query-socket"];
+        if cfg!(feature = "http") {
+            features.push("http");
+        }
+        if cfg!(feature = "remote") {
+            features.push("remote");
+        }
+        Self {
+            version: PROTOCOL_VERSION,
+            features: features.into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let fields = encoding().serialize(self)?;
+        let mut buf = HELLO_MAGIC.to_vec();
+        buf.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&fields);
+        Ok(buf)
+    }
+
+    /// Size of the fields of a greeting from the header following [HELLO_MAGIC].
+    fn size(header: [u8; 4]) -> Result<usize> {
+        match u32::from_le_bytes(header) {
+            size if size > MAX_HELLO_SIZE => {
+                Err(Error::Protocol("the greeting is too large".to_string()))
+            }
+            size => Ok(size as usize),
+        }
+    }
+
+    /// Check the greeting of operator, whose protocol must be the one of the client.
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.version {
+            PROTOCOL_VERSION => Ok(()),
+            version => Err(Error::Incompatible(Some(version))),
+        }
+    }
+}
+
+/// First frame of a bincode peer, a message if the peer is older than the greetings, or
+/// refused the connection before greeting, e.g. with [IPCMessage::Busy].
+pub(crate) enum Frame {
+    Hello(Hello),
+    Message(Box<IPCMessage>),
+}
+
+/// Read the first frame of a peer.
+pub(crate) fn read_frame(mut reader: impl io::Read) -> Result<Frame> {
+    let mut head = [0; 4];
+    reader.read_exact(&mut head)?;
+    if head != HELLO_MAGIC {
+        return Ok(Frame::Message(Box::new(read_message(
+            (&head[..]).chain(reader),
+        )?)));
+    }
+
+    reader.read_exact(&mut head)?;
+    let mut fields = vec![0; Hello::size(head)?];
+    reader.read_exact(&mut fields)?;
+    Ok(Frame::Hello(encoding().deserialize(&fields)?))
+}
+
+/// Decode the first frame of a peer at the start of `buf`, `None` if it's not whole yet,
+/// with the number of bytes it took.
+#[cfg(feature = "tokio")]
+fn decode_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>> {
+    if buf.len() < 4 || buf[..4] != HELLO_MAGIC {
+        let msg = Wire::Bincode.decode(buf)?;
+        return Ok(msg.map(|(msg, len)| (Frame::Message(Box::new(msg)), len)));
+    }
+    let Some(header) = buf.get(4..8) else {
+        return Ok(None);
+    };
+    let size = Hello::size(header.try_into().expect("4 bytes"))?;
+    match buf.get(8..8 + size) {
+        Some(fields) => Ok(Some((
+            Frame::Hello(encoding().deserialize(fields)?),
+            8 + size,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Write the greeting of this build.
+pub(crate) fn greet(mut writer: impl io::Write) -> Result<()> {
+    writer.write_all(&Hello::current().encode()?)?;
+    Ok(writer.flush()?)
+}
+
+/// Answer the greeting of a client with the one of operator, an error if the client
+/// speaks another version of the protocol, then its messages can't be read.
+pub(crate) fn answer(writer: impl io::Write, hello: &Hello) -> Result<()> {
+    greet(writer)?;
+    match hello.version {
+        PROTOCOL_VERSION => Ok(()),
+        version => Err(Error::Protocol(format!(
+            "the client speaks version {version} of the protocol, operator {PROTOCOL_VERSION}"
+        ))),
+    }
+}
+
+/// Read the greeting of operator, or the message it refused the client with.
+pub(crate) fn read_greeting(reader: impl io::Read) -> Result<Frame> {
+    match read_frame(reader) {
+        // an operator older than the greetings drops the client that sends one.
+        Err(Error::Io(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            Err(Error::Incompatible(None))
+        }
+        frame => frame,
+    }
+}
 
 /// Message format used to communicate b/w operator and operatorctl.
 #[derive(Debug, Serialize, Deserialize)]
@@ -21,64 +311,1031 @@ pub enum IPCMessage {
     Start { name: String },
     /// Stop a service.
     Stop { name: String },
+    /// Stop a service and start it again once it exited.
+    Restart { name: String },
+    /// Start, stop or restart the services matched by the filter.
+    Bulk {
+        action: BulkAction,
+        filter: service::Filter,
+    },
     /// Status of a service.
     Status { name: String },
+    /// Reload the configuration of a running service.
+    ReloadService { name: String },
+    /// Start a service at boot.
+    Enable { name: String },
+    /// Do not start a service at boot.
+    Disable { name: String },
+    /// Prevent a service from being started.
+    Mask { name: String },
+    /// Allow a masked service to be started again.
+    Unmask { name: String },
+    /// Set the number of replicas of a replicated service.
+    Scale { name: String, replicas: u32 },
+    /// Re-execute operator without restarting the services.
+    ReExec,
+    /// List the loaded services matched by the filter.
+    List { filter: service::Filter },
+    /// List the service files that failed to load.
+    LoadErrors,
+    /// Show the configuration and runtime state of a service.
+    Show { name: String },
+    /// Show how long the services took to start at boot.
+    Analyze,
+    /// Dump the dependency graph of the services.
+    Graph,
+    /// Receive the state changes of the services on this connection.
+    Subscribe,
+    /// The recent state changes of the services matched by the query.
+    Events { query: journal::EventQuery },
+    /// The last `limit` entries of the audit log.
+    Audit { limit: usize },
+    /// The last `limit` starts and exits of a service.
+    History { name: String, limit: usize },
+    /// The last `lines` lines of output of a service, kept in memory.
+    Output { name: String, lines: usize },
+    /// Read the service file of a service and its drop-ins again.
+    ReloadFile { name: String },
+    /// The service file and drop-ins a service was loaded from.
+    Sources { name: String },
+    /// Override properties of a service, and persist them to a drop-in if `persist`.
+    SetProperty {
+        name: String,
+        properties: Vec<(String, String)>,
+        persist: bool,
+    },
+    /// Run a command in the context of a service, followed by its stdin, stdout and stderr
+    /// sent with [IPCStream::send_fds].
+    Exec { name: String, command: Vec<String> },
+    /// Receive the output of a service on this connection as it's written, and write the
+    /// [IPCMessage::Input] sent on it to its stdin if `stdin`.
+    Attach { name: String, stdin: bool },
+    /// Bytes written to the stdin of the service attached to.
+    Input(Vec<u8>),
+    /// Wait until a service is in a state, e.g. `running`, has failed, or `timeout`
+    /// elapsed.
+    WaitFor {
+        name: String,
+        state: String,
+        timeout: Duration,
+    },
+    /// Several requests handled in order on this connection, except the ones that keep
+    /// the connection like [IPCMessage::Subscribe], [IPCMessage::Exec],
+    /// [IPCMessage::Attach] and [IPCMessage::WaitFor].
+    Batch(Vec<IPCMessage>),
+    /// Token of a remote client without a certificate, sent before its request.
+    Authenticate { token: String },
 
+    /// Response for the [IPCMessage::Bulk] command, the names of the services matched.
+    BulkResponse(Vec<String>),
     /// Response for the [IPCMessage::Status] command.
-    StatusResponse(Option<(i32, service::Status)>),
+    StatusResponse(Option<service::ServiceInfo>),
+    /// Response for the [IPCMessage::List] command.
+    ListResponse(Vec<service::ServiceInfo>),
+    /// Response for the [IPCMessage::LoadErrors] command.
+    LoadErrorsResponse(Vec<service::LoadError>),
+    /// Response for the [IPCMessage::Show] command.
+    ShowResponse(Option<Vec<(String, String)>>),
+    /// Response for the [IPCMessage::Analyze] command.
+    AnalyzeResponse(Vec<service::BootTiming>),
+    /// Response for the [IPCMessage::Graph] command.
+    GraphResponse {
+        services: Vec<String>,
+        edges: Vec<service::Edge>,
+    },
+    /// Response for the [IPCMessage::Events] command, the oldest first.
+    EventsResponse(Vec<webhook::StateChange>),
+    /// Response for the [IPCMessage::Audit] command.
+    AuditResponse(Vec<audit::AuditEntry>),
+    /// Response for the [IPCMessage::History] command.
+    HistoryResponse(Vec<history::HistoryEntry>),
+    /// Response for the [IPCMessage::Output] command.
+    OutputResponse(Option<Vec<String>>),
+    /// Response for the [IPCMessage::ReloadFile] command, why it failed if it did.
+    ReloadFileResponse(Option<Result<(), String>>),
+    /// Response for the [IPCMessage::Sources] command.
+    SourcesResponse(Option<Vec<PathBuf>>),
+    /// Response for the [IPCMessage::SetProperty] command, why it was rejected if it was.
+    SetPropertyResponse(Option<Result<(), String>>),
+    /// Response for the [IPCMessage::Exec] command, the pid of the command.
+    ExecResponse(Option<Result<i32, String>>),
+    /// Exit code of the command of [IPCMessage::Exec], 128 + the signal that killed it.
+    ExecExited(i32),
+    /// Response for the [IPCMessage::Attach] command, whether the service runs in a
+    /// terminal.
+    AttachResponse(Option<Result<bool, String>>),
+    /// Output of the service attached to.
+    OutputData(Vec<u8>),
+    /// State change sent to the connections of [IPCMessage::Subscribe].
+    Event(webhook::StateChange),
+    /// Sent instead of a response to a client over the [Limits], before its connection
+    /// is closed.
+    Busy,
+    /// Response for the [IPCMessage::WaitFor] command, the status of the service once
+    /// the wait ended.
+    WaitForResponse(Option<service::ServiceInfo>),
+    /// Response for the [IPCMessage::Batch] command, the response of each request in
+    /// order, `None` for the ones that have none.
+    BatchResponse(Vec<Option<IPCMessage>>),
+    /// Sent instead of a response to a remote client whose token was refused.
+    Unauthorized,
+    /// Sent instead of a response to a request of the query socket that is not a query.
+    ReadOnly,
+    /// Stop the services, then power off or reboot the machine operator is the init of.
+    Power(PowerAction),
+    /// Response for the [IPCMessage::Power] command, why it was rejected if it was.
+    PowerResponse(Result<(), String>),
+    /// Response for the commands that act on a service, e.g. [IPCMessage::Enable], once
+    /// it was done.
+    Ok,
+    /// Response for the commands that act on a service, why it couldn't be done.
+    Error(String),
+}
+
+impl From<std::result::Result<(), String>> for IPCMessage {
+    fn from(result: std::result::Result<(), String>) -> Self {
+        match result {
+            Ok(()) => IPCMessage::Ok,
+            Err(reason) => IPCMessage::Error(reason),
+        }
+    }
+}
+
+impl IPCMessage {
+    /// Whether the request only reads the state of the services, the ones answered on
+    /// the query socket.
+    pub fn is_query(&self) -> bool {
+        match self {
+            IPCMessage::Status { .. }
+            | IPCMessage::List { .. }
+            | IPCMessage::LoadErrors
+            | IPCMessage::Analyze
+            | IPCMessage::Graph
+            | IPCMessage::Subscribe
+            | IPCMessage::Events { .. }
+            | IPCMessage::History { .. }
+            | IPCMessage::Output { .. }
+            | IPCMessage::Attach { stdin: false, .. }
+            | IPCMessage::WaitFor { .. } => true,
+            IPCMessage::Batch(requests) => requests.iter().all(IPCMessage::is_query),
+            _ => false,
+        }
+    }
+}
+
+/// What is done to the services matched by [IPCMessage::Bulk].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum BulkAction {
+    Start,
+    Stop,
+    Restart,
+}
+
+/// Configuration of the IPC sockets, `[ipc]` in `operator.toml`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IpcConfig {
+    /// Socket accessible by all the users that only answers the queries, e.g. for
+    /// monitoring agents, not bound if unset
+    pub query_socket: Option<PathBuf>,
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+
+/// Limits of the clients of an IPC server
+///
+/// A client over them gets [IPCMessage::Busy], so a misbehaving script can't keep
+/// operator from supervising the services or the other users from reaching it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Limits {
+    /// Connections open at once, e.g. of `operatorctl attach`
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// Connections open at once by the clients of a user
+    #[serde(default = "default_client_connections")]
+    pub client_connections: usize,
+    /// Requests accepted per second from all the clients
+    #[serde(default = "default_requests_per_sec")]
+    pub requests_per_sec: u32,
+    /// Requests accepted per second from the clients of a user
+    #[serde(default = "default_client_requests_per_sec")]
+    pub client_requests_per_sec: u32,
+}
+
+fn default_max_connections() -> usize {
+    256
+}
+
+fn default_client_connections() -> usize {
+    64
+}
+
+fn default_requests_per_sec() -> u32 {
+    1000
+}
+
+fn default_client_requests_per_sec() -> u32 {
+    200
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_connections(),
+            client_connections: default_client_connections(),
+            requests_per_sec: default_requests_per_sec(),
+            client_requests_per_sec: default_client_requests_per_sec(),
+        }
+    }
+}
+
+/// Requests that can be accepted, refilled at a rate of requests per second up to the
+/// rate itself.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.refilled = now;
+    }
+}
+
+/// Enforces the [Limits] on the connections accepted by a server.
+#[derive(Debug)]
+struct Limiter {
+    limits: Limits,
+    /// connections open, decremented as they're closed.
+    open: Arc<AtomicUsize>,
+    /// connections open by the clients of each user.
+    users: Mutex<HashMap<u32, Arc<AtomicUsize>>>,
+    /// requests of all the clients, and of the clients of each user.
+    buckets: Mutex<(Bucket, HashMap<u32, Bucket>)>,
+}
+
+impl Limiter {
+    fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            open: Arc::default(),
+            users: Mutex::default(),
+            buckets: Mutex::new((Bucket::new(limits.requests_per_sec), HashMap::new())),
+        }
+    }
+
+    /// Admit a connection of `peer`, `None` if it's over the limits.
+    fn admit(&self, peer: Option<Peer>) -> Option<Arc<Connection>> {
+        if self.open.load(Ordering::Relaxed) >= self.limits.max_connections {
+            return None;
+        }
+        let user_open = peer.map(|peer| {
+            let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+            users.entry(peer.uid).or_default().clone()
+        });
+        if user_open
+            .as_ref()
+            .is_some_and(|open| open.load(Ordering::Relaxed) >= self.limits.client_connections)
+        {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let (all, users) = &mut *buckets;
+        all.refill(self.limits.requests_per_sec, now);
+        // a client whose credentials can't be read is only limited with the others.
+        let rate = self.limits.client_requests_per_sec;
+        let mut user = peer.map(|peer| users.entry(peer.uid).or_insert_with(|| Bucket::new(rate)));
+        if let Some(ref mut user) = user {
+            user.refill(rate, now);
+        }
+        if all.tokens < 1.0 || user.as_ref().is_some_and(|user| user.tokens < 1.0) {
+            return None;
+        }
+        all.tokens -= 1.0;
+        if let Some(user) = user {
+            user.tokens -= 1.0;
+        }
+
+        self.open.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref open) = user_open {
+            open.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(Arc::new(Connection(self.open.clone(), user_open)))
+    }
+}
+
+/// A connection counted as open, in all and for its user, until all the handles to it
+/// are dropped.
+#[derive(Debug)]
+struct Connection(Arc<AtomicUsize>, Option<Arc<AtomicUsize>>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        if let Some(ref user) = self.1 {
+            user.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
 }
 
 /// An Unix socket stream.
-pub struct IPCStream(UnixStream, SocketAddr);
+pub struct IPCStream {
+    stream: UnixStream,
+    /// slot of the connection in the limits of the server
+    connection: Option<Arc<Connection>>,
+    /// encoding of the messages, shared with the clones of the stream
+    wire: Arc<OnceLock<Wire>>,
+    /// whether this end is the client, that greets operator along with its first message
+    client: bool,
+    greeting: Arc<Greeting>,
+}
+
+/// Progress of the greetings of a connection, see [Hello].
+#[derive(Debug, Default)]
+struct Greeting {
+    /// whether the client sent its greeting
+    sent: AtomicBool,
+    /// whether the first frame of the peer, that may be its greeting, was read
+    read: AtomicBool,
+}
 
 impl IPCStream {
     /// Connect to a unix socket.
-    pub fn connect(path: &str) -> anyhow::Result<Self> {
+    pub fn connect(path: &str) -> Result<Self> {
         let stream = UnixStream::connect(path)?;
-        let addr = stream.peer_addr()?;
 
-        Ok(Self(stream, addr))
+        Ok(Self {
+            stream,
+            connection: None,
+            wire: Arc::new(OnceLock::from(Wire::Bincode)),
+            client: true,
+            greeting: Arc::default(),
+        })
+    }
+
+    /// Stream of a client accepted by a server, its encoding is found from its request.
+    fn accepted(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            connection: None,
+            wire: Arc::new(OnceLock::new()),
+            client: false,
+            greeting: Arc::default(),
+        }
     }
 
     /// Read a message from the unix socket.
-    pub fn read(&self) -> anyhow::Result<IPCMessage> {
-        bincode::deserialize_from(&self.0).map_err(|err| anyhow::Error::msg(format!("{err}")))
+    pub fn read(&self) -> Result<IPCMessage> {
+        let wire = self.wire(true)?;
+        // JSON is self-describing, its clients don't greet.
+        if wire == Wire::Bincode && !self.greeting.read.swap(true, Ordering::Relaxed) {
+            return self.read_first();
+        }
+
+        let msg = wire.read(&self.stream)?;
+        if wire == Wire::Json {
+            self.skip_whitespace();
+        }
+        Ok(msg)
+    }
+
+    /// Read the first message of the peer, after its greeting if it sent one.
+    fn read_first(&self) -> Result<IPCMessage> {
+        if self.client {
+            return match read_greeting(&self.stream)? {
+                Frame::Hello(hello) => {
+                    hello.check()?;
+                    read_message(&self.stream)
+                }
+                Frame::Message(msg) => Ok(*msg),
+            };
+        }
+        match read_frame(&self.stream)? {
+            Frame::Hello(hello) => {
+                answer(&self.stream, &hello)?;
+                read_message(&self.stream)
+            }
+            Frame::Message(msg) => Ok(*msg),
+        }
+    }
+
+    /// Consume the whitespace already sent after a JSON message, e.g. its newline, as
+    /// closing the connection with unread bytes resets it.
+    fn skip_whitespace(&self) {
+        let mut byte = [0];
+        let flags = MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT;
+        while let Ok(1) = recv(self.stream.as_raw_fd(), &mut byte, flags) {
+            if !byte[0].is_ascii_whitespace() {
+                break;
+            }
+            _ = recv(self.stream.as_raw_fd(), &mut byte, MsgFlags::MSG_DONTWAIT);
+        }
     }
 
     /// Write a message to the unix socket.
-    pub fn write(&self, msg: &IPCMessage) -> anyhow::Result<()> {
-        bincode::serialize_into(&self.0, msg)
-            .map_err(|err| anyhow::Error::msg(format!("{err}")))?;
+    pub fn write(&self, msg: &IPCMessage) -> Result<()> {
+        // a client answered before its request was read is answered in bincode.
+        let wire = self.wire(false).unwrap_or(Wire::Bincode);
+        let mut buf = vec![];
+        if self.client && !self.greeting.sent.swap(true, Ordering::Relaxed) {
+            buf = Hello::current().encode()?;
+        }
+        buf.extend_from_slice(&wire.encode(msg)?);
+        (&self.stream).write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Encoding of the connection, found by peeking at the first byte the client sent.
+    ///
+    /// Without `wait`, bincode is assumed if the client didn't send anything yet.
+    fn wire(&self, wait: bool) -> Result<Wire> {
+        if let Some(wire) = self.wire.get() {
+            return Ok(*wire);
+        }
+
+        let mut byte = [0];
+        let flags = match wait {
+            true => MsgFlags::MSG_PEEK,
+            false => MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT,
+        };
+        match recv(self.stream.as_raw_fd(), &mut byte, flags) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => Ok(*self.wire.get_or_init(|| Wire::of(byte[0]))),
+            Err(Errno::EAGAIN) if !wait => Ok(Wire::Bincode),
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+
+    /// Credentials of the peer, `None` if they can't be read.
+    pub fn peer(&self) -> Option<Peer> {
+        let cred = getsockopt(&self.stream, sockopt::PeerCredentials).ok()?;
+        Some(Peer {
+            uid: cred.uid(),
+            pid: cred.pid(),
+        })
+    }
+
+    /// Another handle to the same connection.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+            connection: self.connection.clone(),
+            wire: self.wire.clone(),
+            client: self.client,
+            greeting: self.greeting.clone(),
+        })
+    }
+
+    /// Set how long reading waits for a message, forever if `None`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.stream.set_read_timeout(timeout)?)
+    }
+
+    /// Whether the peer closed the connection, without reading what it sent.
+    pub fn is_closed(&self) -> bool {
+        let mut byte = [0];
+        match recv(
+            self.stream.as_raw_fd(),
+            &mut byte,
+            MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT,
+        ) {
+            Ok(n) => n == 0,
+            Err(e) => e != Errno::EAGAIN,
+        }
+    }
+
+    /// Send file descriptors to the peer, they are duplicated in its process.
+    pub fn send_fds(&self, fds: &[RawFd]) -> Result<()> {
+        // the fds are sent along with a byte.
+        sendmsg::<()>(
+            self.stream.as_raw_fd(),
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .map_err(io::Error::from)?;
         Ok(())
     }
+
+    /// Receive the file descriptors sent by [IPCStream::send_fds], 3 at most.
+    pub fn recv_fds(&self) -> Result<Vec<OwnedFd>> {
+        let mut byte = [0];
+        let mut iov = [IoSliceMut::new(&mut byte)];
+        let mut space = nix::cmsg_space!([RawFd; 3]);
+        let msg = recvmsg::<()>(
+            self.stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut space),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(io::Error::from)?;
+
+        let mut fds = vec![];
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                fds.extend(
+                    received
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+        Ok(fds)
+    }
+}
+
+/// Forward a client speaking on stdin and stdout to the socket at `path`, like
+/// `operatorctl --stdio` does for the clients of `operatorctl --ssh`.
+///
+/// Returns once operator closed the connection.
+pub fn proxy_stdio(path: impl AsRef<Path>) -> Result<()> {
+    let stream = UnixStream::connect(path)?;
+    let mut requests = stream.try_clone()?;
+    std::thread::spawn(move || {
+        _ = io::copy(&mut io::stdin().lock(), &mut requests);
+        _ = requests.shutdown(Shutdown::Write);
+    });
+
+    // stdout is line buffered, each response is flushed as soon as it's read.
+    let mut stdout = io::stdout().lock();
+    let mut buf = [0; 8192];
+    loop {
+        match (&stream).read(&mut buf)? {
+            0 => return Ok(()),
+            n => {
+                stdout.write_all(&buf[..n])?;
+                stdout.flush()?;
+            }
+        }
+    }
+}
+
+/// Let all the users connect to the socket at `path`, e.g. to the query socket.
+pub(crate) fn open_to_all(path: &Path) -> Result<()> {
+    Ok(std::fs::set_permissions(
+        path,
+        Permissions::from_mode(0o666),
+    )?)
+}
+
+/// Path of the lock held by the operator serving the socket at `path`, `<path>.lock`.
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.as_os_str().to_os_string();
+    lock.push(".lock");
+    PathBuf::from(lock)
+}
+
+/// Pid of the operator serving the socket at `path` if one is, found by connecting to it.
+///
+/// The pid of a process of another user isn't trusted, it's not the one to stop.
+fn serving(path: &Path) -> Option<Option<i32>> {
+    let stream = UnixStream::connect(path).ok()?;
+    Some(
+        getsockopt(&stream, sockopt::PeerCredentials)
+            .ok()
+            .filter(|cred| cred.uid() == geteuid().as_raw())
+            .map(|cred| cred.pid()),
+    )
+}
+
+/// Fail unless `path` is owned by the user of operator, a lock or socket of another
+/// user, e.g. created in its place beforehand, isn't trusted.
+fn check_owner(path: &Path, metadata: &Metadata) -> Result<()> {
+    let uid = geteuid().as_raw();
+    match metadata.uid() == uid {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is owned by uid {}, not by the uid {uid} of operator",
+                path.display(),
+                metadata.uid()
+            ),
+        )
+        .into()),
+    }
+}
+
+/// Claim the socket at `path`, [Error::AlreadyRunning] if another operator serves it.
+///
+/// With `take_over`, that operator is sent SIGTERM instead, and the socket is claimed
+/// once it stopped its services and exited. The returned lock must be held as long as
+/// the socket is served, a socket left by an operator that is gone is removed.
+fn claim(path: &Path, take_over: bool) -> Result<File> {
+    // the runtime dir by default.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        create_private_dir(dir)?;
+    }
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(lock_path(path))?;
+    check_owner(&lock_path(path), &lock.metadata()?)?;
+    if let Ok(socket) = path.symlink_metadata() {
+        check_owner(path, &socket)?;
+    }
+
+    let mut stopped = None;
+    loop {
+        let owner = match flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            // an operator that doesn't lock the socket is only found by connecting to it.
+            Ok(()) => match serving(path) {
+                Some(pid) => pid,
+                None => break,
+            },
+            // waiting for the operator that was stopped to exit.
+            Err(Errno::EWOULDBLOCK) if stopped.is_some() => stopped,
+            // the socket may not be bound yet, its pid is known on a next try.
+            Err(Errno::EWOULDBLOCK) => serving(path).flatten(),
+            Err(e) => return Err(io::Error::from(e).into()),
+        };
+        if !take_over {
+            return Err(Error::AlreadyRunning(owner));
+        }
+        if let Some(pid) = owner.filter(|pid| stopped != Some(*pid)) {
+            info!("Stopping the operator with pid {pid} to take over its socket");
+            match kill(Pid::from_raw(pid), Signal::SIGTERM) {
+                Ok(()) | Err(Errno::ESRCH) => stopped = Some(pid),
+                Err(e) => return Err(io::Error::from(e).into()),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    if path.exists() {
+        _ = std::fs::remove_file(path)
+    }
+    Ok(lock)
 }
 
 /// IPC Server for comms b/w operator and operatorctl.
-pub struct IPCServer(UnixListener);
+pub struct IPCServer {
+    listener: UnixListener,
+    limiter: Limiter,
+    /// claim on the socket, held as long as it's served.
+    _lock: File,
+}
 
 impl IPCServer {
     /// Create a new IPC server.
-    pub fn new() -> anyhow::Result<Self> {
-        let socket_path = Path::new("/tmp/operator.sock");
-        if Path::exists(socket_path) {
-            _ = std::fs::remove_file(socket_path)
-        }
+    pub fn new() -> Result<Self> {
+        Self::bind(op_socket_path())
+    }
+
+    /// Create a new IPC server listening on `path`.
+    ///
+    /// It fails with [Error::AlreadyRunning] if another operator is serving the socket.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        Self::listen(path.as_ref(), false)
+    }
 
+    /// Create a new IPC server listening on `path`, stopping the operator serving the
+    /// socket first if there is one.
+    pub fn take_over(path: impl AsRef<Path>) -> Result<Self> {
+        Self::listen(path.as_ref(), true)
+    }
+
+    fn listen(socket_path: &Path, take_over: bool) -> Result<Self> {
+        let lock = claim(socket_path, take_over)?;
         let listener = UnixListener::bind(socket_path)?;
         listener.set_nonblocking(true)?;
-        Ok(Self(listener))
+        Ok(Self {
+            listener,
+            limiter: Limiter::new(Limits::default()),
+            _lock: lock,
+        })
+    }
+
+    /// Limit the connections and requests of the clients.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limiter = Limiter::new(limits);
+        self
     }
 
     /// Accept a new incoming connection.
-    pub fn accept(&self) -> anyhow::Result<IPCStream> {
-        let (stream, addr) = self.0.accept()?;
-        Ok(IPCStream(stream, addr))
+    ///
+    /// Reading from it times out after [READ_TIMEOUT]. A client over the limits gets
+    /// [IPCMessage::Busy] and [Error::Busy] is returned.
+    pub fn accept(&self) -> Result<IPCStream> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut stream = IPCStream::accepted(stream);
+        match self.limiter.admit(stream.peer()) {
+            Some(connection) => {
+                stream.connection = Some(connection);
+                Ok(stream)
+            }
+            None => {
+                _ = stream.write(&IPCMessage::Busy);
+                Err(Error::Busy)
+            }
+        }
     }
+}
 
+impl AsFd for IPCServer {
     /// Get the underlying fd.
     ///
     /// NOTE: we use it to poll instead of blocking.
-    pub fn as_fd(&self) -> BorrowedFd<'_> {
-        self.0.as_fd()
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+/// A request read from a client of the engine, with the stream to answer it on.
+pub(crate) struct Request {
+    pub stream: IPCStream,
+    pub msg: IPCMessage,
+    /// whether the client connected to the query socket
+    pub query: bool,
+    /// stdin, stdout and stderr sent along with [IPCMessage::Exec]
+    pub stdio: Option<Result<Vec<OwnedFd>>>,
+}
+
+/// Reads the requests of the clients accepted by the engine from a thread per client, so
+/// a client that is slow to send its request doesn't block the engine.
+///
+/// The engine polls it and only gets the requests that were read completely.
+pub(crate) struct Requests {
+    sender: mpsc::Sender<Request>,
+    receiver: mpsc::Receiver<Request>,
+    /// written by the readers once they sent a request, readable by the engine's reactor
+    waker: (UnixStream, UnixStream),
+}
+
+impl Requests {
+    pub(crate) fn new() -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let waker = UnixStream::pair()?;
+        waker.0.set_nonblocking(true)?;
+        waker.1.set_nonblocking(true)?;
+        Ok(Self {
+            sender,
+            receiver,
+            waker,
+        })
+    }
+
+    /// Read the request of a client from a thread, it's dropped if it sends no valid
+    /// request within [READ_TIMEOUT].
+    pub(crate) fn read(&self, stream: IPCStream, query: bool) {
+        let sender = self.sender.clone();
+        let waker = match self.waker.1.try_clone() {
+            Ok(waker) => waker,
+            Err(e) => {
+                error!("Failed to read the request of an IPC client: {e}");
+                return;
+            }
+        };
+        let spawned = std::thread::Builder::new()
+            .name("ipc-client".to_string())
+            .spawn(move || {
+                let msg = match stream.read() {
+                    Ok(msg) => msg,
+                    // e.g. a client that only wanted the greeting of operator.
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+                    Err(e) => {
+                        warn!("Dropping an IPC client that sent no valid request: {e}");
+                        return;
+                    }
+                };
+                let stdio = matches!(msg, IPCMessage::Exec { .. }).then(|| stream.recv_fds());
+                let request = Request {
+                    stream,
+                    msg,
+                    query,
+                    stdio,
+                };
+                if sender.send(request).is_ok() {
+                    // the engine is woken up already if the waker is full.
+                    _ = (&waker).write(&[0]);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Failed to start the thread of an IPC client: {e}");
+        }
+    }
+
+    /// The requests read since the last call.
+    pub(crate) fn received(&self) -> Vec<Request> {
+        let mut buf = [0; 64];
+        while matches!((&self.waker.0).read(&mut buf), Ok(n) if n > 0) {}
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl AsFd for Requests {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.waker.0.as_fd()
+    }
+}
+
+/// An async Unix socket stream.
+#[cfg(feature = "tokio")]
+pub struct AsyncIPCStream {
+    stream: tokio::net::UnixStream,
+    /// slot of the connection in the limits of the server
+    connection: Option<Arc<Connection>>,
+    /// encoding of the messages, `None` until a server read the request
+    wire: Option<Wire>,
+    /// whether this end is the client, that greets operator along with its first message
+    client: bool,
+    /// whether the client sent its greeting
+    greeting_sent: bool,
+    /// whether the first frame of the peer, that may be its greeting, was read
+    greeting_read: bool,
+    /// bytes read past the last message
+    pending: Vec<u8>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncIPCStream {
+    /// Connect to a unix socket.
+    pub async fn connect(path: &str) -> Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(Self {
+            wire: Some(Wire::Bincode),
+            client: true,
+            ..Self::accepted(stream)
+        })
+    }
+
+    /// Stream of a client accepted by a server, its encoding is found from its request.
+    fn accepted(stream: tokio::net::UnixStream) -> Self {
+        Self {
+            stream,
+            connection: None,
+            wire: None,
+            client: false,
+            greeting_sent: false,
+            greeting_read: false,
+            pending: vec![],
+        }
+    }
+
+    /// Credentials of the peer, `None` if they can't be read.
+    pub fn peer(&self) -> Option<Peer> {
+        let cred = self.stream.peer_cred().ok()?;
+        Some(Peer {
+            uid: cred.uid(),
+            pid: cred.pid()?,
+        })
+    }
+
+    /// Read a message from the unix socket.
+    pub async fn read(&mut self) -> Result<IPCMessage> {
+        use tokio::io::AsyncReadExt;
+
+        // messages are not framed, so read until a whole message can be deserialized.
+        let mut chunk = [0; 1024];
+        loop {
+            if let Some(msg) = self.decode().await? {
+                return Ok(msg);
+            }
+
+            let e = match self.stream.read(&mut chunk).await {
+                Ok(0) => io::ErrorKind::UnexpectedEof.into(),
+                Ok(n) => {
+                    self.pending.extend_from_slice(&chunk[..n]);
+                    continue;
+                }
+                Err(e) => e,
+            };
+            // an operator older than the greetings drops the client that sends one.
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+                    if self.client && !self.greeting_read =>
+                {
+                    Err(Error::Incompatible(None))
+                }
+                _ => Err(e.into()),
+            };
+        }
+    }
+
+    /// Take the first message of the bytes read, after the greeting of the peer.
+    async fn decode(&mut self) -> Result<Option<IPCMessage>> {
+        use tokio::io::AsyncWriteExt;
+
+        let Some(&byte) = self.pending.first() else {
+            return Ok(None);
+        };
+        let wire = *self.wire.get_or_insert(Wire::of(byte));
+        // JSON is self-describing, its clients don't greet.
+        if wire == Wire::Bincode && !self.greeting_read {
+            let Some((frame, len)) = decode_frame(&self.pending)? else {
+                return Ok(None);
+            };
+            self.pending.drain(..len);
+            self.greeting_read = true;
+            match frame {
+                Frame::Message(msg) => return Ok(Some(*msg)),
+                Frame::Hello(hello) if self.client => hello.check()?,
+                Frame::Hello(hello) => {
+                    let mut greeting = vec![];
+                    let answered = answer(&mut greeting, &hello);
+                    self.stream.write_all(&greeting).await?;
+                    answered?;
+                }
+            }
+        }
+
+        let Some((msg, len)) = wire.decode(&self.pending)? else {
+            return Ok(None);
+        };
+        self.pending.drain(..len);
+        Ok(Some(msg))
+    }
+
+    /// Write a message to the unix socket.
+    pub async fn write(&mut self, msg: &IPCMessage) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        // a client answered before its request was read is answered in bincode.
+        let wire = self.wire.unwrap_or(Wire::Bincode);
+        let mut buf = vec![];
+        if self.client && !self.greeting_sent {
+            self.greeting_sent = true;
+            buf = Hello::current().encode()?;
+        }
+        buf.extend_from_slice(&wire.encode(msg)?);
+        self.stream.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+/// Async IPC Server for comms b/w operator and operatorctl.
+#[cfg(feature = "tokio")]
+pub struct AsyncIPCServer {
+    listener: tokio::net::UnixListener,
+    limiter: Limiter,
+    /// claim on the socket, held as long as it's served.
+    _lock: File,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncIPCServer {
+    /// Create a new IPC server.
+    ///
+    /// It fails with [Error::AlreadyRunning] if another operator is serving the socket.
+    ///
+    /// NOTE: must be called from the context of a tokio runtime.
+    pub fn new() -> Result<Self> {
+        Self::bind(op_socket_path())
+    }
+
+    /// Create a new IPC server listening on `path`.
+    ///
+    /// It fails with [Error::AlreadyRunning] if another operator is serving the socket.
+    ///
+    /// NOTE: must be called from the context of a tokio runtime.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let socket_path = path.as_ref();
+        let lock = claim(socket_path, false)?;
+
+        Ok(Self {
+            listener: tokio::net::UnixListener::bind(socket_path)?,
+            limiter: Limiter::new(Limits::default()),
+            _lock: lock,
+        })
+    }
+
+    /// Limit the connections and requests of the clients.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limiter = Limiter::new(limits);
+        self
+    }
+
+    /// Accept a new incoming connection.
+    ///
+    /// A client over the limits gets [IPCMessage::Busy] and [Error::Busy] is returned.
+    pub async fn accept(&self) -> Result<AsyncIPCStream> {
+        let (stream, _) = self.listener.accept().await?;
+        let mut stream = AsyncIPCStream::accepted(stream);
+        match self.limiter.admit(stream.peer()) {
+            Some(connection) => {
+                stream.connection = Some(connection);
+                Ok(stream)
+            }
+            None => {
+                _ = stream.write(&IPCMessage::Busy).await;
+                Err(Error::Busy)
+            }
+        }
     }
 }
@@ -0,0 +1,159 @@
+//! Journal of the recent state changes of the services.
+//!
+//! The changes sent to the webhooks and the subscribers are kept in memory, the last
+//! `capacity` of them, so what happened around an incident can be queried by
+//! `operatorctl events`. With a `file`, they're also appended to it as JSON lines and
+//! read back when operator starts.
+
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{helper::glob_match, webhook::StateChange};
+
+/// Configuration of the journal, `[journal]` in `operator.toml`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalConfig {
+    /// Number of changes kept in memory
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    /// File the changes are appended to, not kept on disk if unset
+    pub file: Option<PathBuf>,
+    /// Size in bytes past which the file is rotated to `<file>.1`
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+}
+
+fn default_capacity() -> usize {
+    1000
+}
+
+fn default_max_size() -> u64 {
+    10 << 20
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_capacity(),
+            file: None,
+            max_size: default_max_size(),
+        }
+    }
+}
+
+/// Which changes are returned by a query, all of them by default
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EventQuery {
+    /// Only the changes at or after this time, in seconds since the epoch
+    pub since: Option<u64>,
+    /// Only the changes at or before this time, in seconds since the epoch
+    pub until: Option<u64>,
+    /// Only the changes of the services matching this name or glob pattern
+    pub service: Option<String>,
+}
+
+impl EventQuery {
+    /// Whether a change is matched.
+    pub fn matches(&self, change: &StateChange) -> bool {
+        self.since.is_none_or(|since| change.timestamp >= since)
+            && self.until.is_none_or(|until| change.timestamp <= until)
+            && self
+                .service
+                .as_deref()
+                .is_none_or(|pattern| glob_match(pattern, &change.service))
+    }
+}
+
+/// The recent state changes of the services
+#[derive(Debug, Default)]
+pub struct Journal {
+    config: JournalConfig,
+    changes: VecDeque<StateChange>,
+}
+
+impl Journal {
+    /// Create a journal, with the last changes of its file if it has one.
+    pub fn new(config: JournalConfig) -> Self {
+        let mut journal = Self {
+            config,
+            changes: VecDeque::new(),
+        };
+        if let Some(file) = journal.config.file.clone() {
+            for file in [rotated(&file), file] {
+                match std::fs::read_to_string(&file) {
+                    // a line cut short by a crash of operator is skipped.
+                    Ok(contents) => contents
+                        .lines()
+                        .filter_map(|line| serde_json::from_str(line).ok())
+                        .for_each(|change| journal.push(change)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => error!("Failed to read the journal {}: {e}", file.display()),
+                }
+            }
+        }
+        journal
+    }
+
+    /// Record a change.
+    pub fn record(&mut self, change: &StateChange) {
+        self.push(change.clone());
+        if let Err(e) = self.append(change) {
+            error!("Failed to write to the journal: {e}");
+        }
+    }
+
+    /// The changes matched by a query, the oldest first.
+    pub fn query(&self, query: &EventQuery) -> Vec<StateChange> {
+        self.changes
+            .iter()
+            .filter(|change| query.matches(change))
+            .cloned()
+            .collect()
+    }
+
+    fn push(&mut self, change: StateChange) {
+        if self.changes.len() >= self.config.capacity {
+            self.changes.pop_front();
+        }
+        if self.config.capacity > 0 {
+            self.changes.push_back(change);
+        }
+    }
+
+    /// Append a change to the file of the journal, rotated once it's too large.
+    fn append(&self, change: &StateChange) -> io::Result<()> {
+        let Some(ref file) = self.config.file else {
+            return Ok(());
+        };
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if std::fs::metadata(file).is_ok_and(|metadata| metadata.len() >= self.config.max_size) {
+            std::fs::rename(file, rotated(file))?;
+        }
+
+        let mut line = serde_json::to_string(change).map_err(io::Error::other)?;
+        line.push('\n');
+        // a single write, so a crash doesn't leave half a line in the middle.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?
+            .write_all(line.as_bytes())
+    }
+}
+
+/// Path the file of the journal is rotated to, `<file>.1`.
+fn rotated(file: &Path) -> PathBuf {
+    let mut rotated = file.as_os_str().to_os_string();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
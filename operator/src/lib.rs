@@ -1,5 +1,49 @@
+#[cfg(feature = "tokio")]
+pub mod async_engine;
+pub mod audit;
+pub mod backend;
+pub mod capabilities;
+pub mod cgroup;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod client;
+pub mod config;
+pub mod container;
+pub mod coredump;
+pub mod daemon;
 pub mod engine;
+pub mod error;
+pub mod exec;
+pub mod executor;
+pub mod expand;
+pub mod foreground;
+pub mod format;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod helper;
+pub mod history;
+#[cfg(feature = "http")]
+pub mod http;
 pub mod ipc;
+pub mod journal;
 pub mod log;
+pub mod network;
+pub mod notify;
+pub mod output;
+pub mod power;
+pub mod property;
+pub mod reactor;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod sandbox;
+pub mod schedule;
+pub mod secrets;
 pub mod service;
+pub mod socket;
+pub mod state;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod usage;
+pub mod webhook;
+
+pub use error::{Error, Result};
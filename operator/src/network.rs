@@ -0,0 +1,127 @@
+//! Network isolation of the services.
+//!
+//! A service with `private_network` runs in a new network namespace with only a
+//! loopback interface, and optionally a veth pair linking it to the host.
+
+use std::{
+    fs::File,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    process::Command,
+};
+
+use log::error;
+use nix::{
+    errno::Errno,
+    libc,
+    sched::{setns, unshare, CloneFlags},
+    unistd::getppid,
+};
+use serde::{Deserialize, Serialize};
+
+/// Name of the veth interface in the namespace of a service.
+pub const INTERFACE: &str = "eth0";
+
+/// A veth pair between the host and the network namespace of a service
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Veth {
+    /// Name of the interface on the host, 15 characters at most
+    pub name: String,
+    /// Address of the interface on the host, e.g. `"10.0.0.1/24"`
+    pub host_address: Option<String>,
+    /// Address of `eth0` in the service, e.g. `"10.0.0.2/24"`
+    pub address: Option<String>,
+    /// Default gateway of the service
+    pub gateway: Option<String>,
+}
+
+/// Move the current process in a new network namespace, bring its loopback interface up
+/// and create the veth pair.
+///
+/// This should only be run in the context of a forked child process.
+pub fn enter(veth: Option<&Veth>) -> io::Result<()> {
+    let host = File::open("/proc/self/ns/net")?;
+    unshare(CloneFlags::CLONE_NEWNET)?;
+    up("lo")?;
+
+    let Some(veth) = veth else {
+        return Ok(());
+    };
+
+    // the pair is created in the new namespace and the host end moved to the namespace
+    // of operator, so it's destroyed along with the namespace once the service exits.
+    ip(&[
+        "link", "add", INTERFACE, "type", "veth", "peer", "name", &veth.name,
+    ])?;
+    ip(&[
+        "link",
+        "set",
+        &veth.name,
+        "netns",
+        &getppid().as_raw().to_string(),
+    ])?;
+    if let Some(ref address) = veth.address {
+        ip(&["address", "add", address, "dev", INTERFACE])?;
+    }
+    up(INTERFACE)?;
+    if let Some(ref gateway) = veth.gateway {
+        ip(&["route", "add", "default", "via", gateway])?;
+    }
+
+    // the host end is configured from the namespace of operator.
+    let private = File::open("/proc/self/ns/net")?;
+    setns(&host, CloneFlags::CLONE_NEWNET)?;
+    if let Some(ref address) = veth.host_address {
+        ip(&["address", "add", address, "dev", &veth.name])?;
+    }
+    up(&veth.name)?;
+    setns(&private, CloneFlags::CLONE_NEWNET)?;
+
+    Ok(())
+}
+
+/// Move the current process in the network namespace of the running process `pid` of a
+/// service.
+///
+/// This should only be run in the context of a forked child process.
+pub fn join(pid: i32) -> io::Result<()> {
+    let namespace = File::open(format!("/proc/{pid}/ns/net"))?;
+    setns(&namespace, CloneFlags::CLONE_NEWNET)?;
+    Ok(())
+}
+
+/// Run `ip` (iproute2), its errors are written to the log of the service.
+fn ip(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("ip").args(args).status()?;
+    if !status.success() {
+        error!("`ip {}` failed with {status}", args.join(" "));
+        return Err(Errno::EIO.into());
+    }
+    Ok(())
+}
+
+/// Bring up a network interface.
+fn up(interface: &str) -> io::Result<()> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    // the name is null terminated.
+    let name = interface.bytes().take(libc::IFNAMSIZ - 1);
+    for (dst, src) in request.ifr_name.iter_mut().zip(name) {
+        *dst = src as libc::c_char;
+    }
+
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
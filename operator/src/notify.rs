@@ -0,0 +1,68 @@
+//! sd_notify compatible readiness protocol.
+//!
+//! A service with `notify` or `watchdog_sec` gets its own datagram socket in the notify dir,
+//! passed in `NOTIFY_SOCKET`, on which it sends newline separated `KEY=VALUE` messages
+//! like `READY=1`, `STATUS=...` and `WATCHDOG=1`.
+
+use std::{
+    io,
+    os::unix::{fs::chown, net::UnixDatagram},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use nix::unistd::{getpid, User};
+
+use crate::helper::{op_notify_dir, setenv};
+
+/// Maximum size of a notification.
+pub const MAX_MESSAGE: usize = 4096;
+
+/// Path of the notification socket of a service.
+pub fn path(name: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{name}.sock", op_notify_dir()))
+}
+
+/// Bind the notification socket of a service, writable by `user`.
+pub fn bind(name: &str, user: Option<&str>) -> io::Result<UnixDatagram> {
+    let path = path(name);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // the socket file left by a previous operator would make bind() fail.
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let socket = UnixDatagram::bind(&path)?;
+    socket.set_nonblocking(true)?;
+    if let Some(user) = user {
+        let user = User::from_name(user)?.ok_or(io::ErrorKind::NotFound)?;
+        chown(&path, Some(user.uid.as_raw()), Some(user.gid.as_raw()))?;
+    }
+    Ok(socket)
+}
+
+/// Read the pending notifications of a socket.
+pub fn recv(socket: &UnixDatagram) -> Vec<String> {
+    let mut messages = vec![];
+    let mut buf = [0; MAX_MESSAGE];
+    while let Ok(n) = socket.recv(&mut buf) {
+        messages.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+    }
+    messages
+}
+
+/// Pass the notification socket and the watchdog interval to the program about to be
+/// executed.
+///
+/// This should only be run in the context of a forked child process.
+pub fn pass(path: &Path, watchdog: Option<Duration>) -> io::Result<()> {
+    setenv("NOTIFY_SOCKET", &path.to_string_lossy())?;
+    if let Some(watchdog) = watchdog {
+        setenv("WATCHDOG_USEC", &watchdog.as_micros().to_string())?;
+        setenv("WATCHDOG_PID", &getpid().to_string())?;
+    }
+    Ok(())
+}
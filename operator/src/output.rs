@@ -0,0 +1,247 @@
+//! Capture of the output of the services.
+//!
+//! The stdout and stderr of a service are a pipe read by a thread of operator, which
+//! appends the output to the log file of the service and keeps its last bytes in
+//! memory. They are kept across restarts of the service, so the last lines of a
+//! service that crashed can be shown even once its log file was rotated away.
+//!
+//! The output is also sent live to the followers attached with `operatorctl attach`,
+//! which may write to the stdin of a service reading it from a pipe.
+//!
+//! A service with `tty` runs in a pseudo-terminal instead, its output is read from the
+//! master side and its input written to it.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::{FileTypeExt, OpenOptionsExt},
+    },
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use log::warn;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    libc,
+    pty::{openpty, Winsize},
+    unistd::pipe2,
+};
+
+use crate::helper::op_service_log_dir;
+
+/// Number of bytes of output kept in memory per service.
+pub const BUFFER_SIZE: usize = 16 * 1024;
+
+/// The output of a service, shared with the thread capturing it.
+#[derive(Debug, Default)]
+struct Captured {
+    /// the last bytes written by the service.
+    bytes: VecDeque<u8>,
+    /// the read end of the pipe of the running process, while it's being read.
+    reader: Option<RawFd>,
+    /// receive the new output as it's read.
+    followers: Vec<Sender<Vec<u8>>>,
+    /// the write end of the stdin of the process, if it's a pipe.
+    input: Option<File>,
+}
+
+/// The last output of a service.
+#[derive(Debug, Clone, Default)]
+pub struct Output(Arc<Mutex<Captured>>);
+
+impl Output {
+    /// Capture the output of a new process of a service.
+    ///
+    /// Returns the write end of the pipe, to pass as the stdout and stderr of the
+    /// process. It's closed on exec, like the read end.
+    pub fn capture(&self, name: &str) -> io::Result<OwnedFd> {
+        let log = open_log(name)?;
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+        let (reader, writer) =
+            unsafe { (OwnedFd::from_raw_fd(reader), OwnedFd::from_raw_fd(writer)) };
+        self.spawn(name, reader, log)?;
+        Ok(writer)
+    }
+
+    /// Run the next process of a service in a new pseudo-terminal, its output is captured
+    /// and its input written to the master side.
+    ///
+    /// Returns the slave side, to pass as the controlling terminal and stdio of the
+    /// process. It's closed on exec, like the master side.
+    pub fn capture_tty(&self, name: &str) -> io::Result<OwnedFd> {
+        let log = open_log(name)?;
+        let size = Winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let pty = openpty(Some(&size), None)?;
+        for fd in [&pty.master, &pty.slave] {
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
+        self.0.lock().unwrap().input = Some(File::from(pty.master.try_clone()?));
+        self.spawn(name, pty.master, log)?;
+        Ok(pty.slave)
+    }
+
+    /// Keep capturing the output of a process from the read end of its pipe, or the
+    /// master side of its terminal, left open by the operator that re-executed itself.
+    pub fn resume(&self, name: &str, reader: RawFd) -> io::Result<()> {
+        let reader = unsafe { File::from_raw_fd(reader) };
+        let file_type = reader.metadata()?.file_type();
+        if !file_type.is_fifo() && !file_type.is_char_device() {
+            // don't close an fd that is not ours.
+            std::mem::forget(reader);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the output fd is not a pipe or a terminal",
+            ));
+        }
+        fcntl(reader.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        // the input of a terminal is written to the same fd.
+        if file_type.is_char_device() {
+            self.0.lock().unwrap().input = Some(reader.try_clone()?);
+        }
+        self.spawn(name, reader.into(), open_log(name)?)
+    }
+
+    /// Keep the read end of the pipe open across the exec of a new operator.
+    ///
+    /// Returns the fd, `None` if the process is not running anymore.
+    pub fn inherit(&self) -> Option<RawFd> {
+        let captured = self.0.lock().unwrap();
+        let reader = captured.reader?;
+        fcntl(reader, FcntlArg::F_SETFD(FdFlag::empty())).ok()?;
+        Some(reader)
+    }
+
+    /// Give the next process of a service a pipe as stdin, written by [Output::write_input].
+    ///
+    /// Returns the read end, closed on exec.
+    pub fn capture_input(&self) -> io::Result<OwnedFd> {
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+        let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(reader), File::from_raw_fd(writer)) };
+        self.0.lock().unwrap().input = Some(writer);
+        Ok(reader)
+    }
+
+    /// Close the stdin pipe of the previous process, the next one doesn't have one.
+    pub fn close_input(&self) {
+        self.0.lock().unwrap().input = None;
+    }
+
+    /// Write to the stdin of the process.
+    pub fn write_input(&self, bytes: &[u8]) -> io::Result<()> {
+        // the pipe may be full, the output must not wait for it.
+        let input = match self.0.lock().unwrap().input {
+            Some(ref input) => input.try_clone()?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "the service has no stdin pipe",
+                ))
+            }
+        };
+        (&input).write_all(bytes)
+    }
+
+    /// Receive the output from now on, until the receiver is dropped.
+    pub fn follow(&self) -> Receiver<Vec<u8>> {
+        let (follower, receiver) = mpsc::channel();
+        self.0.lock().unwrap().followers.push(follower);
+        receiver
+    }
+
+    /// The last `count` lines of output.
+    pub fn lines(&self, count: usize) -> Vec<String> {
+        let captured = self.0.lock().unwrap();
+        let bytes = captured.bytes.iter().copied().collect::<Vec<_>>();
+        let text = String::from_utf8_lossy(&bytes);
+        // the lines of a terminal end with `\r\n`.
+        let mut lines = text
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .collect::<Vec<_>>();
+        // the first line is likely cut by the start of the buffer.
+        if captured.bytes.len() == BUFFER_SIZE && lines.len() > 1 {
+            lines.remove(0);
+        }
+        lines[lines.len().saturating_sub(count)..]
+            .iter()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    /// Read the pipe from a thread until all the processes writing to it are gone.
+    fn spawn(&self, name: &str, reader: OwnedFd, mut log: File) -> io::Result<()> {
+        let captured = self.0.clone();
+        let fd = reader.as_raw_fd();
+        captured.lock().unwrap().reader = Some(fd);
+
+        let name = name.to_string();
+        let spawned = std::thread::Builder::new()
+            .name(format!("output-{name}"))
+            .spawn(move || {
+                let mut reader = File::from(reader);
+                let mut buf = [0; 4096];
+                let mut log_failed = false;
+                loop {
+                    let n = match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        // the master side of a terminal fails once the slave side is closed.
+                        Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                        Err(e) => {
+                            warn!("Failed to read the output of {name}: {e}");
+                            break;
+                        }
+                    };
+
+                    if let Err(e) = log.write_all(&buf[..n]) {
+                        if !log_failed {
+                            warn!("Failed to write the log of {name}: {e}");
+                        }
+                        log_failed = true;
+                    }
+
+                    let mut captured = captured.lock().unwrap();
+                    captured
+                        .followers
+                        .retain(|follower| follower.send(buf[..n].to_vec()).is_ok());
+                    captured.bytes.extend(&buf[..n]);
+                    let excess = captured.bytes.len().saturating_sub(BUFFER_SIZE);
+                    captured.bytes.drain(..excess);
+                }
+
+                // the fd is closed while locked, so it's not inherited once reused.
+                let mut captured = captured.lock().unwrap();
+                if captured.reader == Some(fd) {
+                    captured.reader = None;
+                }
+                drop(reader);
+            });
+
+        if let Err(e) = spawned {
+            self.0.lock().unwrap().reader = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Open the log file of a service for appending.
+fn open_log(name: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o660)
+        .open(format!("{}/{name}.log", op_service_log_dir()))
+}
@@ -0,0 +1,43 @@
+//! Powering off and rebooting the machine, when operator is its init.
+//!
+//! `operatorctl poweroff` and `operatorctl reboot`, or SIGUSR2 and SIGUSR1 sent to an
+//! `--init` operator, stop the services like a shutdown. Once they exited, operator
+//! syncs the filesystems and calls reboot(2): the machine is powered off or rebooted,
+//! and the container is stopped when operator is the PID 1 of a PID namespace.
+
+use std::{fmt, io};
+
+use nix::{
+    sys::reboot::{reboot, RebootMode},
+    unistd::sync,
+};
+use serde::{Deserialize, Serialize};
+
+/// What operator does once the services stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerAction {
+    PowerOff,
+    Reboot,
+}
+
+impl fmt::Display for PowerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerAction::PowerOff => write!(f, "power off"),
+            PowerAction::Reboot => write!(f, "reboot"),
+        }
+    }
+}
+
+/// Sync the filesystems, then power off or reboot the machine.
+///
+/// Only returns if reboot(2) failed, e.g. without `CAP_SYS_BOOT`.
+pub(crate) fn power(action: PowerAction) -> io::Error {
+    sync();
+    let mode = match action {
+        PowerAction::PowerOff => RebootMode::RB_POWER_OFF,
+        PowerAction::Reboot => RebootMode::RB_AUTOBOOT,
+    };
+    let Err(e) = reboot(mode);
+    e.into()
+}
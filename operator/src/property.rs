@@ -0,0 +1,256 @@
+//! Runtime overrides of the properties of the services.
+//!
+//! `operatorctl set-property` changes the restart policy, env vars and limits of a
+//! loaded service without editing its file. The overrides apply from the next start of
+//! the service and last until operator reads the service files again, unless they are
+//! persisted to a drop-in of the service.
+
+use std::{io, path::PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::{
+    format::{self, Format},
+    service::{Limit, Service},
+    Error, Result,
+};
+
+/// Name of the drop-in the overrides are persisted to.
+pub const DROP_IN: &str = "50-set-property.toml";
+
+/// Properties that can be set, besides `env.<VAR>` and `limits.<name>`.
+pub const PROPERTIES: [&str; 6] = [
+    "restart",
+    "nice",
+    "memory_max",
+    "cpu_weight",
+    "cpu_max",
+    "pids_max",
+];
+
+/// Apply `(key, value)` overrides to a service.
+///
+/// Returns them as the document of a drop-in. None is applied if one of them is invalid.
+pub fn apply(service: &mut Service, properties: &[(String, String)]) -> Result<Value, String> {
+    let mut updated = service.clone();
+    let mut document = Map::new();
+
+    for (key, value) in properties {
+        match key.split_once('.') {
+            Some(("env", var)) if !var.is_empty() => {
+                updated
+                    .env
+                    .get_or_insert_with(Default::default)
+                    .insert(var.to_string(), value.clone());
+                // the drop-in is expanded when it's loaded, the value must stay as is.
+                let escaped = value.replace("${", "$${").replace('%', "%%");
+                table(&mut document, "env").insert(var.to_string(), Value::String(escaped));
+            }
+            Some(("limits", name)) => {
+                let limit = updated
+                    .limits
+                    .get_mut(name)
+                    .ok_or_else(|| format!("unknown limit `{name}`"))?;
+                let value = parse(value);
+                *limit = Some(deserialize::<Limit>(key, &value)?);
+                table(&mut document, "limits").insert(name.to_string(), value);
+            }
+            Some(_) => return Err(unknown(key)),
+            None => {
+                // `cpu_max` is `"$MAX $PERIOD"`, which may look like a number.
+                let value = match key.as_str() {
+                    "cpu_max" => Value::String(value.clone()),
+                    _ => parse(value),
+                };
+                match key.as_str() {
+                    "restart" => updated.restart = deserialize(key, &value)?,
+                    "nice" => updated.nice = Some(deserialize(key, &value)?),
+                    "memory_max" => updated.memory_max = Some(deserialize(key, &value)?),
+                    "cpu_weight" => updated.cpu_weight = Some(deserialize(key, &value)?),
+                    "cpu_max" => updated.cpu_max = Some(value.as_str().unwrap().to_string()),
+                    "pids_max" => updated.pids_max = Some(deserialize(key, &value)?),
+                    _ => return Err(unknown(key)),
+                }
+                document.insert(key.clone(), value);
+            }
+        }
+    }
+
+    *service = updated;
+    Ok(Value::Object(document))
+}
+
+/// Merge overrides into the drop-in of the service persisting them.
+///
+/// Returns the path of the drop-in.
+pub fn persist(service: &Service, overrides: Value) -> Result<PathBuf> {
+    // the drop-in of a template would apply to all its instances.
+    if service.replica.is_some() || service.name.contains('@') {
+        return Err(Error::Rejected(format!(
+            "{} is an instance, only the file of its template can be changed",
+            service.name
+        )));
+    }
+    let Some(source) = service.sources.first() else {
+        return Err(Error::Rejected(format!(
+            "{} has no service file",
+            service.name
+        )));
+    };
+
+    let mut dir = source.as_os_str().to_owned();
+    dir.push(".d");
+    let dir = PathBuf::from(dir);
+    std::fs::create_dir_all(&dir)?;
+
+    let path = dir.join(DROP_IN);
+    let mut document = match std::fs::read_to_string(&path) {
+        Ok(contents) => Format::Toml
+            .value(&contents)
+            .map_err(|e| Error::parse(&path, e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Value::Object(Map::new()),
+        Err(e) => return Err(e.into()),
+    };
+    format::merge(&mut document, overrides);
+
+    let contents = toml::to_string(&document).map_err(io::Error::other)?;
+    std::fs::write(
+        &path,
+        format!("# written by `operatorctl set-property --persist`\n{contents}"),
+    )?;
+    Ok(path)
+}
+
+/// Error of a property that can't be set.
+fn unknown(key: &str) -> String {
+    format!(
+        "unknown property `{key}`, expected {}, `env.<VAR>` or `limits.<name>`",
+        PROPERTIES.map(|key| format!("`{key}`")).join(", ")
+    )
+}
+
+/// A table of a drop-in document.
+fn table<'a>(document: &'a mut Map<String, Value>, key: &str) -> &'a mut Map<String, Value> {
+    match document
+        .entry(key)
+        .or_insert_with(|| Value::Object(Map::new()))
+    {
+        Value::Object(table) => table,
+        _ => unreachable!("only tables are inserted for {key}"),
+    }
+}
+
+/// Value of a property as it would be written in a service file.
+fn parse(value: &str) -> Value {
+    if let Ok(number) = value.parse::<u64>() {
+        return number.into();
+    }
+    if let Ok(number) = value.parse::<i64>() {
+        return number.into();
+    }
+    Value::String(value.to_string())
+}
+
+/// Check a value against the type of its property.
+fn deserialize<T: DeserializeOwned>(key: &str, value: &Value) -> Result<T, String> {
+    serde_json::from_value(value.clone()).map_err(|e| format!("invalid `{key}`: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::resource::RLIM_INFINITY;
+    use serde_json::json;
+
+    use super::*;
+    use crate::service::Restart;
+
+    fn service() -> Service {
+        Service::parse(
+            "name = \"web\"\nexecutable = \"/bin/sh\"\nrestart = \"never\"\n",
+            Format::Toml,
+        )
+        .unwrap()
+    }
+
+    fn properties(properties: &[(&str, &str)]) -> Vec<(String, String)> {
+        properties
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn applied() {
+        let mut web = service();
+        let document = apply(
+            &mut web,
+            &properties(&[
+                ("restart", "always"),
+                ("nice", "-5"),
+                ("cpu_max", "50000"),
+                ("env.GREETING", "hi ${USER} 100%"),
+                ("limits.nofile", "infinity"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(web.restart, Restart::Always);
+        assert_eq!(web.nice, Some(-5));
+        assert_eq!(web.cpu_max.as_deref(), Some("50000"));
+        assert_eq!(web.env.as_ref().unwrap()["GREETING"], "hi ${USER} 100%");
+        assert_eq!(web.limits.nofile, Some(Limit(RLIM_INFINITY)));
+        assert_eq!(
+            document,
+            json!({
+                "restart": "always",
+                "nice": -5,
+                "cpu_max": "50000",
+                "env": {"GREETING": "hi $${USER} 100%%"},
+                "limits": {"nofile": "infinity"},
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_keys() {
+        for key in ["executable", "limits.stack", "env.", "other.key"] {
+            let e = apply(&mut service(), &properties(&[(key, "1")])).unwrap_err();
+            assert!(e.starts_with("unknown"), "{key}: {e}");
+        }
+    }
+
+    #[test]
+    fn type_errors() {
+        for (key, value) in [
+            ("restart", "sometimes"),
+            ("nice", "low"),
+            ("memory_max", "-1"),
+            ("pids_max", "many"),
+            ("limits.core", "unlimited"),
+        ] {
+            let e = apply(&mut service(), &properties(&[(key, value)])).unwrap_err();
+            assert!(e.starts_with(&format!("invalid `{key}`")), "{e}");
+        }
+
+        // none is applied when one is invalid.
+        let mut web = service();
+        apply(
+            &mut web,
+            &properties(&[("restart", "always"), ("nice", "low")]),
+        )
+        .unwrap_err();
+        assert_eq!(web.restart, Restart::Never);
+    }
+
+    #[test]
+    fn runtime_only_properties() {
+        // the state of a service is changed by its commands, not set.
+        for key in ["pid", "status", "enabled", "masked", "replicas"] {
+            let mut web = service();
+            let e = apply(&mut web, &properties(&[(key, "1")])).unwrap_err();
+            assert!(e.contains(&format!("unknown property `{key}`")), "{e}");
+            assert_eq!(web.pid, None);
+        }
+    }
+}
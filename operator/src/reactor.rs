@@ -0,0 +1,168 @@
+//! Event loop for operator.
+//!
+//! Sources (fds) and timers are registered with a token, and [Reactor::poll] returns the
+//! tokens of the ones that are ready so the caller can dispatch on them.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    os::fd::AsFd,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
+};
+
+/// Id of a timer, used to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+/// An epoll based event loop.
+pub struct Reactor<T> {
+    /// the epoll instance sources are registered with.
+    epoll: Epoll,
+    /// tokens of the registered sources, keyed by the epoll data.
+    sources: HashMap<u64, T>,
+    /// pending timers ordered by deadline.
+    deadlines: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    /// tokens of the pending timers.
+    timers: HashMap<TimerId, T>,
+    /// id of the next source or timer.
+    next_id: u64,
+    /// current time of a paused clock, the timers only expire when it's advanced.
+    paused: Option<Instant>,
+}
+
+impl<T: Clone> Reactor<T> {
+    /// Create a new reactor.
+    pub fn new() -> nix::Result<Self> {
+        Ok(Self {
+            epoll: Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?,
+            sources: HashMap::new(),
+            deadlines: BinaryHeap::new(),
+            timers: HashMap::new(),
+            next_id: 0,
+            paused: None,
+        })
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Current time of the timers.
+    fn now(&self) -> Instant {
+        self.paused.unwrap_or_else(Instant::now)
+    }
+
+    /// Pause the clock of the timers, so they expire once [Reactor::advance] moved it
+    /// past their deadline rather than in real time.
+    pub fn pause(&mut self) {
+        self.paused.get_or_insert_with(Instant::now);
+    }
+
+    /// Move the paused clock forward.
+    pub fn advance(&mut self, by: Duration) {
+        if let Some(ref mut now) = self.paused {
+            *now += by;
+        }
+    }
+
+    /// Register a source, `token` is returned by [Reactor::poll] when it's readable.
+    pub fn register(&mut self, fd: impl AsFd, token: T) -> nix::Result<()> {
+        let id = self.next_id();
+        self.epoll
+            .add(fd, EpollEvent::new(EpollFlags::EPOLLIN, id))?;
+        self.sources.insert(id, token);
+        Ok(())
+    }
+
+    /// Deregister a source.
+    ///
+    /// NOTE: sources must be deregistered before they are closed.
+    pub fn deregister(&mut self, fd: impl AsFd, token: &T) -> nix::Result<()>
+    where
+        T: PartialEq,
+    {
+        self.sources.retain(|_, t| t != token);
+        self.epoll.delete(fd)
+    }
+
+    /// Add a timer, `token` is returned by [Reactor::poll] once `after` has elapsed.
+    pub fn add_timer(&mut self, after: Duration, token: T) -> TimerId {
+        let id = TimerId(self.next_id());
+        self.deadlines.push(Reverse((self.now() + after, id)));
+        self.timers.insert(id, token);
+        id
+    }
+
+    /// Cancel a pending timer.
+    pub fn cancel_timer(&mut self, id: TimerId) {
+        self.timers.remove(&id);
+    }
+
+    /// Deadline of the next pending timer.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        // drop the cancelled timers so they don't wake us up.
+        while let Some(Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.timers.contains_key(id) {
+                return Some(*deadline);
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
+
+    /// Remove the timers that expired and return their tokens.
+    pub fn expired(&mut self) -> Vec<T> {
+        let mut expired = vec![];
+        let now = self.now();
+        while let Some(Reverse((deadline, id))) = self.deadlines.peek() {
+            if *deadline > now {
+                break;
+            }
+            if let Some(token) = self.timers.remove(id) {
+                expired.push(token);
+            }
+            self.deadlines.pop();
+        }
+        expired
+    }
+
+    /// Wait until sources are readable or timers expire and return their tokens.
+    pub fn poll(&mut self) -> nix::Result<Vec<T>> {
+        let timeout = match self.next_deadline() {
+            Some(deadline) => {
+                // round up so we don't wake up right before the deadline.
+                let left = deadline.saturating_duration_since(self.now());
+                left.as_micros().div_ceil(1000) as isize
+            }
+            None => -1,
+        };
+
+        let mut events = [EpollEvent::empty(); 32];
+        let n = loop {
+            match self.epoll.wait(&mut events, timeout) {
+                Err(Errno::EINTR) => continue,
+                res => break res?,
+            }
+        };
+
+        let mut ready = events[..n]
+            .iter()
+            .filter_map(|event| self.sources.get(&event.data()).cloned())
+            .collect::<Vec<_>>();
+        ready.extend(self.expired());
+
+        Ok(ready)
+    }
+}
+
+impl<T: Clone> Default for Reactor<T> {
+    fn default() -> Self {
+        Self::new().expect("Failed to create an epoll instance")
+    }
+}
@@ -0,0 +1,306 @@
+//! Remote management of operator over TCP + TLS.
+//!
+//! When `[remote]` is set in `operator.toml`, operator listens on a TCP address for
+//! `operatorctl --host`, speaking the same framed protocol as its IPC socket. A client
+//! is authenticated with a certificate signed by `client_ca`, or with the token of
+//! `token_file` sent in [IPCMessage::Authenticate] before its request. The request is
+//! then forwarded to the IPC socket, and the responses back to the client, so the remote
+//! clients are limited like the local ones.
+//!
+//! `exec` and attaching to the stdin of a service are refused: the first passes the fds
+//! of the client, and the second writes to the connection after the request.
+
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{info, warn};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helper::{constant_time_eq, op_socket_path},
+    ipc::{self, Frame, IPCMessage, IPCStream, LOCAL_ONLY, READ_TIMEOUT},
+    Error, Result,
+};
+
+/// Port of `operatorctl --host` when the host has none.
+pub const DEFAULT_PORT: u16 = 7070;
+
+/// Config of the remote listener, `[remote]` in `operator.toml`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RemoteConfig {
+    /// Address the listener binds to, e.g. `"0.0.0.0:7070"`
+    pub listen: SocketAddr,
+    /// PEM certificate chain of the listener
+    pub cert: PathBuf,
+    /// PEM private key of the certificate
+    pub key: PathBuf,
+    /// PEM certificates of the CAs the certificates of the clients are signed by
+    pub client_ca: Option<PathBuf>,
+    /// File containing the token the clients without a certificate must send
+    pub token_file: Option<PathBuf>,
+}
+
+/// A TLS connection of a client.
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Start serving the remote clients from a thread.
+pub fn serve(config: RemoteConfig) -> io::Result<()> {
+    let token = match config.token_file {
+        Some(ref file) => {
+            let token = std::fs::read_to_string(file)?.trim().to_string();
+            if token.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the token file is empty",
+                ));
+            }
+            Some(token)
+        }
+        None => None,
+    };
+    let tls = Arc::new(server_config(&config, token.is_some())?);
+
+    let listener = TcpListener::bind(config.listen)?;
+    spawn("remote", move || {
+        for stream in listener.incoming().flatten() {
+            let (tls, token) = (tls.clone(), token.clone());
+            let handled = spawn("remote-client", move || {
+                let peer = stream.peer_addr().ok();
+                match handle(stream, tls, token.as_deref()) {
+                    Ok(()) => {}
+                    // e.g. a client that only wanted the greeting of operator.
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                    Err(e) => warn!("Dropping the remote client {peer:?}: {e}"),
+                }
+            });
+            if let Err(e) = handled {
+                warn!("Failed to start the thread of a remote client: {e}");
+            }
+        }
+    })?;
+
+    info!("Serving remote clients on {}", config.listen);
+    Ok(())
+}
+
+/// Spawn a named thread, the threads of the clients are detached.
+fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .map(drop)
+}
+
+/// TLS config of the listener, the clients without a certificate are accepted if they
+/// can send a `token`.
+fn server_config(config: &RemoteConfig, token: bool) -> io::Result<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+
+    let builder = match config.client_ca {
+        Some(ref ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots(ca)?, provider);
+            let verifier = match token {
+                true => verifier.allow_unauthenticated(),
+                false => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(invalid)?)
+        }
+        None if token => builder.with_no_client_auth(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a client_ca or a token_file is required",
+            ))
+        }
+    };
+    builder
+        .with_single_cert(certs(&config.cert)?, key(&config.key)?)
+        .map_err(invalid)
+}
+
+/// Forward the request of a client to the IPC socket, and the responses back to it.
+fn handle(stream: TcpStream, tls: Arc<ServerConfig>, token: Option<&str>) -> Result<()> {
+    // the request is read in time, the responses may take as long as they need.
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut stream = StreamOwned::new(ServerConnection::new(tls).map_err(invalid)?, stream);
+
+    let mut request = match ipc::read_frame(&mut stream)? {
+        Frame::Hello(hello) => {
+            ipc::answer(&mut stream, &hello)?;
+            ipc::read_message(&mut stream)?
+        }
+        // a client older than the greetings.
+        Frame::Message(msg) => *msg,
+    };
+    // the TLS handshake is done once a message was read.
+    if stream.conn.peer_certificates().is_none() {
+        let authorized = match (request, token) {
+            (IPCMessage::Authenticate { token: given }, Some(token)) => {
+                constant_time_eq(given.as_bytes(), token.as_bytes())
+            }
+            _ => false,
+        };
+        if !authorized {
+            ipc::write_message(&mut stream, &IPCMessage::Unauthorized)?;
+            return Err(Error::Rejected("invalid token".to_string()));
+        }
+        request = ipc::read_message(&mut stream)?;
+    }
+
+    let refused = match request {
+        IPCMessage::Exec { .. } => {
+            Some(IPCMessage::ExecResponse(Some(Err(LOCAL_ONLY.to_string()))))
+        }
+        IPCMessage::Attach { stdin: true, .. } => Some(IPCMessage::AttachResponse(Some(Err(
+            LOCAL_ONLY.to_string(),
+        )))),
+        _ => None,
+    };
+    if let Some(response) = refused {
+        return ipc::write_message(&mut stream, &response);
+    }
+
+    let local = IPCStream::connect(&op_socket_path())?;
+    local.write(&request)?;
+    loop {
+        match local.read() {
+            Ok(msg) => ipc::write_message(&mut stream, &msg)?,
+            // operator closes the connection once it's done with the request.
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    stream.conn.send_close_notify();
+    Ok(stream.flush()?)
+}
+
+/// How `operatorctl --host` reaches a remote operator
+///
+/// It's configured with env vars: `OP_REMOTE_CA` is the PEM certificate of the CA the
+/// certificate of the listener is signed by, and the client is authenticated with the
+/// certificate and key of `OP_REMOTE_CERT` and `OP_REMOTE_KEY`, or with the token of
+/// `OP_REMOTE_TOKEN_FILE`.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    /// `<host>:<port>` of the listener
+    address: String,
+    /// name the certificate of the listener must be valid for
+    name: ServerName<'static>,
+    tls: Arc<ClientConfig>,
+    /// token sent by a client without a certificate
+    token: Option<String>,
+}
+
+impl Remote {
+    /// Reach the operator listening on `host`, `<host>[:<port>]`, with the config of
+    /// the env.
+    pub fn from_env(host: &str) -> Result<Self> {
+        let var = |name: &str| std::env::var_os(name).map(PathBuf::from);
+
+        // the port is the part after the last colon, unless it's in an IPv6 address.
+        let (name, port) = match host.rsplit_once(':') {
+            Some((name, port)) if !name.is_empty() && !port.ends_with(']') => {
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|e| invalid(format!("{host}: {e}")))?;
+                (name, port)
+            }
+            _ => (host, DEFAULT_PORT),
+        };
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+        let address = match name.contains(':') {
+            true => format!("[{name}]:{port}"),
+            false => format!("{name}:{port}"),
+        };
+        let name = ServerName::try_from(name.to_string()).map_err(invalid)?;
+
+        let ca = var("OP_REMOTE_CA").ok_or_else(|| invalid("OP_REMOTE_CA is not set"))?;
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_root_certificates(roots(&ca)?);
+        // a client with a certificate is authenticated by the handshake.
+        let (tls, token) = match (var("OP_REMOTE_CERT"), var("OP_REMOTE_KEY")) {
+            (Some(cert), Some(key)) => {
+                let tls = builder
+                    .with_client_auth_cert(certs(&cert)?, self::key(&key)?)
+                    .map_err(invalid)?;
+                (tls, None)
+            }
+            (None, None) => {
+                let file = var("OP_REMOTE_TOKEN_FILE").ok_or_else(|| {
+                    invalid("OP_REMOTE_CERT and OP_REMOTE_KEY, or OP_REMOTE_TOKEN_FILE must be set")
+                })?;
+                let token = std::fs::read_to_string(file)?.trim().to_string();
+                (builder.with_no_client_auth(), Some(token))
+            }
+            _ => {
+                return Err(invalid("OP_REMOTE_CERT and OP_REMOTE_KEY must be set together").into())
+            }
+        };
+
+        Ok(Self {
+            address,
+            name,
+            tls: Arc::new(tls),
+            token,
+        })
+    }
+
+    /// Connect to the listener, greeted and authenticated.
+    pub(crate) fn connect(&self) -> Result<TlsStream> {
+        let stream = TcpStream::connect(&self.address)?;
+        let connection =
+            ClientConnection::new(self.tls.clone(), self.name.clone()).map_err(invalid)?;
+        let mut stream = StreamOwned::new(connection, stream);
+        ipc::greet(&mut stream)?;
+        if let Some(ref token) = self.token {
+            ipc::write_message(
+                &mut stream,
+                &IPCMessage::Authenticate {
+                    token: token.clone(),
+                },
+            )?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Root certificates of the CAs in a PEM file.
+fn roots(file: &Path) -> io::Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(file)? {
+        roots.add(cert).map_err(invalid)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+/// Certificates of a PEM file.
+fn certs(file: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(file)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| invalid(format!("{}: {e}", file.display())))
+}
+
+/// Private key of a PEM file.
+fn key(file: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(file).map_err(|e| invalid(format!("{}: {e}", file.display())))
+}
+
+/// Error of an invalid config.
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
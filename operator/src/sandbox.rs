@@ -0,0 +1,252 @@
+//! Filesystem sandboxing of the services.
+//!
+//! A service with a `root_dir` or `[mounts]` runs in its own mount namespace, so the
+//! mounts made for it are not seen by operator or the other services.
+
+use std::{
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use nix::{
+    mount::{mount, MsFlags},
+    sched::{setns, unshare, CloneFlags},
+    unistd::{chdir, chroot, fchdir},
+};
+use serde::{Deserialize, Serialize};
+
+/// Mounts of a service
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Mounts {
+    /// Make the root filesystem and everything mounted under it read-only
+    #[serde(default)]
+    pub read_only: bool,
+    /// Mount an empty tmpfs on /tmp
+    #[serde(default)]
+    pub private_tmp: bool,
+    /// Paths bind mounted in the root of the service
+    #[serde(default)]
+    pub bind: Vec<BindMount>,
+    /// Paths bind mounted read-only in the root of the service
+    #[serde(default)]
+    pub bind_read_only: Vec<BindMount>,
+}
+
+/// A bind mount, `"/source:/target"` or `"/path"` to mount a path at the same place
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct BindMount {
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
+
+impl TryFrom<String> for BindMount {
+    type Error = String;
+
+    fn try_from(bind: String) -> Result<Self, Self::Error> {
+        let (source, target) = bind.split_once(':').unwrap_or((&bind, &bind));
+        let (source, target) = (PathBuf::from(source), PathBuf::from(target));
+        if !source.is_absolute() || !target.is_absolute() {
+            return Err(format!(
+                "invalid bind mount `{bind}`, expected absolute paths like \"/source:/target\""
+            ));
+        }
+        Ok(BindMount { source, target })
+    }
+}
+
+impl From<BindMount> for String {
+    fn from(bind: BindMount) -> Self {
+        bind.to_string()
+    }
+}
+
+impl std::fmt::Display for BindMount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.source.display(), self.target.display())
+    }
+}
+
+impl Mounts {
+    /// Whether the service needs its own mount namespace.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Path of `path` in `root`.
+pub fn in_root(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Move the current process in a new mount namespace, make the mounts of the service
+/// and change its root to `root_dir`.
+///
+/// This should only be run in the context of a forked child process.
+pub fn enter(root_dir: Option<&Path>, mounts: &Mounts) -> io::Result<()> {
+    if root_dir.is_none() && mounts.is_empty() {
+        return Ok(());
+    }
+
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    // the mounts would otherwise propagate back to the namespace of operator.
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )?;
+
+    let root = root_dir.unwrap_or(Path::new("/"));
+    if root_dir.is_some() {
+        // the root dir has to be a mount point to be remounted read-only.
+        bind(root, root)?;
+    }
+
+    // the binds are made before /tmp is hidden by private_tmp, in case a source is in it.
+    for BindMount { source, target } in &mounts.bind {
+        bind(source, &in_root(root, target))?;
+    }
+    for BindMount { source, target } in &mounts.bind_read_only {
+        let target = in_root(root, target);
+        bind(source, &target)?;
+        remount_read_only(&target, MsFlags::empty())?;
+    }
+
+    if mounts.read_only {
+        let writable = mounts
+            .bind
+            .iter()
+            .map(|bind| in_root(root, &bind.target))
+            .collect::<Vec<_>>();
+        read_only(root, &writable)?;
+    }
+
+    if mounts.private_tmp {
+        mount(
+            Some("tmpfs"),
+            &in_root(root, Path::new("/tmp")),
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("mode=1777"),
+        )?;
+    }
+
+    if let Some(root_dir) = root_dir {
+        chroot(root_dir)?;
+        chdir("/")?;
+    }
+
+    Ok(())
+}
+
+/// Move the current process in the mount namespace and the root of the running process
+/// `pid` of a service.
+///
+/// This should only be run in the context of a forked child process.
+pub fn join(pid: i32) -> io::Result<()> {
+    // the paths of /proc may not be reachable from the namespace.
+    let root = File::open(format!("/proc/{pid}/root"))?;
+    let namespace = File::open(format!("/proc/{pid}/ns/mnt"))?;
+    setns(&namespace, CloneFlags::CLONE_NEWNS)?;
+
+    fchdir(root.as_raw_fd())?;
+    chroot(".")?;
+    chdir("/")?;
+    Ok(())
+}
+
+/// Bind mount `source` and the mounts under it at `target`.
+fn bind(source: &Path, target: &Path) -> io::Result<()> {
+    mount(
+        Some(source),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
+/// Remount `root` and every mount under it read-only, except the ones in `writable`.
+fn read_only(root: &Path, writable: &[PathBuf]) -> io::Result<()> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+
+    for line in mountinfo.lines() {
+        // the mount point and its options are the 5th and 6th fields.
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (Some(mount_point), Some(options)) = (fields.get(4), fields.get(5)) else {
+            continue;
+        };
+        let mount_point = PathBuf::from(unescape(mount_point));
+        if !mount_point.starts_with(root)
+            || writable.iter().any(|path| mount_point.starts_with(path))
+        {
+            continue;
+        }
+
+        // the flags the mount already has must be kept, the kernel refuses to clear
+        // some of them.
+        let flags = options
+            .split(',')
+            .fold(MsFlags::empty(), |flags, option| match option {
+                "nosuid" => flags | MsFlags::MS_NOSUID,
+                "nodev" => flags | MsFlags::MS_NODEV,
+                "noexec" => flags | MsFlags::MS_NOEXEC,
+                "noatime" => flags | MsFlags::MS_NOATIME,
+                "nodiratime" => flags | MsFlags::MS_NODIRATIME,
+                "relatime" => flags | MsFlags::MS_RELATIME,
+                _ => flags,
+            });
+
+        match remount_read_only(&mount_point, flags) {
+            Ok(()) => {}
+            // the root itself has to be read-only, a mount under it that can't be
+            // remounted (e.g. over an inaccessible path) is left as it is.
+            Err(e) if mount_point == root => return Err(e),
+            Err(e) => warn!("Failed to remount {} read-only: {e}", mount_point.display()),
+        }
+    }
+
+    Ok(())
+}
+
+fn remount_read_only(target: &Path, flags: MsFlags) -> io::Result<()> {
+    mount(
+        None::<&str>,
+        target,
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY | flags,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
+/// Unescape a path of `/proc/self/mountinfo`, where spaces and the like are written
+/// as octal escapes (`\040`).
+fn unescape(path: &str) -> String {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        match rest
+            .get(i + 1..i + 4)
+            .map(|code| u8::from_str_radix(code, 8))
+        {
+            Some(Ok(byte)) => {
+                unescaped.push(byte as char);
+                rest = &rest[i + 4..];
+            }
+            _ => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
@@ -0,0 +1,229 @@
+//! Schedules of the periodic restarts of the services, `restart_every`.
+//!
+//! A schedule is a duration like `24h` or `1h30m`, counted from the start of the
+//! service, or a cron expression like `0 4 * * *` (minute, hour, day of month, month,
+//! day of week) in UTC.
+
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// When a service is restarted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// As written in the service file.
+    text: String,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Every(Duration),
+    Cron(Cron),
+}
+
+/// The values matched by the fields of a cron expression, as bitsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// `*` as the day of month or week, the days match both fields instead of either.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// The next restart of a service started at `started`, after `now`, in seconds since
+    /// the epoch.
+    pub fn next(&self, started: u64, now: u64) -> Option<u64> {
+        match self.kind {
+            Kind::Every(period) => {
+                let period = period.as_secs();
+                // the restarts missed, e.g. while operator was re-executing, are skipped.
+                let periods = now.saturating_sub(started) / period + 1;
+                Some(started + periods * period)
+            }
+            Kind::Cron(ref cron) => cron.next(now),
+        }
+    }
+
+    /// Time between the restarts around `at`, in seconds.
+    pub fn period(&self, at: u64) -> u64 {
+        match self.kind {
+            Kind::Every(period) => period.as_secs(),
+            Kind::Cron(ref cron) => cron
+                .next(at)
+                .and_then(|next| Some(cron.next(next)? - next))
+                .unwrap_or(0),
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let kind = match text.split_whitespace().count() {
+            1 => Kind::Every(duration(&text)?),
+            5 => Kind::Cron(Cron::parse(&text)?),
+            _ => {
+                return Err(format!(
+                "invalid schedule `{text}`, expected a duration like \"24h\" or a cron expression"
+            ))
+            }
+        };
+        Ok(Schedule { text, kind })
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.text
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Parse a duration made of numbers followed by `d`, `h`, `m` or `s`, e.g. `1h30m`.
+pub fn duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration `{text}`, expected e.g. \"24h\" or \"1h30m\"");
+    let mut secs = 0u64;
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let n = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('d') => 86400,
+            Some('h') => 3600,
+            Some('m') => 60,
+            Some('s') => 1,
+            _ => return Err(invalid()),
+        };
+        secs = n
+            .checked_mul(unit)
+            .and_then(|n| secs.checked_add(n))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+    if secs == 0 {
+        return Err(format!("invalid duration `{text}`, it must not be zero"));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+impl Cron {
+    fn parse(text: &str) -> Result<Self, String> {
+        let fields = text.split_whitespace().collect::<Vec<_>>();
+        let field = |index: usize, name: &str, min: u64, max: u64| {
+            bits(fields[index], min, max)
+                .map_err(|e| format!("invalid {name} `{}` in `{text}`: {e}", fields[index]))
+        };
+        let mut weekdays = field(4, "day of week", 0, 7)?;
+        // 7 is also sunday.
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Cron {
+            minutes: field(0, "minute", 0, 59)?,
+            hours: field(1, "hour", 0, 23)?,
+            days: field(2, "day of month", 1, 31)?,
+            months: field(3, "month", 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// The first minute matching the expression after `now`.
+    fn next(&self, now: u64) -> Option<u64> {
+        let first_day = now / 86400;
+        // the expression matches at least once in 4 years if it ever does, e.g. on the
+        // 29th of february.
+        for day in first_day..first_day + 4 * 366 {
+            let (_, month, day_of_month) = civil(day);
+            // the 1st of january 1970 was a thursday.
+            let weekday = (day + 4) % 7;
+            let day_matches = match (self.any_day, self.any_weekday) {
+                (false, false) => {
+                    self.days & 1 << day_of_month != 0 || self.weekdays & 1 << weekday != 0
+                }
+                _ => self.days & 1 << day_of_month != 0 && self.weekdays & 1 << weekday != 0,
+            };
+            if self.months & 1 << month == 0 || !day_matches {
+                continue;
+            }
+
+            for hour in (0..24).filter(|hour| self.hours & 1 << hour != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & 1 << minute != 0) {
+                    let at = day * 86400 + hour * 3600 + minute * 60;
+                    if at > now {
+                        return Some(at);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The values matched by a field of a cron expression: `*`, `5`, `1-5`, `*/15`, `0-30/10`
+/// or a list of them separated by commas.
+fn bits(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u64>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or("invalid step")?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/10` is from 5 to the end.
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("out of range {min}-{max}"));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn value(text: &str) -> Result<u64, String> {
+    text.parse()
+        .map_err(|_| format!("`{text}` is not a number"))
+}
+
+/// Year, month and day of a day since the epoch.
+fn civil(days: u64) -> (u64, u64, u64) {
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let days = days + 719468;
+    let era = days / 146097;
+    let doe = days % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
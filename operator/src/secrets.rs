@@ -0,0 +1,52 @@
+//! Secrets of the services.
+//!
+//! The files of the `[secrets]` of a service are read right before it's started and
+//! their contents exported in the environment of its process only, they are not kept
+//! by operator nor logged.
+
+use std::{collections::HashMap, fmt, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::helper::setenv;
+
+/// Contents of the secrets of a service, keyed by env var
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Secrets(Vec<(String, String)>);
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(key, _)| format!("{key}=<redacted>")))
+            .finish()
+    }
+}
+
+impl Secrets {
+    /// Read the files of the secrets, without their trailing newline.
+    ///
+    /// Returns the file that couldn't be read on error.
+    pub fn read(files: &HashMap<String, PathBuf>) -> Result<Self, (&PathBuf, io::Error)> {
+        files
+            .iter()
+            .map(|(key, file)| {
+                let mut secret = std::fs::read_to_string(file).map_err(|e| (file, e))?;
+                if secret.ends_with('\n') {
+                    secret.pop();
+                }
+                Ok((key.clone(), secret))
+            })
+            .collect::<Result<_, _>>()
+            .map(Secrets)
+    }
+
+    /// Export the secrets to the program about to be executed.
+    ///
+    /// This should only be run in the context of a forked child process.
+    pub fn export(&self) -> io::Result<()> {
+        for (key, secret) in &self.0 {
+            setenv(key, secret)?;
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,56 @@
-use log::{error, info};
-use nix::errno::{errno, Errno};
+use log::{error, info, warn};
+use nix::{
+    errno::{errno, Errno},
+    sched::{sched_setaffinity, CpuSet},
+    sys::{
+        resource::{setrlimit, Resource, RLIM_INFINITY},
+        signal::{self, kill, SigHandler, SigSet, Signal},
+        stat::{umask, Mode},
+    },
+    unistd::{
+        access, chdir, getpgid, getsid, initgroups, setgid, setpgid, setsid, setuid, AccessFlags,
+        Group, Pid, User,
+    },
+};
 use serde::{Deserialize, Serialize};
-use std::{ffi::CString, path::PathBuf, process::exit};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::File,
+    io::Write,
+    os::{
+        fd::{OwnedFd, RawFd},
+        unix::fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use crate::helper::{op_service_dir, op_service_log_dir};
+use crate::{
+    backend::{self, ServiceBackend},
+    capabilities::{self, Capability},
+    cgroup,
+    expand::{self, Specifiers},
+    format::{self, Format},
+    helper::{
+        close_on_exec, create_state_dir, glob_match, op_service_dirs, op_service_log_dir,
+        op_state_dir, retain_env, setenv,
+    },
+    network::{self, Veth},
+    notify,
+    reactor::TimerId,
+    sandbox::{self, Mounts},
+    schedule::Schedule,
+    secrets::Secrets,
+    socket::{self, Socket},
+    usage::{CpuSample, Usage},
+    Error,
+};
 use nix::libc::{
-    dup2, open, O_APPEND, O_CREAT, O_WRONLY, STDERR_FILENO, STDOUT_FILENO, S_IRGRP, S_IRUSR,
-    S_IWGRP, S_IWUSR,
+    self, dup2, open, O_APPEND, O_CREAT, O_WRONLY, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+    S_IRGRP, S_IRUSR, S_IWGRP, S_IWUSR,
 };
 
 /// Status of the service
@@ -18,17 +62,724 @@ pub enum Status {
     Stopped,
     /// The process exited but waiting to be cleaned up
     Zombie,
+    /// The service is masked and can't be started
+    Masked,
+    /// The service is started on the first connection to its socket
+    Listening,
+    /// The service is running but didn't notify it's ready yet
+    Starting,
+    /// The service failed to start
+    Failed { reason: FailReason },
+}
+
+impl Status {
+    /// Name of the status, e.g. `running`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Running => "running",
+            Status::Stopped => "stopped",
+            Status::Zombie => "zombie",
+            Status::Masked => "masked",
+            Status::Listening => "listening",
+            Status::Starting => "starting",
+            Status::Failed { .. } => "failed",
+        }
+    }
+}
+
+/// Why a service failed to start
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum FailReason {
+    /// exec() failed with the errno
+    ExecError(i32),
+    /// The executable does not exist
+    ExecutableNotFound,
+    /// The executable is not executable
+    NotExecutable,
+    /// The user does not exist
+    UserNotFound,
+    /// The group does not exist
+    GroupNotFound,
+    /// The working directory does not exist
+    WorkingDirNotFound,
+    /// The root directory does not exist
+    RootDirNotFound,
+    /// The log directory is not writable
+    LogDirNotWritable,
+    /// A service it requires failed to start
+    DependencyFailed,
+    /// Its socket couldn't be bound, with the errno
+    ListenError(i32),
+    /// One of its secrets couldn't be read, with the errno
+    SecretError(i32),
+    /// It didn't become ready within its `start_timeout_secs`
+    StartTimeout,
+}
+
+impl std::fmt::Display for FailReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailReason::ExecError(errno) => {
+                write!(f, "exec error: {}", Errno::from_i32(*errno).desc())
+            }
+            FailReason::ExecutableNotFound => write!(f, "executable not found"),
+            FailReason::NotExecutable => write!(f, "executable is not executable"),
+            FailReason::UserNotFound => write!(f, "user not found"),
+            FailReason::GroupNotFound => write!(f, "group not found"),
+            FailReason::WorkingDirNotFound => write!(f, "working directory not found"),
+            FailReason::RootDirNotFound => write!(f, "root directory not found"),
+            FailReason::LogDirNotWritable => write!(f, "log directory is not writable"),
+            FailReason::DependencyFailed => write!(f, "a required service failed to start"),
+            FailReason::ListenError(errno) => {
+                write!(f, "failed to listen: {}", Errno::from_i32(*errno).desc())
+            }
+            FailReason::SecretError(errno) => {
+                write!(
+                    f,
+                    "failed to read a secret: {}",
+                    Errno::from_i32(*errno).desc()
+                )
+            }
+            FailReason::StartTimeout => write!(f, "start timeout"),
+        }
+    }
+}
+
+/// A resource limit, a number or `"infinity"`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "LimitValue", into = "LimitValue")]
+pub struct Limit(pub u64);
+
+/// A resource limit as written in a service file
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum LimitValue {
+    Value(u64),
+    Keyword(String),
+}
+
+impl TryFrom<LimitValue> for Limit {
+    type Error = String;
+
+    fn try_from(value: LimitValue) -> Result<Self, Self::Error> {
+        match value {
+            LimitValue::Value(value) => Ok(Limit(value)),
+            LimitValue::Keyword(keyword) if keyword == "infinity" => Ok(Limit(RLIM_INFINITY)),
+            LimitValue::Keyword(keyword) => Err(format!(
+                "invalid limit `{keyword}`, expected a number or \"infinity\""
+            )),
+        }
+    }
+}
+
+impl From<Limit> for LimitValue {
+    fn from(limit: Limit) -> Self {
+        match limit {
+            Limit(RLIM_INFINITY) => LimitValue::Keyword("infinity".to_string()),
+            Limit(value) => LimitValue::Value(value),
+        }
+    }
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            RLIM_INFINITY => write!(f, "infinity"),
+            value => write!(f, "{value}"),
+        }
+    }
+}
+
+/// Resource limits of a service, each one sets both the soft and the hard limit
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Limits {
+    /// Maximum number of open files
+    pub nofile: Option<Limit>,
+    /// Maximum number of processes of the user
+    pub nproc: Option<Limit>,
+    /// Maximum size of a core dump, in bytes
+    pub core: Option<Limit>,
+    /// Maximum size of the locked memory, in bytes
+    pub memlock: Option<Limit>,
+    /// Maximum CPU time, in seconds
+    pub cpu: Option<Limit>,
+    /// Maximum size of a file written, in bytes
+    pub fsize: Option<Limit>,
+}
+
+impl Limits {
+    /// The limits with their resource, name and label in `/proc/<pid>/limits`.
+    fn resources(&self) -> [(Resource, &'static str, &'static str, Option<Limit>); 6] {
+        [
+            (
+                Resource::RLIMIT_NOFILE,
+                "nofile",
+                "Max open files",
+                self.nofile,
+            ),
+            (Resource::RLIMIT_NPROC, "nproc", "Max processes", self.nproc),
+            (
+                Resource::RLIMIT_CORE,
+                "core",
+                "Max core file size",
+                self.core,
+            ),
+            (
+                Resource::RLIMIT_MEMLOCK,
+                "memlock",
+                "Max locked memory",
+                self.memlock,
+            ),
+            (Resource::RLIMIT_CPU, "cpu", "Max cpu time", self.cpu),
+            (Resource::RLIMIT_FSIZE, "fsize", "Max file size", self.fsize),
+        ]
+    }
+
+    /// The limit of a resource by its name, e.g. `nofile`.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Option<Limit>> {
+        match name {
+            "nofile" => Some(&mut self.nofile),
+            "nproc" => Some(&mut self.nproc),
+            "core" => Some(&mut self.core),
+            "memlock" => Some(&mut self.memlock),
+            "cpu" => Some(&mut self.cpu),
+            "fsize" => Some(&mut self.fsize),
+            _ => None,
+        }
+    }
+
+    /// Set the limits of the current process.
+    pub fn apply(&self) -> nix::Result<()> {
+        for (resource, _, _, limit) in self.resources() {
+            if let Some(Limit(limit)) = limit {
+                setrlimit(resource, limit, limit)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits of a running process as `(name, soft:hard)`, read from `/proc/<pid>/limits`.
+    pub fn effective(pid: i32) -> Vec<(&'static str, String)> {
+        let Ok(limits) = std::fs::read_to_string(format!("/proc/{pid}/limits")) else {
+            return vec![];
+        };
+
+        Limits::default()
+            .resources()
+            .into_iter()
+            .filter_map(|(_, name, label, _)| {
+                let line = limits.lines().find(|line| line.starts_with(label))?;
+                let mut values = line[label.len()..].split_whitespace();
+                let (soft, hard) = (values.next()?, values.next()?);
+                Some((
+                    name,
+                    format!(
+                        "{}:{}",
+                        soft.replace("unlimited", "infinity"),
+                        hard.replace("unlimited", "infinity")
+                    ),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// A file mode creation mask, written in octal (`"0027"`)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Umask(pub u32);
+
+impl TryFrom<String> for Umask {
+    type Error = String;
+
+    fn try_from(umask: String) -> Result<Self, Self::Error> {
+        match u32::from_str_radix(&umask, 8) {
+            Ok(mask) if mask <= 0o777 => Ok(Umask(mask)),
+            _ => Err(format!(
+                "invalid umask `{umask}`, expected an octal mode like \"0027\""
+            )),
+        }
+    }
+}
+
+impl From<Umask> for String {
+    fn from(umask: Umask) -> Self {
+        umask.to_string()
+    }
+}
+
+impl std::fmt::Display for Umask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
+/// IO scheduling class of a service
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoniceClass {
+    /// Served first, needs root
+    Realtime,
+    /// The default class
+    #[default]
+    BestEffort,
+    /// Only served when no other process needs the disk
+    Idle,
+}
+
+impl std::fmt::Display for IoniceClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoniceClass::Realtime => write!(f, "realtime"),
+            IoniceClass::BestEffort => write!(f, "best-effort"),
+            IoniceClass::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+/// Kind of a dependency between two services
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dependency {
+    /// The service is started after the other one at boot
+    After,
+    /// The other service is started along with it, and it fails if the other one failed
+    Requires,
+    /// The other service is started along with it
+    Wants,
+}
+
+impl std::fmt::Display for Dependency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dependency::After => write!(f, "after"),
+            Dependency::Requires => write!(f, "requires"),
+            Dependency::Wants => write!(f, "wants"),
+        }
+    }
+}
+
+/// A dependency of a service on another one
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Edge {
+    /// Name of the service depending on the other one
+    pub from: String,
+    /// Name of the service it depends on
+    pub to: String,
+    pub dependency: Dependency,
+}
+
+/// When a service is restarted after it exits
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    /// Never restart the service
+    #[default]
+    Never,
+    /// Restart the service if it exited with a non zero code or was killed by a signal
+    OnFailure,
+    /// Always restart the service
+    Always,
+}
+
+/// When a service is started
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Start {
+    /// At boot if it's enabled
+    #[default]
+    Boot,
+    /// When it's started by operatorctl or pulled in by a service that depends on it
+    Manual,
+}
+
+/// How a running service is restarted
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartStrategy {
+    /// Stop the process, then start a new one once it exited
+    #[default]
+    StopThenStart,
+    /// Start a new process, then stop the previous one once the new one is ready
+    StartThenStop,
+}
+
+/// Which processes of a service are signaled to stop it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KillMode {
+    /// All the processes of the service, the ones left are killed once it exited
+    #[default]
+    Group,
+    /// Only the main process, the ones it leaves keep running
+    Process,
+    /// The main process, the ones left are killed with SIGKILL once it exited
+    Mixed,
+}
+
+/// Stdin of the program of a service
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stdin {
+    /// The stdin of operator
+    #[default]
+    Inherit,
+    /// A pipe written by `operatorctl attach --stdin`
+    Pipe,
+}
+
+/// Device, inode and modification time of an executable
+pub type ExecutableId = (u64, u64, i64, i64);
+
+/// How the program of a service is run
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServiceType {
+    /// Fork and exec the executable
+    #[default]
+    Exec,
+    /// Run the `command` with `/bin/sh -c`, the default when `command` is set
+    Shell,
+    /// Run the image in a container with podman or docker
+    Container,
+    /// Run a daemon that forks into the background, tracked with its `pid_file`
+    Forking,
+}
+
+/// How a service is asked to reload its configuration
+#[derive(Debug)]
+pub enum Reload {
+    /// Send a signal to the service
+    Signal(Signal),
+    /// Run a shell command
+    Command(String),
+}
+
+/// Summary of a loaded service sent to operatorctl
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceInfo {
+    /// Name of the service
+    pub name: String,
+    /// What the service is
+    pub description: Option<String>,
+    /// Tags of the service
+    pub tags: Vec<String>,
+    /// The pid of the service if it was started
+    pub pid: Option<i32>,
+    /// The status of the service
+    pub status: Status,
+    /// Whether the service is started at boot
+    pub enabled: bool,
+    /// Nice level of the program
+    pub nice: Option<i32>,
+    /// CPUs the program can run on
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// IO scheduling class and priority of the program
+    pub ionice: Option<(IoniceClass, u8)>,
+    /// umask of the program
+    pub umask: Option<String>,
+    /// Status sent by the program with `STATUS=`
+    pub status_text: Option<String>,
+    /// Memory and CPU usage of the service if it's running
+    pub usage: Option<Usage>,
+    /// When the running service was started, in seconds since the epoch
+    pub active_since: Option<u64>,
+    /// How long the running service has been running
+    pub uptime: Option<Duration>,
+    /// Whether the executable changed since the running service was started
+    #[serde(default)]
+    pub stale: bool,
+    /// Where the core dumped by the last process of the service went
+    #[serde(default)]
+    pub core: Option<String>,
+    /// Diagnostics of the last crash of the service
+    #[serde(default)]
+    pub last_crash: Option<Crash>,
+}
+
+/// Diagnostics of a process of a service that crashed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Crash {
+    /// How the process exited, e.g. `killed by SIGSEGV`
+    pub reason: String,
+    /// The signal that killed the process
+    pub signal: Option<String>,
+    /// When the process exited, in seconds since the epoch
+    pub timestamp: u64,
+    /// The last lines of output of the service
+    pub log: Vec<String>,
+    /// `/proc/<pid>/status` of the exited process, if it was read before it was reaped
+    pub proc_status: Option<String>,
+}
+
+/// Which services are listed, matched by operator
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Filter {
+    /// Glob pattern of the names, e.g. `worker-*`
+    pub pattern: Option<String>,
+    /// Names of the statuses of the services, e.g. `running`, any if empty
+    pub states: Vec<String>,
+    /// Tags the services all have
+    pub tags: Vec<String>,
+}
+
+impl Filter {
+    /// Whether a service is matched.
+    pub fn matches(&self, info: &ServiceInfo) -> bool {
+        self.pattern
+            .as_deref()
+            .is_none_or(|pattern| glob_match(pattern, &info.name))
+            && (self.states.is_empty() || self.states.iter().any(|s| s == info.status.name()))
+            && self.tags.iter().all(|tag| info.tags.contains(tag))
+    }
+}
+
+/// A service file that failed to load
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoadError {
+    /// Path of the service file
+    pub path: PathBuf,
+    /// Why it failed to load
+    pub error: String,
+}
+
+impl LoadError {
+    fn new(path: &Path, error: impl std::fmt::Display) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            error: error.to_string(),
+        }
+    }
+}
+
+impl From<Error> for LoadError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::ParseError { file, source } => LoadError::new(&file, source),
+            e => LoadError::new(Path::new(""), e),
+        }
+    }
+}
+
+/// Timestamps of the start of a service at boot, since operator started.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BootTiming {
+    /// Name of the service
+    pub name: String,
+    /// When its service file was loaded
+    pub loaded: Duration,
+    /// When its process was forked
+    pub forked: Option<Duration>,
+    /// When its process executed the executable
+    pub exec: Option<Duration>,
+    /// When it was ready, services are ready once they executed the executable
+    pub ready: Option<Duration>,
+    /// Services it was started after
+    pub after: Vec<String>,
+}
+
+/// Shell running the `command` of the services.
+/// `PATH` in which the executable is looked up if operator and the service have none,
+/// the one of execvp().
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+/// The args are written as strings, they can't contain a nul byte.
+mod args {
+    use std::ffi::CString;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(args: &Option<Vec<CString>>, s: S) -> Result<S::Ok, S::Error> {
+        args.as_ref()
+            .map(|args| {
+                args.iter()
+                    .map(|arg| arg.to_string_lossy())
+                    .collect::<Vec<_>>()
+            })
+            .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<CString>>, D::Error> {
+        let Some(args) = Option::<Vec<String>>::deserialize(d)? else {
+            return Ok(None);
+        };
+        args.into_iter()
+            .map(|arg| {
+                CString::new(arg).map_err(|e| {
+                    let arg = String::from_utf8_lossy(&e.into_vec()).into_owned();
+                    D::Error::custom(format!("argument {arg:?} contains a nul byte"))
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_inherit_env() -> bool {
+    true
+}
+
+fn default_setsid() -> bool {
+    true
 }
 
 /// Represents a service
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Service {
     /// Name of the service
     pub name: String,
-    /// The path to the executable
+    /// What the service is, shown by operatorctl
+    pub description: Option<String>,
+    /// Free-form tags selecting the service in operatorctl, e.g. `["web", "prod"]`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// How the program is run
+    #[serde(default, rename = "type")]
+    pub service_type: ServiceType,
+    /// The path to the executable, or its name to find it in `PATH`
+    #[serde(default)]
     pub executable: PathBuf,
     /// Arguments to the program
+    #[serde(default, with = "args")]
     pub args: Option<Vec<CString>>,
+    /// Shell command run with `/bin/sh -c` instead of `executable` and `args`
+    pub command: Option<String>,
+    /// Image of the container, the args are passed to its entrypoint
+    pub image: Option<String>,
+    /// Ports published by the container, `"host:container"`
+    #[serde(default)]
+    pub ports: Vec<String>,
+    /// Volumes mounted in the container, `"source:destination"`
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    /// Network the container is connected to
+    pub network: Option<String>,
+    /// File the daemon of a forking service writes its pid to
+    pub pid_file: Option<PathBuf>,
+    /// Command or signal name used to reload the service
+    pub exec_reload: Option<String>,
+    /// Environment variables set for the program
+    pub env: Option<HashMap<String, String>>,
+    /// Env vars set to the contents of files, read when the program is started
+    #[serde(default)]
+    pub secrets: HashMap<String, PathBuf>,
+    /// Whether the program gets the env of operator, only its `pass_environment` if
+    /// set in the config
+    #[serde(default = "default_inherit_env")]
+    pub inherit_env: bool,
+    /// User the program runs as
+    pub user: Option<String>,
+    /// Group the program runs as, defaults to the primary group of `user`
+    pub group: Option<String>,
+    /// Working directory of the program
+    pub working_dir: Option<PathBuf>,
+    /// Where the program reads its stdin from
+    #[serde(default)]
+    pub stdin: Stdin,
+    /// Run the program in a pseudo-terminal, as its controlling terminal and stdio
+    #[serde(default)]
+    pub tty: bool,
+    /// Run the program in a session of its own, detached from the terminal of operator,
+    /// always with `tty`
+    #[serde(default = "default_setsid")]
+    pub setsid: bool,
+    /// File mode creation mask of the program, inherited from operator by default
+    pub umask: Option<Umask>,
+    /// Root directory of the program, the executable and working directory are in it
+    pub root_dir: Option<PathBuf>,
+    /// Mounts made in the mount namespace of the program
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Capabilities the program keeps, all of them are dropped if it's empty
+    pub capabilities: Option<Vec<Capability>>,
+    /// Prevent the program from gaining privileges through setuid binaries
+    #[serde(default)]
+    pub no_new_privs: bool,
+    /// Run the program in a new network namespace with only a loopback interface
+    #[serde(default)]
+    pub private_network: bool,
+    /// veth pair linking the network namespace of the program to the host
+    pub veth: Option<Veth>,
+    /// Socket bound by operator and passed to the program
+    pub socket: Option<Socket>,
+    /// The program notifies operator it's ready by sending `READY=1` on `NOTIFY_SOCKET`
+    #[serde(default)]
+    pub notify: bool,
+    /// The program is killed if it doesn't send `WATCHDOG=1` within this many seconds
+    pub watchdog_sec: Option<u64>,
+    /// The program is killed and failed if it isn't ready within this many seconds
+    pub start_timeout_secs: Option<u64>,
+    /// The program is killed with SIGKILL if it didn't exit within this many seconds of
+    /// being asked to stop
+    pub stop_timeout_secs: Option<u64>,
+    /// Whether the service is started at boot
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// When the service is started
+    #[serde(default)]
+    pub start: Start,
+    /// The service is started at boot once this many seconds elapsed since
+    pub start_delay_secs: Option<u64>,
+    /// Number of instances of a template service started at boot
+    pub instances: Option<u32>,
+    /// Number of replicas of the service
+    pub replicas: Option<u32>,
+    /// When the service is restarted after it exits
+    #[serde(default)]
+    pub restart: Restart,
+    /// How the running service is restarted by operatorctl or its schedule
+    #[serde(default)]
+    pub restart_strategy: RestartStrategy,
+    /// The running service is restarted on this schedule, e.g. `"24h"` or `"0 4 * * *"`
+    pub restart_every: Option<Schedule>,
+    /// The running service is restarted when its executable is replaced, e.g. by a deploy
+    #[serde(default)]
+    pub restart_on_binary_change: bool,
+    /// Exit codes restarting the service whatever its `restart` policy
+    #[serde(default)]
+    pub restart_on_exit_codes: Vec<u8>,
+    /// Exit codes of a clean exit besides 0, the service is not restarted on failure
+    #[serde(default)]
+    pub success_exit_codes: Vec<u8>,
+    /// Which processes are signaled to stop the service
+    #[serde(default)]
+    pub kill_mode: KillMode,
+    /// Services started before this one at boot
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Services started along with this one, it fails to start if one of them failed
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Services started along with this one
+    #[serde(default)]
+    pub wants: Vec<String>,
+    /// Services started when this one fails
+    #[serde(default)]
+    pub on_failure: Vec<String>,
+    /// Resource limits of the program
+    #[serde(default)]
+    pub limits: Limits,
+    /// Nice level of the program, from -20 (highest priority) to 19
+    pub nice: Option<i32>,
+    /// CPUs the program can run on
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// IO scheduling class of the program, defaults to best-effort if `ionice_level` is set
+    pub ionice_class: Option<IoniceClass>,
+    /// IO priority of the program in its class, from 0 (highest) to 7
+    pub ionice_level: Option<u8>,
+    /// Maximum memory used by the service, in bytes
+    pub memory_max: Option<u64>,
+    /// CPU weight of the service, from 1 to 10000, defaults to 100
+    pub cpu_weight: Option<u64>,
+    /// CPU bandwidth of the service as `"$MAX $PERIOD"` in microseconds
+    pub cpu_max: Option<String>,
+    /// Maximum number of processes of the service
+    pub pids_max: Option<u64>,
 
     /// The pid of the service
     #[serde(skip)]
@@ -41,20 +792,160 @@ pub struct Service {
     /// The exit code of the service if it exited
     #[serde(skip)]
     pub exit_code: Option<u8>,
+
+    /// Whether the service is masked
+    #[serde(skip)]
+    pub masked: bool,
+
+    /// Whether the service was asked to stop
+    #[serde(skip)]
+    pub stopping: bool,
+
+    /// When the service was last started
+    #[serde(skip)]
+    pub started_at: Option<Instant>,
+
+    /// When the service was last started, in seconds since the epoch
+    #[serde(skip)]
+    pub active_since: Option<u64>,
+
+    /// Number of consecutive restarts of the service
+    #[serde(skip)]
+    pub restarts: u32,
+
+    /// The name of the replicated service and the index of this replica
+    #[serde(skip)]
+    pub replica: Option<(String, u32)>,
+
+    /// Whether the process of a forking service didn't fork its daemon and exit yet
+    #[serde(skip)]
+    pub forking: bool,
+
+    /// The cgroup of the service if it was created
+    #[serde(skip)]
+    pub cgroup: Option<PathBuf>,
+
+    /// The listening socket passed to the process of the service
+    #[serde(skip)]
+    pub listen_fd: Option<RawFd>,
+
+    /// The write end of the pipe capturing the output of the process of the service
+    #[serde(skip)]
+    pub output_fd: Option<RawFd>,
+
+    /// The read end of the stdin pipe, or the terminal, of the process of the service
+    #[serde(skip)]
+    pub input_fd: Option<RawFd>,
+
+    /// The notification socket passed to the process of the service
+    #[serde(skip)]
+    pub notify_socket: Option<PathBuf>,
+
+    /// Whether the process of the service notified it's ready
+    #[serde(skip)]
+    pub ready: bool,
+
+    /// The last status sent by the process of the service with `STATUS=`
+    #[serde(skip)]
+    pub status_text: Option<String>,
+
+    /// The timer killing the service if it doesn't send `WATCHDOG=1` in time
+    #[serde(skip)]
+    pub watchdog: Option<TimerId>,
+
+    /// The timer killing the service if it isn't ready in time
+    #[serde(skip)]
+    pub start_timeout: Option<TimerId>,
+
+    /// Whether the service was killed for not being ready in time
+    #[serde(skip)]
+    pub timed_out: bool,
+
+    /// The timer killing the service if it doesn't exit in time once asked to stop
+    #[serde(skip)]
+    pub stop_timeout: Option<TimerId>,
+
+    /// The timer restarting the service after it exited
+    #[serde(skip)]
+    pub restart_timer: Option<TimerId>,
+
+    /// The timer restarting the service on its `restart_every` schedule
+    #[serde(skip)]
+    pub scheduled_restart: Option<TimerId>,
+
+    /// Whether the service is stopping to be restarted on its schedule
+    #[serde(skip)]
+    pub restarting_on_schedule: bool,
+
+    /// The executable the running service was started from
+    #[serde(skip)]
+    pub executable_id: Option<ExecutableId>,
+
+    /// The executable found replacing it at the last sample, acted on once it's unchanged
+    #[serde(skip)]
+    pub executable_change: Option<ExecutableId>,
+
+    /// The executable that replaced the one the running service was started from
+    #[serde(skip)]
+    pub replaced_by: Option<ExecutableId>,
+
+    /// The last sample of the memory and CPU usage of the running service
+    #[serde(skip)]
+    pub usage: Option<(Usage, CpuSample)>,
+
+    /// The secrets exported to the process about to be started
+    #[serde(skip)]
+    pub secret_env: Secrets,
+
+    /// The env vars of the failure the process about to be started handles
+    #[serde(skip)]
+    pub failure_env: HashMap<String, String>,
+
+    /// The env vars of operator passed to the process about to be started, all of them
+    /// if `None`
+    #[serde(skip)]
+    pub passed_env: Option<Vec<String>>,
+
+    /// Where the core dumped by the last process went
+    #[serde(skip)]
+    pub core: Option<String>,
+
+    /// Diagnostics of the last crash
+    #[serde(skip)]
+    pub last_crash: Option<Crash>,
+
+    /// The service file and its drop-ins, in the order they were merged
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
 }
 
 impl Service {
     /// Start the service.
     ///
-    /// This should only be run in the context of a forked child process.
+    /// This should only be run by the [executor](crate::executor), or in a child forked
+    /// by a single threaded process, as the setup isn't async-signal-safe.
+    /// If exec() fails, the errno is written to `error_pipe`, which should be close-on-exec
+    /// so the parent reads EOF when exec() succeeds.
     ///
     /// This will not return.
-    pub fn start(&self) -> ! {
+    pub fn start(&self, error_pipe: OwnedFd) -> ! {
         info!("{}: executing {:?}", self.name, self.executable);
 
+        if let Err(e) = self.setup_child() {
+            error!("Failed to setup the process of {}: {e}", self.name);
+            // running the service without its sandbox or privileges dropped is worse
+            // than not running it.
+            let errno = e.raw_os_error().unwrap_or(0);
+            if let Err(e) = File::from(error_pipe).write_all(&errno.to_le_bytes()) {
+                error!("Failed to report the setup error: {e}");
+            }
+            exit(-1)
+        }
+
         let exe_path = CString::new(self.executable.to_str().unwrap()).unwrap();
 
-        let mut args = if let Some(ref args) = self.args {
+        let args = self.exec_args();
+        let mut args = if let Some(ref args) = args {
             [exe_path.as_ptr()]
                 .into_iter()
                 .chain(args.iter().map(|arg| arg.as_ptr()))
@@ -66,7 +957,88 @@ impl Service {
         // null terminate the args array
         args.push(core::ptr::null());
 
-        // create the log file for the service
+        // an executable without a `/` is looked up in `PATH`.
+        let res = unsafe { nix::libc::execvp(exe_path.as_ptr(), args.as_ptr()) };
+        let errno = errno();
+
+        error!("exec() Failed with {res}");
+        error!("errno: {}", Errno::from_i32(errno));
+        if let Err(e) = File::from(error_pipe).write_all(&errno.to_le_bytes()) {
+            error!("Failed to report the exec() error: {e}");
+        }
+        exit(-1)
+    }
+
+    /// Backend running the program of the service.
+    pub fn backend(&self) -> &'static dyn ServiceBackend {
+        backend::of(self.service_type)
+    }
+
+    /// Arguments the executable is run with.
+    pub fn exec_args(&self) -> Option<Vec<CString>> {
+        self.backend().args(self)
+    }
+
+    /// Send a signal asking the service to stop to the processes of its `kill_mode`.
+    pub fn signal_stop(&self, signal: Signal) {
+        match (self.kill_mode, self.pid) {
+            (KillMode::Group, _) => self.signal(signal),
+            (KillMode::Process | KillMode::Mixed, Some(pid)) => {
+                if let Err(e) = kill(Pid::from_raw(pid), signal) {
+                    error!("kill() failed with {e}");
+                }
+            }
+            (_, None) => {}
+        }
+    }
+
+    /// Kill the processes left by the stopped service, according to its `kill_mode`.
+    pub fn kill_remaining(&self) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+        match self.kill_mode {
+            KillMode::Process => return,
+            // the process group outlives its leader while it has members.
+            KillMode::Mixed => _ = kill(Pid::from_raw(-pid), Signal::SIGKILL),
+            KillMode::Group => {}
+        }
+        if let Some(ref cgroup) = self.cgroup {
+            if !cgroup::procs(cgroup).is_empty() {
+                info!("Killing the processes left by service {}", self.name);
+                if let Err(e) = cgroup::kill(cgroup) {
+                    error!("Failed to kill the cgroup of {}: {e}", self.name);
+                }
+            }
+        }
+    }
+
+    /// Send a signal to all the processes of the service.
+    pub fn signal(&self, signal: Signal) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+
+        // the cgroup also has the processes that left the process group of the service.
+        if let Some(ref cgroup) = self.cgroup {
+            for pid in cgroup::procs(cgroup) {
+                _ = kill(Pid::from_raw(pid), signal);
+            }
+        }
+
+        // the service is the leader of its process group, unless it hasn't called setsid() yet.
+        let res = match kill(Pid::from_raw(-pid), signal) {
+            Err(Errno::ESRCH) => kill(Pid::from_raw(pid), signal),
+            res => res,
+        };
+
+        if let Err(e) = res {
+            error!("kill() failed with {e}");
+        }
+    }
+
+    /// Open the log file of the service in the process of the service.
+    fn open_log(&self) -> std::io::Result<RawFd> {
         let stdout_file_path =
             CString::new(format!("{}/{}.log", op_service_log_dir(), self.name)).unwrap();
         let log_fd = unsafe {
@@ -79,6 +1051,7 @@ impl Service {
 
         if log_fd == -1 {
             error!("Failed to create log file {}", Errno::from_i32(errno()));
+            return Err(std::io::Error::last_os_error());
         }
 
         info!(
@@ -86,34 +1059,1011 @@ impl Service {
             self.name, stdout_file_path
         );
 
-        // set the stdout and stderr to the log file
+        Ok(log_fd)
+    }
+
+    /// Setup the process of the service before exec.
+    ///
+    /// This should only be run in the context of a forked child process.
+    pub fn setup_child(&self) -> std::io::Result<()> {
+        // the signal mask is inherited across exec, unblock the signals operator reads
+        // from its signalfd.
+        SigSet::empty().thread_set_mask()?;
+        self.pass_env()?;
+
+        // run in a new session so the service and its children share a process group
+        // that can be terminated as a whole, and the terminal of operator doesn't signal
+        // them. without, the process group is still their own.
+        if self.setsid || self.tty {
+            setsid()?;
+        } else {
+            setpgid(Pid::from_raw(0), Pid::from_raw(0))?;
+        }
+
+        // the output is captured by operator, or written to the log file if it couldn't be.
+        let log_fd = match self.output_fd {
+            Some(fd) => fd,
+            None => self.open_log()?,
+        };
+
+        // set the stdout and stderr to the pipe or the log file
         unsafe {
             dup2(log_fd, STDOUT_FILENO);
             dup2(log_fd, STDERR_FILENO);
+            if let Some(fd) = self.input_fd {
+                dup2(fd, STDIN_FILENO);
+            }
+        }
+        // the terminal is controlled by the new session, its keys send signals that may
+        // be ignored by operator, e.g. SIGINT when it was started in the background.
+        if self.tty && self.output_fd.is_some() {
+            Errno::result(unsafe { libc::ioctl(STDIN_FILENO, libc::TIOCSCTTY, 0) })?;
+            for signal in [Signal::SIGINT, Signal::SIGQUIT, Signal::SIGTSTP] {
+                unsafe { signal::signal(signal, SigHandler::SigDfl) }?;
+            }
         }
 
-        let res = unsafe { nix::libc::execv(exe_path.as_ptr(), args.as_ptr()) };
+        if let Some(fd) = self.listen_fd {
+            socket::pass(fd, &self.name)?;
+        }
+        if let Some(ref path) = self.notify_socket {
+            notify::pass(path, self.watchdog_interval())?;
+        }
 
-        error!("exec() Failed with {res}");
-        error!("errno: {}", Errno::from_i32(errno()));
-        exit(-1)
+        // the program only inherits its stdio and socket, not e.g. the IPC socket of
+        // operator or the log files of the other services.
+        close_on_exec(match self.listen_fd {
+            Some(_) => socket::LISTEN_FDS_START + 1,
+            None => socket::LISTEN_FDS_START,
+        })?;
+
+        // the log file is opened first since it's outside of the root dir.
+        self.enter_context(None)?;
+
+        self.backend().prepare(self)
+    }
+
+    /// Move the current process in the context of the service: its cgroup, namespaces,
+    /// working dir, limits, user and env.
+    ///
+    /// The namespaces of `running`, the process of the running service, are joined
+    /// instead of creating new ones.
+    /// This should only be run in the context of a forked child process.
+    pub fn enter_context(&self, running: Option<i32>) -> std::io::Result<()> {
+        if let Some(ref cgroup) = self.cgroup {
+            cgroup::join(cgroup)?;
+        }
+
+        let private_network = self.private_network || self.veth.is_some();
+        match running {
+            Some(pid) => {
+                if private_network {
+                    network::join(pid)?;
+                }
+                if self.root_dir.is_some() || !self.mounts.is_empty() {
+                    sandbox::join(pid)?;
+                }
+            }
+            None => {
+                // before the root dir changes, `ip` may not be in it.
+                if private_network {
+                    network::enter(self.veth.as_ref())?;
+                }
+                sandbox::enter(self.root_dir.as_deref(), &self.mounts)?;
+            }
+        }
+
+        if let Some(ref working_dir) = self.working_dir {
+            chdir(working_dir)?;
+        }
+
+        if let Some(Umask(mask)) = self.umask {
+            umask(Mode::from_bits_truncate(mask));
+        }
+
+        // raising hard limits, a negative nice level and the realtime IO class need the
+        // privileges we are about to drop.
+        self.limits.apply()?;
+        self.set_scheduling()?;
+
+        if let Some(ref caps) = self.capabilities {
+            capabilities::bound(caps)?;
+        }
+
+        // drop privileges last, the group first since we can't change it as the user.
+        let user = match self.user {
+            Some(ref user) => Some(User::from_name(user)?.ok_or(Errno::EINVAL)?),
+            None => None,
+        };
+        let gid = match self.group {
+            Some(ref group) => Some(Group::from_name(group)?.ok_or(Errno::EINVAL)?.gid),
+            None => user.as_ref().map(|user| user.gid),
+        };
+
+        if let Some(gid) = gid {
+            if let Some(ref user) = user {
+                initgroups(&CString::new(user.name.as_str()).unwrap(), gid)?;
+            }
+            setgid(gid)?;
+        }
+        if let Some(user) = user {
+            setuid(user.uid)?;
+        }
+
+        if let Some(ref caps) = self.capabilities {
+            capabilities::restrict(caps)?;
+        }
+        if self.no_new_privs {
+            capabilities::no_new_privs()?;
+        }
+
+        if let Some(ref env) = self.env {
+            for (key, value) in env {
+                setenv(key, value)?;
+            }
+        }
+        for (key, value) in &self.failure_env {
+            setenv(key, value)?;
+        }
+        self.secret_env.export()?;
+        Ok(())
+    }
+
+    /// Pass the env vars of operator to the program, all of them or the ones of
+    /// `pass_environment` from [Config](crate::config::Config), none without
+    /// `inherit_env`.
+    pub fn pass_environment(&mut self, pass_environment: Option<&[String]>) {
+        self.passed_env = match pass_environment {
+            _ if !self.inherit_env => Some(vec![]),
+            pass_environment => pass_environment.map(<[String]>::to_vec),
+        };
+    }
+
+    /// Remove the env vars of operator that aren't passed to the program, it gets the
+    /// default `PATH` if its own isn't passed.
+    ///
+    /// This should only be run in the context of a forked child process.
+    pub(crate) fn pass_env(&self) -> std::io::Result<()> {
+        if let Some(ref passed) = self.passed_env {
+            retain_env(passed)?;
+            if std::env::var_os("PATH").is_none() {
+                setenv("PATH", DEFAULT_PATH)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the nice level, CPU affinity and IO priority of the current process.
+    fn set_scheduling(&self) -> std::io::Result<()> {
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        if let Some(ref cpus) = self.cpu_affinity {
+            let mut cpu_set = CpuSet::new();
+            for cpu in cpus {
+                cpu_set.set(*cpu)?;
+            }
+            sched_setaffinity(nix::unistd::Pid::from_raw(0), &cpu_set)?;
+        }
+
+        if let Some((class, level)) = self.ionice() {
+            // see ioprio_set(2).
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            const IOPRIO_CLASS_SHIFT: u32 = 13;
+            let class = match class {
+                IoniceClass::Realtime => 1,
+                IoniceClass::BestEffort => 2,
+                IoniceClass::Idle => 3,
+            };
+            let ioprio = (class << IOPRIO_CLASS_SHIFT) | level as u32;
+
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// umask of the process while it's running, as it may have changed it, otherwise
+    /// the configured one.
+    fn effective_umask(&self) -> Option<String> {
+        match self.pid {
+            Some(pid) if matches!(self.status, Some(Status::Running)) => {
+                let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+                status
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Umask:"))
+                    .map(|umask| umask.trim().to_string())
+            }
+            _ => self.umask.map(|umask| umask.to_string()),
+        }
+    }
+
+    /// IO scheduling class and priority of the service, if set.
+    fn ionice(&self) -> Option<(IoniceClass, u8)> {
+        match (self.ionice_class, self.ionice_level) {
+            (None, None) => None,
+            // the idle class has no priority levels.
+            (Some(IoniceClass::Idle), _) => Some((IoniceClass::Idle, 0)),
+            (class, level) => Some((class.unwrap_or_default(), level.unwrap_or(4))),
+        }
+    }
+
+    /// Whether the service is running but didn't notify it's ready yet.
+    pub fn is_starting(&self) -> bool {
+        matches!(self.status, Some(Status::Running)) && (self.notify || self.forking) && !self.ready
+    }
+
+    /// Whether the service is started at boot by itself, not only when pulled in.
+    pub fn starts_at_boot(&self) -> bool {
+        self.enabled && self.start == Start::Boot && !self.masked
+    }
+
+    /// How long after boot the service is started.
+    pub fn start_delay(&self) -> Option<Duration> {
+        self.start_delay_secs.map(Duration::from_secs)
+    }
+
+    /// How long the service has to become ready.
+    pub fn start_timeout(&self) -> Option<Duration> {
+        self.start_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// How long the service has to exit once asked to stop.
+    pub fn stop_timeout(&self) -> Option<Duration> {
+        self.stop_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// Interval in which the service has to send `WATCHDOG=1`.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_sec.map(Duration::from_secs)
+    }
+
+    /// Check that the service can be started.
+    pub fn validate(&self) -> Result<(), FailReason> {
+        let root = self.root_dir.as_deref().unwrap_or(Path::new("/"));
+        if !root.is_dir() {
+            return Err(FailReason::RootDirNotFound);
+        }
+
+        let executable = self
+            .find_executable(root)
+            .ok_or(FailReason::ExecutableNotFound)?;
+        if access(&executable, AccessFlags::X_OK).is_err() {
+            return Err(FailReason::NotExecutable);
+        }
+        if let Some(ref user) = self.user {
+            if !matches!(User::from_name(user), Ok(Some(_))) {
+                return Err(FailReason::UserNotFound);
+            }
+        }
+        if let Some(ref group) = self.group {
+            if !matches!(Group::from_name(group), Ok(Some(_))) {
+                return Err(FailReason::GroupNotFound);
+            }
+        }
+        if let Some(ref working_dir) = self.working_dir {
+            if !sandbox::in_root(root, working_dir).is_dir() {
+                return Err(FailReason::WorkingDirNotFound);
+            }
+        }
+        if access(op_service_log_dir().as_str(), AccessFlags::W_OK).is_err() {
+            return Err(FailReason::LogDirNotWritable);
+        }
+
+        Ok(())
+    }
+
+    /// Path of the executable in `root`, looked up in `PATH` like execvp() does if it has
+    /// no `/`.
+    fn find_executable(&self, root: &Path) -> Option<PathBuf> {
+        if self.executable.to_string_lossy().contains('/') {
+            let executable = sandbox::in_root(root, &self.executable);
+            return executable.is_file().then_some(executable);
+        }
+
+        std::env::split_paths(&self.path())
+            .map(|dir| sandbox::in_root(root, &dir.join(&self.executable)))
+            .find(|executable| executable.is_file())
+    }
+
+    /// Identity of the executable of the service, to detect that it was replaced.
+    ///
+    /// Only the programs run directly are watched, not the shell or the container runtime.
+    pub fn executable_id(&self) -> Option<ExecutableId> {
+        if !matches!(self.service_type, ServiceType::Exec | ServiceType::Forking) {
+            return None;
+        }
+        let root = self.root_dir.as_deref().unwrap_or(Path::new("/"));
+        let metadata = std::fs::metadata(self.find_executable(root)?).ok()?;
+        Some((
+            metadata.dev(),
+            metadata.ino(),
+            metadata.mtime(),
+            metadata.mtime_nsec(),
+        ))
+    }
+
+    /// `PATH` of the program, it gets the env passed by operator along with the one of
+    /// the service.
+    pub(crate) fn path(&self) -> String {
+        self.env
+            .as_ref()
+            .and_then(|env| env.get("PATH").cloned())
+            .or_else(|| match self.passed_env {
+                Some(ref passed) if !passed.iter().any(|key| key == "PATH") => None,
+                _ => std::env::var("PATH").ok(),
+            })
+            .unwrap_or_else(|| DEFAULT_PATH.to_string())
+    }
+
+    /// Whether the service is a template (`worker@`) to be instantiated as `worker@<instance>`.
+    pub fn is_template(&self) -> bool {
+        self.name.ends_with('@')
+    }
+
+    /// Create an instance of a template service.
+    ///
+    /// `%i` in args and env is replaced by the instance.
+    pub fn instantiate(&self, instance: &str) -> Service {
+        self.with_instance(format!("{}{instance}", self.name), instance)
+    }
+
+    /// Create the replica `index` (`name-<index>`) of a replicated service.
+    ///
+    /// `%i` in args and env is replaced by the index.
+    pub fn replica(&self, index: u32) -> Service {
+        let mut service = self.with_instance(format!("{}-{index}", self.name), &index.to_string());
+        service.replica = Some((self.name.clone(), index));
+        service
+    }
+
+    /// Copy the service as `name`, replacing `%i` by `instance`.
+    fn with_instance(&self, name: String, instance: &str) -> Service {
+        let expand = |s: &str| s.replace("%i", instance);
+
+        // the variables of the template were checked when it was loaded.
+        let mut service = self.expanded(&name, Some(instance)).unwrap_or_else(|e| {
+            error!("Failed to expand the variables of {name}: {e}");
+            self.clone()
+        });
+        service.name = name;
+        service.instances = None;
+        service.replicas = None;
+        service.after = self.after.iter().map(|dep| expand(dep)).collect();
+        service.requires = self.requires.iter().map(|dep| expand(dep)).collect();
+        service.wants = self.wants.iter().map(|dep| expand(dep)).collect();
+        service.on_failure = self.on_failure.iter().map(|dep| expand(dep)).collect();
+        service.apply_markers();
+
+        service
+    }
+
+    /// Dependencies of the service on other services.
+    pub fn edges(&self) -> Vec<Edge> {
+        let edges = |deps: &[String], dependency| {
+            deps.iter()
+                .map(|dep| Edge {
+                    from: self.name.clone(),
+                    to: dep.clone(),
+                    dependency,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        [
+            edges(&self.after, Dependency::After),
+            edges(&self.requires, Dependency::Requires),
+            edges(&self.wants, Dependency::Wants),
+        ]
+        .concat()
+    }
+
+    /// Keep the runtime state of the loaded service `loaded`, this one being its service
+    /// file read again.
+    pub fn keep_runtime(&mut self, loaded: &Service) {
+        self.pid = loaded.pid;
+        self.status = loaded.status;
+        self.exit_code = loaded.exit_code;
+        self.stopping = loaded.stopping;
+        self.started_at = loaded.started_at;
+        self.active_since = loaded.active_since;
+        self.restarts = loaded.restarts;
+        self.replica = loaded.replica.clone();
+        self.forking = loaded.forking;
+        self.cgroup = loaded.cgroup.clone();
+        self.listen_fd = loaded.listen_fd;
+        self.output_fd = loaded.output_fd;
+        self.input_fd = loaded.input_fd;
+        self.notify_socket = loaded.notify_socket.clone();
+        self.ready = loaded.ready;
+        self.status_text = loaded.status_text.clone();
+        self.watchdog = loaded.watchdog;
+        self.start_timeout = loaded.start_timeout;
+        self.timed_out = loaded.timed_out;
+        self.restart_timer = loaded.restart_timer;
+        self.scheduled_restart = loaded.scheduled_restart;
+        self.restarting_on_schedule = loaded.restarting_on_schedule;
+        self.executable_id = loaded.executable_id;
+        self.executable_change = loaded.executable_change;
+        self.replaced_by = loaded.replaced_by;
+        self.usage = loaded.usage;
+        self.apply_markers();
+    }
+
+    /// Apply the persistent state set by operatorctl.
+    pub(crate) fn apply_markers(&mut self) {
+        // markers set by `operatorctl enable|disable` take precedence.
+        if Self::enabled_marker(&self.name, true).exists() {
+            self.enabled = true;
+        } else if Self::enabled_marker(&self.name, false).exists() {
+            self.enabled = false;
+        }
+        self.masked = Self::marker(&self.name, "masked").exists();
+    }
+
+    /// Configuration and runtime state of the service as `(key, value)` pairs.
+    ///
+    /// The limits shown are the effective ones of the process if it's running.
+    pub fn properties(&self) -> Vec<(String, String)> {
+        let info = self.info();
+        let mut properties = vec![
+            ("name".to_string(), self.name.clone()),
+            ("status".to_string(), format!("{:?}", info.status)),
+            ("enabled".to_string(), self.enabled.to_string()),
+            ("start".to_string(), format!("{:?}", self.start)),
+            (
+                "executable".to_string(),
+                self.executable.display().to_string(),
+            ),
+        ];
+        let mut push = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                properties.push((key.to_string(), value));
+            }
+        };
+
+        push("description", self.description.clone());
+        push("tags", (!self.tags.is_empty()).then(|| self.tags.join(" ")));
+        push("pid", self.pid.map(|pid| pid.to_string()));
+        if let Some(pid) = self
+            .pid
+            .filter(|_| matches!(self.status, Some(Status::Running)))
+        {
+            let pid = Some(Pid::from_raw(pid));
+            push("session", getsid(pid).ok().map(|sid| sid.to_string()));
+            push("pgid", getpgid(pid).ok().map(|pgid| pgid.to_string()));
+        }
+        push(
+            "active_since",
+            info.active_since.map(|since| since.to_string()),
+        );
+        push(
+            "uptime",
+            info.uptime.map(|uptime| uptime.as_secs().to_string()),
+        );
+        push(
+            "sources",
+            (!self.sources.is_empty()).then(|| {
+                self.sources
+                    .iter()
+                    .map(|source| source.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }),
+        );
+        push("command", self.command.clone());
+        push(
+            "args",
+            self.args.as_ref().map(|args| {
+                args.iter()
+                    .map(|arg| arg.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }),
+        );
+        push("type", Some(format!("{:?}", self.service_type)));
+        for (key, value) in self.backend().properties(self) {
+            push(&key, Some(value));
+        }
+        if let Some((set, index)) = self.replica.as_ref() {
+            push("replica_of", Some(set.clone()));
+            push("replica", Some(index.to_string()));
+        }
+        // the variables are shown expanded, as the process gets them.
+        let mut env = self.env.iter().flatten().collect::<Vec<_>>();
+        env.sort();
+        for (key, value) in env {
+            push(&format!("env.{key}"), Some(value.clone()));
+        }
+        push("inherit_env", Some(self.inherit_env.to_string()));
+        push("exec_reload", self.exec_reload.clone());
+        push("user", self.user.clone());
+        push("group", self.group.clone());
+        push(
+            "working_dir",
+            self.working_dir
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+        );
+        push("stdin", Some(format!("{:?}", self.stdin)));
+        push("tty", Some(self.tty.to_string()));
+        push("setsid", Some(self.setsid.to_string()));
+        push("restart", Some(format!("{:?}", self.restart)));
+        push(
+            "restart_on_exit_codes",
+            Some(format!("{:?}", self.restart_on_exit_codes)),
+        );
+        push(
+            "success_exit_codes",
+            Some(format!("{:?}", self.success_exit_codes)),
+        );
+        push("kill_mode", Some(format!("{:?}", self.kill_mode)));
+        push(
+            "restart_strategy",
+            Some(format!("{:?}", self.restart_strategy)),
+        );
+        push(
+            "restart_every",
+            self.restart_every.as_ref().map(ToString::to_string),
+        );
+        push(
+            "restart_on_binary_change",
+            Some(self.restart_on_binary_change.to_string()),
+        );
+        push("restarts", Some(self.restarts.to_string()));
+        push("exit_code", self.exit_code.map(|code| code.to_string()));
+        for (key, deps) in [
+            ("after", &self.after),
+            ("requires", &self.requires),
+            ("wants", &self.wants),
+        ] {
+            push(key, (!deps.is_empty()).then(|| deps.join(" ")));
+        }
+        push(
+            "on_failure",
+            (!self.on_failure.is_empty()).then(|| self.on_failure.join(" ")),
+        );
+        push(
+            "root_dir",
+            self.root_dir.as_ref().map(|dir| dir.display().to_string()),
+        );
+        push(
+            "capabilities",
+            self.capabilities.as_ref().map(|caps| {
+                caps.iter()
+                    .map(|cap| cap.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }),
+        );
+        push("no_new_privs", Some(self.no_new_privs.to_string()));
+        push(
+            "private_network",
+            Some((self.private_network || self.veth.is_some()).to_string()),
+        );
+        if let Some(ref veth) = self.veth {
+            push("veth.name", Some(veth.name.clone()));
+            push("veth.host_address", veth.host_address.clone());
+            push("veth.address", veth.address.clone());
+            push("veth.gateway", veth.gateway.clone());
+        }
+        push("notify", Some(self.notify.to_string()));
+        push(
+            "notify_socket",
+            self.notify_socket
+                .as_ref()
+                .map(|path| path.display().to_string()),
+        );
+        push("ready", Some(self.ready.to_string()));
+        push(
+            "watchdog_sec",
+            self.watchdog_sec.map(|watchdog| watchdog.to_string()),
+        );
+        push(
+            "start_delay_secs",
+            self.start_delay_secs.map(|delay| delay.to_string()),
+        );
+        push(
+            "start_timeout_secs",
+            self.start_timeout_secs.map(|timeout| timeout.to_string()),
+        );
+        push(
+            "stop_timeout_secs",
+            self.stop_timeout_secs.map(|timeout| timeout.to_string()),
+        );
+        push("status_text", self.status_text.clone());
+        if let Some(ref socket) = self.socket {
+            push("socket.listen", Some(socket.listen.to_string()));
+            push("socket.eager", Some(socket.eager.to_string()));
+        }
+        if !self.mounts.is_empty() {
+            let binds = |binds: &[sandbox::BindMount]| {
+                binds
+                    .iter()
+                    .map(|bind| bind.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            push("mounts.read_only", Some(self.mounts.read_only.to_string()));
+            push(
+                "mounts.private_tmp",
+                Some(self.mounts.private_tmp.to_string()),
+            );
+            push("mounts.bind", Some(binds(&self.mounts.bind)));
+            push(
+                "mounts.bind_read_only",
+                Some(binds(&self.mounts.bind_read_only)),
+            );
+        }
+        // only the files of the secrets are shown, never their contents.
+        let mut secrets = self.secrets.iter().collect::<Vec<_>>();
+        secrets.sort();
+        for (key, file) in secrets {
+            push(&format!("secrets.{key}"), Some(file.display().to_string()));
+        }
+        push("umask", self.effective_umask());
+        push("nice", self.nice.map(|nice| nice.to_string()));
+        push(
+            "cpu_affinity",
+            self.cpu_affinity.as_ref().map(|cpus| {
+                cpus.iter()
+                    .map(|cpu| cpu.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }),
+        );
+        push(
+            "ionice",
+            self.ionice()
+                .map(|(class, level)| format!("{class} {level}")),
+        );
+
+        push("memory_max", self.memory_max.map(|max| max.to_string()));
+        push(
+            "cpu_weight",
+            self.cpu_weight.map(|weight| weight.to_string()),
+        );
+        push("cpu_max", self.cpu_max.clone());
+        push("pids_max", self.pids_max.map(|max| max.to_string()));
+        push(
+            "cgroup",
+            self.cgroup
+                .as_ref()
+                .map(|cgroup| cgroup.display().to_string()),
+        );
+
+        match self.pid {
+            Some(pid) if matches!(self.status, Some(Status::Running)) => {
+                for (name, limit) in Limits::effective(pid) {
+                    push(&format!("limits.{name}"), Some(limit));
+                }
+            }
+            _ => {
+                for (_, name, _, limit) in self.limits.resources() {
+                    push(
+                        &format!("limits.{name}"),
+                        limit.map(|limit| limit.to_string()),
+                    );
+                }
+            }
+        }
+
+        properties
     }
 
-    /// Read the services files located in /tmp/op
-    pub fn read_service_files() -> std::io::Result<Vec<Service>> {
+    /// Status of the service as shown to the user.
+    pub fn current_status(&self) -> Status {
+        match self.status {
+            Some(Status::Running) if self.is_starting() => Status::Starting,
+            Some(Status::Running) => Status::Running,
+            _ if self.masked => Status::Masked,
+            status => status.unwrap_or(Status::Stopped),
+        }
+    }
+
+    /// Summary of the service.
+    pub fn info(&self) -> ServiceInfo {
+        // the start of a service that is not running anymore is not shown.
+        let active = matches!(self.status, Some(Status::Running));
+        ServiceInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            tags: self.tags.clone(),
+            pid: self.pid,
+            status: self.current_status(),
+            enabled: self.enabled,
+            nice: self.nice,
+            cpu_affinity: self.cpu_affinity.clone(),
+            ionice: self.ionice(),
+            umask: self.effective_umask(),
+            status_text: self.status_text.clone(),
+            usage: self.usage.map(|(usage, _)| usage),
+            active_since: self.active_since.filter(|_| active),
+            uptime: self
+                .started_at
+                .filter(|_| active)
+                .map(|started_at| started_at.elapsed()),
+            stale: self.replaced_by.is_some() && active,
+            core: self.core.clone(),
+            last_crash: self.last_crash.clone(),
+        }
+    }
+
+    /// Path of a marker file that records persistent state of a service.
+    fn marker(name: &str, marker: &str) -> PathBuf {
+        PathBuf::from(format!("{}/{name}.{marker}", op_state_dir()))
+    }
+
+    /// Path of the marker file that overrides the `enabled` flag of a service.
+    fn enabled_marker(name: &str, enabled: bool) -> PathBuf {
+        Self::marker(name, if enabled { "enabled" } else { "disabled" })
+    }
+
+    /// Enable or disable the service at boot.
+    ///
+    /// The flag is persisted as a marker file in the state dir, the service file is left untouched.
+    pub fn set_enabled(&mut self, enabled: bool) -> std::io::Result<()> {
+        create_state_dir()?;
+        match std::fs::remove_file(Self::enabled_marker(&self.name, !enabled)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::write(Self::enabled_marker(&self.name, enabled), "")?;
+
+        self.enabled = enabled;
+        Ok(())
+    }
+
+    /// Mask or unmask the service.
+    ///
+    /// A masked service can't be started until it's unmasked.
+    pub fn set_masked(&mut self, masked: bool) -> std::io::Result<()> {
+        let marker = Self::marker(&self.name, "masked");
+        if masked {
+            create_state_dir()?;
+            std::fs::write(marker, "")?;
+        } else {
+            match std::fs::remove_file(marker) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        self.masked = masked;
+        Ok(())
+    }
+
+    /// Get the reload action of the service, if any.
+    ///
+    /// `exec_reload` is treated as a signal if it names one (`SIGHUP` or `HUP`),
+    /// otherwise it's run as a shell command.
+    pub fn reload_action(&self) -> Option<Reload> {
+        let exec_reload = self.exec_reload.as_ref()?.trim();
+        let signal = if exec_reload.starts_with("SIG") {
+            Signal::from_str(exec_reload)
+        } else {
+            Signal::from_str(&format!("SIG{exec_reload}"))
+        };
+
+        Some(match signal {
+            Ok(signal) => Reload::Signal(signal),
+            Err(_) => Reload::Command(exec_reload.to_string()),
+        })
+    }
+
+    /// Parse a service file, in the format of its extension, with its drop-ins merged
+    /// over it.
+    pub fn from_file(path: &Path) -> crate::Result<Service> {
+        let format = Format::of(path).ok_or_else(|| {
+            Error::parse(
+                path,
+                "unknown extension, expected .toml, .yaml, .yml or .json",
+            )
+        })?;
+        let contents = std::fs::read_to_string(path).map_err(|e| Error::parse(path, e))?;
+        let drop_ins = Self::drop_ins(path).map_err(|e| Error::parse(path, e))?;
+
+        let mut service = if drop_ins.is_empty() {
+            // the errors point to the line of the file.
+            format.parse::<Service>(&contents)
+        } else {
+            let mut document = format.value(&contents).map_err(|e| Error::parse(path, e))?;
+            for (drop_in, format) in &drop_ins {
+                let contents =
+                    std::fs::read_to_string(drop_in).map_err(|e| Error::parse(drop_in, e))?;
+                let overrides = format
+                    .value(&contents)
+                    .map_err(|e| Error::parse(drop_in, e))?;
+                format::merge(&mut document, overrides);
+            }
+            format::from_value(document)
+        }
+        .map_err(|e| Error::parse(path, e))?;
+
+        service.sources = std::iter::once(path.to_path_buf())
+            .chain(drop_ins.into_iter().map(|(drop_in, _)| drop_in))
+            .collect();
+        service
+            .with_backend()
+            .and_then(Service::with_variables)
+            .map_err(|e| Error::parse(path, e))
+    }
+
+    /// Parse a service from the contents of a service file, e.g. in tests.
+    pub fn parse(contents: &str, format: Format) -> Result<Service, String> {
+        format
+            .parse::<Service>(contents)?
+            .with_backend()
+            .and_then(Service::with_variables)
+    }
+
+    /// Drop-ins of a service file, the service files in `<file>.d` in alphabetical order.
+    fn drop_ins(path: &Path) -> std::io::Result<Vec<(PathBuf, Format)>> {
+        let mut dir = path.as_os_str().to_owned();
+        dir.push(".d");
+        let mut drop_ins = match std::fs::read_dir(dir) {
+            Ok(dir) => dir
+                .flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    Format::of(&path).map(|format| (path, format))
+                })
+                .collect::<Vec<_>>(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        drop_ins.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(drop_ins)
+    }
+
+    /// Expand the variables of the service.
+    ///
+    /// The ones of templates and replicated services are only checked, they are expanded
+    /// for each instance.
+    fn with_variables(self) -> Result<Self, String> {
+        if self.is_template() || self.replicas.is_some() {
+            self.expanded(&self.name, Some(""))?;
+            return Ok(self);
+        }
+        self.expanded(&self.name, None)
+    }
+
+    /// Copy the service with the variables of its args, command, env and working dir
+    /// expanded.
+    fn expanded(&self, name: &str, instance: Option<&str>) -> Result<Service, String> {
+        let specifiers = Specifiers { name, instance };
+        let expand = |s: &str, key: &str| {
+            expand::expand(s, &specifiers).map_err(|e| format!("{e} in `{key}`"))
+        };
+
+        let mut service = self.clone();
+        if let Some(ref args) = self.args {
+            service.args = Some(
+                args.iter()
+                    .map(|arg| {
+                        let arg = expand(&arg.to_string_lossy(), "args")?;
+                        CString::new(arg).map_err(|_| "nul byte in `args`".to_string())
+                    })
+                    .collect::<Result<_, _>>()?,
+            );
+        }
+        if let Some(ref command) = self.command {
+            service.command = Some(expand(command, "command")?);
+        }
+        if let Some(ref env) = self.env {
+            service.env = Some(
+                env.iter()
+                    .map(|(key, value)| Ok((key.clone(), expand(value, "env")?)))
+                    .collect::<Result<_, String>>()?,
+            );
+        }
+        if let Some(ref working_dir) = self.working_dir {
+            service.working_dir =
+                Some(expand(&working_dir.to_string_lossy(), "working_dir")?.into());
+        }
+
+        Ok(service)
+    }
+
+    /// Check the keys of the service, then the ones of its backend.
+    fn with_backend(mut self) -> Result<Self, String> {
+        if let Some(code) = self
+            .restart_on_exit_codes
+            .iter()
+            .find(|code| **code == 0 || self.success_exit_codes.contains(code))
+        {
+            return Err(format!(
+                "exit code {code} is in `restart_on_exit_codes` but is a success"
+            ));
+        }
+        // `command` alone selects the shell.
+        if self.service_type == ServiceType::Exec && self.command.is_some() {
+            self.service_type = ServiceType::Shell;
+        }
+        self.backend().load(&mut self)?;
+        Ok(self)
+    }
+
+    /// Read the services files located in the service dirs.
+    ///
+    /// Files that can't be read or parsed are skipped and returned as errors, so are the
+    /// ones defining a service name that was already defined by a previous file of the
+    /// same dir. A service of a dir shadows the one of the same name of a previous dir.
+    pub fn read_service_files() -> std::io::Result<(Vec<Service>, Vec<LoadError>)> {
         let mut services = vec![];
-        let dir = std::fs::read_dir(op_service_dir())?.flatten();
-
-        for entry in dir {
-            if entry.file_type().unwrap().is_file() {
-                let contents = std::fs::read_to_string(entry.path())?;
-                match toml::from_str::<Service>(&contents) {
-                    Ok(service) => services.push(service),
-                    Err(e) => panic!("{e}"),
+        let mut errors = vec![];
+        // files defining each service name, and their index in `services`.
+        let mut names = HashMap::<String, (PathBuf, usize)>::new();
+
+        for dir in op_service_dirs() {
+            // the packaged or runtime dirs may not exist.
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            // files are read in order so the same duplicate is always rejected.
+            let mut paths = entries
+                .flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    let known = Format::of(path).is_some();
+                    if !known {
+                        warn!("Skipping {path:?}, it's not a .toml, .yaml, .yml or .json file");
+                    }
+                    known
+                })
+                .collect::<Vec<_>>();
+            paths.sort();
+
+            for path in paths {
+                let res = Self::from_file(&path)
+                    .map_err(LoadError::from)
+                    .and_then(|service| match names.get(&service.name) {
+                        Some((first, _)) if first.parent() == Some(&dir) => Err(LoadError::new(
+                            &path,
+                            format!(
+                                "duplicate service name `{}`, already defined in {}",
+                                service.name,
+                                first.display()
+                            ),
+                        )),
+                        _ => Ok(service),
+                    });
+
+                let mut service = match res {
+                    Ok(service) => service,
+                    Err(e) => {
+                        error!("Failed to load service file {:?}: {}", e.path, e.error);
+                        errors.push(e);
+                        continue;
+                    }
+                };
+                service.apply_markers();
+
+                match names.get(&service.name) {
+                    Some((shadowed, i)) => {
+                        info!("{path:?} shadows {shadowed:?}");
+                        let i = *i;
+                        names.insert(service.name.clone(), (path, i));
+                        services[i] = service;
+                    }
+                    None => {
+                        names.insert(service.name.clone(), (path, services.len()));
+                        services.push(service);
+                    }
                 }
             }
         }
 
-        Ok(services)
+        Ok((services, errors))
     }
 }
@@ -0,0 +1,107 @@
+//! Socket activation of the services.
+//!
+//! operator binds the socket of a service and passes the listening fd to its process
+//! following the `sd_listen_fds` convention: it's fd 3 and `LISTEN_FDS`, `LISTEN_PID`
+//! and `LISTEN_FDNAMES` are set.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    os::{
+        fd::{OwnedFd, RawFd},
+        unix::net::UnixListener,
+    },
+    path::PathBuf,
+};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    unistd::{dup2, getpid},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::helper::setenv;
+
+/// First fd passed to the services, after stdin, stdout and stderr.
+pub(crate) const LISTEN_FDS_START: RawFd = 3;
+
+/// Socket of a service
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Socket {
+    /// Address the socket listens on
+    pub listen: Listen,
+    /// Start the service at boot instead of on the first connection
+    #[serde(default)]
+    pub eager: bool,
+}
+
+/// Address of a socket, `"tcp:0.0.0.0:8080"` or `"unix:/run/app.sock"`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl TryFrom<String> for Listen {
+    type Error = String;
+
+    fn try_from(listen: String) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "invalid socket address `{listen}`, expected \"tcp:<address>:<port>\" or \"unix:<path>\""
+            )
+        };
+        match listen.split_once(':').ok_or_else(invalid)? {
+            ("tcp", addr) => addr.parse().map(Listen::Tcp).map_err(|_| invalid()),
+            ("unix", path) if path.starts_with('/') => Ok(Listen::Unix(path.into())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<Listen> for String {
+    fn from(listen: Listen) -> Self {
+        listen.to_string()
+    }
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Bind a listening socket, it's closed on exec.
+pub fn bind(listen: &Listen) -> io::Result<OwnedFd> {
+    match listen {
+        Listen::Tcp(addr) => Ok(TcpListener::bind(addr)?.into()),
+        Listen::Unix(path) => {
+            // the socket file left by a previous operator would make bind() fail.
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            Ok(UnixListener::bind(path)?.into())
+        }
+    }
+}
+
+/// Pass a listening socket to the program about to be executed.
+///
+/// This should only be run in the context of a forked child process.
+pub fn pass(fd: RawFd, name: &str) -> io::Result<()> {
+    if fd == LISTEN_FDS_START {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+    } else {
+        // the duplicate is not closed on exec.
+        dup2(fd, LISTEN_FDS_START)?;
+    }
+
+    setenv("LISTEN_FDS", "1")?;
+    setenv("LISTEN_PID", &getpid().to_string())?;
+    setenv("LISTEN_FDNAMES", name)
+}
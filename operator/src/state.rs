@@ -0,0 +1,141 @@
+//! Runtime state of the services.
+//!
+//! It's persisted in the runtime dir so a restarted operator can adopt the processes left
+//! running by the previous one instead of starting them a second time.
+
+use std::{
+    collections::HashMap,
+    io,
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::libc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helper::{create_runtime_dir, op_runtime_dir},
+    service::Status,
+    Error, Result,
+};
+
+/// Runtime state of the services, keyed by name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    pub services: HashMap<String, ServiceState>,
+}
+
+/// Runtime state of a service.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ServiceState {
+    /// pid of the last process of the service.
+    pub pid: i32,
+    /// start time of the process in clock ticks after boot, so a process reusing
+    /// the pid isn't mistaken for it.
+    pub start_time: u64,
+    /// when the service was started, in seconds since the epoch.
+    pub started_at: u64,
+    pub status: Status,
+    /// whether a restart of the service is pending.
+    #[serde(default)]
+    pub restart: bool,
+    /// read end of the pipe capturing the output of the process, left open across a
+    /// re-exec of operator.
+    #[serde(default)]
+    pub output_fd: Option<RawFd>,
+}
+
+impl State {
+    /// Path of the state file.
+    fn path() -> PathBuf {
+        PathBuf::from(format!("{}/runtime.toml", op_runtime_dir()))
+    }
+
+    /// Read the state file, the state is empty if there is none.
+    pub fn read() -> Result<Self> {
+        let path = Self::path();
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| Error::parse(path, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the state file.
+    pub fn write(&self) -> Result<()> {
+        create_runtime_dir()?;
+
+        // the file is replaced at once so it's never read half written.
+        let path = Self::path();
+        let tmp = path.with_extension("toml.tmp");
+        let contents = toml::to_string(self).map_err(io::Error::other)?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+impl ServiceState {
+    /// State of a process that was just started.
+    pub fn new(pid: i32, status: Status) -> Self {
+        Self {
+            pid,
+            start_time: proc_stat(pid).map_or(0, |stat| stat.start_time),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            status,
+            restart: false,
+            output_fd: None,
+        }
+    }
+
+    /// Whether the process of the service is still running.
+    pub fn is_alive(&self) -> bool {
+        matches!(self.status, Status::Running)
+            && proc_stat(self.pid).is_some_and(|stat| stat.start_time == self.start_time)
+    }
+}
+
+/// Fields of `/proc/<pid>/stat` used by operator.
+pub struct ProcStat {
+    /// state of the process, e.g. `Z` for a zombie.
+    pub state: char,
+    /// pid of the parent process.
+    pub ppid: i32,
+    /// CPU time spent in user and kernel mode, in clock ticks.
+    pub cpu_time: u64,
+    /// start time of the process in clock ticks after boot.
+    pub start_time: u64,
+}
+
+/// Read `/proc/<pid>/stat`, `None` if the process doesn't exist.
+pub fn proc_stat(pid: i32) -> Option<ProcStat> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+
+    // the command name is in parentheses and can contain spaces, the fields after it
+    // start with the state (3rd field).
+    let fields = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect::<Vec<_>>();
+
+    Some(ProcStat {
+        state: fields.first()?.chars().next()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        cpu_time: fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?,
+        start_time: fields.get(19)?.parse().ok()?,
+    })
+}
+
+/// Open a pidfd for a process, it's readable once the process exited.
+///
+/// Unlike waitpid() it works for processes that are not our children.
+pub fn pidfd_open(pid: i32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
@@ -0,0 +1,227 @@
+//! In-process harness to test the supervision of the services.
+//!
+//! With the `testing` feature, a [Harness] runs an [Engine] without its IPC socket and
+//! without forking the services, so the starts, stops, restarts and dependencies are
+//! tested deterministically and fast: the requests of operatorctl are handled in
+//! memory, the processes of the services are fakes whose exits are scripted by the
+//! test, and the timers of the engine, e.g. of the restart delays, only expire when the
+//! test advances its clock.
+//!
+//! A fake process exits as if killed by SIGTERM once operator asks it to stop, unless
+//! the test made it hang, and as if killed by SIGKILL once operator kills it. The
+//! fake pids are past the largest pid of Linux, so the signals operator sends them
+//! reach no real process. The history and the logs of the services are still written
+//! to their dirs, `OP_STATE_DIR`, `OP_RUNTIME_DIR` and `OP_SERVICE_LOG_DIR` should point
+//! to a temporary dir.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Write,
+    os::fd::{FromRawFd, OwnedFd},
+    sync::mpsc,
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sys::signal::Signal,
+    sys::wait::WaitStatus,
+    unistd::{pipe2, Pid},
+};
+
+use crate::{
+    engine::{Engine, Launcher},
+    ipc::IPCMessage,
+    service::{Service, ServiceInfo, Status},
+    webhook::StateChange,
+};
+
+/// Pid of the first fake process, `PID_MAX_LIMIT` of Linux is 2^22.
+const FIRST_PID: i32 = 1 << 30;
+
+/// Processes of the services of a [Harness], never forked
+#[derive(Debug)]
+pub(crate) struct FakeProcesses {
+    next_pid: i32,
+    /// errno of the exec() of the services that fail to start, keyed by name.
+    exec_errors: HashMap<String, i32>,
+    /// services whose processes don't exit when asked to stop.
+    hanging: HashSet<String>,
+    /// processes killed with SIGKILL that weren't reaped yet.
+    killed: Vec<i32>,
+}
+
+impl FakeProcesses {
+    /// Start a fake process for a service.
+    ///
+    /// Returns its pid, and its error pipe with the errno of exec() if it failed, closed
+    /// like the one of a real process.
+    pub(crate) fn spawn(&mut self, name: &str) -> std::io::Result<(i32, OwnedFd)> {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+
+        let (r_fd, w_fd) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+        let (r_fd, w_fd) = unsafe { (OwnedFd::from_raw_fd(r_fd), OwnedFd::from_raw_fd(w_fd)) };
+        if let Some(errno) = self.exec_errors.get(name) {
+            File::from(w_fd).write_all(&errno.to_le_bytes())?;
+        }
+        Ok((pid, r_fd))
+    }
+
+    /// Kill the fake process of a service, it exits on the next settle.
+    pub(crate) fn kill(&mut self, pid: Option<i32>) {
+        self.killed.extend(pid);
+    }
+}
+
+/// An engine supervising fake processes, driven by a test
+pub struct Harness {
+    engine: Engine,
+}
+
+impl Harness {
+    /// Engine supervising `services`, started like the ones of the service files at boot.
+    pub fn new(services: Vec<Service>) -> Self {
+        Self::with_engine(Engine::new(), services)
+    }
+
+    /// Like [Harness::new], with an engine set up by the test, e.g. with
+    /// [Engine::set_max_parallel_starts].
+    pub fn with_engine(mut engine: Engine, services: Vec<Service>) -> Self {
+        engine.launcher = Launcher::Fake(FakeProcesses {
+            next_pid: FIRST_PID,
+            exec_errors: HashMap::new(),
+            hanging: HashSet::new(),
+            killed: vec![],
+        });
+        engine.reactor.pause();
+        engine.boot_services(services);
+
+        let mut harness = Self { engine };
+        harness.settle();
+        harness
+    }
+
+    /// Handle a request like the IPC socket, and return its response if it has one.
+    ///
+    /// The requests that keep their connection, `Subscribe`, `Exec`, `Attach` and
+    /// `WaitFor`, are not supported.
+    pub fn request(&mut self, msg: IPCMessage) -> Option<IPCMessage> {
+        let response = self.engine.handle_message(msg, None);
+        self.settle();
+        response
+    }
+
+    /// Status of a service, `None` if it's not loaded.
+    pub fn status(&mut self, name: &str) -> Option<ServiceInfo> {
+        match self.request(IPCMessage::Status {
+            name: name.to_string(),
+        }) {
+            Some(IPCMessage::StatusResponse(info)) => info,
+            _ => None,
+        }
+    }
+
+    /// The state changes of the services from now on, like the webhooks get them.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<StateChange> {
+        let (tx, rx) = mpsc::channel();
+        self.engine
+            .subscribe(move |change| tx.send(change.clone()).is_ok());
+        rx
+    }
+
+    /// Exit the process of a service with `code`.
+    pub fn exit(&mut self, name: &str, code: i32) {
+        self.wait(name, |pid| WaitStatus::Exited(pid, code));
+    }
+
+    /// Kill the process of a service with `signal`.
+    pub fn kill(&mut self, name: &str, signal: Signal) {
+        self.wait(name, |pid| WaitStatus::Signaled(pid, signal, false));
+    }
+
+    /// Send a notification from a service, e.g. `READY=1`.
+    pub fn notify(&mut self, name: &str, message: &str) {
+        self.engine.handle_notify(name, message);
+        self.settle();
+    }
+
+    /// Fail the exec() of the next starts of a service with `errno`, `None` to start it
+    /// again.
+    pub fn fail_exec(&mut self, name: &str, errno: Option<Errno>) {
+        if let Launcher::Fake(ref mut processes) = self.engine.launcher {
+            match errno {
+                Some(errno) => processes.exec_errors.insert(name.to_string(), errno as i32),
+                None => processes.exec_errors.remove(name),
+            };
+        }
+    }
+
+    /// Make the processes of a service ignore the requests to stop, they only exit once
+    /// killed.
+    pub fn hang(&mut self, name: &str) {
+        if let Launcher::Fake(ref mut processes) = self.engine.launcher {
+            processes.hanging.insert(name.to_string());
+        }
+    }
+
+    /// Send a signal to operator, e.g. SIGTERM to shut it down.
+    pub fn signal(&mut self, signal: Signal) {
+        self.engine.handle_signal(signal as i32);
+        self.settle();
+    }
+
+    /// Advance the clock of the engine, expiring its timers.
+    pub fn advance(&mut self, by: Duration) {
+        self.engine.reactor.advance(by);
+        self.settle();
+    }
+
+    /// Reap the process of a running service.
+    fn wait(&mut self, name: &str, status: impl FnOnce(Pid) -> WaitStatus) {
+        let Some(pid) = self
+            .status(name)
+            .filter(|info| matches!(info.status, Status::Running | Status::Starting))
+            .and_then(|info| info.pid)
+        else {
+            return;
+        };
+        self.engine.handle_exit(pid, status(Pid::from_raw(pid)));
+        self.settle();
+    }
+
+    /// Handle the expired timers and exit the processes asked to stop or killed until
+    /// nothing changes, then send the state changes.
+    fn settle(&mut self) {
+        loop {
+            let expired = self.engine.reactor.expired();
+            let stopping = self.engine.stopping();
+            let Launcher::Fake(ref mut processes) = self.engine.launcher else {
+                unreachable!("the harness runs fake processes");
+            };
+            let killed = std::mem::take(&mut processes.killed);
+            let stopping = stopping
+                .into_iter()
+                .filter(|(pid, name)| !processes.hanging.contains(name) && !killed.contains(pid))
+                .map(|(pid, _)| (pid, Signal::SIGTERM));
+            let exits = killed
+                .iter()
+                .map(|pid| (*pid, Signal::SIGKILL))
+                .chain(stopping)
+                .collect::<Vec<_>>();
+            if expired.is_empty() && exits.is_empty() {
+                break;
+            }
+            for token in expired {
+                self.engine.handle_timer(token);
+            }
+            for (pid, signal) in exits {
+                let status = WaitStatus::Signaled(Pid::from_raw(pid), signal, false);
+                self.engine.handle_exit(pid, status);
+            }
+        }
+        self.engine.notify_changes();
+    }
+}
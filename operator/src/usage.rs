@@ -0,0 +1,95 @@
+//! Memory and CPU usage of the services.
+//!
+//! It's read from the cgroup of a service, which accounts for all of its processes, or
+//! from `/proc/<pid>` of its main process if it has none.
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use nix::unistd::{sysconf, SysconfVar};
+use serde::{Deserialize, Serialize};
+
+use crate::{service::Service, state::proc_stat};
+
+/// Interval between two samples of the usage of the services.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Memory and CPU usage of a service
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Usage {
+    /// Resident memory, in bytes
+    pub memory: u64,
+    /// CPU usage since the previous sample, in percent of one CPU
+    pub cpu: f32,
+}
+
+/// CPU time of a service at a point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuSample {
+    at: Instant,
+    cpu_time: Duration,
+}
+
+/// Sample the usage of a running service.
+///
+/// The CPU usage is computed since the `previous` sample, it's 0 without one.
+pub fn sample(service: &Service, previous: Option<CpuSample>) -> Option<(Usage, CpuSample)> {
+    let pid = service.pid?;
+    let cgroup = service.cgroup.as_deref();
+
+    let memory = cgroup
+        .and_then(cgroup_memory)
+        .or_else(|| proc_memory(pid))?;
+    let cpu_sample = CpuSample {
+        at: Instant::now(),
+        cpu_time: cgroup
+            .and_then(cgroup_cpu_time)
+            .or_else(|| proc_cpu_time(pid))?,
+    };
+
+    let cpu = match previous {
+        Some(previous) if cpu_sample.at > previous.at => {
+            let elapsed = (cpu_sample.at - previous.at).as_secs_f32();
+            let used = cpu_sample.cpu_time.saturating_sub(previous.cpu_time);
+            used.as_secs_f32() / elapsed * 100.0
+        }
+        _ => 0.0,
+    };
+
+    Some((Usage { memory, cpu }, cpu_sample))
+}
+
+/// Memory used by the processes of a cgroup.
+fn cgroup_memory(cgroup: &Path) -> Option<u64> {
+    std::fs::read_to_string(cgroup.join("memory.current"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// CPU time used by the processes of a cgroup.
+fn cgroup_cpu_time(cgroup: &Path) -> Option<Duration> {
+    let stat = std::fs::read_to_string(cgroup.join("cpu.stat")).ok()?;
+    let usage = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))?;
+    Some(Duration::from_micros(usage.trim().parse().ok()?))
+}
+
+/// Resident memory of a process.
+fn proc_memory(pid: i32) -> Option<u64> {
+    let statm = std::fs::read_to_string(format!("/proc/{pid}/statm")).ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    let page_size = sysconf(SysconfVar::PAGE_SIZE).ok()??;
+    Some(pages * page_size as u64)
+}
+
+/// CPU time used by a process.
+fn proc_cpu_time(pid: i32) -> Option<Duration> {
+    let ticks = proc_stat(pid)?.cpu_time;
+    let ticks_per_sec = sysconf(SysconfVar::CLK_TCK).ok()??;
+    Some(Duration::from_secs_f64(ticks as f64 / ticks_per_sec as f64))
+}
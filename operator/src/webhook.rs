@@ -0,0 +1,318 @@
+//! Webhook notifications of the state changes of the services.
+//!
+//! The changes are POSTed as JSON to the endpoints of the `[[webhooks]]` of
+//! `operator.toml` from a thread of their own, so a slow endpoint never blocks the engine.
+//! `https://` endpoints need the `https` feature.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::mpsc::{channel, Sender},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::service::{Crash, Status};
+
+/// Timeout of the connection to an endpoint and of its response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before the first retry of a failed delivery, doubled on each retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// CA bundles of the system the `https://` endpoints are verified with, the first found.
+#[cfg(feature = "https")]
+const CA_BUNDLES: [&str; 3] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// An endpoint notified of the state changes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    /// URL the changes are POSTed to, `http[s]://<host>[:<port>]/<path>`
+    pub url: String,
+    /// PEM certificates of the CAs an `https://` endpoint is verified with, the ones of
+    /// the system if unset
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+    /// Headers added to the requests, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Number of times a failed delivery is retried
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl Webhook {
+    /// Check that the URL can be POSTed to.
+    pub fn check(&self) -> Result<(), String> {
+        self.target().map(drop)
+    }
+
+    /// Whether the endpoint uses TLS, its `<host>[:<port>]` and the path of the URL.
+    fn target(&self) -> Result<(bool, &str, &str), String> {
+        let (tls, rest) = match self.url.split_once("://") {
+            Some(("http", rest)) => (false, rest),
+            Some(("https", _)) if cfg!(not(feature = "https")) => {
+                return Err(format!(
+                    "webhook {}: https:// needs operator built with the `https` feature",
+                    self.url
+                ))
+            }
+            Some(("https", rest)) => (true, rest),
+            _ => {
+                return Err(format!(
+                    "webhook {}: expected an http:// or https:// URL",
+                    self.url
+                ))
+            }
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(format!("webhook {}: the URL has no host", self.url));
+        }
+        Ok((tls, authority, path))
+    }
+}
+
+/// A state change of a service, the body of the requests
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateChange {
+    pub service: String,
+    pub old_state: String,
+    pub new_state: String,
+    /// Why the service failed to start, or how operator lost track of its process
+    pub reason: Option<String>,
+    /// Exit code of the last process of the service
+    pub exit_code: Option<u8>,
+    /// Where the core dumped by the last process of the service went
+    #[serde(default)]
+    pub core: Option<String>,
+    /// Diagnostics of the crash of the last process of the service
+    #[serde(default)]
+    pub crash: Option<Crash>,
+    /// When the state changed, in seconds since the epoch
+    pub timestamp: u64,
+}
+
+impl StateChange {
+    pub fn new(service: &str, old: Status, new: Status, exit_code: Option<u8>) -> Self {
+        StateChange {
+            service: service.to_string(),
+            old_state: state(old).to_string(),
+            new_state: state(new).to_string(),
+            reason: match new {
+                Status::Failed { reason } => Some(reason.to_string()),
+                _ => None,
+            },
+            exit_code,
+            core: None,
+            crash: None,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Name of a state in the notifications.
+pub fn state(status: Status) -> &'static str {
+    match status {
+        Status::Running => "running",
+        Status::Stopped => "stopped",
+        Status::Zombie => "zombie",
+        Status::Masked => "masked",
+        Status::Listening => "listening",
+        Status::Starting => "starting",
+        Status::Failed { .. } => "failed",
+    }
+}
+
+/// Sends the state changes to the webhooks.
+#[derive(Default)]
+pub struct Notifier {
+    /// changes waiting to be delivered by the thread, `None` without webhooks.
+    changes: Option<Sender<StateChange>>,
+}
+
+impl Notifier {
+    /// Start the thread delivering the changes to `webhooks`.
+    pub fn new(webhooks: Vec<Webhook>) -> io::Result<Self> {
+        if webhooks.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let (sender, receiver) = channel::<StateChange>();
+        std::thread::Builder::new()
+            .name("webhooks".to_string())
+            .spawn(move || {
+                for change in receiver {
+                    let body = match serde_json::to_string(&change) {
+                        Ok(body) => body,
+                        Err(e) => {
+                            error!("Failed to serialize {change:?}: {e}");
+                            continue;
+                        }
+                    };
+                    for webhook in &webhooks {
+                        deliver(webhook, &body);
+                    }
+                }
+            })?;
+
+        Ok(Notifier {
+            changes: Some(sender),
+        })
+    }
+
+    /// Queue a change to be delivered.
+    pub fn send(&self, change: StateChange) {
+        if let Some(ref changes) = self.changes {
+            _ = changes.send(change);
+        }
+    }
+}
+
+/// POST `body` to a webhook, retrying with a growing delay if it fails.
+fn deliver(webhook: &Webhook, body: &str) {
+    let mut delay = RETRY_DELAY;
+    for attempt in 0..=webhook.retries {
+        match post(webhook, body) {
+            Ok(()) => return,
+            Err(e) if attempt < webhook.retries => {
+                warn!(
+                    "Failed to notify {}: {e}, retrying in {delay:?}",
+                    webhook.url
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => error!("Failed to notify {}: {e}", webhook.url),
+        }
+    }
+}
+
+/// POST a JSON `body` to the URL of a webhook, the endpoint must answer with a 2xx
+/// status.
+fn post(webhook: &Webhook, body: &str) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+
+    let (tls, authority, path) = webhook.target().map_err(|e| invalid(&e))?;
+    // the port is the part after the last colon, unless it's in an IPv6 address.
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.ends_with(']') => (host, port),
+        _ if tls => (authority, "443"),
+        _ => (authority, "80"),
+    };
+    let addr = format!("{host}:{port}");
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid("the host has no address"))?;
+
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    #[cfg(feature = "https")]
+    if tls {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = tls::connect(stream, host, webhook.ca.as_deref())?;
+        return send(stream, authority, path, webhook, body);
+    }
+    send(stream, authority, path, webhook, body)
+}
+
+/// Send the request of a webhook on a connection to its endpoint and check its response.
+fn send(
+    mut stream: impl Read + Write,
+    authority: &str,
+    path: &str,
+    webhook: &Webhook,
+    body: &str,
+) -> io::Result<()> {
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in &webhook.headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+
+    // only the status line of the response is needed, e.g. `HTTP/1.1 200 OK`.
+    let mut response = [0; 64];
+    let n = stream.read(&mut response)?;
+    let response = String::from_utf8_lossy(&response[..n]);
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        let status_line = response.lines().next().unwrap_or_default().to_string();
+        return Err(io::Error::other(format!(
+            "unexpected response `{status_line}`"
+        )));
+    }
+    Ok(())
+}
+
+/// TLS connections to the `https://` endpoints.
+#[cfg(feature = "https")]
+mod tls {
+    use std::{io, net::TcpStream, path::Path, sync::Arc};
+
+    use rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    };
+
+    use super::CA_BUNDLES;
+
+    /// Start a TLS session with `host` on `stream`, verified with the CAs of `ca` or the
+    /// ones of the system.
+    pub(super) fn connect(
+        stream: TcpStream,
+        host: &str,
+        ca: Option<&Path>,
+    ) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+        let ca = match ca {
+            Some(ca) => ca,
+            None => CA_BUNDLES
+                .iter()
+                .map(Path::new)
+                .find(|bundle| bundle.exists())
+                .ok_or_else(|| invalid("no CA bundle found, set the `ca` of the webhook"))?,
+        };
+        let mut roots = RootCertStore::empty();
+        let certs = CertificateDer::pem_file_iter(ca)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(format!("{}: {e}", ca.display())))?;
+        // a bundle of the system may have certificates rustls doesn't support.
+        roots.add_parsable_certificates(certs);
+
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(host.to_string()).map_err(invalid)?;
+        let connection = ClientConnection::new(Arc::new(config), name).map_err(invalid)?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    fn invalid(e: impl ToString) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
@@ -0,0 +1,48 @@
+//! The faults of the chaos mode, picked with their chances and seed.
+#![cfg(feature = "chaos")]
+
+use std::time::Duration;
+
+use operator::{
+    chaos::{Chaos, ChaosConfig, Faults},
+    ipc::IPCMessage,
+};
+
+fn config(chance: f64) -> ChaosConfig {
+    ChaosConfig {
+        restart_delay_chance: chance,
+        max_restart_delay_ms: 100,
+        slow_start_chance: chance,
+        max_slow_start_ms: 100,
+        drop_response_chance: chance,
+        seed: Some(42),
+    }
+}
+
+#[test]
+fn chances() {
+    let mut never = Chaos::new(config(0.0));
+    let mut always = Chaos::new(config(1.0));
+    for _ in 0..100 {
+        assert_eq!(never.restart_delay("web"), Duration::ZERO);
+        assert_eq!(never.start_delay("web"), Duration::ZERO);
+        assert!(!never.drop_response(&IPCMessage::Graph));
+
+        assert!(always.restart_delay("web") <= Duration::from_millis(100));
+        assert!(always.start_delay("web") <= Duration::from_millis(100));
+        assert!(always.drop_response(&IPCMessage::Graph));
+    }
+}
+
+#[test]
+fn seed() {
+    let delays = |mut chaos: Chaos| {
+        (0..100)
+            .map(|_| chaos.restart_delay("web"))
+            .collect::<Vec<_>>()
+    };
+    let first = delays(Chaos::new(config(0.5)));
+    assert_eq!(first, delays(Chaos::new(config(0.5))));
+    assert!(first.iter().any(Duration::is_zero));
+    assert!(!first.iter().all(Duration::is_zero));
+}
@@ -0,0 +1,334 @@
+//! The starts, stops, restarts and dependencies of the services, supervised by the
+//! engine of the test harness with fake processes.
+#![cfg(feature = "testing")]
+
+use std::time::Duration;
+
+use nix::{errno::Errno, sys::signal::Signal};
+use operator::{
+    engine::Engine,
+    format::Format,
+    ipc::IPCMessage,
+    power::PowerAction,
+    service::{FailReason, Service, Status},
+    testing::Harness,
+};
+
+/// Harness supervising the services of TOML service files.
+fn harness(services: &[&str]) -> Harness {
+    harness_with(Engine::new(), services)
+}
+
+/// Like [harness], with an engine set up by the test.
+fn harness_with(engine: Engine, services: &[&str]) -> Harness {
+    let dir = std::env::temp_dir().join(format!("operator-engine-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_var("OP_STATE_DIR", &dir);
+    std::env::set_var("OP_RUNTIME_DIR", &dir);
+    std::env::set_var("OP_SERVICE_LOG_DIR", &dir);
+
+    let services = services
+        .iter()
+        .map(|service| Service::parse(service, Format::Toml).unwrap())
+        .collect();
+    Harness::with_engine(engine, services)
+}
+
+fn status(harness: &mut Harness, name: &str) -> Status {
+    harness.status(name).unwrap().status
+}
+
+#[test]
+fn restarts() {
+    let mut harness = harness(&[r#"
+        name = "web"
+        executable = "/bin/sh"
+        restart = "on-failure"
+    "#]);
+    let pid = harness.status("web").unwrap().pid;
+    assert!(matches!(status(&mut harness, "web"), Status::Running));
+
+    // restarted after 100ms, then 200ms.
+    harness.exit("web", 1);
+    assert!(matches!(status(&mut harness, "web"), Status::Stopped));
+    harness.advance(Duration::from_millis(100));
+    assert!(matches!(status(&mut harness, "web"), Status::Running));
+    assert_ne!(harness.status("web").unwrap().pid, pid);
+
+    harness.exit("web", 1);
+    harness.advance(Duration::from_millis(100));
+    assert!(matches!(status(&mut harness, "web"), Status::Stopped));
+    harness.advance(Duration::from_millis(100));
+    assert!(matches!(status(&mut harness, "web"), Status::Running));
+
+    // a success isn't a failure.
+    harness.exit("web", 0);
+    harness.advance(Duration::from_secs(60));
+    assert!(matches!(status(&mut harness, "web"), Status::Stopped));
+}
+
+#[test]
+fn masked_not_restarted() {
+    let mut harness = harness(&[r#"
+        name = "masked-worker"
+        executable = "/bin/sh"
+        restart = "always"
+    "#]);
+
+    // the pending restart is cancelled.
+    harness.exit("masked-worker", 1);
+    harness.request(IPCMessage::Mask {
+        name: "masked-worker".to_string(),
+    });
+    harness.advance(Duration::from_secs(60));
+    assert!(matches!(
+        status(&mut harness, "masked-worker"),
+        Status::Masked
+    ));
+
+    harness.request(IPCMessage::Unmask {
+        name: "masked-worker".to_string(),
+    });
+    harness.request(IPCMessage::Start {
+        name: "masked-worker".to_string(),
+    });
+    assert!(matches!(
+        status(&mut harness, "masked-worker"),
+        Status::Running
+    ));
+
+    // nor is one restarted once it's masked while running.
+    harness.request(IPCMessage::Mask {
+        name: "masked-worker".to_string(),
+    });
+    harness.request(IPCMessage::Restart {
+        name: "masked-worker".to_string(),
+    });
+    harness.advance(Duration::from_secs(60));
+    assert!(matches!(
+        status(&mut harness, "masked-worker"),
+        Status::Masked
+    ));
+
+    harness.request(IPCMessage::Unmask {
+        name: "masked-worker".to_string(),
+    });
+}
+
+#[test]
+fn commands_answered() {
+    let mut harness = harness(&[r#"
+        name = "cache"
+        executable = "/bin/sh"
+    "#]);
+    let cache = || "cache".to_string();
+
+    for request in [
+        IPCMessage::Disable { name: cache() },
+        IPCMessage::Enable { name: cache() },
+        IPCMessage::Mask { name: cache() },
+        IPCMessage::Unmask { name: cache() },
+    ] {
+        assert!(matches!(harness.request(request), Some(IPCMessage::Ok)));
+    }
+
+    // why it couldn't be done is sent back.
+    for request in [
+        IPCMessage::Enable {
+            name: "nope".to_string(),
+        },
+        IPCMessage::Mask {
+            name: "nope".to_string(),
+        },
+        IPCMessage::ReloadService { name: cache() },
+        IPCMessage::Scale {
+            name: cache(),
+            replicas: 2,
+        },
+    ] {
+        assert!(matches!(
+            harness.request(request),
+            Some(IPCMessage::Error(_))
+        ));
+    }
+}
+
+#[test]
+fn stop_and_start() {
+    let mut harness = harness(&[r#"
+        name = "db"
+        executable = "/bin/sh"
+        restart = "always"
+    "#]);
+    let changes = harness.subscribe();
+
+    // the fake process exits once it's asked to stop, and isn't restarted.
+    harness.request(IPCMessage::Stop {
+        name: "db".to_string(),
+    });
+    harness.advance(Duration::from_secs(60));
+    assert!(matches!(status(&mut harness, "db"), Status::Stopped));
+
+    harness.request(IPCMessage::Start {
+        name: "db".to_string(),
+    });
+    assert!(matches!(status(&mut harness, "db"), Status::Running));
+
+    let states = changes
+        .try_iter()
+        .map(|change| change.new_state)
+        .collect::<Vec<_>>();
+    assert_eq!(states, ["stopped", "running"]);
+}
+
+#[test]
+fn dependencies() {
+    let mut harness = harness(&[
+        r#"
+        name = "api"
+        executable = "/bin/sh"
+        requires = ["db"]
+        after = ["db"]
+        "#,
+        r#"
+        name = "db"
+        executable = "/bin/sh"
+        notify = true
+        "#,
+    ]);
+
+    // api waits for db to be ready.
+    assert!(matches!(status(&mut harness, "db"), Status::Starting));
+    assert!(matches!(status(&mut harness, "api"), Status::Stopped));
+    harness.notify("db", "READY=1");
+    assert!(matches!(status(&mut harness, "api"), Status::Running));
+
+    // api can't be started once db failed to.
+    harness.request(IPCMessage::Stop {
+        name: "api".to_string(),
+    });
+    harness.fail_exec("db", Some(Errno::EACCES));
+    harness.request(IPCMessage::Restart {
+        name: "db".to_string(),
+    });
+    harness.request(IPCMessage::Start {
+        name: "api".to_string(),
+    });
+    assert!(matches!(
+        status(&mut harness, "api"),
+        Status::Failed {
+            reason: FailReason::DependencyFailed
+        }
+    ));
+}
+
+#[test]
+fn parallel_starts() {
+    let mut engine = Engine::new();
+    engine.set_max_parallel_starts(2);
+    let services = ["a", "b", "c", "d"]
+        .map(|name| format!("name = \"{name}\"\nexecutable = \"/bin/sh\"\nnotify = true"));
+    let mut harness = harness_with(engine, &services.each_ref().map(String::as_str));
+
+    // c and d wait for a slot, freed once a service is ready or exited.
+    assert!(matches!(status(&mut harness, "a"), Status::Starting));
+    assert!(matches!(status(&mut harness, "b"), Status::Starting));
+    assert!(matches!(status(&mut harness, "c"), Status::Stopped));
+    harness.notify("b", "READY=1");
+    assert!(matches!(status(&mut harness, "c"), Status::Starting));
+    assert!(matches!(status(&mut harness, "d"), Status::Stopped));
+    harness.exit("a", 1);
+    assert!(matches!(status(&mut harness, "d"), Status::Starting));
+}
+
+#[test]
+fn shutdown() {
+    let mut engine = Engine::new();
+    engine.set_shutdown_timeout(Duration::from_secs(10));
+    let mut harness = harness_with(
+        engine,
+        &[
+            r#"
+            name = "api"
+            executable = "/bin/sh"
+            requires = ["db"]
+            stop_timeout_secs = 5
+            "#,
+            r#"
+            name = "db"
+            executable = "/bin/sh"
+            restart = "always"
+            "#,
+            r#"
+            name = "worker"
+            executable = "/bin/sh"
+            "#,
+        ],
+    );
+    harness.hang("api");
+    harness.hang("worker");
+
+    // db is stopped once api, killed after its stop timeout, exited.
+    harness.signal(Signal::SIGTERM);
+    assert!(matches!(status(&mut harness, "db"), Status::Running));
+    harness.advance(Duration::from_secs(5));
+    assert!(matches!(status(&mut harness, "api"), Status::Stopped));
+    assert!(matches!(status(&mut harness, "db"), Status::Stopped));
+    assert!(matches!(status(&mut harness, "worker"), Status::Running));
+
+    // the services left are killed after the shutdown timeout, and none is restarted.
+    harness.advance(Duration::from_secs(5));
+    assert!(matches!(status(&mut harness, "worker"), Status::Stopped));
+    harness.advance(Duration::from_secs(60));
+    assert!(matches!(status(&mut harness, "db"), Status::Stopped));
+}
+
+#[test]
+fn power() {
+    let web = r#"
+        name = "web"
+        executable = "/bin/sh"
+    "#;
+
+    // only an init powers off.
+    let mut harness = harness(&[web]);
+    assert!(matches!(
+        harness.request(IPCMessage::Power(PowerAction::Reboot)),
+        Some(IPCMessage::PowerResponse(Err(_)))
+    ));
+    assert!(matches!(status(&mut harness, "web"), Status::Running));
+
+    let mut engine = Engine::new();
+    engine.set_init(true);
+    let mut harness = harness_with(engine, &[web]);
+    assert!(matches!(
+        harness.request(IPCMessage::Power(PowerAction::Reboot)),
+        Some(IPCMessage::PowerResponse(Ok(())))
+    ));
+    assert!(matches!(status(&mut harness, "web"), Status::Stopped));
+}
+
+#[test]
+fn crash_diagnostics() {
+    let mut harness = harness(&[r#"
+        name = "web"
+        executable = "/bin/sh"
+    "#]);
+    let changes = harness.subscribe();
+
+    // a stop is not a crash.
+    harness.request(IPCMessage::Restart {
+        name: "web".to_string(),
+    });
+    assert!(harness.status("web").unwrap().last_crash.is_none());
+
+    harness.kill("web", Signal::SIGSEGV);
+    let crash = harness.status("web").unwrap().last_crash.unwrap();
+    assert_eq!(crash.reason, "killed by SIGSEGV");
+    assert_eq!(crash.signal.as_deref(), Some("SIGSEGV"));
+    let crashes = changes
+        .try_iter()
+        .filter_map(|change| change.crash)
+        .collect::<Vec<_>>();
+    assert_eq!(crashes.len(), 1);
+}
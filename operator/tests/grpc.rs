@@ -0,0 +1,195 @@
+//! The gRPC API called by a tonic client, the calls forwarded to the engine of the test
+//! harness served on an IPC socket.
+#![cfg(all(feature = "grpc", feature = "testing"))]
+
+use std::{net::SocketAddr, path::Path, sync::mpsc};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use operator::{
+    format::Format,
+    grpc::{
+        proto::{
+            operator_client::OperatorClient, HistoryRequest, ListServicesRequest, ServiceRequest,
+        },
+        GrpcApi,
+    },
+    ipc::IPCServer,
+    service::Service,
+    testing::Harness,
+};
+use tonic::{
+    metadata::MetadataValue,
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::Channel,
+    Code, Request, Status,
+};
+
+const TOKEN: &str = "s3cret";
+
+/// Serve a harness supervising `services` on the socket of the clients, the one the API
+/// forwards the calls to.
+fn operator(dir: &Path, services: &'static [&'static str]) {
+    let socket = dir.join("operator.sock");
+    std::env::set_var("OP_SOCKET", &socket);
+    std::env::set_var("OP_STATE_DIR", dir);
+    std::env::set_var("OP_RUNTIME_DIR", dir);
+    std::env::set_var("OP_SERVICE_LOG_DIR", dir);
+
+    let (bound, listening) = mpsc::channel();
+    std::thread::spawn(move || {
+        let services = services
+            .iter()
+            .map(|service| Service::parse(service, Format::Toml).unwrap())
+            .collect();
+        let mut harness = Harness::new(services);
+        let server = IPCServer::bind(&socket).unwrap();
+        bound.send(()).unwrap();
+
+        loop {
+            poll(&mut [PollFd::new(&server, PollFlags::POLLIN)], -1).unwrap();
+            let Ok(stream) = server.accept() else {
+                continue;
+            };
+            if let Ok(request) = stream.read() {
+                if let Some(response) = harness.request(request) {
+                    _ = stream.write(&response);
+                }
+            }
+        }
+    });
+    listening.recv().unwrap();
+}
+
+/// Sends the token it has as the `authorization` metadata of the calls.
+#[derive(Clone)]
+struct Bearer(Option<&'static str>);
+
+impl Interceptor for Bearer {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = self.0 {
+            let value = MetadataValue::try_from(format!("Bearer {token}")).unwrap();
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+}
+
+/// Client of the API on `listen`, with the token of the config unless it's `None`.
+async fn client(
+    listen: SocketAddr,
+    token: Option<&'static str>,
+) -> OperatorClient<InterceptedService<Channel, Bearer>> {
+    let channel = Channel::from_shared(format!("http://{listen}"))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    OperatorClient::with_interceptor(channel, Bearer(token))
+}
+
+#[tokio::test]
+async fn calls() {
+    let dir = std::env::temp_dir().join(format!("operator-grpc-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    operator(
+        &dir,
+        &[r#"
+        name = "web"
+        executable = "/bin/sh"
+        tags = ["frontend"]
+        "#],
+    );
+    let token_file = dir.join("token");
+    std::fs::write(&token_file, format!("{TOKEN}\n")).unwrap();
+
+    // it listens on loopback unless told otherwise.
+    let api: GrpcApi = toml::from_str(&format!("token_file = {token_file:?}")).unwrap();
+    assert!(api.listen.ip().is_loopback());
+    let listen = operator::grpc::serve(GrpcApi {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..api
+    })
+    .unwrap();
+
+    let web = || ServiceRequest {
+        name: "web".to_string(),
+    };
+    let mut api = client(listen, Some(TOKEN)).await;
+
+    let services = api
+        .list_services(ListServicesRequest {
+            tags: vec!["frontend".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .services;
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].name, "web");
+    assert_eq!(services[0].state, "running");
+    assert!(services[0].pid.is_some());
+
+    api.stop_service(web()).await.unwrap();
+    let service = api.get_service(web()).await.unwrap().into_inner();
+    assert_eq!(service.state, "stopped");
+    api.start_service(web()).await.unwrap();
+    let service = api.get_service(web()).await.unwrap().into_inner();
+    assert_eq!(service.state, "running");
+
+    let history = api
+        .get_history(HistoryRequest {
+            name: "web".to_string(),
+            limit: 0,
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .entries;
+    let events = history
+        .iter()
+        .map(|entry| entry.event.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(events, ["start", "stop", "start"]);
+
+    // the errors of the engine and of the requests have their codes.
+    let status = api
+        .start_service(ServiceRequest {
+            name: "nope".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let status = api
+        .get_service(ServiceRequest::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    // the token is required.
+    for token in [None, Some("wrong")] {
+        let status = client(listen, token)
+            .await
+            .get_service(web())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+}
+
+#[test]
+fn plaintext_only_on_loopback() {
+    let token_file =
+        std::env::temp_dir().join(format!("operator-grpc-token-{}", std::process::id()));
+    std::fs::write(&token_file, TOKEN).unwrap();
+
+    let e = operator::grpc::serve(GrpcApi {
+        listen: "0.0.0.0:0".parse().unwrap(),
+        cert: None,
+        key: None,
+        token_file: token_file.clone(),
+    })
+    .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    std::fs::remove_file(token_file).unwrap();
+}
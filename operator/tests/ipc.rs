@@ -0,0 +1,356 @@
+//! Malformed requests, clients over the limits, a second server on a socket in use,
+//! the requests that are not queries on the query socket and the clients of another
+//! version of the protocol are rejected, and the clients speaking JSON are answered in
+//! JSON.
+
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use operator::{
+    ipc::{Hello, IPCMessage, IPCServer, IPCStream, Limits, MAX_MESSAGE_SIZE, PROTOCOL_VERSION},
+    Error,
+};
+
+/// Path of a new socket.
+fn socket() -> PathBuf {
+    static SOCKETS: AtomicUsize = AtomicUsize::new(0);
+    PathBuf::from(format!(
+        "{}/operator-test-{}-{}.sock",
+        std::env::temp_dir().display(),
+        std::process::id(),
+        SOCKETS.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Send `bytes` on a new connection and read them as a request.
+fn read(bytes: &[u8]) -> operator::Result<IPCMessage> {
+    let path = socket();
+    let server = IPCServer::bind(&path).unwrap();
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(bytes).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let result = server.accept().unwrap().read();
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+    result
+}
+
+/// A valid encoded request.
+fn start() -> Vec<u8> {
+    bincode::serialize(&IPCMessage::Start {
+        name: "web".to_string(),
+    })
+    .unwrap()
+}
+
+#[test]
+fn valid_request() {
+    assert!(matches!(
+        read(&start()),
+        Ok(IPCMessage::Start { name }) if name == "web"
+    ));
+}
+
+#[test]
+fn batch_request() {
+    let batch = IPCMessage::Batch(vec![
+        IPCMessage::Stop {
+            name: "web".to_string(),
+        },
+        IPCMessage::Status {
+            name: "web".to_string(),
+        },
+    ]);
+    assert!(matches!(
+        read(&bincode::serialize(&batch).unwrap()),
+        Ok(IPCMessage::Batch(requests)) if matches!(
+            requests.as_slice(),
+            [IPCMessage::Stop { .. }, IPCMessage::Status { .. }]
+        )
+    ));
+
+    // a batch claiming more requests than sent is rejected.
+    let mut request = bincode::serialize(&batch).unwrap();
+    request[4..12].copy_from_slice(&3u64.to_le_bytes());
+    assert!(read(&request).is_err());
+}
+
+#[test]
+fn empty_connection() {
+    assert!(matches!(read(&[]), Err(Error::Io(_))));
+}
+
+#[test]
+fn truncated_request() {
+    let request = start();
+    for len in 0..request.len() {
+        assert!(read(&request[..len]).is_err(), "{len} bytes were accepted");
+    }
+}
+
+#[test]
+fn unknown_message() {
+    assert!(matches!(
+        read(&u32::MAX.to_le_bytes()),
+        Err(Error::Protocol(_))
+    ));
+}
+
+#[test]
+fn oversized_request() {
+    // a name claiming to be larger than the limit is rejected before it's read.
+    let mut request = 0u32.to_le_bytes().to_vec();
+    request.extend_from_slice(&(MAX_MESSAGE_SIZE + 1).to_le_bytes());
+    assert!(matches!(read(&request), Err(Error::Protocol(_))));
+
+    let mut request = 0u32.to_le_bytes().to_vec();
+    request.extend_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(read(&request), Err(Error::Protocol(_))));
+}
+
+#[test]
+fn random_requests() {
+    // xorshift, so the inputs are the same on every run.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..500 {
+        let len = (next() % 64) as usize;
+        let mut bytes = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+        // keep the variant valid half of the time, so the fields are decoded too.
+        if len >= 4 && next() % 2 == 0 {
+            bytes[..4].copy_from_slice(&((next() % 24) as u32).to_le_bytes());
+        }
+        // whatever the bytes are, they are decoded or rejected without panicking.
+        _ = read(&bytes);
+    }
+}
+
+#[test]
+fn over_the_limits() {
+    let path = socket();
+    let server = IPCServer::bind(&path).unwrap().with_limits(Limits {
+        max_connections: 1,
+        client_connections: 1,
+        requests_per_sec: 100,
+        client_requests_per_sec: 2,
+    });
+    let connect = || IPCStream::connect(&path.to_string_lossy()).unwrap();
+
+    // a second connection is refused while the first one is open.
+    let _client = connect();
+    let first = server.accept().unwrap();
+    let client = connect();
+    assert!(matches!(server.accept(), Err(Error::Busy)));
+    assert!(matches!(client.read(), Ok(IPCMessage::Busy)));
+
+    // the requests of the client are over its rate once the first one is closed.
+    drop(first);
+    let _client = connect();
+    assert!(server.accept().is_ok());
+    let client = connect();
+    assert!(matches!(server.accept(), Err(Error::Busy)));
+    assert!(matches!(client.read(), Ok(IPCMessage::Busy)));
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+}
+
+#[test]
+fn over_the_client_connections() {
+    let path = socket();
+    let server = IPCServer::bind(&path).unwrap().with_limits(Limits {
+        max_connections: 8,
+        client_connections: 2,
+        ..Limits::default()
+    });
+    let connect = || IPCStream::connect(&path.to_string_lossy()).unwrap();
+
+    // the connections of a user are limited before all the connections are taken.
+    let _clients = [connect(), connect()];
+    let first = server.accept().unwrap();
+    let _second = server.accept().unwrap();
+    let client = connect();
+    assert!(matches!(server.accept(), Err(Error::Busy)));
+    assert!(matches!(client.read(), Ok(IPCMessage::Busy)));
+
+    // a connection closed makes room for another one.
+    drop(first);
+    let _client = connect();
+    assert!(server.accept().is_ok());
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+}
+
+#[test]
+fn socket_in_use() {
+    let path = socket();
+    let server = IPCServer::bind(&path).unwrap();
+    assert!(matches!(
+        IPCServer::bind(&path),
+        Err(Error::AlreadyRunning(Some(pid))) if pid == std::process::id() as i32
+    ));
+
+    // the socket left by a server that is gone is replaced.
+    drop(server);
+    assert!(path.exists());
+    let server = IPCServer::bind(&path).unwrap();
+    let client = IPCStream::connect(&path.to_string_lossy()).unwrap();
+    client.write(&IPCMessage::ReExec).unwrap();
+    assert!(matches!(
+        server.accept().unwrap().read(),
+        Ok(IPCMessage::ReExec)
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+}
+
+#[test]
+fn socket_of_another_user() {
+    // a lock planted as a symlink isn't followed.
+    let path = socket();
+    let lock = format!("{}.lock", path.display());
+    std::os::unix::fs::symlink("/dev/null", &lock).unwrap();
+    assert!(IPCServer::bind(&path).is_err());
+    std::fs::remove_file(&lock).unwrap();
+
+    // nor are the lock and socket of another user trusted.
+    if !nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let nobody = Some(nix::unistd::Uid::from_raw(65534));
+    for planted in [lock.clone(), path.display().to_string()] {
+        std::fs::write(&planted, "").unwrap();
+        nix::unistd::chown(planted.as_str(), nobody, None).unwrap();
+        assert!(matches!(
+            IPCServer::bind(&path),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::PermissionDenied
+        ));
+        std::fs::remove_file(&planted).unwrap();
+    }
+    _ = std::fs::remove_file(&lock);
+}
+
+#[test]
+fn query_requests() {
+    let status = || IPCMessage::Status {
+        name: "web".to_string(),
+    };
+    let stop = || IPCMessage::Stop {
+        name: "web".to_string(),
+    };
+    assert!(status().is_query());
+    assert!(!stop().is_query());
+    assert!(IPCMessage::Batch(vec![status(), status()]).is_query());
+    assert!(!IPCMessage::Batch(vec![status(), stop()]).is_query());
+}
+
+#[test]
+fn json_requests() {
+    assert!(matches!(
+        read(br#"{"Start": {"name": "web"}}"#),
+        Ok(IPCMessage::Start { name }) if name == "web"
+    ));
+    assert!(matches!(
+        read(b"{\"LoadErrors\": null}\n"),
+        Ok(IPCMessage::LoadErrors)
+    ));
+    assert!(matches!(read(br#"{"Start": {}}"#), Err(Error::Protocol(_))));
+    assert!(matches!(read(br#"{"Start": {"na"#), Err(Error::Io(_))));
+
+    // the client is answered in JSON, a line per message.
+    let path = socket();
+    let server = IPCServer::bind(&path).unwrap();
+    let mut client = UnixStream::connect(&path).unwrap();
+    client
+        .write_all(b"{\"Graph\": null}\n{\"Analyze\": null}\n")
+        .unwrap();
+    let stream = server.accept().unwrap();
+    assert!(matches!(stream.read(), Ok(IPCMessage::Graph)));
+    assert!(matches!(stream.read(), Ok(IPCMessage::Analyze)));
+    stream.write(&IPCMessage::Unauthorized).unwrap();
+    drop(stream);
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "\"Unauthorized\"\n");
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+}
+
+/// A greeting of a client speaking `version` of the protocol.
+fn hello(version: u32) -> Vec<u8> {
+    let fields = bincode::serialize(&Hello {
+        version,
+        features: vec![],
+    })
+    .unwrap();
+    let mut hello = b"OPHI".to_vec();
+    hello.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+    hello.extend_from_slice(&fields);
+    hello
+}
+
+#[test]
+fn greetings() {
+    let path = socket();
+    let server = IPCServer::bind(&path).unwrap();
+
+    // the greetings are exchanged along with the first messages.
+    let client = IPCStream::connect(&path.to_string_lossy()).unwrap();
+    client.write(&IPCMessage::Graph).unwrap();
+    let stream = server.accept().unwrap();
+    assert!(matches!(stream.read(), Ok(IPCMessage::Graph)));
+    stream.write(&IPCMessage::ReadOnly).unwrap();
+    assert!(matches!(client.read(), Ok(IPCMessage::ReadOnly)));
+
+    // the request of a client of another version is not read, it gets the greeting of
+    // the server.
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(&hello(PROTOCOL_VERSION + 1)).unwrap();
+    client.write_all(&start()).unwrap();
+    assert!(matches!(
+        server.accept().unwrap().read(),
+        Err(Error::Protocol(_))
+    ));
+    let mut greeting = [0; 8];
+    client.read_exact(&mut greeting).unwrap();
+    assert_eq!(&greeting[..4], b"OPHI");
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+
+    // a server of another version, or older than the greetings, is told apart.
+    let path = socket();
+    let listener = UnixListener::bind(&path).unwrap();
+    let connect = || {
+        let client = IPCStream::connect(&path.to_string_lossy()).unwrap();
+        client.write(&IPCMessage::Graph).unwrap();
+        client
+    };
+    let client = connect();
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.write_all(&hello(PROTOCOL_VERSION + 1)).unwrap();
+    assert!(matches!(
+        client.read(),
+        Err(Error::Incompatible(Some(version))) if version == PROTOCOL_VERSION + 1
+    ));
+    let client = connect();
+    drop(listener.accept().unwrap());
+    assert!(matches!(client.read(), Err(Error::Incompatible(None))));
+
+    std::fs::remove_file(&path).unwrap();
+}
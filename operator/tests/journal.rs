@@ -0,0 +1,67 @@
+//! The journal of the state changes, queried and read back from its file.
+
+use operator::{
+    journal::{EventQuery, Journal, JournalConfig},
+    service::Status,
+    webhook::StateChange,
+};
+
+fn change(service: &str, timestamp: u64) -> StateChange {
+    let mut change = StateChange::new(service, Status::Stopped, Status::Running, None);
+    change.timestamp = timestamp;
+    change
+}
+
+fn services(changes: Vec<StateChange>) -> Vec<String> {
+    changes.into_iter().map(|change| change.service).collect()
+}
+
+#[test]
+fn queries() {
+    let mut journal = Journal::new(JournalConfig::default());
+    for (service, timestamp) in [("web-1", 100), ("db", 200), ("web-2", 300)] {
+        journal.record(&change(service, timestamp));
+    }
+
+    assert_eq!(services(journal.query(&EventQuery::default())).len(), 3);
+    let query = EventQuery {
+        since: Some(200),
+        ..EventQuery::default()
+    };
+    assert_eq!(services(journal.query(&query)), ["db", "web-2"]);
+    let query = EventQuery {
+        until: Some(200),
+        service: Some("web-*".to_string()),
+        ..EventQuery::default()
+    };
+    assert_eq!(services(journal.query(&query)), ["web-1"]);
+}
+
+#[test]
+fn capacity_and_file() {
+    let file = std::env::temp_dir().join(format!("operator-journal-{}", std::process::id()));
+    let config = || JournalConfig {
+        capacity: 3,
+        file: Some(file.join("events.jsonl")),
+        // rotated after every change.
+        max_size: 1,
+    };
+
+    let mut journal = Journal::new(config());
+    for timestamp in 0..5 {
+        journal.record(&change(&format!("s{timestamp}"), timestamp));
+    }
+    assert_eq!(
+        services(journal.query(&EventQuery::default())),
+        ["s2", "s3", "s4"]
+    );
+
+    // the file and the one it was rotated to are read back.
+    let journal = Journal::new(config());
+    assert_eq!(
+        services(journal.query(&EventQuery::default())),
+        ["s3", "s4"]
+    );
+
+    std::fs::remove_dir_all(file).unwrap();
+}
@@ -0,0 +1,59 @@
+//! The next restarts of the `restart_every` schedules.
+
+use operator::schedule::Schedule;
+
+/// 2024-02-28 23:30:00 UTC, a wednesday.
+const NOW: u64 = 1709163000;
+
+fn schedule(text: &str) -> Result<Schedule, String> {
+    Schedule::try_from(text.to_string())
+}
+
+#[test]
+fn durations() {
+    let every = schedule("1h30m").unwrap();
+    assert_eq!(every.next(NOW, NOW), Some(NOW + 5400));
+    // the restarts missed are skipped.
+    assert_eq!(every.next(NOW - 6000, NOW), Some(NOW - 6000 + 10800));
+    assert_eq!(every.period(NOW), 5400);
+
+    assert!(schedule("0s").is_err());
+    assert!(schedule("24").is_err());
+    assert!(schedule("1w").is_err());
+}
+
+#[test]
+fn cron_expressions() {
+    // at 4:00 every day.
+    let daily = schedule("0 4 * * *").unwrap();
+    assert_eq!(daily.next(NOW, NOW), Some(NOW + 4 * 3600 + 1800));
+    assert_eq!(daily.period(NOW), 86400);
+
+    // every 15 minutes.
+    assert_eq!(
+        schedule("*/15 * * * *").unwrap().next(NOW, NOW),
+        Some(NOW + 900)
+    );
+
+    // on the 29th of february, 2024 is a leap year.
+    assert_eq!(
+        schedule("0 0 29 2 *").unwrap().next(NOW, NOW),
+        Some(NOW + 1800)
+    );
+
+    // on sundays, as 0 or 7.
+    let sunday = NOW + 1800 + 3 * 86400;
+    assert_eq!(schedule("0 0 * * 0").unwrap().next(NOW, NOW), Some(sunday));
+    assert_eq!(schedule("0 0 * * 7").unwrap().next(NOW, NOW), Some(sunday));
+
+    // on the 1st of the month or on thursdays, whichever comes first.
+    assert_eq!(
+        schedule("0 0 1 * 4").unwrap().next(NOW, NOW),
+        Some(NOW + 1800)
+    );
+
+    assert!(schedule("60 * * * *").is_err());
+    assert!(schedule("* * 0 * *").is_err());
+    assert!(schedule("*/0 * * * *").is_err());
+    assert!(schedule("* * *").is_err());
+}
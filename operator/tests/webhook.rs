@@ -0,0 +1,25 @@
+//! The URLs of the webhooks checked when the config is read.
+
+use operator::webhook::Webhook;
+
+fn webhook(url: &str) -> Webhook {
+    toml::from_str(&format!("url = {url:?}")).unwrap()
+}
+
+#[test]
+fn urls() {
+    assert!(webhook("http://127.0.0.1:9000/hooks").check().is_ok());
+    assert!(webhook("http://localhost").check().is_ok());
+    assert!(webhook("ftp://localhost/hooks")
+        .check()
+        .unwrap_err()
+        .contains("expected an http:// or https:// URL"));
+    assert!(webhook("http:///hooks").check().is_err());
+    assert!(webhook("localhost:9000").check().is_err());
+
+    let https = webhook("https://hooks.slack.com/services/T0/B0/X").check();
+    match cfg!(feature = "https") {
+        true => assert!(https.is_ok()),
+        false => assert!(https.unwrap_err().contains("the `https` feature")),
+    }
+}
@@ -0,0 +1,67 @@
+//! Boot-time analysis of the services.
+
+use std::time::Duration;
+
+use colored::*;
+use operator::service::BootTiming;
+
+/// Timings of the services that were started at boot.
+fn timings() -> Vec<BootTiming> {
+    crate::client()
+        .analyze()
+        .unwrap()
+        .into_iter()
+        .filter(|timing| timing.ready.is_some())
+        .collect()
+}
+
+/// How long the service took to be ready once forked.
+fn startup(timing: &BootTiming) -> Duration {
+    timing
+        .ready
+        .unwrap_or_default()
+        .saturating_sub(timing.forked.unwrap_or_default())
+}
+
+fn ms(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Print the services started at boot, the slowest first.
+pub fn blame() {
+    let mut timings = timings();
+    timings.sort_by_key(|timing| std::cmp::Reverse(startup(timing)));
+
+    for timing in timings {
+        println!("{:>12} {}", ms(startup(&timing)), timing.name);
+    }
+}
+
+/// Print the chain of services that delayed the end of the boot.
+///
+/// It starts from the last service to be ready and follows, through `after`, the
+/// service it waited on the longest.
+pub fn critical_path() {
+    let timings = timings();
+    let mut next = timings.iter().max_by_key(|timing| timing.ready);
+
+    let mut depth = 0;
+    while let Some(timing) = next {
+        let prefix = match depth {
+            0 => String::new(),
+            depth => format!("{}└─", "  ".repeat(depth - 1)),
+        };
+        println!(
+            "{prefix}{} @{} +{}",
+            timing.name.red(),
+            ms(timing.ready.unwrap_or_default()),
+            ms(startup(timing))
+        );
+
+        next = timings
+            .iter()
+            .filter(|dep| timing.after.contains(&dep.name))
+            .max_by_key(|dep| dep.ready);
+        depth += 1;
+    }
+}
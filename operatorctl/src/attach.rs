@@ -0,0 +1,107 @@
+//! Live output of a service, and input to its stdin.
+//!
+//! The terminal is in raw mode while attached to the stdin of a service running in a
+//! terminal, so the keys are read by the service: Ctrl-C interrupts it, Ctrl-] detaches.
+
+use std::io::{IsTerminal, Read, Write};
+
+use colored::*;
+use operator::Error;
+use ratatui::crossterm::terminal;
+
+/// The key detaching from a service running in a terminal, Ctrl-].
+const DETACH: u8 = 0x1d;
+
+/// Print the output of a service as it's written, and its changes of state, until
+/// interrupted.
+///
+/// What's read from stdin is written to the stdin of the service if `stdin`.
+pub fn attach(name: &str, stdin: bool) {
+    let attachment = match crate::client().attach(name, stdin) {
+        Ok(attachment) => attachment,
+        Err(Error::NotFound(_)) => {
+            println!("{}", format!("no {name} service found.").red());
+            std::process::exit(1);
+        }
+        Err(e) => crate::fail(e),
+    };
+    let raw = stdin && attachment.tty() && std::io::stdin().is_terminal();
+    let detach = if raw { "Ctrl-]" } else { "Ctrl-C" };
+    eprintln!(
+        "{}",
+        format!("Attached to {name}, press {detach} to detach.").green()
+    );
+
+    if stdin {
+        let input = attachment.input().unwrap_or_else(|e| crate::fail(e));
+        if raw {
+            terminal::enable_raw_mode().unwrap_or_else(|e| crate::fail(e.into()));
+        }
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            // the service keeps its stdin open once ours is closed.
+            while let Ok(n @ 1..) = std::io::stdin().read(&mut buf) {
+                let detached = buf[..n].iter().position(|&byte| raw && byte == DETACH);
+                let end = detached.unwrap_or(n);
+                if input.write(&buf[..end]).is_err() {
+                    break;
+                }
+                if detached.is_some() {
+                    restore(raw);
+                    eprintln!();
+                    std::process::exit(0);
+                }
+            }
+        });
+    }
+
+    // the changes of state are written between the lines of output.
+    let subscription = crate::client()
+        .subscribe()
+        .unwrap_or_else(|e| crate::fail(e));
+    let service = name.to_string();
+    std::thread::spawn(move || {
+        for change in subscription.flatten() {
+            if change.service != service {
+                continue;
+            }
+            let mut message = format!(
+                "-- {}: {} -> {}",
+                change.service, change.old_state, change.new_state
+            );
+            if let Some(code) = change.exit_code {
+                message.push_str(&format!(", exit code {code}"));
+            }
+            if let Some(reason) = change.reason {
+                message.push_str(&format!(", {reason}"));
+            }
+            // the lines end with `\r\n` in raw mode.
+            eprint!("{}{}", message.yellow(), if raw { "\r\n" } else { "\n" });
+        }
+    });
+
+    let mut stdout = std::io::stdout();
+    for bytes in attachment {
+        let bytes = bytes.unwrap_or_else(|e| {
+            restore(raw);
+            crate::fail(e)
+        });
+        if stdout
+            .write_all(&bytes)
+            .and_then(|()| stdout.flush())
+            .is_err()
+        {
+            restore(raw);
+            return;
+        }
+    }
+    restore(raw);
+    eprintln!("{}", "operator closed the connection.".red());
+}
+
+/// Leave the raw mode of the terminal, if it was entered.
+fn restore(raw: bool) {
+    if raw {
+        _ = terminal::disable_raw_mode();
+    }
+}
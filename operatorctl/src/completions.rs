@@ -0,0 +1,151 @@
+//! Shell completions of operatorctl.
+//!
+//! The scripts generated by clap only complete the commands and flags, a function
+//! asking the running operator for its services (`operatorctl list --names`) is added to
+//! complete the name of the commands taking a service.
+
+use clap_complete::Shell;
+
+/// Print the completion script of a shell.
+pub fn completions(shell: Shell) {
+    let mut cli = crate::cli();
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut cli, "operatorctl", &mut script);
+    let script = String::from_utf8(script).expect("the completion script is UTF-8");
+
+    let commands = service_commands(&cli).join(" ");
+    let options = value_options(&cli).join(" ");
+    let script = match shell {
+        Shell::Bash => format!("{script}{}", bash(&commands, &options)),
+        Shell::Zsh => zsh(&script),
+        Shell::Fish => format!("{script}{}", fish(&commands, &options)),
+        // the other shells only complete the commands and flags.
+        _ => script,
+    };
+    print!("{script}");
+}
+
+/// The commands whose first argument is the name of a service.
+fn service_commands(cli: &clap::Command) -> Vec<String> {
+    cli.get_subcommands()
+        .filter(|command| command.get_arguments().any(|arg| arg.get_id() == "name"))
+        .map(|command| command.get_name().to_string())
+        .collect()
+}
+
+/// The flags followed by a value, which is not an argument of the command.
+fn value_options(cli: &clap::Command) -> Vec<String> {
+    let mut options = cli
+        .get_subcommands()
+        .flat_map(|command| command.get_arguments())
+        .chain(cli.get_arguments())
+        .filter(|arg| !arg.is_positional() && arg.get_action().takes_values())
+        .flat_map(|arg| {
+            let long = arg.get_long().map(|long| format!("--{long}"));
+            let short = arg.get_short().map(|short| format!("-{short}"));
+            long.into_iter().chain(short)
+        })
+        .collect::<Vec<_>>();
+    options.sort();
+    options.dedup();
+    options
+}
+
+/// Completion of the services in bash, wrapping the generated `_operatorctl`.
+fn bash(commands: &str, options: &str) -> String {
+    format!(
+        r#"
+_operatorctl_services() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" i word cmd="" user="" value=0 positionals=0
+    [[ ${{cur}} == -* ]] && return 1
+    for (( i = 1; i < COMP_CWORD; i++ )); do
+        word="${{COMP_WORDS[i]}}"
+        if (( value )); then
+            value=0
+        elif [[ " {options} " == *" ${{word}} "* ]]; then
+            value=1
+        elif [[ ${{word}} == --user ]]; then
+            user=--user
+        elif [[ ${{word}} != -* ]]; then
+            [[ -z ${{cmd}} ]] && cmd="${{word}}"
+            (( positionals += 1 ))
+        fi
+    done
+    (( value == 0 && positionals == 1 )) || return 1
+    [[ " {commands} " == *" ${{cmd}} "* ]] || return 1
+
+    local names
+    names="$(operatorctl ${{user}} list --names 2>/dev/null)" || return 1
+    COMPREPLY=( $(compgen -W "${{names}}" -- "${{cur}}") )
+}}
+
+_operatorctl_dynamic() {{
+    _operatorctl "$@"
+    _operatorctl_services
+    return 0
+}}
+
+if [[ "${{BASH_VERSINFO[0]}}" -eq 4 && "${{BASH_VERSINFO[1]}}" -ge 4 || "${{BASH_VERSINFO[0]}}" -gt 4 ]]; then
+    complete -F _operatorctl_dynamic -o nosort -o bashdefault -o default operatorctl
+else
+    complete -F _operatorctl_dynamic -o bashdefault -o default operatorctl
+fi
+"#
+    )
+}
+
+/// Completion of the services in zsh, as the action of the `name` arguments.
+fn zsh(script: &str) -> String {
+    const SERVICES: &str = r#"(( $+functions[_operatorctl_services] )) ||
+_operatorctl_services() {
+    local names
+    names="$(operatorctl ${words[(r)--user]} list --names 2>/dev/null)" || return 1
+    local -a services
+    services=(${(f)names})
+    compadd -a services
+}
+
+"#;
+
+    let script = script
+        .replace("':name:' \\", "':name:_operatorctl_services' \\")
+        .replace("'::name:' \\", "'::name:_operatorctl_services' \\");
+    // the functions must be defined before the completion is run at the end.
+    match script.rfind("if [ \"$funcstack[1]\" = \"_operatorctl\" ]") {
+        Some(end) => format!("{}{SERVICES}{}", &script[..end], &script[end..]),
+        None => script,
+    }
+}
+
+/// Completion of the services in fish.
+fn fish(commands: &str, options: &str) -> String {
+    format!(
+        r#"
+function __operatorctl_needs_service
+    set -l cmd
+    set -l positionals 0
+    set -l value 0
+    for word in (commandline -opc)[2..-1]
+        if test $value = 1
+            set value 0
+        else if contains -- $word {options}
+            set value 1
+        else if not string match -q -- '-*' $word
+            test -z "$cmd"; and set cmd $word
+            set positionals (math $positionals + 1)
+        end
+    end
+    test $value = 0; and test $positionals = 1; and contains -- "$cmd" {commands}
+end
+
+function __operatorctl_services
+    set -l user
+    __fish_contains_opt user; and set user --user
+    set -l names (operatorctl $user list --names 2>/dev/null); or return 1
+    printf '%s\n' $names
+end
+
+complete -c operatorctl -n "__operatorctl_needs_service" -f -a "(__operatorctl_services)"
+"#
+    )
+}
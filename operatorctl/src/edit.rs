@@ -0,0 +1,146 @@
+//! Edition of the service files of a loaded service.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use colored::*;
+use operator::Error;
+
+use crate::validate;
+
+/// Drop-in created by `operatorctl edit`, merged after the other ones.
+const DROP_IN: &str = "override.toml";
+
+/// Edit the drop-in `override.toml` of a service, or its service file if `full`, in
+/// `$VISUAL` or `$EDITOR`.
+///
+/// The file is edited as a copy and only replaced once it's saved, then the service
+/// file is validated with its drop-ins and read again by operator. An invalid change is
+/// reverted, the edited copy is kept.
+/// Returns whether the change was applied.
+pub fn edit(name: &str, full: bool) -> bool {
+    let sources = match crate::client().sources(name) {
+        Ok(sources) => sources,
+        Err(Error::NotFound(_)) => {
+            println!("{}", format!("no {name} service found.").red());
+            return false;
+        }
+        Err(e) => crate::fail(e),
+    };
+    let Some(file) = sources.first() else {
+        println!("{}", format!("{name} has no service file.").red());
+        return false;
+    };
+
+    let target = if full {
+        file.clone()
+    } else {
+        let mut dir = file.as_os_str().to_owned();
+        dir.push(".d");
+        PathBuf::from(dir).join(DROP_IN)
+    };
+    let original = match std::fs::read_to_string(&target) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return error(&target, e),
+    };
+
+    // the copy keeps the extension, so the editor knows the format.
+    let copy = std::env::temp_dir().join(format!(
+        "operatorctl-edit-{}-{}",
+        std::process::id(),
+        target.file_name().unwrap().to_string_lossy()
+    ));
+    let initial = original.clone().unwrap_or_else(|| {
+        format!(
+            "# drop-in of {}, merged over it\n",
+            file.file_name().unwrap().to_string_lossy()
+        )
+    });
+    if let Err(e) = std::fs::write(&copy, &initial) {
+        return error(&copy, e);
+    }
+
+    let edited = match run_editor(&copy).and_then(|()| std::fs::read_to_string(&copy)) {
+        Ok(edited) => edited,
+        Err(e) => return error(&copy, e),
+    };
+    if edited == initial {
+        _ = std::fs::remove_file(&copy);
+        println!("{}", "No changes.".green());
+        return true;
+    }
+
+    if let Err(e) = target
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&target, &edited))
+    {
+        return error(&target, e);
+    }
+    if !validate::validate(Some(file.clone())) {
+        let reverted = match original {
+            Some(original) => std::fs::write(&target, original),
+            None => std::fs::remove_file(&target),
+        };
+        if let Err(e) = reverted {
+            return error(&target, e);
+        }
+        println!(
+            "{}",
+            format!(
+                "{} was not changed, the edited copy is kept in {}",
+                target.display(),
+                copy.display()
+            )
+            .red()
+        );
+        return false;
+    }
+    _ = std::fs::remove_file(&copy);
+
+    match crate::client().reload_file(name) {
+        Ok(()) => {
+            println!(
+                "{}",
+                format!(
+                    "{} saved and {name} reloaded, the changes apply from its next start.",
+                    target.display()
+                )
+                .green()
+            );
+            true
+        }
+        Err(e) => crate::fail(e),
+    }
+}
+
+/// Open a file in the editor of the user and wait for it to exit.
+fn run_editor(path: &Path) -> std::io::Result<()> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|editor| !editor.is_empty()))
+        .unwrap_or_else(|| "vi".to_string());
+
+    // the editor may have arguments, e.g. `code --wait`.
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(path)
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(std::io::Error::other(format!(
+            "{editor} failed with {status}"
+        ))),
+    }
+}
+
+/// Print an error about a file.
+fn error(path: &Path, e: std::io::Error) -> bool {
+    println!("{}", format!("{}: {e}", path.display()).red());
+    false
+}
@@ -0,0 +1,40 @@
+//! Commands run in the context of a service.
+
+use std::sync::Arc;
+
+use colored::*;
+use operator::Error;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGWINCH},
+    iterator::Signals,
+};
+
+/// Run a command in the context of a service, with the stdio of operatorctl.
+///
+/// The signals sent to operatorctl, e.g. by Ctrl-C, are forwarded to the command.
+/// Returns the exit code of the command.
+pub fn exec(name: &str, command: Vec<String>) -> i32 {
+    // the signals are caught before the command starts, so none is missed.
+    let mut signals = match Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGWINCH]) {
+        Ok(signals) => signals,
+        Err(e) => crate::fail(e.into()),
+    };
+
+    let exec = match crate::client().exec(name, command) {
+        Ok(exec) => Arc::new(exec),
+        Err(Error::NotFound(_)) => {
+            println!("{}", format!("no {name} service found.").red());
+            return 1;
+        }
+        Err(e) => crate::fail(e),
+    };
+
+    let forwarded = exec.clone();
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            _ = forwarded.kill(signal);
+        }
+    });
+
+    exec.wait().unwrap_or_else(|e| crate::fail(e))
+}
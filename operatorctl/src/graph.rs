@@ -0,0 +1,48 @@
+//! Export of the dependency graph of the services.
+
+use clap::ValueEnum;
+use operator::service::{Dependency, Edge};
+
+/// Output format of the graph.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// Graphviz DOT, render it with `dot -Tsvg`
+    Dot,
+    Json,
+}
+
+/// Print the dependency graph of the loaded services.
+pub fn graph(format: Format) {
+    let (services, edges) = crate::client().graph().unwrap();
+
+    match format {
+        Format::Dot => print_dot(&services, &edges),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "services": services,
+                "edges": edges,
+            }))
+            .unwrap()
+        ),
+    }
+}
+
+fn print_dot(services: &[String], edges: &[Edge]) {
+    println!("digraph operator {{");
+    for service in services {
+        println!("    {service:?};");
+    }
+    for edge in edges {
+        let color = match edge.dependency {
+            Dependency::After => "green",
+            Dependency::Requires => "black",
+            Dependency::Wants => "grey66",
+        };
+        println!(
+            "    {:?} -> {:?} [label=\"{}\", color=\"{color}\"];",
+            edge.from, edge.to, edge.dependency
+        );
+    }
+    println!("}}");
+}
@@ -0,0 +1,364 @@
+//! Import of the programs of a supervisord config as service files.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use operator::helper::op_service_dirs;
+use serde::Serialize;
+
+/// A service file converted from a `[program:x]` section.
+#[derive(Debug, Serialize)]
+struct ImportedService {
+    name: String,
+    command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enabled: Option<bool>,
+    restart: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    working_dir: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+}
+
+/// A section of an ini file, its keys in order.
+struct Section {
+    name: String,
+    keys: Vec<(String, String)>,
+}
+
+impl Section {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Write a service file in `output` for each program of the supervisord config at `config`.
+///
+/// Existing service files are only overwritten with `force`.
+/// Returns whether all the programs were imported.
+pub fn import_supervisord(config: &Path, output: Option<PathBuf>, force: bool) -> bool {
+    let output = output
+        .or_else(|| op_service_dirs().pop())
+        .unwrap_or_else(|| PathBuf::from("."));
+    let contents = match std::fs::read_to_string(config) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("{}", format!("{}: {e}", config.display()).red());
+            return false;
+        }
+    };
+    // `%(here)s` is the directory of the config.
+    let here = config
+        .canonicalize()
+        .ok()
+        .and_then(|config| config.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+
+    let mut imported = true;
+    for section in parse_ini(&contents) {
+        if section.name == "include" {
+            println!(
+                "{}",
+                "[include] is not followed, import the included files too".yellow()
+            );
+            continue;
+        }
+        let Some(name) = section.name.strip_prefix("program:") else {
+            continue;
+        };
+
+        let file = output.join(format!("{name}.toml"));
+        let result = convert(name, &section, &here).and_then(|service| {
+            if file.exists() && !force {
+                return Err("already exists, use --force to overwrite it".to_string());
+            }
+            let contents = toml::to_string(&service).map_err(|e| e.to_string())?;
+            std::fs::write(&file, contents).map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(()) => println!("{}", format!("{}: imported", file.display()).green()),
+            Err(e) => {
+                println!("{}", format!("{}: {e}", file.display()).red());
+                imported = false;
+            }
+        }
+    }
+
+    imported
+}
+
+/// Convert a `[program:x]` section.
+fn convert(name: &str, section: &Section, here: &Path) -> Result<ImportedService, String> {
+    let expand = |value: &str| expand(value, here);
+
+    let mut command = format!(
+        "exec {}",
+        expand(section.get("command").ok_or("no command")?)?
+    );
+    // supervisord writes the output of the program to its own files, operator to the
+    // log of the service.
+    match section.get("stdout_logfile") {
+        None | Some("AUTO" | "NONE") => {}
+        Some(logfile) => {
+            command.push_str(&format!(" >> {}", expand(logfile)?));
+            if section.get("redirect_stderr") == Some("true") {
+                command.push_str(" 2>&1");
+            }
+        }
+    }
+    if section.get("numprocs").is_some_and(|n| n != "1") {
+        println!(
+            "{}",
+            format!("{name}: numprocs is not imported, see `replicas`").yellow()
+        );
+    }
+
+    Ok(ImportedService {
+        name: name.to_string(),
+        command,
+        enabled: match section.get("autostart") {
+            Some("false") => Some(false),
+            _ => None,
+        },
+        restart: match section.get("autorestart") {
+            Some("true") => "always",
+            Some("false") => "never",
+            // "unexpected" is the default of supervisord.
+            _ => "on-failure",
+        },
+        user: section.get("user").map(str::to_string),
+        working_dir: section.get("directory").map(expand).transpose()?,
+        env: match section.get("environment") {
+            Some(environment) => parse_environment(&expand(environment)?)?,
+            None => BTreeMap::new(),
+        },
+    })
+}
+
+/// Convert the `%(name)s` expressions of supervisord to the variables of operator.
+fn expand(value: &str, here: &Path) -> Result<String, String> {
+    // a literal `${` would be expanded by operator.
+    let value = value.replace("${", "$${");
+
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value.as_str();
+    while let Some(i) = rest.find("%(") {
+        expanded.push_str(&rest[..i]);
+        let end = rest[i..]
+            .find(')')
+            .ok_or_else(|| format!("unterminated expression in `{value}`"))?;
+        let key = &rest[i + 2..i + end];
+        // the conversion type follows, e.g. `s` or `02d`.
+        let after = &rest[i + end + 1..];
+        let conversion = after
+            .find(|c: char| c.is_ascii_alphabetic())
+            .ok_or_else(|| format!("invalid expression in `{value}`"))?;
+        rest = &after[conversion + 1..];
+
+        match key {
+            "program_name" => expanded.push_str("%n"),
+            "process_num" => expanded.push_str("%i"),
+            "here" => expanded.push_str(&here.to_string_lossy()),
+            key if key.starts_with("ENV_") => expanded.push_str(&format!("${{{}}}", &key[4..])),
+            key => return Err(format!("unsupported expression `%({key})`")),
+        }
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Parse `KEY="value",KEY2=value2`.
+fn parse_environment(environment: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut env = BTreeMap::new();
+    let mut rest = environment.trim();
+    while !rest.is_empty() {
+        let (key, after) = rest
+            .split_once('=')
+            .ok_or_else(|| format!("invalid environment `{environment}`"))?;
+        let after = after.trim_start();
+
+        let (value, after) = match after.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let end = after[1..]
+                    .find(quote)
+                    .ok_or_else(|| format!("unterminated quote in `{environment}`"))?;
+                (&after[1..end + 1], &after[end + 2..])
+            }
+            _ => after.split_at(after.find(',').unwrap_or(after.len())),
+        };
+        env.insert(key.trim().to_string(), value.trim().to_string());
+
+        rest = after.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Ok(env)
+}
+
+/// Parse an ini file, with `;` and `#` comments and indented continuation lines.
+fn parse_ini(contents: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = vec![];
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            sections.push(Section {
+                name: name.trim().to_string(),
+                keys: vec![],
+            });
+            continue;
+        }
+        let Some(section) = sections.last_mut() else {
+            continue;
+        };
+
+        // an inline comment starts with ` ;`.
+        let trimmed = trimmed.split(" ;").next().unwrap_or_default().trim_end();
+        if line.starts_with(char::is_whitespace) {
+            if let Some((_, value)) = section.keys.last_mut() {
+                value.push(' ');
+                value.push_str(trimmed);
+                continue;
+            }
+        }
+        if let Some((key, value)) = trimmed.split_once(['=', ':']) {
+            section
+                .keys
+                .push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The service converted from the first program of a config.
+    fn import(config: &str) -> Result<ImportedService, String> {
+        let sections = parse_ini(config);
+        let section = sections
+            .iter()
+            .find(|section| section.name.starts_with("program:"))
+            .unwrap();
+        convert(
+            section.name.strip_prefix("program:").unwrap(),
+            section,
+            Path::new("/etc/supervisor"),
+        )
+    }
+
+    #[test]
+    fn sections() {
+        let sections = parse_ini(
+            "; a comment\n\
+             [supervisord]\n\
+             logfile = /var/log/supervisord.log\n\
+             \n\
+             [program:web]\n\
+             # another comment\n\
+             command = /usr/bin/web --port 80 ; an inline comment\n\
+             environment = A=1,\n  B=2\n\
+             directory: /srv/web\n",
+        );
+        let names = sections
+            .iter()
+            .map(|section| section.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["supervisord", "program:web"]);
+
+        let web = &sections[1];
+        assert_eq!(web.get("command"), Some("/usr/bin/web --port 80"));
+        assert_eq!(web.get("environment"), Some("A=1, B=2"));
+        assert_eq!(web.get("directory"), Some("/srv/web"));
+        assert_eq!(web.get("logfile"), None);
+    }
+
+    #[test]
+    fn expressions() {
+        let service = import(
+            "[program:worker]\n\
+             command = /usr/bin/%(program_name)s --id %(process_num)02d\n\
+             directory = %(here)s/%(program_name)s\n\
+             environment = HOME=\"%(ENV_HOME)s\",RAW='${HOME}'\n",
+        )
+        .unwrap();
+        assert_eq!(service.command, "exec /usr/bin/%n --id %i");
+        assert_eq!(service.working_dir.as_deref(), Some("/etc/supervisor/%n"));
+        assert_eq!(service.env["HOME"], "${HOME}");
+        assert_eq!(service.env["RAW"], "$${HOME}");
+
+        let e = import("[program:worker]\ncommand = /usr/bin/%(group_name)s\n").unwrap_err();
+        assert_eq!(e, "unsupported expression `%(group_name)`");
+        assert!(import("[program:worker]\ncommand = /usr/bin/%(program_name\n").is_err());
+    }
+
+    #[test]
+    fn autostart_and_autorestart() {
+        let service = import("[program:web]\ncommand = /usr/bin/web\n").unwrap();
+        assert_eq!(service.enabled, None);
+        assert_eq!(service.restart, "on-failure");
+
+        let service = import(
+            "[program:web]\ncommand = /usr/bin/web\nautostart = false\nautorestart = true\n",
+        )
+        .unwrap();
+        assert_eq!(service.enabled, Some(false));
+        assert_eq!(service.restart, "always");
+
+        let service = import(
+            "[program:web]\ncommand = /usr/bin/web\nautostart = true\nautorestart = false\n",
+        )
+        .unwrap();
+        assert_eq!(service.enabled, None);
+        assert_eq!(service.restart, "never");
+
+        let service =
+            import("[program:web]\ncommand = /usr/bin/web\nautorestart = unexpected\n").unwrap();
+        assert_eq!(service.restart, "on-failure");
+    }
+
+    #[test]
+    fn unsupported_keys() {
+        // they're left out of the service file.
+        let service = import(
+            "[program:web]\n\
+             command = /usr/bin/web\n\
+             priority = 10\n\
+             startsecs = 5\n\
+             numprocs = 2\n\
+             stdout_logfile = AUTO\n",
+        )
+        .unwrap();
+        assert_eq!(service.command, "exec /usr/bin/web");
+        let contents = toml::to_string(&service).unwrap();
+        assert!(!contents.contains("priority"), "{contents}");
+        assert!(!contents.contains("numprocs"), "{contents}");
+
+        // the logs of supervisord are written by the command.
+        let service = import(
+            "[program:web]\n\
+             command = /usr/bin/web\n\
+             stdout_logfile = /var/log/%(program_name)s.log\n\
+             redirect_stderr = true\n",
+        )
+        .unwrap();
+        assert_eq!(service.command, "exec /usr/bin/web >> /var/log/%n.log 2>&1");
+
+        assert_eq!(
+            import("[program:web]\nuser = www\n").unwrap_err(),
+            "no command"
+        );
+    }
+}
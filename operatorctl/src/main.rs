@@ -1,70 +1,994 @@
+mod analyze;
+mod attach;
+mod completions;
+mod edit;
+mod exec;
+mod graph;
+mod import;
+mod top;
+mod ui;
+mod validate;
+mod watch;
+
+use std::{
+    path::PathBuf,
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
 use clap::{Parser, Subcommand};
 use colored::*;
 use operator::{
-    ipc::{IPCMessage, IPCStream},
-    service,
+    audit::Outcome,
+    client::Client,
+    helper,
+    history::RunEvent,
+    ipc::{self, BulkAction},
+    journal::EventQuery,
+    power::PowerAction,
+    schedule,
+    service::{self, Filter},
+    Error,
 };
+use watch::Transitions;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
 struct Cli {
+    /// Control the operator of the current user, started with `operator --user`
+    #[arg(long, global = true)]
+    user: bool,
+    /// Control the operator of another host, `<host>[:<port>]`, over TLS
+    #[cfg(feature = "remote")]
+    #[arg(long, global = true, value_name = "HOST", conflicts_with = "ssh")]
+    host: Option<String>,
+    /// Control the operator of another host through `operatorctl --stdio` run with ssh,
+    /// e.g. `admin@mybox`
+    #[arg(long, global = true, value_name = "DESTINATION")]
+    ssh: Option<String>,
+    /// Forward the messages of a client on stdin and stdout to operator, for `--ssh`
+    #[arg(long, conflicts_with = "ssh")]
+    stdio: bool,
     #[command(subcommand)]
     command: Option<Command>,
 }
 
+/// Names of the statuses of the services.
+const STATES: [&str; 7] = [
+    "running",
+    "starting",
+    "stopped",
+    "failed",
+    "listening",
+    "masked",
+    "zombie",
+];
+
+/// Keys of `/proc/<pid>/status` of a crashed process shown by `status`, its memory is
+/// already freed.
+const CRASH_STATUS_KEYS: [&str; 7] = [
+    "State", "Threads", "SigPnd", "ShdPnd", "SigBlk", "SigIgn", "SigCgt",
+];
+
 #[derive(Subcommand)]
 enum Command {
-    /// check the status of a service
-    Status { name: String },
-    /// Stop a service by name
-    Stop { name: String },
+    /// check the status of a service, or of the ones matching a glob pattern (`worker-*`)
+    Status {
+        name: String,
+        /// Refresh the status every few seconds (2 by default), highlighting the changes of state
+        #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "2")]
+        watch: Option<u64>,
+    },
+    /// Show the configuration and runtime state of a service
+    Show {
+        name: String,
+        /// Print the properties as a JSON object
+        #[arg(long)]
+        json: bool,
+    },
+    /// List all the loaded services
+    List {
+        /// Only list the services whose name matches a glob pattern, e.g. `worker-*`
+        pattern: Option<String>,
+        /// Only list the services in these states
+        #[arg(long, value_delimiter = ',', value_parser = STATES)]
+        state: Vec<String>,
+        /// Only list the services with this tag, can be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+        /// List the service files that failed to load instead
+        #[arg(long)]
+        failed_to_load: bool,
+        /// Only print the names of the services, one per line
+        #[arg(long)]
+        names: bool,
+        /// Refresh the list every few seconds (2 by default), highlighting the changes of state
+        #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "2", conflicts_with_all = ["names", "failed_to_load"])]
+        watch: Option<u64>,
+    },
+    /// Show the running services sorted by their usage, refreshed live
+    Top {
+        #[arg(long, value_enum, default_value_t = top::Sort::Cpu)]
+        sort: top::Sort,
+        /// Seconds between two refreshes
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Open a dashboard of the services with the output of the selected one, to start, stop and restart them
+    Ui {
+        /// Seconds between two refreshes
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Show the last starts and exits of a service
+    History {
+        name: String,
+        /// Number of entries shown
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+    },
+    /// Show the last control actions, who sent them and their outcome
+    Audit {
+        /// Number of entries shown
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+    },
+    /// Show the recent state changes of the services
+    Events {
+        /// Only the changes since this long ago, e.g. `10m` or `1h30m`
+        #[arg(long, value_parser = schedule::duration)]
+        since: Option<Duration>,
+        /// Only the changes until this long ago
+        #[arg(long, value_parser = schedule::duration)]
+        until: Option<Duration>,
+        /// Only the changes of the services matching a name or glob pattern
+        #[arg(long)]
+        service: Option<String>,
+    },
+    /// Start a service by name, or the ones matching a glob pattern or tags
+    Start {
+        #[arg(required_unless_present = "tag")]
+        name: Option<String>,
+        /// Only the services with this tag, can be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+        /// Wait until the services are running
+        #[arg(long)]
+        wait: bool,
+        /// How long to wait at most with `--wait`
+        #[arg(long, value_name = "SECONDS", default_value_t = 90, requires = "wait")]
+        timeout: u64,
+    },
+    /// Stop a service by name, or the ones matching a glob pattern or tags
+    Stop {
+        #[arg(required_unless_present = "tag")]
+        name: Option<String>,
+        /// Only the services with this tag, can be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+        /// Wait until the services are stopped
+        #[arg(long)]
+        wait: bool,
+        /// How long to wait at most with `--wait`
+        #[arg(long, value_name = "SECONDS", default_value_t = 90, requires = "wait")]
+        timeout: u64,
+    },
+    /// Restart a service by name, or the ones matching a glob pattern or tags
+    Restart {
+        #[arg(required_unless_present = "tag")]
+        name: Option<String>,
+        /// Only the services with this tag, can be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+    },
+    /// Reload the configuration of a service by name
+    ReloadService { name: String },
+    /// Start a service at boot
+    Enable { name: String },
+    /// Do not start a service at boot
+    Disable { name: String },
+    /// Prevent a service from being started
+    Mask { name: String },
+    /// Allow a masked service to be started again
+    Unmask { name: String },
+    /// Set the number of replicas of a replicated service
+    Scale { name: String, replicas: u32 },
+    /// Print the service file of a service and its drop-ins
+    Cat { name: String },
+    /// Edit a drop-in of a service in $EDITOR, then validate it and reload the service
+    Edit {
+        name: String,
+        /// Edit the service file instead of the drop-in `override.toml`
+        #[arg(long)]
+        full: bool,
+    },
+    /// Change properties of a service, e.g. `restart=always env.RUST_LOG=debug limits.nofile=4096`
+    SetProperty {
+        name: String,
+        /// Properties as `KEY=VALUE`, applied from the next start of the service
+        #[arg(required = true, value_parser = property)]
+        properties: Vec<(String, String)>,
+        /// Also write them to a drop-in, so they are kept when the files are read again
+        #[arg(long)]
+        persist: bool,
+    },
+    /// Run a command with the user, env, cgroup and namespaces of a service, e.g. `exec db -- psql`
+    Exec {
+        name: String,
+        /// The command and its arguments
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Print the output of a service as it's written, until interrupted
+    Attach {
+        name: String,
+        /// Also write what's typed to the stdin of the service, which must have `stdin = "pipe"`
+        #[arg(long)]
+        stdin: bool,
+    },
+    /// Re-execute operator, e.g. after upgrading it, without restarting the services
+    ReExec,
+    /// Stop the services and power off the machine, operator must be its init
+    Poweroff,
+    /// Stop the services and reboot the machine, operator must be its init
+    Reboot,
+    /// Show the versions of the protocol operatorctl and operator speak, and the features
+    /// of operator
+    Version,
+    /// Show how long the services took to start at boot
+    Analyze {
+        #[command(subcommand)]
+        command: Option<Analyze>,
+    },
+    /// Dump the dependency graph of the services
+    Graph {
+        #[arg(long, value_enum, default_value_t = graph::Format::Dot)]
+        format: graph::Format,
+    },
+    /// Check a service file, or all the service files of a directory, without operator running
+    Validate {
+        /// Service file or directory, defaults to the service dir
+        path: Option<PathBuf>,
+    },
+    /// Convert the programs of a supervisord config to service files
+    ImportSupervisord {
+        /// supervisord config, e.g. /etc/supervisord.conf
+        config: PathBuf,
+        /// Directory to write the service files in, defaults to the last service dir
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Overwrite the existing service files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the completion script of a shell, completing the names of the loaded services
+    Completions { shell: clap_complete::Shell },
+}
+
+#[derive(Subcommand)]
+enum Analyze {
+    /// List the services started at boot, the slowest first (default)
+    Blame,
+    /// Show the chain of services that delayed the end of the boot
+    CriticalPath,
+}
+
+/// The command line interface, for the completions.
+fn cli() -> clap::Command {
+    <Cli as clap::CommandFactory>::command()
 }
 
 fn main() {
     let cli = Cli::parse();
+    helper::set_user_mode(cli.user);
+    if cli.stdio {
+        if let Err(e) = ipc::proxy_stdio(helper::op_socket_path()) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(destination) = cli.ssh {
+        _ = CLIENT.set(Client::ssh(&destination));
+    }
+    #[cfg(feature = "remote")]
+    if let Some(host) = cli.host {
+        let remote = operator::remote::Remote::from_env(&host).unwrap_or_else(|e| fail(e));
+        _ = CLIENT.set(Client::remote(remote));
+    }
     match cli.command {
-        Some(Command::Status { name }) => {
-            let socket = sock();
-
-            socket
-                .write(&operator::ipc::IPCMessage::Status {
-                    name: name.to_string(),
-                })
-                .unwrap();
-
-            let data = socket.read().unwrap();
-            match data {
-                IPCMessage::StatusResponse(Some((pid, status))) => {
-                    println!("{}", format!("{name}.service").green());
-                    println!("{}", format!("pid: {pid}").green());
-                    let status = match status {
-                        service::Status::Running => "running".green(),
-                        service::Status::Stopped => "stopped".red(),
-                        _ => "unknow".red(),
-                    };
-                    println!("{}", format!("status: {}", status).green());
+        Some(Command::Status { name, watch }) => {
+            let print = |services, transitions: &_| print_statuses(&name, services, transitions);
+            match watch {
+                Some(interval) => watch::watch(
+                    &format!("status {name}"),
+                    interval,
+                    || statuses(&name),
+                    print,
+                ),
+                None => print(statuses(&name), &Transitions::default()),
+            }
+        }
+        Some(Command::Show { name, json }) => match client().show(&name) {
+            Ok(properties) if json => {
+                let properties = properties
+                    .into_iter()
+                    .map(|(key, value)| (key, serde_json::Value::String(value)))
+                    .collect::<serde_json::Map<_, _>>();
+                println!("{}", serde_json::Value::Object(properties));
+            }
+            Ok(properties) => {
+                for (key, value) in properties {
+                    println!("{key}={value}");
                 }
-                IPCMessage::StatusResponse(None) => {
-                    println!("{}", format!("no {name} service found.").red());
+            }
+            Err(Error::NotFound(_)) => {
+                println!("{}", format!("no {name} service found.").red());
+            }
+            Err(e) => fail(e),
+        },
+        Some(Command::List {
+            failed_to_load: true,
+            ..
+        }) => {
+            for error in client().load_errors().unwrap() {
+                println!("{}", format!("{}:", error.path.display()).red());
+                println!("{}", error.error.trim_end());
+            }
+        }
+        Some(Command::List {
+            pattern,
+            state,
+            tag,
+            names,
+            watch,
+            ..
+        }) => {
+            let filter = Filter {
+                pattern,
+                states: state,
+                tags: tag,
+            };
+            let list = || {
+                client()
+                    .list_matching(filter.clone())
+                    .unwrap_or_else(|e| fail(e))
+            };
+            match watch {
+                Some(interval) => watch::watch("list", interval, list, print_list),
+                None if names => {
+                    for info in list() {
+                        println!("{}", info.name);
+                    }
                 }
-                _ => {}
+                None => print_list(list(), &Transitions::default()),
+            }
+        }
+        Some(Command::Top { sort, interval }) => top::top(sort, interval),
+        Some(Command::Ui { interval }) => ui::ui(interval),
+        Some(Command::History { name, limit }) => {
+            let entries = client().history(&name, limit).unwrap_or_else(|e| fail(e));
+
+            println!(
+                "{:<23}  {:<5} {:<8} {:>4} {:>8}  REASON",
+                "TIME", "EVENT", "PID", "EXIT", "DURATION"
+            );
+            for entry in entries {
+                let event = match entry.event {
+                    RunEvent::Start => "start".green(),
+                    RunEvent::Stop => "stop".normal(),
+                    RunEvent::Crash => "crash".red(),
+                    RunEvent::Restart => "restart".yellow(),
+                };
+                println!(
+                    "{:<23}  {:<5} {:<8} {:>4} {:>8}  {}",
+                    datetime(entry.timestamp),
+                    event,
+                    entry.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                    entry
+                        .exit_code
+                        .map(|code| code.to_string())
+                        .unwrap_or_default(),
+                    entry.duration.map(elapsed).unwrap_or_default(),
+                    entry.reason.unwrap_or_default()
+                );
+            }
+        }
+        Some(Command::Audit { limit }) => {
+            let entries = client().audit(limit).unwrap_or_else(|e| fail(e));
+
+            println!(
+                "{:<23}  {:>6} {:<8} {:<12} {:<24} {:<10} DETAIL",
+                "TIME", "UID", "PID", "ACTION", "SERVICE", "OUTCOME"
+            );
+            for entry in entries {
+                let (outcome, reason) = match entry.outcome {
+                    Outcome::Done => ("done".green(), None),
+                    Outcome::NotFound => ("not found".red(), None),
+                    Outcome::Rejected(reason) => ("rejected".red(), Some(reason)),
+                };
+                let detail = [entry.detail, reason]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                println!(
+                    "{:<23}  {:>6} {:<8} {:<12} {:<24} {:<10} {}",
+                    datetime(entry.timestamp),
+                    entry.uid.map(|uid| uid.to_string()).unwrap_or_default(),
+                    entry.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                    entry.action,
+                    entry.service.unwrap_or_default(),
+                    outcome,
+                    detail
+                );
+            }
+        }
+        Some(Command::Events {
+            since,
+            until,
+            service,
+        }) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let query = EventQuery {
+                since: since.map(|since| now.saturating_sub(since.as_secs())),
+                until: until.map(|until| now.saturating_sub(until.as_secs())),
+                service,
             };
+            let changes = client().events(query).unwrap_or_else(|e| fail(e));
+
+            println!(
+                "{:<23}  {:<24} {:<20} {:>4}  REASON",
+                "TIME", "SERVICE", "CHANGE", "EXIT"
+            );
+            for change in changes {
+                let transition = format!("{} -> {}", change.old_state, change.new_state);
+                let transition = match change.new_state.as_str() {
+                    "running" => transition.green(),
+                    "stopped" | "failed" | "zombie" => transition.red(),
+                    _ => transition.yellow(),
+                };
+                println!(
+                    "{:<23}  {:<24} {:<20} {:>4}  {}",
+                    datetime(change.timestamp),
+                    change.service,
+                    transition,
+                    change
+                        .exit_code
+                        .map(|code| code.to_string())
+                        .unwrap_or_default(),
+                    change.reason.unwrap_or_default()
+                );
+            }
+        }
+        Some(Command::Start {
+            name,
+            tag,
+            wait,
+            timeout,
+        }) => match name {
+            Some(name) if tag.is_empty() && !is_pattern(&name) => {
+                client().start(&name).unwrap_or_else(|e| fail(e));
+
+                match wait {
+                    true => wait_for(&[name], "running", timeout),
+                    false => println!("{}", format!("Start command has been sent to operator. Please check the status using `operatorctl status {name}`").green()),
+                }
+            }
+            pattern => bulk(BulkAction::Start, pattern, tag, wait.then_some(timeout)),
+        },
+        Some(Command::Stop {
+            name,
+            tag,
+            wait,
+            timeout,
+        }) => match name {
+            Some(name) if tag.is_empty() && !is_pattern(&name) => {
+                client().stop(&name).unwrap_or_else(|e| fail(e));
+
+                match wait {
+                    true => wait_for(&[name], "stopped", timeout),
+                    false => println!("{}", format!("Stop command has been sent to operator. Please check the status using `operatorctl status {name}`").green()),
+                }
+            }
+            pattern => bulk(BulkAction::Stop, pattern, tag, wait.then_some(timeout)),
+        },
+        Some(Command::Restart { name, tag }) => match name {
+            Some(name) if tag.is_empty() && !is_pattern(&name) => {
+                client().restart(&name).unwrap_or_else(|e| fail(e));
+
+                println!("{}", format!("Restart command has been sent to operator. Please check the status using `operatorctl status {name}`").green());
+            }
+            pattern => bulk(BulkAction::Restart, pattern, tag, None),
+        },
+        Some(Command::ReloadService { name }) => {
+            client().reload(&name).unwrap_or_else(|e| fail(e));
+
+            println!(
+                "{}",
+                format!("{name} was asked to reload. Please check its logs to confirm the reload")
+                    .green()
+            );
+        }
+        Some(Command::Enable { name }) => {
+            client()
+                .set_enabled(&name, true)
+                .unwrap_or_else(|e| fail(e));
+
+            println!("{}", format!("{name} will be started at boot.").green());
+        }
+        Some(Command::Disable { name }) => {
+            client()
+                .set_enabled(&name, false)
+                .unwrap_or_else(|e| fail(e));
+
+            println!("{}", format!("{name} will not be started at boot.").green());
         }
-        Some(Command::Stop { name }) => {
-            let socket = sock();
+        Some(Command::Mask { name }) => {
+            client().set_masked(&name, true).unwrap_or_else(|e| fail(e));
 
-            socket
-                .write(&operator::ipc::IPCMessage::Stop {
-                    name: name.to_string(),
-                })
-                .unwrap();
+            println!(
+                "{}",
+                format!("{name} is masked and can't be started.").green()
+            );
+        }
+        Some(Command::Unmask { name }) => {
+            client()
+                .set_masked(&name, false)
+                .unwrap_or_else(|e| fail(e));
+
+            println!("{}", format!("{name} is unmasked.").green());
+        }
+        Some(Command::Scale { name, replicas }) => {
+            client().scale(&name, replicas).unwrap_or_else(|e| fail(e));
+
+            println!("{}", format!("{name} is scaled to {replicas} replicas. Please check them using `operatorctl list`").green());
+        }
+        Some(Command::Cat { name }) => match client().sources(&name) {
+            Ok(sources) => {
+                for (i, source) in sources.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("{}", format!("# {}", source.display()).blue());
+                    match std::fs::read_to_string(source) {
+                        Ok(contents) => println!("{}", contents.trim_end_matches('\n')),
+                        // the file may have been removed since it was loaded.
+                        Err(e) => println!("{}", format!("# {e}").red()),
+                    }
+                }
+            }
+            Err(Error::NotFound(_)) => {
+                println!("{}", format!("no {name} service found.").red());
+            }
+            Err(e) => fail(e),
+        },
+        Some(Command::Edit { name, full }) => {
+            let edited = edit::edit(&name, full);
+            if !edited {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::SetProperty {
+            name,
+            properties,
+            persist,
+        }) => match client().set_property(&name, properties, persist) {
+            Ok(()) => println!(
+                "{}",
+                format!("Properties of {name} set, they apply from its next start.").green()
+            ),
+            Err(Error::NotFound(_)) => {
+                println!("{}", format!("no {name} service found.").red());
+            }
+            Err(e) => fail(e),
+        },
+        Some(Command::Exec { name, command }) => {
+            let code = exec::exec(&name, command);
+            std::process::exit(code);
+        }
+        Some(Command::Attach { name, stdin }) => attach::attach(&name, stdin),
+        Some(Command::ReExec) => {
+            client().re_exec().unwrap_or_else(|e| fail(e));
 
-            println!("{}", format!("Stop command has been sent to operator. Please check the status using `operatorctl status {name}`").green());
+            println!("{}", "Re-exec command has been sent to operator. Please check the logs of operator to confirm it".green());
+        }
+        Some(Command::Poweroff) => {
+            client()
+                .power(PowerAction::PowerOff)
+                .unwrap_or_else(|e| fail(e));
+            println!("{}", "Stopping the services to power off.".green());
+        }
+        Some(Command::Reboot) => {
+            client()
+                .power(PowerAction::Reboot)
+                .unwrap_or_else(|e| fail(e));
+            println!("{}", "Stopping the services to reboot.".green());
+        }
+        Some(Command::Version) => {
+            println!(
+                "operatorctl {}, protocol version {}",
+                env!("CARGO_PKG_VERSION"),
+                ipc::PROTOCOL_VERSION
+            );
+            let hello = client().hello().unwrap_or_else(|e| fail(e));
+            println!(
+                "operator: protocol version {}, features: {}",
+                hello.version,
+                hello.features.join(", ")
+            );
         }
+        Some(Command::Analyze { command }) => match command {
+            Some(Analyze::Blame) | None => analyze::blame(),
+            Some(Analyze::CriticalPath) => analyze::critical_path(),
+        },
+        Some(Command::Graph { format }) => graph::graph(format),
+        Some(Command::Validate { path }) => {
+            let valid = validate::validate(path);
+            if !valid {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::ImportSupervisord {
+            config,
+            output,
+            force,
+        }) => {
+            let imported = import::import_supervisord(&config, output, force);
+            if !imported {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Completions { shell }) => completions::completions(shell),
         None => {}
     }
 }
 
-fn sock() -> IPCStream {
-    operator::ipc::IPCStream::connect("/tmp/operator.sock").unwrap()
+/// Whether a service name is a glob pattern.
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Send an action for all the services matching a glob pattern and tags.
+///
+/// With `wait`, waits for `wait` seconds at most until they are running or stopped.
+fn bulk(action: BulkAction, pattern: Option<String>, tags: Vec<String>, wait: Option<u64>) {
+    let filter = Filter {
+        pattern,
+        tags,
+        ..Filter::default()
+    };
+    let names = client().bulk(action, filter).unwrap_or_else(|e| fail(e));
+    if names.is_empty() {
+        println!("{}", "no service matches.".red());
+        return;
+    }
+    if let Some(timeout) = wait {
+        let state = match action {
+            BulkAction::Stop => "stopped",
+            _ => "running",
+        };
+        return wait_for(&names, state, timeout);
+    }
+    let action = format!("{action:?}");
+    println!(
+        "{}",
+        format!(
+            "{action} command has been sent to operator for {}. Please check their status using `operatorctl list`",
+            names.join(", ")
+        )
+        .green()
+    );
+}
+
+/// Wait until the services are in `state`, for `timeout` seconds at most in total.
+///
+/// Exits with an error if one of them failed or didn't reach the state in time.
+fn wait_for(names: &[String], state: &str, timeout: u64) {
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let mut reached = true;
+    for name in names {
+        let info = client()
+            .wait_for(
+                name,
+                state,
+                deadline.saturating_duration_since(Instant::now()),
+            )
+            .unwrap_or_else(|e| fail(e));
+        match info.status {
+            status if status.name() == state => {
+                println!("{}", format!("{name} is {state}").green())
+            }
+            service::Status::Failed { reason } => {
+                reached = false;
+                println!("{}", format!("{name} failed: {reason}").red());
+            }
+            status => {
+                reached = false;
+                println!(
+                    "{}",
+                    format!(
+                        "timed out waiting for {name} to be {state}, it's {}",
+                        status.name()
+                    )
+                    .red()
+                );
+            }
+        }
+    }
+    if !reached {
+        std::process::exit(1);
+    }
+}
+
+/// The services matching the name of `operatorctl status`, a glob pattern or a name.
+fn statuses(name: &str) -> Vec<service::ServiceInfo> {
+    if is_pattern(name) {
+        let filter = Filter {
+            pattern: Some(name.to_string()),
+            ..Filter::default()
+        };
+        return client().list_matching(filter).unwrap_or_else(|e| fail(e));
+    }
+    match client().status(name) {
+        Ok(info) => vec![info],
+        Err(Error::NotFound(_)) => vec![],
+        Err(e) => fail(e),
+    }
+}
+
+/// Print the status of the services matching the name of `operatorctl status`.
+fn print_statuses(name: &str, services: Vec<service::ServiceInfo>, transitions: &Transitions) {
+    if services.is_empty() {
+        match is_pattern(name) {
+            true => println!("{}", format!("no service matches {name}.").red()),
+            false => println!("{}", format!("no {name} service found.").red()),
+        }
+    }
+    for (i, info) in services.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let changed = transitions.from(&info.name);
+        print_status(info, changed);
+    }
+}
+
+/// Print the table of the services, their state highlighted if it just changed.
+fn print_list(services: Vec<service::ServiceInfo>, transitions: &Transitions) {
+    println!(
+        "{:<24} {:<10} {:<8} {:>10} {:>8} {:>7} {:<7} {:<16} DESCRIPTION",
+        "NAME", "STATUS", "PID", "UPTIME", "MEM", "CPU", "ENABLED", "TAGS"
+    );
+    for info in services {
+        let pid = info.pid.map(|pid| pid.to_string()).unwrap_or_default();
+        let uptime = info.uptime.map(elapsed).unwrap_or_default();
+        let (mem, cpu) = match info.usage {
+            Some(usage) => (memory(usage.memory), format!("{:.1}%", usage.cpu)),
+            None => (String::new(), String::new()),
+        };
+        // a service running a replaced executable is shown as stale.
+        let state = match info.stale {
+            true => "stale".yellow(),
+            false => status(info.status),
+        };
+        let state = match transitions.from(&info.name) {
+            Some(_) => state.reversed(),
+            None => state,
+        };
+        println!(
+            "{:<24} {:<10} {:<8} {:>10} {:>8} {:>7} {:<7} {:<16} {}",
+            info.name,
+            state,
+            pid,
+            uptime,
+            mem,
+            cpu,
+            info.enabled,
+            info.tags.join(","),
+            info.description.unwrap_or_default()
+        );
+    }
+}
+
+/// Print the status of a service, and its last lines of output.
+///
+/// `changed` is the state it just changed from, highlighted.
+fn print_status(info: service::ServiceInfo, changed: Option<&str>) {
+    match info.description {
+        Some(description) => {
+            println!(
+                "{}",
+                format!("{}.service - {description}", info.name).green()
+            )
+        }
+        None => println!("{}", format!("{}.service", info.name).green()),
+    }
+    if !info.tags.is_empty() {
+        println!("{}", format!("tags: {}", info.tags.join(", ")).green());
+    }
+    if let Some(pid) = info.pid {
+        println!("{}", format!("pid: {pid}").green());
+    }
+    match changed {
+        Some(from) => println!(
+            "{}",
+            format!("status: {} (was {from})", info.status.name())
+                .bold()
+                .reversed()
+        ),
+        None => println!("{}", format!("status: {}", status(info.status)).green()),
+    }
+    if info.stale {
+        println!(
+            "{}",
+            "stale: the executable changed since it was started".yellow()
+        );
+    }
+    if let (Some(since), Some(uptime)) = (info.active_since, info.uptime) {
+        println!(
+            "{}",
+            format!(
+                "active since: {} ({} ago)",
+                datetime(since),
+                elapsed(uptime)
+            )
+            .green()
+        );
+    }
+    if let Some(text) = info.status_text {
+        println!("{}", format!("status text: {text}").green());
+    }
+    if let service::Status::Failed { reason } = info.status {
+        println!("{}", format!("reason: {reason}").red());
+    }
+    if let Some(core) = info.core {
+        println!("{}", format!("core: {core}").red());
+    }
+    // the process status and the output at the time of the crash, the current output
+    // follows.
+    if let Some(crash) = info.last_crash {
+        println!(
+            "{}",
+            format!(
+                "last crash: {} at {}",
+                crash.reason,
+                datetime(crash.timestamp)
+            )
+            .red()
+        );
+        for line in crash.proc_status.iter().flat_map(|status| status.lines()) {
+            if CRASH_STATUS_KEYS
+                .iter()
+                .any(|key| line.split(':').next() == Some(key))
+            {
+                println!("  {line}");
+            }
+        }
+        if !crash.log.is_empty() {
+            println!("  {}", "output:".red());
+            for line in crash.log {
+                println!("  {line}");
+            }
+        }
+    }
+    println!("{}", format!("enabled: {}", info.enabled).green());
+    if let Some(usage) = info.usage {
+        println!("{}", format!("memory: {}", memory(usage.memory)).green());
+        println!("{}", format!("cpu: {:.1}%", usage.cpu).green());
+    }
+    if let Some(nice) = info.nice {
+        println!("{}", format!("nice: {nice}").green());
+    }
+    if let Some(cpus) = info.cpu_affinity {
+        let cpus = cpus.iter().map(|cpu| cpu.to_string()).collect::<Vec<_>>();
+        println!("{}", format!("cpu affinity: {}", cpus.join(",")).green());
+    }
+    if let Some((class, level)) = info.ionice {
+        println!("{}", format!("ionice: {class} {level}").green());
+    }
+    if let Some(umask) = info.umask {
+        println!("{}", format!("umask: {umask}").green());
+    }
+    // like `systemctl status`, the last lines of output follow.
+    match client().output(&info.name, 10) {
+        Ok(lines) if !lines.is_empty() => {
+            println!();
+            for line in lines {
+                println!("{line}");
+            }
+        }
+        Ok(_) | Err(Error::NotFound(_)) => {}
+        Err(e) => fail(e),
+    }
+}
+
+/// Colored representation of a service status.
+fn status(status: service::Status) -> ColoredString {
+    match status {
+        service::Status::Running => "running".green(),
+        service::Status::Stopped => "stopped".red(),
+        service::Status::Masked => "masked".yellow(),
+        service::Status::Listening => "listening".yellow(),
+        service::Status::Starting => "starting".yellow(),
+        service::Status::Failed { .. } => "failed".red(),
+        _ => "unknow".red(),
+    }
+}
+
+/// Human readable size of memory.
+fn memory(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "K", "M", "G"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1}{}", UNITS[unit])
+}
+
+/// Time since the epoch as a UTC date.
+fn datetime(secs: u64) -> String {
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = secs % 86400;
+    format!(
+        "{year}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Human readable duration, its two largest units.
+fn elapsed(duration: std::time::Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = units.iter().position(|(n, _)| *n > 0).unwrap_or(3);
+    units[first..]
+        .iter()
+        .take(2)
+        .map(|(n, unit)| format!("{n}{unit}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A `KEY=VALUE` argument.
+fn property(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("`{arg}` is not KEY=VALUE"))
+}
+
+/// Client of the operator controlled, the one of another host with `--host`.
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Client of the running operator.
+fn client() -> Client {
+    CLIENT.get_or_init(Client::new).clone()
+}
+
+/// Print the error of a request and exit.
+fn fail(e: Error) -> ! {
+    println!("{}", e.to_string().red());
+    std::process::exit(1)
 }
@@ -0,0 +1,48 @@
+//! Live view of the usage of the services.
+
+use std::time::Duration;
+
+use clap::ValueEnum;
+use colored::*;
+use operator::service::ServiceInfo;
+
+/// Column the services are sorted by.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Sort {
+    Cpu,
+    Memory,
+}
+
+/// Print the running services sorted by usage, refreshed every `interval` seconds.
+pub fn top(sort: Sort, interval: u64) {
+    loop {
+        let services = crate::client().list().unwrap();
+
+        let mut services = services
+            .into_iter()
+            .filter_map(|info| Some((info.usage?, info)))
+            .collect::<Vec<_>>();
+        match sort {
+            Sort::Cpu => services.sort_by(|(a, _), (b, _)| b.cpu.total_cmp(&a.cpu)),
+            Sort::Memory => services.sort_by_key(|(usage, _)| std::cmp::Reverse(usage.memory)),
+        }
+
+        // clear the screen and move the cursor to the top left.
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "{}",
+            format!("{:<24} {:<8} {:>8} {:>7}", "NAME", "PID", "MEM", "CPU").bold()
+        );
+        for (usage, ServiceInfo { name, pid, .. }) in services {
+            println!(
+                "{:<24} {:<8} {:>8} {:>6.1}%",
+                name,
+                pid.unwrap_or_default(),
+                crate::memory(usage.memory),
+                usage.cpu
+            );
+        }
+
+        std::thread::sleep(Duration::from_secs(interval));
+    }
+}
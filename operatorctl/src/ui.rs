@@ -0,0 +1,193 @@
+//! Full-screen dashboard of the services.
+
+use std::time::{Duration, Instant};
+
+use operator::{service::ServiceInfo, Result};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table, TableState},
+    DefaultTerminal, Frame,
+};
+
+/// Number of lines of output asked for the selected service.
+const OUTPUT_LINES: usize = 200;
+
+/// State of the dashboard.
+#[derive(Default)]
+struct Ui {
+    services: Vec<ServiceInfo>,
+    table: TableState,
+    /// last lines of output of the selected service.
+    output: Vec<String>,
+    /// result of the last action, or the last error.
+    message: Option<(String, Color)>,
+}
+
+impl Ui {
+    /// Name of the selected service.
+    fn selected(&self) -> Option<&str> {
+        let info = self.services.get(self.table.selected()?)?;
+        Some(&info.name)
+    }
+
+    /// Ask operator for the services and the output of the selected one.
+    fn refresh(&mut self) -> Result<()> {
+        let selected = self.selected().map(str::to_string);
+        self.services = crate::client().list()?;
+
+        // the selection follows the service when others are added or removed.
+        let index = selected
+            .and_then(|name| self.services.iter().position(|info| info.name == name))
+            .or_else(|| self.table.selected())
+            .map(|index| index.min(self.services.len().saturating_sub(1)))
+            .or(Some(0))
+            .filter(|_| !self.services.is_empty());
+        self.table.select(index);
+
+        self.output = match self.selected() {
+            Some(name) => crate::client().output(name, OUTPUT_LINES)?,
+            None => vec![],
+        };
+        Ok(())
+    }
+
+    /// Start, stop or restart the selected service.
+    fn act(&mut self, verb: &str, action: fn(&operator::client::Client, &str) -> Result<()>) {
+        let Some(name) = self.selected().map(str::to_string) else {
+            return;
+        };
+        self.message = Some(match action(&crate::client(), &name) {
+            Ok(()) => (format!("{verb} {name}"), Color::Green),
+            Err(e) => (format!("{verb} {name}: {e}"), Color::Red),
+        });
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [services, output, footer] = Layout::vertical([
+            Constraint::Percentage(50),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let header = Row::new(["NAME", "STATUS", "PID", "UPTIME", "MEM", "CPU"])
+            .style(Style::new().add_modifier(Modifier::BOLD));
+        let rows = self.services.iter().map(|info| {
+            let (mem, cpu) = match &info.usage {
+                Some(usage) => (crate::memory(usage.memory), format!("{:.1}%", usage.cpu)),
+                None => (String::new(), String::new()),
+            };
+            Row::new([
+                info.name.clone().into(),
+                Line::from(info.status.name()).style(status_color(info)),
+                info.pid
+                    .map(|pid| pid.to_string())
+                    .unwrap_or_default()
+                    .into(),
+                info.uptime.map(crate::elapsed).unwrap_or_default().into(),
+                mem.into(),
+                cpu.into(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Min(24),
+                Constraint::Length(10),
+                Constraint::Length(8),
+                Constraint::Length(10),
+                Constraint::Length(8),
+                Constraint::Length(7),
+            ],
+        )
+        .header(header)
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" services "));
+        frame.render_stateful_widget(table, services, &mut self.table);
+
+        // the last lines that fit in the pane, inside its borders.
+        let height = output.height.saturating_sub(2) as usize;
+        let lines = self.output[self.output.len().saturating_sub(height)..]
+            .iter()
+            .map(|line| Line::from(line.as_str()))
+            .collect::<Vec<_>>();
+        let title = match self.selected() {
+            Some(name) => format!(" output of {name} "),
+            None => " output ".to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            output,
+        );
+
+        let keys = "↑/↓ select  s start  x stop  r restart  q quit";
+        let footer_line = match &self.message {
+            Some((message, color)) => Line::from(vec![
+                keys.dark_gray(),
+                "  ".into(),
+                message.clone().fg(*color),
+            ]),
+            None => Line::from(keys.dark_gray()),
+        };
+        frame.render_widget(footer_line, footer);
+    }
+}
+
+/// Color of the state of a service.
+fn status_color(info: &ServiceInfo) -> Color {
+    match info.status.name() {
+        "running" => Color::Green,
+        "stopped" | "failed" | "zombie" => Color::Red,
+        _ => Color::Yellow,
+    }
+}
+
+/// Run the dashboard until `q` is pressed, refreshed every `interval` seconds.
+pub fn ui(interval: u64) {
+    let mut ui = Ui::default();
+    // fail before taking over the terminal if operator is not running.
+    if let Err(e) = ui.refresh() {
+        crate::fail(e);
+    }
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut ui, Duration::from_secs(interval));
+    ratatui::restore();
+    if let Err(e) = result {
+        crate::fail(e.into());
+    }
+}
+
+fn run(terminal: &mut DefaultTerminal, ui: &mut Ui, interval: Duration) -> std::io::Result<()> {
+    let mut refreshed = Instant::now();
+    loop {
+        terminal.draw(|frame| ui.draw(frame))?;
+
+        let timeout = interval.saturating_sub(refreshed.elapsed());
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Down | KeyCode::Char('j') => ui.table.select_next(),
+                    KeyCode::Up | KeyCode::Char('k') => ui.table.select_previous(),
+                    KeyCode::Char('s') => ui.act("start", |client, name| client.start(name)),
+                    KeyCode::Char('x') => ui.act("stop", |client, name| client.stop(name)),
+                    KeyCode::Char('r') => ui.act("restart", |client, name| client.restart(name)),
+                    _ => continue,
+                }
+            }
+        }
+
+        // an action or a new selection is shown right away.
+        if let Err(e) = ui.refresh() {
+            ui.message = Some((e.to_string(), Color::Red));
+        }
+        refreshed = Instant::now();
+    }
+}
@@ -0,0 +1,180 @@
+//! Offline linter for service files.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use operator::{
+    format::Format,
+    helper::op_service_dirs,
+    service::{FailReason, Service},
+    Error,
+};
+
+/// Validate the service file at `path`, or all the service files in it if it's a directory,
+/// or in the service dirs by default.
+///
+/// Dependencies on other services are only checked for directories, whose files that are
+/// not service files are skipped like operator does.
+/// Returns whether all the service files are valid.
+pub fn validate(path: Option<PathBuf>) -> bool {
+    let (dirs, is_dir) = match path {
+        Some(path) if !path.is_dir() => (vec![path], false),
+        Some(dir) => (vec![dir], true),
+        None => (op_service_dirs(), true),
+    };
+
+    let mut files = vec![];
+    if !is_dir {
+        files = dirs;
+    } else {
+        for dir in dirs {
+            let mut dir_files = match std::fs::read_dir(&dir) {
+                Ok(dir) => dir
+                    .flatten()
+                    .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                    .map(|entry| entry.path())
+                    .filter(|path| Format::of(path).is_some())
+                    .collect::<Vec<_>>(),
+                // the service dirs that don't exist are skipped by operator.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    println!("{}", format!("{}: {e}", dir.display()).red());
+                    return false;
+                }
+            };
+            dir_files.sort();
+            files.extend(dir_files);
+        }
+    }
+
+    let services = files
+        .into_iter()
+        .map(|file| {
+            let service = Service::from_file(&file);
+            (file, service)
+        })
+        .collect::<Vec<_>>();
+    let loaded = services
+        .iter()
+        .filter_map(|(_, service)| service.as_ref().ok())
+        .collect::<Vec<_>>();
+
+    let mut valid = true;
+    // first file defining each service name, a file of another dir shadows it.
+    let mut names = HashMap::<String, PathBuf>::new();
+
+    for (file, service) in &services {
+        let service = match service {
+            Ok(service) => service,
+            Err(e) => {
+                // the toml error already points to the line and column.
+                let error = match e {
+                    Error::ParseError { source, .. } => source.to_string(),
+                    e => e.to_string(),
+                };
+                diagnostic(file, None, error.trim_end());
+                valid = false;
+                continue;
+            }
+        };
+
+        let contents = std::fs::read_to_string(file).unwrap_or_default();
+        let mut errors = vec![];
+
+        if let Some(first) = names
+            .get(&service.name)
+            .filter(|first| first.parent() == file.parent())
+        {
+            errors.push((
+                "name".to_string(),
+                format!(
+                    "duplicate service name `{}`, also defined in {}",
+                    service.name,
+                    first.display()
+                ),
+            ));
+        } else {
+            names.insert(service.name.clone(), file.clone());
+        }
+
+        if is_dir {
+            for edge in service.edges() {
+                if !loaded.iter().any(|service| provides(service, &edge.to)) {
+                    let key = edge.dependency.to_string();
+                    errors.push((key, format!("unknown service `{}`", edge.to)));
+                }
+            }
+            for handler in &service.on_failure {
+                if !loaded.iter().any(|service| provides(service, handler)) {
+                    let error = format!("unknown service `{handler}`");
+                    errors.push(("on_failure".to_string(), error));
+                }
+            }
+        }
+
+        match service.validate() {
+            // the log dir belongs to the daemon's environment, not to the service file.
+            Ok(()) | Err(FailReason::LogDirNotWritable) => {}
+            Err(reason) => {
+                let key = match reason {
+                    FailReason::UserNotFound => "user",
+                    FailReason::GroupNotFound => "group",
+                    FailReason::WorkingDirNotFound => "working_dir",
+                    FailReason::RootDirNotFound => "root_dir",
+                    _ => "executable",
+                };
+                errors.push((key.to_string(), reason.to_string()));
+            }
+        }
+
+        if errors.is_empty() {
+            println!("{}", format!("{}: ok", file.display()).green());
+        }
+        for (key, error) in errors {
+            diagnostic(file, line_of(&contents, &key), &error);
+            valid = false;
+        }
+    }
+
+    valid
+}
+
+/// Whether `service` defines the service named `name`, directly or as an instance or replica.
+fn provides(service: &Service, name: &str) -> bool {
+    if service.is_template() {
+        return name
+            .split_once('@')
+            .is_some_and(|(template, _)| service.name == format!("{template}@"));
+    }
+
+    if service.replicas.is_some() {
+        return name
+            .rsplit_once('-')
+            .is_some_and(|(set, index)| service.name == set && index.parse::<u32>().is_ok());
+    }
+
+    service.name == name
+}
+
+/// Print a diagnostic for a service file.
+fn diagnostic(file: &Path, line: Option<usize>, error: &str) {
+    match line {
+        Some(line) => println!("{}", format!("{}:{line}:1: {error}", file.display()).red()),
+        None => println!("{}", format!("{}: {error}", file.display()).red()),
+    }
+}
+
+/// Line number (1-based) where `key` is set in a toml document.
+fn line_of(contents: &str, key: &str) -> Option<usize> {
+    contents
+        .lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+        .map(|line| line + 1)
+}
@@ -0,0 +1,76 @@
+//! Refresh of the status of the services in place, with `--watch`.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use colored::*;
+use operator::service::ServiceInfo;
+
+/// How long a service that changed state stays highlighted.
+const HIGHLIGHT: Duration = Duration::from_secs(10);
+
+/// The recent changes of state of the services.
+#[derive(Default)]
+pub struct Transitions {
+    /// state of the services at the last refresh.
+    last: HashMap<String, &'static str>,
+    /// state the services changed from, and when.
+    changed: HashMap<String, (&'static str, Instant)>,
+}
+
+impl Transitions {
+    /// Record the states of a refresh.
+    fn update(&mut self, services: &[ServiceInfo]) {
+        let now = Instant::now();
+        self.changed.retain(|_, (_, at)| now - *at < HIGHLIGHT);
+        for info in services {
+            let state = info.status.name();
+            match self.last.insert(info.name.clone(), state) {
+                Some(last) if last != state => {
+                    self.changed.insert(info.name.clone(), (last, now));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The state a service changed from lately, if it did.
+    pub fn from(&self, name: &str) -> Option<&'static str> {
+        self.changed.get(name).map(|(state, _)| *state)
+    }
+}
+
+/// Print the services returned by `fetch` every `interval` seconds, until interrupted.
+pub fn watch(
+    command: &str,
+    interval: u64,
+    fetch: impl Fn() -> Vec<ServiceInfo>,
+    print: impl Fn(Vec<ServiceInfo>, &Transitions),
+) -> ! {
+    let mut transitions = Transitions::default();
+    loop {
+        let services = fetch();
+        transitions.update(&services);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        // clear the screen and move the cursor to the top left.
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "{}",
+            format!(
+                "Every {interval}s: operatorctl {command}, {}",
+                crate::datetime(now)
+            )
+            .bold()
+        );
+        println!();
+        print(services, &transitions);
+
+        std::thread::sleep(Duration::from_secs(interval));
+    }
+}